@@ -0,0 +1,43 @@
+/// Writes the inputs for the fuzzer
+
+use std::io::Write;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+	let in_dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("in");
+	if in_dir.exists() {
+		std::fs::remove_dir_all(&in_dir)?;
+	}
+	std::fs::create_dir(&in_dir)?;
+
+	let c2d_topic_name = format!(
+		"{}%24.mid=message-id&%24.to=%2Fdevices%2Fdevice%2Fmessages%2Fdevicebound&iothub-ack=full&%24.cid=correlation-id&foo=bar%20baz",
+		azure_iot_mqtt::FUZZ_C2D_PREFIX,
+	);
+
+	let publications: Vec<(&str, &str, &[u8])> = vec![
+		("c2d", c2d_topic_name.as_str(), &b"\x00\x01\x02\xFF\xFE\xFD"[..]),
+
+		("direct-method", "$iothub/methods/POST/reboot/?$rid=1", &br#"{"delay":5}"#[..]),
+
+		("twin-initial", "$iothub/twin/res/200/?$rid=1", &br#"{"desired":{"$version":5,"foo":"bar"},"reported":{"$version":3,"baz":[1,2,3]}}"#[..]),
+
+		("twin-patch", "$iothub/twin/PATCH/properties/desired/?$version=6", &br#"{"$version":6,"foo":null}"#[..]),
+
+		("twin-reported", "$iothub/twin/res/204/?$rid=2&$version=4", &b""[..]),
+
+		("twin-throttled", "$iothub/twin/res/429/?$rid=3", &b""[..]),
+	];
+
+	for (filename, topic_name, payload) in publications {
+		let file = std::fs::OpenOptions::new().create(true).write(true).open(in_dir.join(filename))?;
+		let mut file = std::io::BufWriter::new(file);
+
+		file.write_all(topic_name.as_bytes())?;
+		file.write_all(b"\0")?;
+		file.write_all(payload)?;
+
+		file.flush()?;
+	}
+
+	Ok(())
+}
@@ -0,0 +1,18 @@
+///     rm -rf out/ && cargo afl build && cargo afl fuzz -i in -o out target/debug/azure-iot-mqtt-fuzz
+
+fn main() {
+	afl::fuzz(|data| {
+		// The input is the topic name and the payload, separated by the first NUL byte.
+		let mut parts = data.splitn(2, |&b| b == b'\0');
+		let topic_name = parts.next().unwrap_or_default();
+		let payload = parts.next().unwrap_or_default();
+
+		// Topic names that are not valid UTF-8 are rejected by `mqtt::proto::PacketCodec`, so they can never reach the parser.
+		let topic_name = match std::str::from_utf8(topic_name) {
+			Ok(topic_name) => topic_name,
+			Err(_) => return,
+		};
+
+		azure_iot_mqtt::fuzz_parse_publication(topic_name, payload);
+	})
+}
@@ -25,6 +25,12 @@ struct Options {
     )]
     iothub_hostname: String,
 
+    #[structopt(
+        help = "Hostname of the gateway to connect to instead of the IoT Hub, if any",
+        long = "gateway-hostname"
+    )]
+    gateway_hostname: Option<String>,
+
     #[structopt(
         help = "SAS token for token authentication",
         long = "sas-token",
@@ -95,6 +101,7 @@ fn main() {
     let Options {
         device_id,
         iothub_hostname,
+        gateway_hostname,
         sas_token,
         certificate_file,
         certificate_file_password,
@@ -113,6 +120,7 @@ fn main() {
 
     let client = azure_iot_mqtt::device::Client::new(
         iothub_hostname,
+        gateway_hostname,
         &device_id,
         authentication,
         if use_websocket {
@@ -120,9 +128,10 @@ fn main() {
         } else {
             azure_iot_mqtt::Transport::Tcp
         },
-        will.map(String::into_bytes),
+        will.map(|will| will.into_bytes().into()),
         max_back_off,
         keep_alive,
+        azure_iot_mqtt::DEFAULT_HANDLE_CHANNEL_CAPACITY,
     )
     .expect("could not create client");
 
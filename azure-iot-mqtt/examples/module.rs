@@ -28,6 +28,12 @@ struct Options {
     )]
     iothub_hostname: String,
 
+    #[structopt(
+        help = "Hostname of the gateway to connect to instead of the IoT Hub, if any",
+        long = "gateway-hostname"
+    )]
+    gateway_hostname: Option<String>,
+
     #[structopt(
         help = "SAS token for token authentication",
         long = "sas-token",
@@ -99,6 +105,7 @@ fn main() {
         device_id,
         module_id,
         iothub_hostname,
+        gateway_hostname,
         sas_token,
         certificate_file,
         certificate_file_password,
@@ -117,6 +124,7 @@ fn main() {
 
     let client = azure_iot_mqtt::module::Client::new(
         iothub_hostname,
+        gateway_hostname,
         &device_id,
         &module_id,
         authentication,
@@ -125,9 +133,10 @@ fn main() {
         } else {
             azure_iot_mqtt::Transport::Tcp
         },
-        will.map(String::into_bytes),
+        will.map(|will| will.into_bytes().into()),
         max_back_off,
         keep_alive,
+        azure_iot_mqtt::DEFAULT_HANDLE_CHANNEL_CAPACITY,
     )
     .expect("could not create client");
 
@@ -7,13 +7,22 @@ use futures::Stream;
 /// A `Client` is a [`Stream`] of [`Message`]s. These messages contain twin state messages, direct method requests and cloud-to-device messages.
 ///
 /// It automatically reconnects if the connection to the server is broken. Each reconnection will yield one [`Message::TwinInitial`] message.
-pub struct Client {
-    inner: mqtt::Client<crate::IoSource>,
-
+pub struct Client<IoS = crate::IoSource>
+where
+    IoS: mqtt::IoSource,
+{
+    inner: mqtt::Client<IoS>,
+
+    iothub_hostname: String,
+    device_id: String,
     c2d_prefix: String,
 
     state: State,
-    previous_request_id: u8,
+
+    /// The `$rid` of the last twin request. Shared by the initial twin state and reported twin state requests so that
+    /// every request has a different `$rid`, and wide enough to never wrap, so a late response to an old request
+    /// can't be mistaken for the response to a newer one.
+    previous_request_id: u64,
 
     desired_properties: crate::twin_state::desired::State,
     reported_properties: crate::twin_state::reported::State,
@@ -36,6 +45,12 @@ impl Client {
     ///
     ///     The hostname of the Azure IoT Hub. Eg "foo.azure-devices.net"
     ///
+    /// * `gateway_hostname`
+    ///
+    ///     If set, the hostname of the gateway that the client connects to instead of the Azure IoT Hub, such as an IoT Edge device.
+    ///     The client resolves and verifies the TLS certificate of this hostname, but still identifies itself to the Azure IoT Hub
+    ///     named by `iothub_hostname`.
+    ///
     /// * `device_id`
     ///
     ///     The ID of the device.
@@ -51,31 +66,39 @@ impl Client {
     /// * `will`
     ///
     ///     If set, this message will be published by the server if this client disconnects uncleanly.
+    ///     A payload can be converted into a message without any properties with `.into()`.
     ///     Use the handle from `.inner().shutdown_handle()` to disconnect cleanly.
     ///
     /// * `max_back_off`
     ///
     ///     Every connection failure or server error will double the back-off period, to a maximum of this value.
+    ///     Values smaller than [`mqtt::ExponentialBackoff::MIN_MAX_BACK_OFF`] are raised to it.
     ///
     /// * `keep_alive`
     ///
     ///     The keep-alive time advertised to the server. The client will ping the server at half this interval.
+    ///
+    /// * `handle_channel_capacity`
+    ///
+    ///     The capacity of the channels behind the client's handles, such as [`crate::DirectMethodResponseHandle`] and [`crate::ReportTwinStateHandle`].
+    ///     See [`mqtt::ClientBuilder::handle_channel_capacity`]. Use [`crate::DEFAULT_HANDLE_CHANNEL_CAPACITY`] if unsure.
     #[allow(clippy::new_ret_no_self)]
     pub fn new(
         iothub_hostname: String,
+        gateway_hostname: Option<String>,
         device_id: &str,
         authentication: crate::Authentication,
         transport: crate::Transport,
 
-        will: Option<Vec<u8>>,
+        will: Option<crate::OutgoingMessage>,
 
         max_back_off: std::time::Duration,
         keep_alive: std::time::Duration,
+        handle_channel_capacity: usize,
     ) -> Result<Self, crate::CreateClientError> {
-        let c2d_prefix = format!("devices/{}/messages/devicebound/", device_id);
-
         let inner = crate::client_new(
-            iothub_hostname,
+            iothub_hostname.clone(),
+            gateway_hostname,
             device_id,
             None,
             authentication,
@@ -83,46 +106,286 @@ impl Client {
             will,
             max_back_off,
             keep_alive,
+            handle_channel_capacity,
         )?;
 
+        Ok(Client::from_inner(
+            inner,
+            iothub_hostname,
+            device_id,
+            max_back_off,
+            keep_alive,
+            handle_channel_capacity,
+        ))
+    }
+
+    /// Returns details about the most recent connection to the Azure IoT Hub, if any
+    pub fn connection_diagnostics(&self) -> Option<crate::ConnectionDiagnostics> {
+        self.inner
+            .io_source()
+            .and_then(crate::IoSource::connection_diagnostics)
+    }
+
+    /// Sets a callback that's invoked on every new TCP connection to the Azure IoT Hub, before the TLS handshake.
+    /// See [`crate::IoSource::set_configure_socket`]
+    pub fn set_configure_socket<F>(&mut self, configure_socket: F)
+    where
+        F: FnMut(&tokio::net::TcpStream) -> std::io::Result<()> + Send + 'static,
+    {
+        if let Some(io_source) = self.inner.io_source() {
+            io_source.set_configure_socket(configure_socket);
+        }
+    }
+
+    /// Sets whether new TCP connections to the Azure IoT Hub disable Nagle's algorithm.
+    /// See [`crate::IoSource::set_nodelay`]
+    pub fn set_nodelay(&mut self, nodelay: bool) {
+        if let Some(io_source) = self.inner.io_source() {
+            io_source.set_nodelay(nodelay);
+        }
+    }
+
+    /// Sets how long a write to the connection may stall before the client reconnects.
+    /// See [`crate::IoSource::set_write_timeout`]
+    pub fn set_write_timeout(&mut self, write_timeout: Option<std::time::Duration>) {
+        if let Some(io_source) = self.inner.io_source() {
+            io_source.set_write_timeout(write_timeout);
+        }
+    }
+}
+
+impl<IoS> Client<IoS>
+where
+    IoS: mqtt::IoSource,
+{
+    /// Creates a new `Client` that uses the given [`mqtt::IoSource`] to connect to the Azure IoT Hub,
+    /// instead of connecting over TCP, TLS and optionally WebSockets itself.
+    ///
+    /// This is useful for transports that `Client::new` does not support, and for tests.
+    ///
+    /// * `iothub_hostname`
+    ///
+    ///     The hostname of the Azure IoT Hub. Eg "foo.azure-devices.net"
+    ///
+    /// * `device_id`
+    ///
+    ///     The ID of the device.
+    ///
+    /// * `sas_token`
+    ///
+    ///     If set, the SAS token sent as the password. Leave unset if the `io_source` authenticates the connection itself, such as with a client certificate.
+    ///
+    /// * `io_source`
+    ///
+    ///     The source of connections to the Azure IoT Hub.
+    ///
+    /// * `will`
+    ///
+    ///     If set, this message will be published by the server if this client disconnects uncleanly.
+    ///     A payload can be converted into a message without any properties with `.into()`.
+    ///     Use the handle from `.inner().shutdown_handle()` to disconnect cleanly.
+    ///
+    /// * `max_back_off`
+    ///
+    ///     Every connection failure or server error will double the back-off period, to a maximum of this value.
+    ///     Values smaller than [`mqtt::ExponentialBackoff::MIN_MAX_BACK_OFF`] are raised to it.
+    ///
+    /// * `keep_alive`
+    ///
+    ///     The keep-alive time advertised to the server. The client will ping the server at half this interval.
+    ///
+    /// * `handle_channel_capacity`
+    ///
+    ///     The capacity of the channels behind the client's handles, such as [`crate::DirectMethodResponseHandle`] and [`crate::ReportTwinStateHandle`].
+    ///     See [`mqtt::ClientBuilder::handle_channel_capacity`]. Use [`crate::DEFAULT_HANDLE_CHANNEL_CAPACITY`] if unsure.
+    pub fn with_io_source(
+        iothub_hostname: &str,
+        device_id: &str,
+        sas_token: Option<String>,
+        io_source: IoS,
+
+        will: Option<crate::OutgoingMessage>,
+
+        max_back_off: std::time::Duration,
+        keep_alive: std::time::Duration,
+        handle_channel_capacity: usize,
+    ) -> Result<Self, crate::CreateClientError> {
+        let inner = crate::client_new_with_io_source(
+            iothub_hostname,
+            device_id,
+            None,
+            sas_token,
+            io_source,
+            will,
+            max_back_off,
+            keep_alive,
+            handle_channel_capacity,
+        )?;
+
+        Ok(Client::from_inner(
+            inner,
+            iothub_hostname.to_string(),
+            device_id,
+            max_back_off,
+            keep_alive,
+            handle_channel_capacity,
+        ))
+    }
+
+    fn from_inner(
+        inner: mqtt::Client<IoS>,
+        iothub_hostname: String,
+        device_id: &str,
+        max_back_off: std::time::Duration,
+        keep_alive: std::time::Duration,
+        handle_channel_capacity: usize,
+    ) -> Self {
+        let c2d_prefix = format!("devices/{}/messages/devicebound/", device_id);
+
         let (direct_method_response_send, direct_method_response_recv) =
-            futures::sync::mpsc::channel(0);
+            futures::sync::mpsc::channel(handle_channel_capacity);
 
-        Ok(Client {
+        Client {
             inner,
 
+            iothub_hostname,
+            device_id: device_id.to_string(),
             c2d_prefix,
 
             state: State::WaitingForSubscriptions {
                 reset_session: true,
             },
-            previous_request_id: u8::max_value(),
+            previous_request_id: u64::max_value(),
 
             desired_properties: crate::twin_state::desired::State::new(max_back_off, keep_alive),
-            reported_properties: crate::twin_state::reported::State::new(max_back_off, keep_alive),
+            reported_properties: crate::twin_state::reported::State::new(
+                max_back_off,
+                keep_alive,
+                handle_channel_capacity,
+            ),
 
             direct_method_response_send,
             direct_method_response_recv,
-        })
+        }
     }
 
     /// Gets a reference to the inner `mqtt::Client`
-    pub fn inner(&self) -> &mqtt::Client<crate::IoSource> {
+    pub fn inner(&self) -> &mqtt::Client<IoS> {
         &self.inner
     }
 
+    /// The hostname of the Azure IoT Hub that this client connects to
+    pub fn iothub_hostname(&self) -> &str {
+        &self.iothub_hostname
+    }
+
+    /// The ID of the device
+    pub fn device_id(&self) -> &str {
+        &self.device_id
+    }
+
     /// Returns a handle that can be used to respond to direct methods
     pub fn direct_method_response_handle(&self) -> crate::DirectMethodResponseHandle {
         crate::DirectMethodResponseHandle(self.direct_method_response_send.clone())
     }
 
+    /// Returns a handle that can be used to send device-to-cloud messages, such as telemetry, to the Azure IoT Hub
+    pub fn send_event_handle(&self) -> Result<crate::SendEventHandle, mqtt::PublishError> {
+        let publish_handle = self.inner.publish_handle()?;
+        Ok(crate::SendEventHandle::new(
+            publish_handle,
+            crate::events_topic_name(&self.device_id, None),
+        ))
+    }
+
     /// Returns a handle that can be used to publish reported twin state to the Azure IoT Hub
     pub fn report_twin_state_handle(&self) -> crate::ReportTwinStateHandle {
         self.reported_properties.report_twin_state_handle()
     }
+
+    /// Sets reported properties that the device already has, such as ones it saved to persistent storage before restarting.
+    ///
+    /// When the client receives the first [`Message::TwinInitial`], these properties are applied like a [`crate::ReportTwinStateRequest::Patch`]
+    /// on top of the reported properties the Azure IoT Hub has, before any reports requested with [`crate::ReportTwinStateHandle`].
+    /// Only the properties whose values differ from the hub's are reported, so nothing is reported if the hub already has them.
+    ///
+    /// This should be called before the client is first polled. Afterwards, it's the same as reporting a patch.
+    pub fn set_initial_reported_state(
+        &mut self,
+        properties: std::collections::HashMap<String, serde_json::Value>,
+    ) {
+        self.reported_properties
+            .set_initial_reported_state(properties);
+    }
+
+    /// Sets the largest payload of a report of the twin state, in bytes of JSON.
+    /// Defaults to [`crate::MAX_REPORTED_TWIN_STATE_LEN`]. `None` means no limit.
+    ///
+    /// Reports with larger payloads aren't sent to the Azure IoT Hub. The client yields a [`Message::ReportedTwinStateTooLarge`] instead.
+    pub fn set_max_reported_twin_state_len(&mut self, max_reported_twin_state_len: Option<usize>) {
+        self.reported_properties
+            .set_max_payload_len(max_reported_twin_state_len);
+    }
+
+    /// Sets whether reports of the twin state requested while an earlier report is waiting for its acknowledgement
+    /// are combined into a single report. Defaults to `true`.
+    ///
+    /// If `false`, each request that changes the reported properties is reported on its own, so the Azure IoT Hub sees
+    /// every intermediate state. This sends more reports, which makes it more likely that the hub throttles them.
+    /// See [`crate::ReportTwinStateHandle::report_twin_state`]
+    pub fn set_coalesce_reported_twin_state(&mut self, coalesce: bool) {
+        self.reported_properties.set_coalesce_reports(coalesce);
+    }
+
+    /// Sets the QoS of the client's subscriptions to twin responses, desired properties patches and direct method requests.
+    /// The client re-subscribes with the new QoS if it has already subscribed.
+    ///
+    /// Fails with [`crate::DefaultSubscriptionQosError::UnsupportedQoS`] if the Azure IoT Hub doesn't support one of the QoS.
+    /// See [`crate::DefaultSubscriptionQos`]
+    pub fn set_default_subscription_qos(
+        &mut self,
+        qos: crate::DefaultSubscriptionQos,
+    ) -> Result<(), crate::DefaultSubscriptionQosError> {
+        crate::set_default_subscription_qos(&mut self.inner, qos)
+    }
+
+    /// Sets the properties that the client appends to its username, such as `DeviceClientType`.
+    /// Defaults to [`crate::default_username_properties`].
+    ///
+    /// The properties are percent-encoded like the properties of a message. Set `DeviceClientType` to override the SDK type
+    /// reported to the Azure IoT Hub, or use an empty map to not send any properties.
+    ///
+    /// This takes effect on the next connection to the Azure IoT Hub.
+    pub fn set_username_properties(
+        &mut self,
+        properties: &std::collections::BTreeMap<String, String>,
+    ) {
+        self.inner.set_username(Some(crate::username(
+            &self.iothub_hostname,
+            &self.device_id,
+            None,
+            properties,
+        )));
+    }
+
+    /// Sets a callback that returns the SAS token to authenticate with every time the client connects to the Azure IoT Hub,
+    /// instead of the token the client was created with. See [`mqtt::Client::set_password_callback`]
+    ///
+    /// This lets the client reconnect after its token has expired, eg with a token that the [`mqtt::IoSource`] got while connecting.
+    pub fn set_sas_token_callback<F>(&mut self, sas_token_callback: F)
+    where
+        F: FnMut() -> Option<String> + Send + 'static,
+    {
+        self.inner
+            .set_password_callback(Some(Box::new(sas_token_callback)));
+    }
 }
 
-impl Stream for Client {
+impl<IoS> Stream for Client<IoS>
+where
+    IoS: mqtt::IoSource + 'static,
+    <<IoS as mqtt::IoSource>::Future as futures::Future>::Error: std::fmt::Display,
+{
     type Item = Message;
     type Error = mqtt::Error;
 
@@ -141,10 +404,8 @@ impl Stream for Client {
                     payload,
                     ack_sender,
                 } = direct_method_response;
-                let payload = serde_json::to_vec(&payload)
-                    .expect("cannot fail to serialize serde_json::Value");
                 let publication = mqtt::proto::Publication {
-                    topic_name: format!("$iothub/methods/res/{}/?$rid={}", status, request_id),
+                    topic_name: crate::direct_method_response_topic_name(status, &request_id),
                     qos: mqtt::proto::QoS::AtLeastOnce,
                     retain: false,
                     payload,
@@ -162,6 +423,7 @@ impl Stream for Client {
                 State::WaitingForSubscriptions { reset_session } => {
                     if *reset_session {
                         match self.inner.poll()? {
+							futures::Async::Ready(Some(mqtt::Event::Connecting { .. })) |
 							futures::Async::Ready(Some(mqtt::Event::NewConnection { .. })) => (),
 
 							futures::Async::Ready(Some(mqtt::Event::Publication(publication))) => match InternalMessage::parse(publication, &self.c2d_prefix) {
@@ -171,6 +433,9 @@ impl Stream for Client {
 								Ok(InternalMessage::DirectMethod { name, payload, request_id }) =>
 									return Ok(futures::Async::Ready(Some(Message::DirectMethod { name, payload, request_id }))),
 
+								Ok(InternalMessage::Unrecognized(publication)) =>
+									return Ok(futures::Async::Ready(Some(Message::Unrecognized(publication)))),
+
 								Ok(message @ InternalMessage::TwinState(_)) =>
 									log::debug!("Discarding message {:?} because we haven't finished subscribing yet", message),
 
@@ -183,6 +448,12 @@ impl Stream for Client {
 								self.state = State::Idle;
 							},
 
+							// The subscriptions are tracked through the SubscriptionUpdates above
+							futures::Async::Ready(Some(mqtt::Event::SubscriptionsSynced)) => (),
+
+							// The client finished shutting down, so the next poll will end the stream
+							futures::Async::Ready(Some(mqtt::Event::Disconnected { .. })) => (),
+
 							futures::Async::Ready(None) => return Ok(futures::Async::Ready(None)),
 
 							futures::Async::NotReady => return Ok(futures::Async::NotReady),
@@ -196,8 +467,11 @@ impl Stream for Client {
                     let mut continue_loop = false;
 
                     let mut twin_state_message = match self.inner.poll()? {
+                        futures::Async::Ready(Some(mqtt::Event::Connecting { .. })) => continue,
+
                         futures::Async::Ready(Some(mqtt::Event::NewConnection {
                             reset_session,
+                            ..
                         })) => {
                             self.state = State::WaitingForSubscriptions { reset_session };
                             self.desired_properties.new_connection();
@@ -225,6 +499,12 @@ impl Stream for Client {
                                     })));
                                 }
 
+                                Ok(InternalMessage::Unrecognized(publication)) => {
+                                    return Ok(futures::Async::Ready(Some(Message::Unrecognized(
+                                        publication,
+                                    ))));
+                                }
+
                                 Ok(InternalMessage::TwinState(message)) => {
                                     // There may be more messages, so continue the loop
                                     continue_loop = true;
@@ -247,6 +527,12 @@ impl Stream for Client {
                             unreachable!()
                         }
 
+                        // Follows the SubscriptionUpdates that moved the client to this state
+                        futures::Async::Ready(Some(mqtt::Event::SubscriptionsSynced)) => continue,
+
+                        // The client finished shutting down, so the next poll will end the stream
+                        futures::Async::Ready(Some(mqtt::Event::Disconnected { .. })) => continue,
+
                         futures::Async::Ready(None) => return Ok(futures::Async::Ready(None)),
 
                         futures::Async::NotReady => None,
@@ -293,6 +579,16 @@ impl Stream for Client {
                                     version,
                                 ))));
                             }
+                            crate::twin_state::reported::Message::Rejected(status) => {
+                                return Ok(futures::Async::Ready(Some(
+                                    Message::ReportedTwinStateRejected(status),
+                                )));
+                            }
+                            crate::twin_state::reported::Message::TooLarge { len, max } => {
+                                return Ok(futures::Async::Ready(Some(
+                                    Message::ReportedTwinStateTooLarge { len, max },
+                                )));
+                            }
                         },
                         Ok(crate::twin_state::Response::Continue) => continue_loop = true,
                         Ok(crate::twin_state::Response::NotReady) => (),
@@ -319,7 +615,7 @@ impl Stream for Client {
 /// A message generated by a [`Client`]
 #[derive(Debug)]
 pub enum Message {
-    /// A cloud-to-device message
+    /// A cloud-to-device message. See [`CloudToDeviceMessage`] for how it's acknowledged.
     CloudToDevice(CloudToDeviceMessage),
 
     /// A direct method invocation
@@ -332,15 +628,35 @@ pub enum Message {
     /// The server acknowledged a report of the twin state. Contains the version number of the updated section.
     ReportedTwinState(usize),
 
+    /// The server rejected a report of the twin state with a status that retrying wouldn't fix, such as 400 Bad Request,
+    /// or 412 Precondition Failed for a [`crate::ReportTwinStateRequest::ConditionalPatch`] whose expected version was outdated.
+    ///
+    /// The rejected changes are discarded, so later reports are applied on top of the reported properties the server still has.
+    /// Reports that fail with 429 Too Many Requests or a 5xx status are retried with back-off instead.
+    ///
+    /// The server rejects a report with 413 Payload Too Large if its reported properties would exceed their size limit,
+    /// even if the report itself is small.
+    ReportedTwinStateRejected(crate::Status),
+
+    /// A report of the twin state wasn't sent because its payload was larger than the maximum set with
+    /// [`Client::set_max_reported_twin_state_len`]. Contains the length of the payload and the maximum.
+    ///
+    /// The changes are discarded like those of a rejected report. Split them into smaller reports and report those instead.
+    ReportedTwinStateTooLarge { len: usize, max: usize },
+
     /// The full twin state, as currently stored in the Azure IoT Hub.
     TwinInitial(crate::TwinState),
 
     /// A patch to the twin state that should be applied to the current state to get the new state.
     TwinPatch(crate::TwinProperties),
+
+    /// A publication on a topic that this client doesn't recognize, such as from a newer Azure IoT Hub feature
+    /// or custom routing. It is passed through as it was received.
+    Unrecognized(mqtt::ReceivedPublication),
 }
 
 #[derive(Debug)]
-enum MessageParseError {
+pub(crate) enum MessageParseError {
     C2DMessageMissingRequiredProperty(&'static str),
     Json(serde_json::Error),
     UnrecognizedMessage(crate::twin_state::MessageParseError),
@@ -375,6 +691,17 @@ impl std::error::Error for MessageParseError {
     }
 }
 
+/// A cloud-to-device message
+///
+/// Over AMQP and HTTPS, a device settles a cloud-to-device message by completing, rejecting or abandoning it.
+/// The Azure IoT Hub's MQTT support has no equivalent of rejecting or abandoning a message. It completes the message
+/// when the device acks its publication, and redelivers it if the device disconnects before acking it.
+///
+/// The client acks the publication as soon as it has received it, before the message is yielded to the application,
+/// so there is no handle to settle the message with, and every message yielded by the client has already been completed.
+/// In particular, if the sender asked for feedback with [`crate::IotHubAck::Full`] or [`crate::IotHubAck::Positive`],
+/// the hub reports the message as delivered even if the application fails to process it. An application that needs to
+/// reject messages should report the failure to the sender some other way, such as with its own device-to-cloud message.
 #[derive(Debug)]
 pub struct CloudToDeviceMessage {
     pub system_properties: crate::SystemProperties,
@@ -383,7 +710,7 @@ pub struct CloudToDeviceMessage {
 }
 
 #[derive(Debug)]
-enum InternalMessage {
+pub(crate) enum InternalMessage {
     CloudToDevice(CloudToDeviceMessage),
 
     DirectMethod {
@@ -393,10 +720,12 @@ enum InternalMessage {
     },
 
     TwinState(crate::twin_state::InternalTwinStateMessage),
+
+    Unrecognized(mqtt::ReceivedPublication),
 }
 
 impl InternalMessage {
-    fn parse(
+    pub(crate) fn parse(
         publication: mqtt::ReceivedPublication,
         c2d_prefix: &str,
     ) -> Result<Self, MessageParseError> {
@@ -405,10 +734,10 @@ impl InternalMessage {
             let mut properties: std::collections::HashMap<_, _> = Default::default();
 
             for (key, value) in
-                url::form_urlencoded::parse(publication.topic_name[c2d_prefix.len()..].as_bytes())
+                crate::property_bag::parse(&publication.topic_name[c2d_prefix.len()..])
             {
-                if let Some(value) = system_properties.try_property(&*key, value) {
-                    properties.insert(key.into_owned(), value.into_owned());
+                if let Some(value) = system_properties.try_property(&key, value.into()) {
+                    properties.insert(key, value.into_owned());
                 }
             }
 
@@ -420,12 +749,11 @@ impl InternalMessage {
                 properties,
                 data: publication.payload,
             }))
-        } else if let Some(captures) = crate::DIRECT_METHOD_REGEX.captures(&publication.topic_name)
+        } else if let Some((name, request_id)) =
+            crate::parse_direct_method_topic_name(&publication.topic_name)
         {
-            let name = captures[1].to_string();
             let payload =
                 serde_json::from_slice(&publication.payload).map_err(MessageParseError::Json)?;
-            let request_id = captures[2].to_string();
 
             Ok(InternalMessage::DirectMethod {
                 name,
@@ -435,8 +763,129 @@ impl InternalMessage {
         } else {
             match crate::twin_state::InternalTwinStateMessage::parse(publication) {
                 Ok(message) => Ok(InternalMessage::TwinState(message)),
+                Err(crate::twin_state::MessageParseError::UnrecognizedMessage(publication)) => {
+                    Ok(InternalMessage::Unrecognized(publication))
+                }
                 Err(err) => Err(MessageParseError::UnrecognizedMessage(err)),
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use futures::{Future, Stream};
+
+    #[test]
+    fn twin_and_direct_method_over_io_source() {
+        let hub = crate::fake_hub::FakeHub::new(
+            serde_json::json!({ "desired": { "$version": 2, "interval": 5 }, "reported": { "$version": 1 } }),
+            vec![mqtt::proto::Packet::Publish {
+                packet_identifier_dup_qos: mqtt::proto::PacketIdentifierDupQoS::AtLeastOnce(
+                    mqtt::proto::PacketIdentifier::new(1).unwrap(),
+                    false,
+                ),
+                retain: false,
+                topic_name: "$iothub/methods/POST/reboot/?$rid=7".to_string(),
+                payload: br#"{"delay":5}"#.to_vec(),
+            }],
+        );
+
+        let client = super::Client::with_io_source(
+            "example.azure-devices.net",
+            "device1",
+            Some("token".to_string()),
+            hub.io_source(),
+            None,
+            std::time::Duration::from_secs(0),
+            std::time::Duration::from_secs(60),
+            crate::DEFAULT_HANDLE_CHANNEL_CAPACITY,
+        )
+        .unwrap();
+
+        let direct_method_response_handle = client.direct_method_response_handle();
+        let mut shutdown_handle = Some(client.inner().shutdown_handle().unwrap());
+
+        let mut runtime = tokio::runtime::current_thread::Runtime::new()
+            .expect("couldn't initialize tokio runtime");
+
+        // Respond to the direct method, and shut down once the hub has acked the response
+        let messages: Vec<_> = runtime
+            .block_on(
+                client
+                    .inspect(move |message| {
+                        if let super::Message::DirectMethod { request_id, .. } = message {
+                            let shutdown_handle = shutdown_handle.take().unwrap();
+                            tokio::spawn(
+                                direct_method_response_handle
+                                    .respond(
+                                        request_id.clone(),
+                                        crate::Status::Ok,
+                                        serde_json::json!({ "rebooting": true }),
+                                    )
+                                    .then(move |result| {
+                                        result.unwrap();
+                                        shutdown_handle.shutdown()
+                                    })
+                                    .map_err(|err| panic!("{:?}", err)),
+                            );
+                        }
+                    })
+                    .collect(),
+            )
+            .unwrap();
+
+        match &messages[..] {
+            [super::Message::TwinInitial(twin_state), super::Message::DirectMethod {
+                name,
+                payload,
+                request_id,
+            }] => {
+                assert_eq!(twin_state.desired.version, 2);
+                assert_eq!(
+                    twin_state.desired.properties["interval"],
+                    serde_json::json!(5)
+                );
+                assert_eq!(twin_state.reported.version, 1);
+
+                assert_eq!(name, "reboot");
+                assert_eq!(payload, &serde_json::json!({ "delay": 5 }));
+                assert_eq!(request_id, "7");
+            }
+            messages => panic!("unexpected messages {:?}", messages),
+        }
+
+        let received = hub.received();
+        match &received[0] {
+            mqtt::proto::Packet::Connect {
+                username,
+                password,
+                client_id,
+                ..
+            } => {
+                assert!(username
+                    .as_ref()
+                    .unwrap()
+                    .starts_with("example.azure-devices.net/device1/?"));
+                assert_eq!(password.as_ref().unwrap(), "token");
+                assert_eq!(
+                    client_id,
+                    &mqtt::proto::ClientId::IdWithCleanSession("device1".to_string())
+                );
+            }
+            packet => panic!("expected CONNECT but got {:?}", packet),
+        }
+        assert!(received.iter().any(|packet| match packet {
+            mqtt::proto::Packet::Publish {
+                topic_name,
+                payload,
+                ..
+            } => {
+                topic_name == "$iothub/methods/res/200/?$rid=7"
+                    && serde_json::from_slice::<serde_json::Value>(payload).unwrap()
+                        == serde_json::json!({ "rebooting": true })
+            }
+            _ => false,
+        }));
+    }
+}
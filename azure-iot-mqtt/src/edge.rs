@@ -0,0 +1,459 @@
+//! This module contains helpers for modules that run on an Azure IoT Edge device.
+//!
+//! The IoT Edge runtime tells every module how to connect to the Azure IoT Hub through `IOTEDGE_*` environment variables,
+//! and signs the module's SAS tokens through its workload API so that the module never sees the key.
+//! [`module_client`] uses these to create a [`crate::module::Client`] that connects through the device's Edge hub.
+
+use futures::{Future, Stream};
+
+/// The API version used if the IoT Edge runtime doesn't set `IOTEDGE_APIVERSION`
+pub const DEFAULT_API_VERSION: &str = "2018-06-28";
+
+/// The connection details that the IoT Edge runtime passes to a module through its environment
+#[derive(Clone, Debug)]
+pub struct Environment {
+    /// The hostname of the Azure IoT Hub, from `IOTEDGE_IOTHUBHOSTNAME`
+    pub iothub_hostname: String,
+
+    /// The hostname of the Edge hub that the module connects to instead of the Azure IoT Hub, from `IOTEDGE_GATEWAYHOSTNAME`, if set
+    pub gateway_hostname: Option<String>,
+
+    /// The ID of the device, from `IOTEDGE_DEVICEID`
+    pub device_id: String,
+
+    /// The ID of the module, from `IOTEDGE_MODULEID`
+    pub module_id: String,
+
+    /// The generation ID of the module, from `IOTEDGE_MODULEGENERATIONID`
+    pub module_generation_id: String,
+
+    /// The URI of the workload API, from `IOTEDGE_WORKLOADURI`. Eg "unix:///var/run/iotedge/workload.sock"
+    pub workload_uri: url::Url,
+
+    /// The version of the workload API to use, from `IOTEDGE_APIVERSION`. Defaults to [`DEFAULT_API_VERSION`].
+    pub api_version: String,
+}
+
+impl Environment {
+    /// Reads the connection details from the `IOTEDGE_*` environment variables of the current process
+    pub fn from_env() -> Result<Self, Error> {
+        fn var(name: &'static str) -> Result<String, Error> {
+            std::env::var(name).map_err(|_| Error::MissingEnvironmentVariable(name))
+        }
+
+        match var("IOTEDGE_AUTHSCHEME") {
+            Ok(ref auth_scheme) if auth_scheme == "sasToken" => (),
+            Ok(auth_scheme) => return Err(Error::UnsupportedAuthScheme(auth_scheme)),
+            Err(err) => return Err(err),
+        }
+
+        let workload_uri = var("IOTEDGE_WORKLOADURI")?;
+        let workload_uri = workload_uri
+            .parse()
+            .map_err(|err| Error::InvalidWorkloadUri(workload_uri, err))?;
+
+        Ok(Environment {
+            iothub_hostname: var("IOTEDGE_IOTHUBHOSTNAME")?,
+            gateway_hostname: var("IOTEDGE_GATEWAYHOSTNAME").ok(),
+            device_id: var("IOTEDGE_DEVICEID")?,
+            module_id: var("IOTEDGE_MODULEID")?,
+            module_generation_id: var("IOTEDGE_MODULEGENERATIONID")?,
+            workload_uri,
+            api_version: var("IOTEDGE_APIVERSION")
+                .unwrap_or_else(|_| DEFAULT_API_VERSION.to_string()),
+        })
+    }
+}
+
+/// Creates a module client that connects to the Edge hub, or to the Azure IoT Hub if the environment has no gateway.
+///
+/// The client authenticates with a SAS token from [`sas_token`] that expires after `token_validity`, and trusts
+/// the certificates from [`trust_bundle`] in addition to the system's trusted roots.
+/// A new token is signed by the workload API for every connection, so the client can keep reconnecting after
+/// the token of an earlier connection has expired. The Azure IoT Hub closes a connection once its token expires,
+/// so `token_validity` also bounds how long a single connection lasts.
+///
+/// See [`crate::module::Client::new`] for the other parameters.
+pub fn module_client(
+    environment: Environment,
+    transport: crate::Transport,
+
+    will: Option<crate::OutgoingMessage>,
+
+    max_back_off: std::time::Duration,
+    keep_alive: std::time::Duration,
+    handle_channel_capacity: usize,
+    token_validity: std::time::Duration,
+) -> impl Future<Item = crate::module::Client<IoSource>, Error = Error> {
+    trust_bundle(&environment).and_then(move |trusted_certificates| {
+        let inner = crate::IoSource::new(
+            environment
+                .gateway_hostname
+                .clone()
+                .unwrap_or_else(|| environment.iothub_hostname.clone())
+                .into(),
+            None.into(),
+            2 * keep_alive,
+            transport,
+        )
+        .map_err(Error::CreateClient)?;
+        inner.set_trusted_certificates(trusted_certificates);
+
+        let sas_token: std::sync::Arc<std::sync::Mutex<Option<String>>> = Default::default();
+        let io_source = IoSource {
+            inner,
+            environment: environment.clone(),
+            token_validity,
+            sas_token: sas_token.clone(),
+        };
+
+        let mut client = crate::module::Client::with_io_source(
+            &environment.iothub_hostname,
+            &environment.device_id,
+            &environment.module_id,
+            None,
+            io_source,
+            will,
+            max_back_off,
+            keep_alive,
+            handle_channel_capacity,
+        )
+        .map_err(Error::CreateClient)?;
+        client.set_sas_token_callback(move || {
+            sas_token.lock().expect("SAS token mutex poisoned").clone()
+        });
+
+        Ok(client)
+    })
+}
+
+/// The [`mqtt::IoSource`] of a client created with [`module_client`].
+///
+/// Connects through a [`crate::IoSource`], and gets a new SAS token from the workload API for every connection
+/// while the connection is being established.
+pub struct IoSource {
+    inner: crate::IoSource,
+    environment: Environment,
+    token_validity: std::time::Duration,
+
+    /// The token for the connection that was established last, sent by the client in its CONNECT packet
+    sas_token: std::sync::Arc<std::sync::Mutex<Option<String>>>,
+}
+
+impl IoSource {
+    /// The [`crate::IoSource`] that makes the connections
+    pub fn inner(&self) -> &crate::IoSource {
+        &self.inner
+    }
+}
+
+impl mqtt::IoSource for IoSource {
+    type Io = <crate::IoSource as mqtt::IoSource>::Io;
+    type Future = Box<dyn Future<Item = Self::Io, Error = std::io::Error> + Send>;
+
+    fn connect(&mut self) -> Self::Future {
+        let sas_token = self.sas_token.clone();
+
+        Box::new(
+            self::sas_token(&self.environment, self.token_validity)
+                .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))
+                .join(self.inner.connect())
+                .map(move |(token, io)| {
+                    *sas_token.lock().expect("SAS token mutex poisoned") = Some(token);
+                    io
+                }),
+        )
+    }
+}
+
+/// Gets a SAS token for the module that expires after `validity`, signed with the module's key by the workload API
+pub fn sas_token(
+    environment: &Environment,
+    validity: std::time::Duration,
+) -> impl Future<Item = String, Error = Error> {
+    #[derive(serde_derive::Serialize)]
+    #[serde(rename_all = "camelCase")]
+    struct SignRequest {
+        key_id: &'static str,
+        algo: &'static str,
+        data: String,
+    }
+
+    #[derive(serde_derive::Deserialize)]
+    struct SignResponse {
+        digest: String,
+    }
+
+    let expiry = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        + validity;
+    let (resource_uri, string_to_sign) = sas_token_resource_uri_and_string_to_sign(
+        &environment.iothub_hostname,
+        &environment.device_id,
+        &environment.module_id,
+        expiry.as_secs(),
+    );
+
+    let path = format!(
+        "/modules/{}/genid/{}/sign?api-version={}",
+        path_segment(&environment.module_id),
+        path_segment(&environment.module_generation_id),
+        environment.api_version,
+    );
+    let body = SignRequest {
+        key_id: "primary",
+        algo: "HMACSHA256",
+        data: base64::encode(&string_to_sign),
+    };
+    let body = serde_json::to_vec(&body).expect("SignRequest is always serializable");
+
+    workload_api_request(
+        &environment.workload_uri,
+        hyper::Method::POST,
+        &path,
+        Some(body),
+    )
+    .and_then(|body| serde_json::from_slice(&body).map_err(Error::ParseWorkloadApiResponse))
+    .map(move |SignResponse { digest }| {
+        format!(
+            "SharedAccessSignature sr={}&sig={}&se={}",
+            resource_uri,
+            url::form_urlencoded::byte_serialize(digest.as_bytes()).collect::<String>(),
+            expiry.as_secs(),
+        )
+    })
+}
+
+/// Gets the certificates that the Edge hub's server certificate chains up to from the workload API
+pub fn trust_bundle(
+    environment: &Environment,
+) -> impl Future<Item = Vec<native_tls::Certificate>, Error = Error> {
+    #[derive(serde_derive::Deserialize)]
+    struct TrustBundleResponse {
+        certificate: String,
+    }
+
+    let path = format!("/trust-bundle?api-version={}", environment.api_version);
+
+    workload_api_request(&environment.workload_uri, hyper::Method::GET, &path, None)
+        .and_then(|body| serde_json::from_slice(&body).map_err(Error::ParseWorkloadApiResponse))
+        .and_then(|TrustBundleResponse { certificate }| {
+            parse_certificates(&certificate).map_err(Error::ParseTrustBundle)
+        })
+}
+
+/// Errors from reading the IoT Edge environment or calling the workload API
+#[derive(Debug)]
+pub enum Error {
+    ConnectWorkloadApi(std::io::Error),
+    CreateClient(crate::CreateClientError),
+    InvalidWorkloadUri(String, url::ParseError),
+    MissingEnvironmentVariable(&'static str),
+    ParseTrustBundle(native_tls::Error),
+    ParseWorkloadApiResponse(serde_json::Error),
+    UnsupportedAuthScheme(String),
+    UnsupportedWorkloadUri(url::Url),
+    WorkloadApi(hyper::Error),
+    WorkloadApiRequest(hyper::http::Error),
+    WorkloadApiStatus(hyper::StatusCode, String),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::ConnectWorkloadApi(err) => {
+                write!(f, "could not connect to the workload API: {}", err)
+            }
+            Error::CreateClient(err) => write!(f, "could not create module client: {}", err),
+            Error::InvalidWorkloadUri(uri, err) => {
+                write!(f, "workload API URI {:?} is invalid: {}", uri, err)
+            }
+            Error::MissingEnvironmentVariable(name) => {
+                write!(f, "environment variable {} is not set", name)
+            }
+            Error::ParseTrustBundle(err) => write!(f, "could not parse trust bundle: {}", err),
+            Error::ParseWorkloadApiResponse(err) => {
+                write!(f, "could not parse workload API response: {}", err)
+            }
+            Error::UnsupportedAuthScheme(auth_scheme) => {
+                write!(f, "auth scheme {:?} is not supported", auth_scheme)
+            }
+            Error::UnsupportedWorkloadUri(uri) => {
+                write!(f, "workload API URI {} is not supported", uri)
+            }
+            Error::WorkloadApi(err) => write!(f, "could not call the workload API: {}", err),
+            Error::WorkloadApiRequest(err) => {
+                write!(f, "could not build workload API request: {}", err)
+            }
+            Error::WorkloadApiStatus(status, body) => {
+                write!(f, "workload API returned {}: {}", status, body)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    #[allow(clippy::match_same_arms)]
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::ConnectWorkloadApi(err) => Some(err),
+            Error::CreateClient(err) => Some(err),
+            Error::InvalidWorkloadUri(_, err) => Some(err),
+            Error::MissingEnvironmentVariable(_) => None,
+            Error::ParseTrustBundle(err) => Some(err),
+            Error::ParseWorkloadApiResponse(err) => Some(err),
+            Error::UnsupportedAuthScheme(_) => None,
+            Error::UnsupportedWorkloadUri(_) => None,
+            Error::WorkloadApi(err) => Some(err),
+            Error::WorkloadApiRequest(err) => Some(err),
+            Error::WorkloadApiStatus(_, _) => None,
+        }
+    }
+}
+
+/// Returns the URL-encoded resource URI of the module's SAS token, and the string that the workload API has to sign for it
+fn sas_token_resource_uri_and_string_to_sign(
+    iothub_hostname: &str,
+    device_id: &str,
+    module_id: &str,
+    expiry: u64,
+) -> (String, String) {
+    let resource_uri = format!(
+        "{}/devices/{}/modules/{}",
+        iothub_hostname, device_id, module_id
+    );
+    let resource_uri: String =
+        url::form_urlencoded::byte_serialize(resource_uri.as_bytes()).collect();
+    let string_to_sign = format!("{}\n{}", resource_uri, expiry);
+    (resource_uri, string_to_sign)
+}
+
+fn path_segment(s: &str) -> String {
+    url::percent_encoding::utf8_percent_encode(s, url::percent_encoding::PATH_SEGMENT_ENCODE_SET)
+        .to_string()
+}
+
+/// Splits a PEM bundle into its certificates
+fn parse_certificates(pem: &str) -> Result<Vec<native_tls::Certificate>, native_tls::Error> {
+    const END_CERTIFICATE: &str = "-----END CERTIFICATE-----";
+
+    let mut certificates = vec![];
+    let mut rest = pem;
+    while let Some(end) = rest.find(END_CERTIFICATE) {
+        let (certificate, remaining) = rest.split_at(end + END_CERTIFICATE.len());
+        certificates.push(native_tls::Certificate::from_pem(certificate.as_bytes())?);
+        rest = remaining;
+    }
+    Ok(certificates)
+}
+
+/// Sends a request to the workload API over a new connection, and returns the body of its response if it succeeded
+fn workload_api_request(
+    workload_uri: &url::Url,
+    method: hyper::Method,
+    path_and_query: &str,
+    body: Option<Vec<u8>>,
+) -> Box<dyn Future<Item = Vec<u8>, Error = Error> + Send> {
+    let mut request = hyper::Request::builder();
+    request
+        .method(method)
+        .uri(path_and_query)
+        .header(hyper::header::HOST, "localhost");
+    let request = match body {
+        Some(body) => request
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .body(body.into()),
+        None => request.body(hyper::Body::empty()),
+    };
+    let request = match request {
+        Ok(request) => request,
+        Err(err) => return Box::new(futures::future::err(Error::WorkloadApiRequest(err))),
+    };
+
+    match workload_uri.scheme() {
+        #[cfg(unix)]
+        "unix" => Box::new(
+            tokio::net::UnixStream::connect(workload_uri.path())
+                .map_err(Error::ConnectWorkloadApi)
+                .and_then(|stream| send_request(stream, request)),
+        ),
+
+        "http" => {
+            let addr = match std::net::ToSocketAddrs::to_socket_addrs(workload_uri).and_then(
+                |mut addrs| {
+                    addrs
+                        .next()
+                        .ok_or_else(|| std::io::ErrorKind::AddrNotAvailable.into())
+                },
+            ) {
+                Ok(addr) => addr,
+                Err(err) => return Box::new(futures::future::err(Error::ConnectWorkloadApi(err))),
+            };
+
+            Box::new(
+                tokio::net::TcpStream::connect(&addr)
+                    .map_err(Error::ConnectWorkloadApi)
+                    .and_then(|stream| send_request(stream, request)),
+            )
+        }
+
+        _ => Box::new(futures::future::err(Error::UnsupportedWorkloadUri(
+            workload_uri.clone(),
+        ))),
+    }
+}
+
+fn send_request<S>(
+    stream: S,
+    request: hyper::Request<hyper::Body>,
+) -> impl Future<Item = Vec<u8>, Error = Error>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Send + 'static,
+{
+    hyper::client::conn::handshake(stream)
+        .and_then(|(mut send_request, connection)| {
+            let response = send_request.send_request(request).and_then(|response| {
+                let status = response.status();
+                response
+                    .into_body()
+                    .concat2()
+                    .map(move |body| (status, body))
+            });
+
+            // `send_request` is dropped here, so the connection completes once the response has been read
+            connection.join(response).map(|((), response)| response)
+        })
+        .map_err(Error::WorkloadApi)
+        .and_then(|(status, body)| {
+            if status.is_success() {
+                Ok(body.to_vec())
+            } else {
+                Err(Error::WorkloadApiStatus(
+                    status,
+                    String::from_utf8_lossy(&body).into_owned(),
+                ))
+            }
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn sas_token_resource_uri_and_string_to_sign() {
+        let (resource_uri, string_to_sign) = super::sas_token_resource_uri_and_string_to_sign(
+            "foo.azure-devices.net",
+            "device1",
+            "module1",
+            1_550_000_000,
+        );
+        assert_eq!(
+            resource_uri,
+            "foo.azure-devices.net%2Fdevices%2Fdevice1%2Fmodules%2Fmodule1"
+        );
+        assert_eq!(
+            string_to_sign,
+            "foo.azure-devices.net%2Fdevices%2Fdevice1%2Fmodules%2Fmodule1\n1550000000"
+        );
+    }
+}
@@ -0,0 +1,161 @@
+//! An in-memory stand-in for the Azure IoT Hub, for tests that drive a device or module client through an [`mqtt::IoSource`]
+
+/// A connection to a fake Azure IoT Hub.
+///
+/// The hub accepts the connection and every subscription, acks QoS 1 publications, and answers a twin GET with `twin`.
+/// It sends `after_twin` right after the first twin response, and records every packet it receives.
+#[derive(Clone, Debug)]
+pub(crate) struct FakeHub(std::sync::Arc<std::sync::Mutex<Inner>>);
+
+#[derive(Debug)]
+struct Inner {
+    codec: mqtt::proto::PacketCodec,
+    to_client: bytes::BytesMut,
+    from_client: bytes::BytesMut,
+    read_task: Option<futures::task::Task>,
+
+    twin: serde_json::Value,
+    after_twin: Vec<mqtt::proto::Packet>,
+
+    received: Vec<mqtt::proto::Packet>,
+}
+
+impl FakeHub {
+    pub(crate) fn new(twin: serde_json::Value, after_twin: Vec<mqtt::proto::Packet>) -> Self {
+        FakeHub(std::sync::Arc::new(std::sync::Mutex::new(Inner {
+            codec: Default::default(),
+            to_client: Default::default(),
+            from_client: Default::default(),
+            read_task: None,
+
+            twin,
+            after_twin,
+
+            received: vec![],
+        })))
+    }
+
+    /// An [`mqtt::IoSource`] whose every connection is to this hub
+    pub(crate) fn io_source(
+        &self,
+    ) -> impl FnMut() -> futures::future::FutureResult<Self, std::io::Error> {
+        let hub = self.clone();
+        move || futures::future::ok(hub.clone())
+    }
+
+    /// The packets the hub has received so far, in order
+    pub(crate) fn received(&self) -> Vec<mqtt::proto::Packet> {
+        self.0.lock().unwrap().received.clone()
+    }
+}
+
+impl Inner {
+    fn send(&mut self, packet: mqtt::proto::Packet) {
+        use tokio::codec::Encoder;
+
+        self.codec.encode(packet, &mut self.to_client).unwrap();
+    }
+
+    fn handle(&mut self, packet: mqtt::proto::Packet) {
+        match &packet {
+            mqtt::proto::Packet::Connect { .. } => self.send(mqtt::proto::Packet::ConnAck {
+                session_present: false,
+                return_code: mqtt::proto::ConnectReturnCode::Accepted,
+            }),
+
+            mqtt::proto::Packet::PingReq => self.send(mqtt::proto::Packet::PingResp),
+
+            mqtt::proto::Packet::Publish {
+                packet_identifier_dup_qos,
+                topic_name,
+                ..
+            } => {
+                if let mqtt::proto::PacketIdentifierDupQoS::AtLeastOnce(packet_identifier, _) =
+                    packet_identifier_dup_qos
+                {
+                    self.send(mqtt::proto::Packet::PubAck {
+                        packet_identifier: *packet_identifier,
+                    });
+                }
+
+                if let Some(request_id) = topic_name.strip_prefix("$iothub/twin/GET/?$rid=") {
+                    let payload = serde_json::to_vec(&self.twin).unwrap();
+                    self.send(mqtt::proto::Packet::Publish {
+                        packet_identifier_dup_qos: mqtt::proto::PacketIdentifierDupQoS::AtMostOnce,
+                        retain: false,
+                        topic_name: format!("$iothub/twin/res/200/?$rid={}", request_id),
+                        payload,
+                    });
+
+                    for packet in std::mem::replace(&mut self.after_twin, vec![]) {
+                        self.send(packet);
+                    }
+                }
+            }
+
+            mqtt::proto::Packet::Subscribe {
+                packet_identifier,
+                subscribe_to,
+            } => self.send(mqtt::proto::Packet::SubAck {
+                packet_identifier: *packet_identifier,
+                qos: subscribe_to
+                    .iter()
+                    .map(|subscribe_to| mqtt::proto::SubAckQos::Success(subscribe_to.qos))
+                    .collect(),
+            }),
+
+            _ => (),
+        }
+
+        self.received.push(packet);
+    }
+}
+
+impl std::io::Read for FakeHub {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut inner = self.0.lock().unwrap();
+
+        if inner.to_client.is_empty() {
+            inner.read_task = Some(futures::task::current());
+            return Err(std::io::ErrorKind::WouldBlock.into());
+        }
+
+        let len = std::cmp::min(buf.len(), inner.to_client.len());
+        buf[..len].copy_from_slice(&inner.to_client.split_to(len));
+        Ok(len)
+    }
+}
+
+impl tokio::io::AsyncRead for FakeHub {}
+
+impl std::io::Write for FakeHub {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        use tokio::codec::Decoder;
+
+        let mut inner = self.0.lock().unwrap();
+        let inner = &mut *inner;
+
+        inner.from_client.extend_from_slice(buf);
+        while let Some(packet) = inner.codec.decode(&mut inner.from_client).unwrap() {
+            inner.handle(packet);
+        }
+
+        if !inner.to_client.is_empty() {
+            if let Some(read_task) = inner.read_task.take() {
+                read_task.notify();
+            }
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl tokio::io::AsyncWrite for FakeHub {
+    fn shutdown(&mut self) -> futures::Poll<(), std::io::Error> {
+        Ok(futures::Async::Ready(()))
+    }
+}
@@ -7,8 +7,35 @@ pub struct IoSource {
     iothub_hostname: std::sync::Arc<str>,
     iothub_host: std::net::SocketAddr,
     certificate: std::sync::Arc<Option<(Vec<u8>, String)>>,
+    trusted_certificates: std::sync::Mutex<Vec<native_tls::Certificate>>,
     timeout: std::time::Duration,
+    write_timeout: std::sync::Mutex<Option<std::time::Duration>>,
+    nodelay: std::sync::atomic::AtomicBool,
     extra: IoSourceExtra,
+    diagnostics: std::sync::Arc<std::sync::Mutex<Option<ConnectionDiagnostics>>>,
+    configure_socket: std::sync::Arc<std::sync::Mutex<Option<Box<ConfigureSocket>>>>,
+}
+
+/// A callback that configures a new TCP connection to the Azure IoT Hub. See [`IoSource::set_configure_socket`]
+pub type ConfigureSocket =
+    dyn FnMut(&tokio::net::TcpStream) -> std::io::Result<()> + Send + 'static;
+
+/// Details about the most recent connection established by an [`IoSource`]
+#[derive(Clone, Debug)]
+pub struct ConnectionDiagnostics {
+    /// The address of the Azure IoT Hub endpoint that the connection was made to, if the socket could report it
+    pub peer_addr: Option<std::net::SocketAddr>,
+
+    /// The local address of the connection, if the socket could report it
+    pub local_addr: Option<std::net::SocketAddr>,
+
+    /// The transport used for the connection
+    pub transport: crate::Transport,
+
+    /// The DER-encoded certificate presented by the server during the TLS handshake, if any.
+    ///
+    /// The negotiated TLS protocol version and cipher suite are not included since `native-tls` does not expose them.
+    pub peer_certificate: Option<Vec<u8>>,
 }
 
 #[derive(Clone, Debug)]
@@ -53,10 +80,77 @@ impl IoSource {
             iothub_hostname,
             iothub_host,
             certificate,
+            trusted_certificates: Default::default(),
             timeout,
+            write_timeout: std::sync::Mutex::new(Some(timeout)),
+            nodelay: std::sync::atomic::AtomicBool::new(true),
             extra,
+            diagnostics: Default::default(),
+            configure_socket: Default::default(),
         })
     }
+
+    /// Returns details about the most recent connection established by this source, if any
+    pub fn connection_diagnostics(&self) -> Option<ConnectionDiagnostics> {
+        self.diagnostics
+            .lock()
+            .expect("connection diagnostics mutex poisoned")
+            .clone()
+    }
+
+    /// Sets a callback that's invoked on every new TCP connection to the Azure IoT Hub, before the TLS handshake.
+    ///
+    /// Use it to set socket options like TCP keep-alive or the send and receive buffer sizes.
+    /// `TCP_NODELAY` has already been set as per [`IoSource::set_nodelay`] by the time the callback is invoked,
+    /// and TCP keep-alive is disabled unless the callback enables it.
+    /// If the callback fails, the connection attempt fails, and the client backs off and tries again as usual.
+    ///
+    /// The callback takes effect from the next connection.
+    pub fn set_configure_socket<F>(&self, configure_socket: F)
+    where
+        F: FnMut(&tokio::net::TcpStream) -> std::io::Result<()> + Send + 'static,
+    {
+        *self
+            .configure_socket
+            .lock()
+            .expect("configure socket mutex poisoned") = Some(Box::new(configure_socket));
+    }
+
+    /// Sets whether new TCP connections to the Azure IoT Hub disable Nagle's algorithm, ie set `TCP_NODELAY`.
+    /// Defaults to `true`, so that small MQTT packets like PINGREQ are sent immediately.
+    ///
+    /// This takes effect from the next connection.
+    pub fn set_nodelay(&self, nodelay: bool) {
+        self.nodelay
+            .store(nodelay, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    /// Sets how long a write to the connection may stall before the connection fails with a [`std::io::ErrorKind::TimedOut`] error,
+    /// which makes the client reconnect. `None` disables the timeout.
+    /// Defaults to twice the keep-alive time, the same as the timeout for reads.
+    ///
+    /// This catches connections that are half-open or stuck in a TLS renegotiation, which would otherwise hang a write forever.
+    ///
+    /// This takes effect from the next connection.
+    pub fn set_write_timeout(&self, write_timeout: Option<std::time::Duration>) {
+        *self
+            .write_timeout
+            .lock()
+            .expect("write timeout mutex poisoned") = write_timeout;
+    }
+
+    /// Sets additional root certificates to trust when verifying the server's certificate, on top of the system's trusted roots.
+    ///
+    /// This is needed when the server's certificate is not issued by a publicly trusted CA, such as when connecting to
+    /// an IoT Edge gateway.
+    ///
+    /// This takes effect from the next connection.
+    pub fn set_trusted_certificates(&self, trusted_certificates: Vec<native_tls::Certificate>) {
+        *self
+            .trusted_certificates
+            .lock()
+            .expect("trusted certificates mutex poisoned") = trusted_certificates;
+    }
 }
 
 impl mqtt::IoSource for IoSource {
@@ -65,12 +159,35 @@ impl mqtt::IoSource for IoSource {
 
     fn connect(&mut self) -> Self::Future {
         let iothub_hostname = self.iothub_hostname.clone();
+        let iothub_host = self.iothub_host;
         let certificate = self.certificate.clone();
+        let trusted_certificates = self
+            .trusted_certificates
+            .lock()
+            .expect("trusted certificates mutex poisoned")
+            .clone();
         let timeout = self.timeout;
+        let write_timeout = *self
+            .write_timeout
+            .lock()
+            .expect("write timeout mutex poisoned");
+        let nodelay = self.nodelay.load(std::sync::atomic::Ordering::Relaxed);
         let extra = self.extra.clone();
+        let transport = match &extra {
+            IoSourceExtra::Raw => crate::Transport::Tcp,
+            IoSourceExtra::WebSocket { .. } => crate::Transport::WebSocket,
+        };
+        let diagnostics = self.diagnostics.clone();
+        let configure_socket = self.configure_socket.clone();
+
+        let connect_tls = move || {
+            let iothub_hostname = iothub_hostname.clone();
+            let certificate = certificate.clone();
+            let trusted_certificates = trusted_certificates.clone();
+            let diagnostics = diagnostics.clone();
+            let configure_socket = configure_socket.clone();
 
-        Box::new(
-            tokio::timer::Timeout::new(tokio::net::TcpStream::connect(&self.iothub_host), timeout)
+            tokio::timer::Timeout::new(tokio::net::TcpStream::connect(&iothub_host), timeout)
                 .map_err(|err| {
                     if err.is_inner() {
                         err.into_inner().unwrap()
@@ -83,28 +200,18 @@ impl mqtt::IoSource for IoSource {
                     }
                 })
                 .and_then(move |stream| {
-                    stream.set_nodelay(true)?;
-
-                    let mut stream = tokio_io_timeout::TimeoutStream::new(stream);
-                    stream.set_read_timeout(Some(timeout));
-
-                    let mut tls_connector_builder = native_tls::TlsConnector::builder();
-                    if let Some((der, password)) = &*certificate {
-                        let identity =
-                            native_tls::Identity::from_pkcs12(der, password).map_err(|err| {
-                                std::io::Error::new(
-                                    std::io::ErrorKind::Other,
-                                    format!("could not parse client certificate: {}", err),
-                                )
-                            })?;
-                        tls_connector_builder.identity(identity);
+                    stream.set_nodelay(nodelay)?;
+
+                    if let Some(configure_socket) = &mut *configure_socket
+                        .lock()
+                        .expect("configure socket mutex poisoned")
+                    {
+                        configure_socket(&stream)?;
                     }
-                    let connector = tls_connector_builder.build().map_err(|err| {
-                        std::io::Error::new(
-                            std::io::ErrorKind::Other,
-                            format!("could not create TLS connector: {}", err),
-                        )
-                    })?;
+
+                    let stream = timeout_stream(stream, timeout, write_timeout);
+
+                    let connector = tls_connector((*certificate).as_ref(), trusted_certificates)?;
                     let connector: tokio_tls::TlsConnector = connector.into();
 
                     Ok(connector
@@ -112,34 +219,110 @@ impl mqtt::IoSource for IoSource {
                         .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err)))
                 })
                 .flatten()
-                .and_then(move |stream| match extra {
-                    IoSourceExtra::Raw => {
-                        futures::future::Either::A(futures::future::ok(Io::Raw(stream)))
-                    }
+                .map(move |stream| {
+                    // Diagnostics are best-effort. A socket that can't report its addresses doesn't fail the connection.
+                    let tls_stream = stream.get_ref();
+                    let tcp_stream = tls_stream.get_ref().get_ref();
+                    let connection_diagnostics = ConnectionDiagnostics {
+                        peer_addr: tcp_stream.peer_addr().ok(),
+                        local_addr: tcp_stream.local_addr().ok(),
+                        transport,
+                        peer_certificate: match tls_stream.peer_certificate() {
+                            Ok(Some(certificate)) => certificate.to_der().ok(),
+                            Ok(None) | Err(_) => None,
+                        },
+                    };
+                    log::debug!("Connected: {:?}", connection_diagnostics);
+                    *diagnostics
+                        .lock()
+                        .expect("connection diagnostics mutex poisoned") =
+                        Some(connection_diagnostics);
+
+                    stream
+                })
+        };
 
-                    IoSourceExtra::WebSocket { url } => {
-                        let request = tungstenite::handshake::client::Request {
-                            url,
-                            extra_headers: Some(vec![(
-                                "sec-websocket-protocol".into(),
-                                "mqtt".into(),
-                            )]),
-                        };
-
-                        let handshake = tungstenite::ClientHandshake::start(stream, request, None);
-
-                        futures::future::Either::B(WsConnect::Handshake(handshake).map(|stream| {
-                            Io::WebSocket {
-                                inner: stream,
-                                pending_read: std::io::Cursor::new(vec![]),
-                            }
-                        }))
-                    }
-                }),
-        )
+        match extra {
+            IoSourceExtra::Raw => Box::new(connect_tls().map(Io::Raw)),
+
+            IoSourceExtra::WebSocket { url } => Box::new(follow_redirect_once(url, move |url| {
+                connect_tls()
+                    .and_then(|stream| WsConnect::new(stream, url))
+                    .map(|stream| Io::WebSocket {
+                        inner: stream,
+                        pending_read: std::io::Cursor::new(vec![]),
+                    })
+            })),
+        }
     }
 }
 
+/// Connects with `connect` to `url`. If the WebSocket handshake is redirected to another URL on the same host,
+/// connects once more to that URL. A second redirect, or a redirect to a different host, fails the connection attempt.
+fn follow_redirect_once<F, T>(
+    url: url::Url,
+    mut connect: F,
+) -> impl Future<Item = T::Item, Error = std::io::Error>
+where
+    F: FnMut(url::Url) -> T,
+    T: Future<Error = std::io::Error>,
+{
+    connect(url.clone()).or_else(move |err| match same_host_redirect(&url, &err) {
+        Some(location) => {
+            log::info!("following WebSocket redirect to {}", location);
+            futures::future::Either::A(connect(location))
+        }
+
+        None => futures::future::Either::B(futures::future::err(err)),
+    })
+}
+
+/// Returns the URL to redirect to if `err` is a WebSocket handshake that was redirected to the same host and port as `url`
+fn same_host_redirect(url: &url::Url, err: &std::io::Error) -> Option<url::Url> {
+    let location = match err
+        .get_ref()
+        .and_then(|err| err.downcast_ref::<WebSocketHandshakeError>())
+    {
+        Some(WebSocketHandshakeError::Redirect(_, Some(location))) => location,
+        _ => return None,
+    };
+
+    // The location may be relative to the original URL
+    let location = url.join(location).ok()?;
+    if location.host_str() == url.host_str() && location.port() == url.port() {
+        Some(location)
+    } else {
+        None
+    }
+}
+
+/// Builds the TLS connector for a connection to the Azure IoT Hub, presenting `certificate` if any
+/// and trusting `trusted_certificates` on top of the system's trusted roots.
+pub(crate) fn tls_connector(
+    certificate: Option<&(Vec<u8>, String)>,
+    trusted_certificates: Vec<native_tls::Certificate>,
+) -> std::io::Result<native_tls::TlsConnector> {
+    let mut tls_connector_builder = native_tls::TlsConnector::builder();
+    if let Some((der, password)) = certificate {
+        let identity = native_tls::Identity::from_pkcs12(der, password).map_err(|err| {
+            std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("could not parse client certificate: {}", err),
+            )
+        })?;
+        tls_connector_builder.identity(identity);
+    }
+    for trusted_certificate in trusted_certificates {
+        tls_connector_builder.add_root_certificate(trusted_certificate);
+    }
+    tls_connector_builder.build().map_err(|err| {
+        std::io::Error::new(
+            std::io::ErrorKind::Other,
+            format!("could not create TLS connector: {}", err),
+        )
+    })
+}
+
 /// The transport to use for the connection to the Azure IoT Hub
 #[derive(Clone, Copy, Debug)]
 pub enum Transport {
@@ -151,17 +334,46 @@ enum WsConnect<S>
 where
     S: std::io::Read + std::io::Write,
 {
-    Handshake(tungstenite::handshake::MidHandshake<tungstenite::ClientHandshake<S>>),
+    Handshake {
+        handshake:
+            tungstenite::handshake::MidHandshake<tungstenite::ClientHandshake<HandshakeStream<S>>>,
+        response: std::sync::Arc<std::sync::Mutex<Vec<u8>>>,
+    },
     Invalid,
 }
 
+impl<S> WsConnect<S>
+where
+    S: std::io::Read + std::io::Write,
+{
+    fn new(stream: S, url: url::Url) -> Self {
+        let request = tungstenite::handshake::client::Request {
+            url,
+            extra_headers: Some(vec![("sec-websocket-protocol".into(), "mqtt".into())]),
+        };
+
+        let response: std::sync::Arc<std::sync::Mutex<Vec<u8>>> = Default::default();
+        let stream = HandshakeStream {
+            inner: stream,
+            response: Some(response.clone()),
+        };
+
+        let handshake = tungstenite::ClientHandshake::start(stream, request, None);
+
+        WsConnect::Handshake {
+            handshake,
+            response,
+        }
+    }
+}
+
 impl<S> std::fmt::Debug for WsConnect<S>
 where
     S: std::io::Read + std::io::Write,
 {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            WsConnect::Handshake(_) => f.debug_struct("Handshake").finish(),
+            WsConnect::Handshake { .. } => f.debug_struct("Handshake").finish(),
             WsConnect::Invalid => f.debug_struct("Invalid").finish(),
         }
     }
@@ -171,19 +383,46 @@ impl<S> Future for WsConnect<S>
 where
     S: std::io::Read + std::io::Write,
 {
-    type Item = tungstenite::WebSocket<S>;
+    type Item = tungstenite::WebSocket<HandshakeStream<S>>;
     type Error = std::io::Error;
 
     fn poll(&mut self) -> futures::Poll<Self::Item, Self::Error> {
         match std::mem::replace(self, WsConnect::Invalid) {
-            WsConnect::Handshake(handshake) => match handshake.handshake() {
-                Ok((stream, _)) => Ok(futures::Async::Ready(stream)),
+            WsConnect::Handshake {
+                handshake,
+                response,
+            } => match handshake.handshake() {
+                Ok((mut stream, _)) => {
+                    stream.get_mut().response = None;
+                    Ok(futures::Async::Ready(stream))
+                }
 
                 Err(tungstenite::HandshakeError::Interrupted(handshake)) => {
-                    *self = WsConnect::Handshake(handshake);
+                    *self = WsConnect::Handshake {
+                        handshake,
+                        response,
+                    };
                     Ok(futures::Async::NotReady)
                 }
 
+                Err(tungstenite::HandshakeError::Failure(tungstenite::Error::Http(status))) => {
+                    let err = match WebSocketHandshakeError::from_status(status) {
+                        WebSocketHandshakeError::Redirect(status, _) => {
+                            let response = response.lock().expect("response mutex poisoned");
+                            WebSocketHandshakeError::Redirect(status, location(&response))
+                        }
+                        err => err,
+                    };
+                    Err(std::io::Error::new(std::io::ErrorKind::Other, err))
+                }
+
+                Err(tungstenite::HandshakeError::Failure(tungstenite::Error::Protocol(
+                    message,
+                ))) => Err(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    WebSocketHandshakeError::Protocol(message.into_owned()),
+                )),
+
                 Err(tungstenite::HandshakeError::Failure(err)) => poll_from_tungstenite_error(err),
             },
 
@@ -192,12 +431,143 @@ where
     }
 }
 
+/// Parses the `Location` header out of the raw HTTP response to the WebSocket handshake
+fn location(response: &[u8]) -> Option<String> {
+    let mut headers = [httparse::EMPTY_HEADER; 32];
+    let mut parsed = httparse::Response::new(&mut headers);
+    match parsed.parse(response) {
+        Ok(httparse::Status::Complete(_)) => (),
+        Ok(httparse::Status::Partial) | Err(_) => return None,
+    }
+
+    let location = parsed
+        .headers
+        .iter()
+        .find(|header| header.name.eq_ignore_ascii_case("location"))?;
+    String::from_utf8(location.value.to_owned()).ok()
+}
+
+/// The stream that the WebSocket runs over.
+///
+/// It keeps a copy of the response to the WebSocket handshake, since the WebSocket library doesn't expose the headers
+/// of a response that fails the handshake, such as the `Location` header of a redirect. Once the handshake is done,
+/// it passes everything through to the inner stream as is.
+#[derive(Debug)]
+pub struct HandshakeStream<S> {
+    inner: S,
+    response: Option<std::sync::Arc<std::sync::Mutex<Vec<u8>>>>,
+}
+
+impl<S> std::io::Read for HandshakeStream<S>
+where
+    S: std::io::Read,
+{
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        if let Some(response) = &self.response {
+            response
+                .lock()
+                .expect("response mutex poisoned")
+                .extend_from_slice(&buf[..read]);
+        }
+        Ok(read)
+    }
+}
+
+impl<S> tokio::io::AsyncRead for HandshakeStream<S> where S: tokio::io::AsyncRead {}
+
+impl<S> std::io::Write for HandshakeStream<S>
+where
+    S: std::io::Write,
+{
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<S> tokio::io::AsyncWrite for HandshakeStream<S>
+where
+    S: tokio::io::AsyncWrite,
+{
+    fn shutdown(&mut self) -> futures::Poll<(), std::io::Error> {
+        self.inner.shutdown()
+    }
+}
+
+/// Why the WebSocket handshake with the Azure IoT Hub failed
+///
+/// The connection attempt fails with a [`std::io::Error`] that wraps this error, so it can be recovered from the error
+/// with `get_ref` and `downcast_ref`.
+#[derive(Debug)]
+pub enum WebSocketHandshakeError {
+    /// The server responded with a redirect, such as from a proxy. Contains the HTTP status code and the `Location` header, if any.
+    ///
+    /// A redirect to the same host is followed once. This error is returned for a second redirect, or a redirect to a different host.
+    Redirect(u16, Option<String>),
+
+    /// The server responded with 401 Unauthorized or 407 Proxy Authentication Required.
+    /// Contains the HTTP status code.
+    Unauthorized(u16),
+
+    /// The server responded with some other status than 101 Switching Protocols. Contains the HTTP status code.
+    Rejected(u16),
+
+    /// The server switched protocols, but its response was not a valid WebSocket upgrade
+    Protocol(String),
+}
+
+impl WebSocketHandshakeError {
+    pub(crate) fn from_status(status: u16) -> Self {
+        match status {
+            300..=399 => WebSocketHandshakeError::Redirect(status, None),
+            401 | 407 => WebSocketHandshakeError::Unauthorized(status),
+            status => WebSocketHandshakeError::Rejected(status),
+        }
+    }
+}
+
+impl std::fmt::Display for WebSocketHandshakeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WebSocketHandshakeError::Redirect(status, Some(location)) => write!(
+                f,
+                "WebSocket handshake was redirected to {} with HTTP status {}",
+                location, status
+            ),
+            WebSocketHandshakeError::Redirect(status, None) => write!(
+                f,
+                "WebSocket handshake was redirected with HTTP status {}",
+                status
+            ),
+            WebSocketHandshakeError::Unauthorized(status) => write!(
+                f,
+                "WebSocket handshake was not authorized with HTTP status {}",
+                status
+            ),
+            WebSocketHandshakeError::Rejected(status) => write!(
+                f,
+                "WebSocket handshake was rejected with HTTP status {}",
+                status
+            ),
+            WebSocketHandshakeError::Protocol(message) => {
+                write!(f, "WebSocket handshake failed: {}", message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for WebSocketHandshakeError {}
+
 /// A wrapper around an inner I/O object
 pub enum Io<S> {
     Raw(S),
 
     WebSocket {
-        inner: tungstenite::WebSocket<S>,
+        inner: tungstenite::WebSocket<HandshakeStream<S>>,
         pending_read: std::io::Cursor<Vec<u8>>,
     },
 }
@@ -338,3 +708,210 @@ fn poll_from_tungstenite_error<T>(err: tungstenite::Error) -> futures::Poll<T, s
         err => Err(std::io::Error::new(std::io::ErrorKind::Other, err)),
     }
 }
+
+/// Wraps `stream` so that a read that stalls for longer than `read_timeout`, or a write that stalls for longer than `write_timeout`,
+/// fails with a [`std::io::ErrorKind::TimedOut`] error.
+fn timeout_stream<S>(
+    stream: S,
+    read_timeout: std::time::Duration,
+    write_timeout: Option<std::time::Duration>,
+) -> tokio_io_timeout::TimeoutStream<S>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite,
+{
+    let mut stream = tokio_io_timeout::TimeoutStream::new(stream);
+    stream.set_read_timeout(Some(read_timeout));
+    stream.set_write_timeout(write_timeout);
+    stream
+}
+
+#[cfg(test)]
+mod tests {
+    /// A stream that reads a canned response from the server and discards everything written to it
+    #[derive(Debug)]
+    struct TestStream(std::io::Cursor<Vec<u8>>);
+
+    impl std::io::Read for TestStream {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+
+    impl std::io::Write for TestStream {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// A stream that never becomes ready for reading or writing, like a half-open connection
+    #[derive(Debug)]
+    struct StallingStream;
+
+    impl std::io::Read for StallingStream {
+        fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+            Err(std::io::ErrorKind::WouldBlock.into())
+        }
+    }
+
+    impl tokio::io::AsyncRead for StallingStream {}
+
+    impl std::io::Write for StallingStream {
+        fn write(&mut self, _buf: &[u8]) -> std::io::Result<usize> {
+            Err(std::io::ErrorKind::WouldBlock.into())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Err(std::io::ErrorKind::WouldBlock.into())
+        }
+    }
+
+    impl tokio::io::AsyncWrite for StallingStream {
+        fn shutdown(&mut self) -> futures::Poll<(), std::io::Error> {
+            Ok(futures::Async::Ready(()))
+        }
+    }
+
+    #[test]
+    fn stalled_write_times_out() {
+        let mut runtime = tokio::runtime::current_thread::Runtime::new()
+            .expect("couldn't initialize tokio runtime");
+
+        let stream = super::timeout_stream(
+            StallingStream,
+            std::time::Duration::from_secs(60),
+            Some(std::time::Duration::from_millis(50)),
+        );
+
+        // Fail the test instead of hanging it if the write never times out
+        let write = tokio::timer::Timeout::new(
+            tokio::io::write_all(stream, b"packet".to_vec()),
+            std::time::Duration::from_secs(10),
+        );
+        let err = runtime
+            .block_on(write)
+            .expect_err("write to stalled stream succeeded");
+        let err = err
+            .into_inner()
+            .expect("write to stalled stream did not time out");
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn web_socket_handshake_error() {
+        use futures::Future;
+
+        use super::WebSocketHandshakeError;
+
+        let handshake = |response: &str| {
+            let stream = TestStream(std::io::Cursor::new(response.as_bytes().to_vec()));
+            let url = "ws://foo.azure-devices.net/$iothub/websocket"
+                .parse()
+                .unwrap();
+            let err = super::WsConnect::new(stream, url).wait().unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::Other);
+            err.into_inner()
+                .unwrap()
+                .downcast::<WebSocketHandshakeError>()
+                .unwrap()
+        };
+
+        match *handshake(
+            "HTTP/1.1 302 Found\r\nLocation: ws://bar.azure-devices.net/$iothub/websocket\r\n\r\n",
+        ) {
+            WebSocketHandshakeError::Redirect(302, Some(ref location))
+                if location == "ws://bar.azure-devices.net/$iothub/websocket" => {}
+            ref err => panic!("{:?}", err),
+        }
+
+        match *handshake("HTTP/1.1 401 Unauthorized\r\n\r\n") {
+            WebSocketHandshakeError::Unauthorized(401) => (),
+            ref err => panic!("{:?}", err),
+        }
+
+        match *handshake("HTTP/1.1 404 Not Found\r\n\r\n") {
+            WebSocketHandshakeError::Rejected(404) => (),
+            ref err => panic!("{:?}", err),
+        }
+
+        match *handshake("HTTP/1.1 101 Switching Protocols\r\n\r\n") {
+            WebSocketHandshakeError::Protocol(_) => (),
+            ref err => panic!("{:?}", err),
+        }
+    }
+
+    #[test]
+    fn web_socket_redirect_is_followed_once_to_the_same_host() {
+        use futures::Future;
+
+        use super::WebSocketHandshakeError;
+
+        // Answers each connection attempt with the next response, and returns the error and the URLs that were connected to
+        let connect = |responses: &[&str]| {
+            let mut responses = responses.iter();
+            let mut urls = vec![];
+            let err = super::follow_redirect_once(
+                "ws://foo.azure-devices.net/$iothub/websocket"
+                    .parse()
+                    .unwrap(),
+                |url: url::Url| {
+                    urls.push(url.to_string());
+                    let response = responses.next().expect("unexpected connection attempt");
+                    let stream = TestStream(std::io::Cursor::new(response.as_bytes().to_vec()));
+                    super::WsConnect::new(stream, url)
+                },
+            )
+            .wait()
+            .unwrap_err();
+            let err = err
+                .into_inner()
+                .unwrap()
+                .downcast::<WebSocketHandshakeError>()
+                .unwrap();
+            (err, urls)
+        };
+
+        // A redirect to the same host is followed, including a relative one
+        let (err, urls) = connect(&[
+            "HTTP/1.1 307 Temporary Redirect\r\nLocation: /$iothub/websocket?redirected\r\n\r\n",
+            "HTTP/1.1 404 Not Found\r\n\r\n",
+        ]);
+        match *err {
+            WebSocketHandshakeError::Rejected(404) => (),
+            ref err => panic!("{:?}", err),
+        }
+        assert_eq!(
+            urls,
+            vec![
+                "ws://foo.azure-devices.net/$iothub/websocket",
+                "ws://foo.azure-devices.net/$iothub/websocket?redirected",
+            ]
+        );
+
+        // A second redirect is not followed
+        let (err, urls) = connect(&[
+            "HTTP/1.1 307 Temporary Redirect\r\nLocation: ws://foo.azure-devices.net/a\r\n\r\n",
+            "HTTP/1.1 307 Temporary Redirect\r\nLocation: ws://foo.azure-devices.net/b\r\n\r\n",
+        ]);
+        match *err {
+            WebSocketHandshakeError::Redirect(307, Some(ref location))
+                if location == "ws://foo.azure-devices.net/b" => {}
+            ref err => panic!("{:?}", err),
+        }
+        assert_eq!(urls.len(), 2);
+
+        // A redirect to a different host is not followed
+        let (err, urls) = connect(&[
+            "HTTP/1.1 302 Found\r\nLocation: ws://bar.azure-devices.net/$iothub/websocket\r\n\r\n",
+        ]);
+        match *err {
+            WebSocketHandshakeError::Redirect(302, Some(ref location))
+                if location == "ws://bar.azure-devices.net/$iothub/websocket" => {}
+            ref err => panic!("{:?}", err),
+        }
+        assert_eq!(urls, vec!["ws://foo.azure-devices.net/$iothub/websocket"]);
+    }
+}
@@ -1,4 +1,7 @@
 //! This crate contains types related to the Azure IoT MQTT server.
+//!
+//! Like the handles of the underlying [`mqtt::Client`], the [`DirectMethodResponseHandle`] and [`ReportTwinStateHandle`]
+//! are `Send` and `Sync`, so they can be used from other threads and tasks than the one polling the client.
 
 #![deny(rust_2018_idioms, warnings)]
 #![deny(clippy::all, clippy::pedantic)]
@@ -19,17 +22,34 @@ use futures::{Future, Sink};
 
 pub mod device;
 
+#[cfg(feature = "edge")]
+pub mod edge;
+
+#[cfg(test)]
+mod fake_hub;
+
 mod io;
-pub use self::io::{Io, IoSource, Transport};
+pub use self::io::{
+    ConfigureSocket, ConnectionDiagnostics, HandshakeStream, Io, IoSource, Transport,
+    WebSocketHandshakeError,
+};
 
 pub mod module;
 
+mod outgoing_message;
+pub use self::outgoing_message::{OutgoingMessage, SendEventError, SendEventHandle};
+
+pub mod property_bag;
+
+#[cfg(feature = "tokio02")]
+pub mod tokio02;
+
 mod system_properties;
 pub use self::system_properties::{IotHubAck, SystemProperties};
 
 mod twin_state;
 pub use self::twin_state::{
-    ReportTwinStateHandle, ReportTwinStateRequest, TwinProperties, TwinState,
+    ReportTwinStateAck, ReportTwinStateHandle, ReportTwinStateRequest, TwinProperties, TwinState,
 };
 
 /// The type of authentication the client should use to connect to the Azure IoT Hub
@@ -43,6 +63,7 @@ pub enum Authentication {
 /// Errors from creating a device or module client
 #[derive(Debug)]
 pub enum CreateClientError {
+    Mqtt(mqtt::CreateClientError),
     ResolveIotHubHostname(Option<std::io::Error>),
     WebSocketUrl(url::ParseError),
 }
@@ -50,6 +71,7 @@ pub enum CreateClientError {
 impl std::fmt::Display for CreateClientError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
+            CreateClientError::Mqtt(err) => write!(f, "could not create MQTT client: {}", err),
             CreateClientError::ResolveIotHubHostname(Some(err)) => {
                 write!(f, "could not resolve Azure IoT Hub hostname: {}", err)
             }
@@ -69,6 +91,7 @@ impl std::fmt::Display for CreateClientError {
 impl std::error::Error for CreateClientError {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match self {
+            CreateClientError::Mqtt(err) => Some(err),
             CreateClientError::ResolveIotHubHostname(Some(err)) => Some(err),
             CreateClientError::ResolveIotHubHostname(None) => None,
             CreateClientError::WebSocketUrl(err) => Some(err),
@@ -82,11 +105,29 @@ pub struct DirectMethodResponseHandle(futures::sync::mpsc::Sender<DirectMethodRe
 
 impl DirectMethodResponseHandle {
     /// Send a direct method response with the given parameters
+    ///
+    /// The response is published with QoS 1, so if the connection breaks before the Azure IoT Hub acks it, the client sends it again
+    /// once it has reconnected, even if the session was reset. The returned future resolves when the Azure IoT Hub has acked the response.
     pub fn respond(
         &self,
         request_id: String,
         status: crate::Status,
         payload: serde_json::Value,
+    ) -> impl Future<Item = (), Error = DirectMethodResponseError> {
+        let payload =
+            serde_json::to_vec(&payload).expect("cannot fail to serialize serde_json::Value");
+        self.respond_raw(request_id, status, payload)
+    }
+
+    /// Send a direct method response with the given parameters and an already-encoded payload
+    ///
+    /// The payload is published as it is, without being serialized as JSON. The Azure IoT Hub still expects it to be JSON,
+    /// so this is useful when the payload has already been encoded, eg by a serializer other than `serde_json`.
+    pub fn respond_raw(
+        &self,
+        request_id: String,
+        status: crate::Status,
+        payload: Vec<u8>,
     ) -> impl Future<Item = (), Error = DirectMethodResponseError> {
         let (ack_sender, ack_receiver) = futures::sync::oneshot::channel();
         let ack_receiver = ack_receiver.map_err(|_| DirectMethodResponseError::ClientDoesNotExist);
@@ -126,13 +167,16 @@ impl std::error::Error for DirectMethodResponseError {}
 struct DirectMethodResponse {
     request_id: String,
     status: crate::Status,
-    payload: serde_json::Value,
+    payload: Vec<u8>,
     ack_sender: futures::sync::oneshot::Sender<
         Box<dyn Future<Item = (), Error = mqtt::PublishError> + Send>,
     >,
 }
 
 /// Represents the status code used in initial twin responses and device method responses
+///
+/// The variants only name the most common codes. The numeric code is available from [`Status::code`] for every variant,
+/// so that applications can act on specific codes, like 412 for a reported properties update with an outdated version.
 #[derive(Clone, Copy, Debug)]
 pub enum Status {
     /// 200
@@ -154,6 +198,39 @@ pub enum Status {
     Other(u32),
 }
 
+impl Status {
+    /// Whether this is a 2xx status, ie the operation succeeded
+    pub fn is_success(self) -> bool {
+        let code = self.code();
+        code >= 200 && code < 300
+    }
+
+    /// Whether this is a 4xx status, ie the request was rejected because of something the client did.
+    ///
+    /// This includes 429, which is also [retryable](Status::is_retryable).
+    pub fn is_client_error(self) -> bool {
+        let code = self.code();
+        code >= 400 && code < 500
+    }
+
+    /// Whether the operation failed but may succeed if retried later, ie the status is 429 or 5xx
+    pub fn is_retryable(self) -> bool {
+        let code = self.code();
+        code == 429 || (code >= 500 && code < 600)
+    }
+
+    /// The numeric status code, as sent by the Azure IoT Hub
+    pub fn code(self) -> u32 {
+        match self {
+            Status::Ok => 200,
+            Status::NoContent => 204,
+            Status::BadRequest => 400,
+            Status::TooManyRequests => 429,
+            Status::Error(raw) | Status::Other(raw) => raw,
+        }
+    }
+}
+
 impl std::fmt::Display for Status {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         #[allow(clippy::match_same_arms)]
@@ -183,27 +260,151 @@ impl std::str::FromStr for Status {
     }
 }
 
-fn client_new(
-    iothub_hostname: String,
+/// The largest message payload accepted by the Azure IoT Hub. Larger publications cause the server to drop the connection.
+pub const MAX_PUBLISH_PAYLOAD_LEN: usize = 256 * 1024;
+
+/// The default capacity of the channels behind a client's handles, the same as the default of the inner `mqtt::Client`.
+/// See the `handle_channel_capacity` parameter of [`device::Client::new`] and [`module::Client::new`].
+pub const DEFAULT_HANDLE_CHANNEL_CAPACITY: usize =
+    mqtt::ClientBuilder::<IoSource>::DEFAULT_HANDLE_CHANNEL_CAPACITY;
+
+/// The largest reported twin state accepted by the Azure IoT Hub, in bytes of JSON.
+///
+/// By default, device and module clients don't send reports with larger payloads. See [`device::Client::set_max_reported_twin_state_len`]
+pub const MAX_REPORTED_TWIN_STATE_LEN: usize = 32 * 1024;
+
+/// The QoS of the subscriptions that device and module clients make to the Azure IoT Hub's topics
+///
+/// The Azure IoT Hub supports QoS 0 and 1 for all of these subscriptions. It doesn't support QoS 2, and only grants QoS 1 when asked for it,
+/// so [`DefaultSubscriptionQos::validate`] rejects it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DefaultSubscriptionQos {
+    /// The QoS of the subscription to twin responses, `$iothub/twin/res/#`. Defaults to [`mqtt::proto::QoS::AtMostOnce`]
+    ///
+    /// The client requests the twin again after every reconnect, so a lost response only delays the twin until the next connection.
+    pub twin_responses: mqtt::proto::QoS,
+
+    /// The QoS of the subscription to desired properties patches, `$iothub/twin/PATCH/properties/desired/#`.
+    /// Defaults to [`mqtt::proto::QoS::AtMostOnce`]
+    ///
+    /// At QoS 0, a patch that's lost because the connection broke is not redelivered. Set this to [`mqtt::proto::QoS::AtLeastOnce`]
+    /// to have the hub redeliver it when the session is resumed.
+    pub twin_patches: mqtt::proto::QoS,
+
+    /// The QoS of the subscription to direct method requests, `$iothub/methods/POST/#`. Defaults to [`mqtt::proto::QoS::AtLeastOnce`]
+    pub direct_methods: mqtt::proto::QoS,
+}
 
-    device_id: &str,
-    module_id: Option<&str>,
+impl DefaultSubscriptionQos {
+    /// Checks that the Azure IoT Hub supports the QoS of every subscription
+    pub fn validate(self) -> Result<(), DefaultSubscriptionQosError> {
+        for subscribe_to in self.subscriptions() {
+            if subscribe_to.qos == mqtt::proto::QoS::ExactlyOnce {
+                return Err(DefaultSubscriptionQosError::UnsupportedQoS(subscribe_to));
+            }
+        }
 
-    authentication: crate::Authentication,
-    transport: crate::Transport,
+        Ok(())
+    }
+
+    fn subscriptions(self) -> Vec<mqtt::proto::SubscribeTo> {
+        vec![
+            // Twin initial GET response
+            mqtt::proto::SubscribeTo {
+                topic_filter: "$iothub/twin/res/#".to_string(),
+                qos: self.twin_responses,
+            },
+            // Twin patches
+            mqtt::proto::SubscribeTo {
+                topic_filter: "$iothub/twin/PATCH/properties/desired/#".to_string(),
+                qos: self.twin_patches,
+            },
+            // Direct methods
+            mqtt::proto::SubscribeTo {
+                topic_filter: "$iothub/methods/POST/#".to_string(),
+                qos: self.direct_methods,
+            },
+        ]
+    }
+}
 
-    will: Option<Vec<u8>>,
+impl Default for DefaultSubscriptionQos {
+    fn default() -> Self {
+        DefaultSubscriptionQos {
+            twin_responses: mqtt::proto::QoS::AtMostOnce,
+            twin_patches: mqtt::proto::QoS::AtMostOnce,
+            direct_methods: mqtt::proto::QoS::AtLeastOnce,
+        }
+    }
+}
 
-    max_back_off: std::time::Duration,
-    keep_alive: std::time::Duration,
-) -> Result<mqtt::Client<crate::IoSource>, crate::CreateClientError> {
-    let client_id = if let Some(module_id) = &module_id {
-        format!("{}/{}", device_id, module_id)
-    } else {
-        device_id.to_string()
-    };
+/// Errors from setting the QoS of a client's default subscriptions
+#[derive(Debug)]
+pub enum DefaultSubscriptionQosError {
+    ClientDoesNotExist,
+    UnsupportedQoS(mqtt::proto::SubscribeTo),
+}
 
-    let username = if let Some(module_id) = &module_id {
+impl std::fmt::Display for DefaultSubscriptionQosError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DefaultSubscriptionQosError::ClientDoesNotExist => write!(f, "client does not exist"),
+            DefaultSubscriptionQosError::UnsupportedQoS(subscribe_to) => write!(
+                f,
+                "the Azure IoT Hub does not support subscribing to {} with QoS {:?}",
+                subscribe_to.topic_filter, subscribe_to.qos,
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DefaultSubscriptionQosError {}
+
+/// Re-subscribes the client to the Azure IoT Hub's topics with the given QoS
+fn set_default_subscription_qos<IoS>(
+    inner: &mut mqtt::Client<IoS>,
+    qos: DefaultSubscriptionQos,
+) -> Result<(), DefaultSubscriptionQosError>
+where
+    IoS: mqtt::IoSource,
+{
+    qos.validate()?;
+
+    match inner.subscribe_many(qos.subscriptions()) {
+        Ok(()) => Ok(()),
+        Err(mqtt::UpdateSubscriptionError::ClientDoesNotExist) => {
+            Err(DefaultSubscriptionQosError::ClientDoesNotExist)
+        }
+    }
+}
+
+/// The default value of the `DeviceClientType` property that device and module clients send in their username,
+/// ie the name and version of this crate. See [`default_username_properties`]
+pub const DEFAULT_DEVICE_CLIENT_TYPE: &str =
+    concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
+/// The properties that device and module clients append to their username by default.
+///
+/// This only contains `DeviceClientType`, set to [`DEFAULT_DEVICE_CLIENT_TYPE`]. The Azure IoT SDKs use this property to report their type
+/// and version, so that the Azure IoT Hub's diagnostics can identify the SDK a device or module connected with.
+pub fn default_username_properties() -> std::collections::BTreeMap<String, String> {
+    let mut properties = std::collections::BTreeMap::new();
+    properties.insert(
+        "DeviceClientType".to_string(),
+        DEFAULT_DEVICE_CLIENT_TYPE.to_string(),
+    );
+    properties
+}
+
+/// The username that device and module clients send to the Azure IoT Hub, ie `{iothub_hostname}/{device_id}/?api-version=...`
+/// for devices and `{iothub_hostname}/{device_id}/{module_id}/?api-version=...` for modules, followed by the given properties
+fn username(
+    iothub_hostname: &str,
+    device_id: &str,
+    module_id: Option<&str>,
+    properties: &std::collections::BTreeMap<String, String>,
+) -> String {
+    let mut username = if let Some(module_id) = module_id {
         format!(
             "{}/{}/{}/?api-version=2018-06-30",
             iothub_hostname, device_id, module_id
@@ -212,85 +413,214 @@ fn client_new(
         format!("{}/{}/?api-version=2018-06-30", iothub_hostname, device_id)
     };
 
+    if !properties.is_empty() {
+        username.push('&');
+        username.push_str(&crate::property_bag::encode(properties));
+    }
+
+    username
+}
+
+fn client_new(
+    iothub_hostname: String,
+    gateway_hostname: Option<String>,
+
+    device_id: &str,
+    module_id: Option<&str>,
+
+    authentication: crate::Authentication,
+    transport: crate::Transport,
+
+    will: Option<crate::OutgoingMessage>,
+
+    max_back_off: std::time::Duration,
+    keep_alive: std::time::Duration,
+    handle_channel_capacity: usize,
+) -> Result<mqtt::Client<crate::IoSource>, crate::CreateClientError> {
     let (password, certificate) = match authentication {
         crate::Authentication::SasToken(sas_token) => (Some(sas_token), None),
         crate::Authentication::Certificate { der, password } => (None, Some((der, password))),
     };
 
-    let will = match (will, module_id.as_ref()) {
-        (Some(payload), Some(module_id)) => Some((
-            format!(
-                "devices/{}/modules/{}/messages/events/",
-                device_id, module_id
-            ),
-            payload,
-        )),
-        (Some(payload), None) => Some((format!("devices/{}/messages/events/", device_id), payload)),
-        (None, _) => None,
-    };
-    let will = will.map(|(topic_name, payload)| mqtt::proto::Publication {
-        topic_name,
-        qos: mqtt::proto::QoS::AtMostOnce,
-        retain: false,
-        payload,
-    });
-
     let io_source = crate::IoSource::new(
-        iothub_hostname.into(),
+        gateway_hostname
+            .unwrap_or_else(|| iothub_hostname.clone())
+            .into(),
         certificate.into(),
         2 * keep_alive,
         transport,
     )?;
 
-    let mut inner = mqtt::Client::new(
-        Some(client_id),
-        Some(username),
+    client_new_with_io_source(
+        &iothub_hostname,
+        device_id,
+        module_id,
         password,
-        will,
         io_source,
+        will,
         max_back_off,
         keep_alive,
+        handle_channel_capacity,
+    )
+}
+
+fn client_new_with_io_source<IoS>(
+    iothub_hostname: &str,
+
+    device_id: &str,
+    module_id: Option<&str>,
+
+    password: Option<String>,
+    io_source: IoS,
+
+    will: Option<crate::OutgoingMessage>,
+
+    max_back_off: std::time::Duration,
+    keep_alive: std::time::Duration,
+    handle_channel_capacity: usize,
+) -> Result<mqtt::Client<IoS>, crate::CreateClientError>
+where
+    IoS: mqtt::IoSource,
+{
+    let client_id = if let Some(module_id) = &module_id {
+        format!("{}/{}", device_id, module_id)
+    } else {
+        device_id.to_string()
+    };
+
+    let username = username(
+        iothub_hostname,
+        device_id,
+        module_id,
+        &default_username_properties(),
     );
 
-    let mut default_subscriptions = vec![
-        // Twin initial GET response
-        mqtt::proto::SubscribeTo {
-            topic_filter: "$iothub/twin/res/#".to_string(),
-            qos: mqtt::proto::QoS::AtMostOnce,
-        },
-        // Twin patches
-        mqtt::proto::SubscribeTo {
-            topic_filter: "$iothub/twin/PATCH/properties/desired/#".to_string(),
-            qos: mqtt::proto::QoS::AtMostOnce,
-        },
-        // Module methods
-        mqtt::proto::SubscribeTo {
-            topic_filter: "$iothub/methods/POST/#".to_string(),
-            qos: mqtt::proto::QoS::AtLeastOnce,
-        },
-    ];
-    if module_id.is_none() {
-        default_subscriptions.push(
-            // Direct methods
-            mqtt::proto::SubscribeTo {
-                topic_filter: "$iothub/methods/POST/#".to_string(),
-                qos: mqtt::proto::QoS::AtLeastOnce,
-            },
-        );
+    let events_topic_name = events_topic_name(device_id, module_id);
+    let will = will.map(|will| mqtt::proto::Publication {
+        topic_name: will.topic_name(&events_topic_name),
+        qos: mqtt::proto::QoS::AtMostOnce,
+        retain: false,
+        payload: will.payload,
+    });
+
+    let mut builder = mqtt::Client::builder(io_source)
+        .client_id(client_id)
+        .username(username)
+        .max_reconnect_back_off(max_back_off)
+        .keep_alive(keep_alive)
+        .max_publish_payload_len(Some(MAX_PUBLISH_PAYLOAD_LEN))
+        .handle_channel_capacity(handle_channel_capacity);
+    if let Some(password) = password {
+        builder = builder.password(password);
+    }
+    if let Some(will) = will {
+        builder = builder.will(will);
     }
+    let mut inner = builder.build().map_err(crate::CreateClientError::Mqtt)?;
 
-    for subscribe_to in default_subscriptions {
-        match inner.subscribe(subscribe_to) {
-            Ok(()) => (),
+    let default_subscriptions = DefaultSubscriptionQos::default().subscriptions();
+    match inner.subscribe_many(default_subscriptions) {
+        Ok(()) => (),
 
-            // The subscription can only fail if `inner` has shut down, which is not the case here
-            Err(mqtt::UpdateSubscriptionError::ClientDoesNotExist) => unreachable!(),
-        }
+        // The subscription can only fail if `inner` has shut down, which is not the case here
+        Err(mqtt::UpdateSubscriptionError::ClientDoesNotExist) => unreachable!(),
     }
 
     Ok(inner)
 }
 
+/// The topic that device-to-cloud messages of the given device or module are published to
+fn events_topic_name(device_id: &str, module_id: Option<&str>) -> String {
+    if let Some(module_id) = module_id {
+        format!(
+            "devices/{}/modules/{}/messages/events/",
+            device_id, module_id
+        )
+    } else {
+        format!("devices/{}/messages/events/", device_id)
+    }
+}
+
+/// Parses the given publication the same way the device and module clients parse publications received from the Azure IoT Hub.
+///
+/// This is only exposed for the fuzzer in `azure-iot-mqtt-fuzz`, with the `fuzzing` feature, and is not part of the public API.
+#[cfg(feature = "fuzzing")]
+#[doc(hidden)]
+pub fn fuzz_parse_publication(topic_name: &str, payload: &[u8]) {
+    let publication = || mqtt::ReceivedPublication {
+        topic_name: topic_name.to_string(),
+        dup: false,
+        qos: mqtt::proto::QoS::AtMostOnce,
+        retain: false,
+        payload: payload.to_vec(),
+    };
+
+    // Re-encoding the parsed properties must not lose or change any of them
+    let properties = crate::property_bag::parse(topic_name);
+    assert_eq!(
+        crate::property_bag::parse(&crate::property_bag::encode(&properties)),
+        properties
+    );
+
+    let _ = crate::device::InternalMessage::parse(publication(), FUZZ_C2D_PREFIX);
+
+    let _ = crate::module::InternalMessage::parse(publication());
+
+    if let Ok(crate::twin_state::InternalTwinStateMessage::Response { payload, .. }) =
+        crate::twin_state::InternalTwinStateMessage::parse(publication())
+    {
+        let _: Result<crate::TwinState, _> = serde_json::from_slice(&payload);
+    }
+}
+
+/// The C2D topic prefix used by [`fuzz_parse_publication`], corresponding to a device with ID "device"
+#[cfg(feature = "fuzzing")]
+#[doc(hidden)]
+pub const FUZZ_C2D_PREFIX: &str = "devices/device/messages/devicebound/";
+
 lazy_static::lazy_static! {
-    static ref DIRECT_METHOD_REGEX: regex::Regex = regex::Regex::new(r"^\$iothub/methods/POST/([^/]+)/\?\$rid=(.+)$").expect("could not compile regex");
+    static ref DIRECT_METHOD_REGEX: regex::Regex = regex::Regex::new(r"^\$iothub/methods/POST/([^/]+)/\?(.+)$").expect("could not compile regex");
+}
+
+/// Parses the topic name of a direct method request into the method name and the request ID
+fn parse_direct_method_topic_name(topic_name: &str) -> Option<(String, String)> {
+    let captures = DIRECT_METHOD_REGEX.captures(topic_name)?;
+    let name = captures[1].to_string();
+    let request_id = crate::property_bag::parse(&captures[2]).remove("$rid")?;
+    Some((name, request_id))
+}
+
+/// The topic name of the response to the direct method request with the given ID
+fn direct_method_response_topic_name(status: crate::Status, request_id: &str) -> String {
+    format!(
+        "$iothub/methods/res/{}/?{}",
+        status,
+        crate::property_bag::encode(std::iter::once(("$rid", request_id)))
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn handles_are_send_and_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+
+        assert_send_sync::<super::DirectMethodResponseHandle>();
+        assert_send_sync::<super::ReportTwinStateHandle>();
+    }
+
+    #[test]
+    fn status_code() {
+        for &(s, expected) in &[
+            ("200", 200),
+            ("204", 204),
+            ("412", 412),
+            ("429", 429),
+            ("503", 503),
+        ] {
+            let status: super::Status = s.parse().unwrap();
+            assert_eq!(status.code(), expected);
+            assert_eq!(status.to_string(), s);
+        }
+    }
 }
@@ -7,11 +7,22 @@ use futures::Stream;
 /// A `Client` is a [`Stream`] of [`Message`]s. These messages contain twin state messages and direct method requests.
 ///
 /// It automatically reconnects if the connection to the server is broken. Each reconnection will yield one [`Message::TwinInitial`] message.
-pub struct Client {
-    inner: mqtt::Client<crate::IoSource>,
+pub struct Client<IoS = crate::IoSource>
+where
+    IoS: mqtt::IoSource,
+{
+    inner: mqtt::Client<IoS>,
+
+    iothub_hostname: String,
+    device_id: String,
+    module_id: String,
 
     state: State,
-    previous_request_id: u8,
+
+    /// The `$rid` of the last twin request. Shared by the initial twin state and reported twin state requests so that
+    /// every request has a different `$rid`, and wide enough to never wrap, so a late response to an old request
+    /// can't be mistaken for the response to a newer one.
+    previous_request_id: u64,
 
     desired_properties: crate::twin_state::desired::State,
     reported_properties: crate::twin_state::reported::State,
@@ -34,6 +45,12 @@ impl Client {
     ///
     ///     The hostname of the Azure IoT Hub. Eg "foo.azure-devices.net"
     ///
+    /// * `gateway_hostname`
+    ///
+    ///     If set, the hostname of the gateway that the client connects to instead of the Azure IoT Hub, such as an IoT Edge device.
+    ///     The client resolves and verifies the TLS certificate of this hostname, but still identifies itself to the Azure IoT Hub
+    ///     named by `iothub_hostname`.
+    ///
     /// * `device_id`
     ///
     ///     The ID of the device.
@@ -53,30 +70,40 @@ impl Client {
     /// * `will`
     ///
     ///     If set, this message will be published by the server if this client disconnects uncleanly.
+    ///     A payload can be converted into a message without any properties with `.into()`.
     ///     Use the handle from `.inner().shutdown_handle()` to disconnect cleanly.
     ///
     /// * `max_back_off`
     ///
     ///     Every connection failure or server error will double the back-off period, to a maximum of this value.
+    ///     Values smaller than [`mqtt::ExponentialBackoff::MIN_MAX_BACK_OFF`] are raised to it.
     ///
     /// * `keep_alive`
     ///
     ///     The keep-alive time advertised to the server. The client will ping the server at half this interval.
+    ///
+    /// * `handle_channel_capacity`
+    ///
+    ///     The capacity of the channels behind the client's handles, such as [`crate::DirectMethodResponseHandle`] and [`crate::ReportTwinStateHandle`].
+    ///     See [`mqtt::ClientBuilder::handle_channel_capacity`]. Use [`crate::DEFAULT_HANDLE_CHANNEL_CAPACITY`] if unsure.
     #[allow(clippy::new_ret_no_self)]
     pub fn new(
         iothub_hostname: String,
+        gateway_hostname: Option<String>,
         device_id: &str,
         module_id: &str,
         authentication: crate::Authentication,
         transport: crate::Transport,
 
-        will: Option<Vec<u8>>,
+        will: Option<crate::OutgoingMessage>,
 
         max_back_off: std::time::Duration,
         keep_alive: std::time::Duration,
+        handle_channel_capacity: usize,
     ) -> Result<Self, crate::CreateClientError> {
         let inner = crate::client_new(
-            iothub_hostname,
+            iothub_hostname.clone(),
+            gateway_hostname,
             device_id,
             Some(module_id),
             authentication,
@@ -84,44 +111,297 @@ impl Client {
             will,
             max_back_off,
             keep_alive,
+            handle_channel_capacity,
         )?;
 
+        Ok(Client::from_inner(
+            inner,
+            iothub_hostname,
+            device_id,
+            module_id,
+            max_back_off,
+            keep_alive,
+            handle_channel_capacity,
+        ))
+    }
+
+    /// Returns details about the most recent connection to the Azure IoT Hub, if any
+    pub fn connection_diagnostics(&self) -> Option<crate::ConnectionDiagnostics> {
+        self.inner
+            .io_source()
+            .and_then(crate::IoSource::connection_diagnostics)
+    }
+
+    /// Sets a callback that's invoked on every new TCP connection to the Azure IoT Hub, before the TLS handshake.
+    /// See [`crate::IoSource::set_configure_socket`]
+    pub fn set_configure_socket<F>(&mut self, configure_socket: F)
+    where
+        F: FnMut(&tokio::net::TcpStream) -> std::io::Result<()> + Send + 'static,
+    {
+        if let Some(io_source) = self.inner.io_source() {
+            io_source.set_configure_socket(configure_socket);
+        }
+    }
+
+    /// Sets whether new TCP connections to the Azure IoT Hub disable Nagle's algorithm.
+    /// See [`crate::IoSource::set_nodelay`]
+    pub fn set_nodelay(&mut self, nodelay: bool) {
+        if let Some(io_source) = self.inner.io_source() {
+            io_source.set_nodelay(nodelay);
+        }
+    }
+
+    /// Sets how long a write to the connection may stall before the client reconnects.
+    /// See [`crate::IoSource::set_write_timeout`]
+    pub fn set_write_timeout(&mut self, write_timeout: Option<std::time::Duration>) {
+        if let Some(io_source) = self.inner.io_source() {
+            io_source.set_write_timeout(write_timeout);
+        }
+    }
+}
+
+impl<IoS> Client<IoS>
+where
+    IoS: mqtt::IoSource,
+{
+    /// Creates a new `Client` that uses the given [`mqtt::IoSource`] to connect to the Azure IoT Hub,
+    /// instead of connecting over TCP, TLS and optionally WebSockets itself.
+    ///
+    /// This is useful for transports that `Client::new` does not support, and for tests.
+    ///
+    /// * `iothub_hostname`
+    ///
+    ///     The hostname of the Azure IoT Hub. Eg "foo.azure-devices.net"
+    ///
+    /// * `device_id`
+    ///
+    ///     The ID of the device.
+    ///
+    /// * `module_id`
+    ///
+    ///     The ID of the module.
+    ///
+    /// * `sas_token`
+    ///
+    ///     If set, the SAS token sent as the password. Leave unset if the `io_source` authenticates the connection itself, such as with a client certificate.
+    ///
+    /// * `io_source`
+    ///
+    ///     The source of connections to the Azure IoT Hub.
+    ///
+    /// * `will`
+    ///
+    ///     If set, this message will be published by the server if this client disconnects uncleanly.
+    ///     A payload can be converted into a message without any properties with `.into()`.
+    ///     Use the handle from `.inner().shutdown_handle()` to disconnect cleanly.
+    ///
+    /// * `max_back_off`
+    ///
+    ///     Every connection failure or server error will double the back-off period, to a maximum of this value.
+    ///     Values smaller than [`mqtt::ExponentialBackoff::MIN_MAX_BACK_OFF`] are raised to it.
+    ///
+    /// * `keep_alive`
+    ///
+    ///     The keep-alive time advertised to the server. The client will ping the server at half this interval.
+    ///
+    /// * `handle_channel_capacity`
+    ///
+    ///     The capacity of the channels behind the client's handles, such as [`crate::DirectMethodResponseHandle`] and [`crate::ReportTwinStateHandle`].
+    ///     See [`mqtt::ClientBuilder::handle_channel_capacity`]. Use [`crate::DEFAULT_HANDLE_CHANNEL_CAPACITY`] if unsure.
+    pub fn with_io_source(
+        iothub_hostname: &str,
+        device_id: &str,
+        module_id: &str,
+        sas_token: Option<String>,
+        io_source: IoS,
+
+        will: Option<crate::OutgoingMessage>,
+
+        max_back_off: std::time::Duration,
+        keep_alive: std::time::Duration,
+        handle_channel_capacity: usize,
+    ) -> Result<Self, crate::CreateClientError> {
+        let inner = crate::client_new_with_io_source(
+            iothub_hostname,
+            device_id,
+            Some(module_id),
+            sas_token,
+            io_source,
+            will,
+            max_back_off,
+            keep_alive,
+            handle_channel_capacity,
+        )?;
+
+        Ok(Client::from_inner(
+            inner,
+            iothub_hostname.to_string(),
+            device_id,
+            module_id,
+            max_back_off,
+            keep_alive,
+            handle_channel_capacity,
+        ))
+    }
+
+    fn from_inner(
+        inner: mqtt::Client<IoS>,
+        iothub_hostname: String,
+        device_id: &str,
+        module_id: &str,
+        max_back_off: std::time::Duration,
+        keep_alive: std::time::Duration,
+        handle_channel_capacity: usize,
+    ) -> Self {
         let (direct_method_response_send, direct_method_response_recv) =
-            futures::sync::mpsc::channel(0);
+            futures::sync::mpsc::channel(handle_channel_capacity);
 
-        Ok(Client {
+        Client {
             inner,
 
+            iothub_hostname,
+            device_id: device_id.to_string(),
+            module_id: module_id.to_string(),
+
             state: State::WaitingForSubscriptions {
                 reset_session: true,
             },
-            previous_request_id: u8::max_value(),
+            previous_request_id: u64::max_value(),
 
             desired_properties: crate::twin_state::desired::State::new(max_back_off, keep_alive),
-            reported_properties: crate::twin_state::reported::State::new(max_back_off, keep_alive),
+            reported_properties: crate::twin_state::reported::State::new(
+                max_back_off,
+                keep_alive,
+                handle_channel_capacity,
+            ),
 
             direct_method_response_send,
             direct_method_response_recv,
-        })
+        }
     }
 
     /// Gets a reference to the inner `mqtt::Client`
-    pub fn inner(&self) -> &mqtt::Client<crate::IoSource> {
+    pub fn inner(&self) -> &mqtt::Client<IoS> {
         &self.inner
     }
 
+    /// The hostname of the Azure IoT Hub that this client connects to
+    pub fn iothub_hostname(&self) -> &str {
+        &self.iothub_hostname
+    }
+
+    /// The ID of the device that the module belongs to
+    pub fn device_id(&self) -> &str {
+        &self.device_id
+    }
+
+    /// The ID of the module
+    pub fn module_id(&self) -> &str {
+        &self.module_id
+    }
+
     /// Returns a handle that can be used to respond to direct methods
     pub fn direct_method_response_handle(&self) -> crate::DirectMethodResponseHandle {
         crate::DirectMethodResponseHandle(self.direct_method_response_send.clone())
     }
 
+    /// Returns a handle that can be used to send device-to-cloud messages, such as telemetry, to the Azure IoT Hub
+    pub fn send_event_handle(&self) -> Result<crate::SendEventHandle, mqtt::PublishError> {
+        let publish_handle = self.inner.publish_handle()?;
+        Ok(crate::SendEventHandle::new(
+            publish_handle,
+            crate::events_topic_name(&self.device_id, Some(&self.module_id)),
+        ))
+    }
+
     /// Returns a handle that can be used to publish reported twin state to the Azure IoT Hub
     pub fn report_twin_state_handle(&self) -> crate::ReportTwinStateHandle {
         self.reported_properties.report_twin_state_handle()
     }
+
+    /// Sets reported properties that the module already has, such as ones it saved to persistent storage before restarting.
+    ///
+    /// When the client receives the first [`Message::TwinInitial`], these properties are applied like a [`crate::ReportTwinStateRequest::Patch`]
+    /// on top of the reported properties the Azure IoT Hub has, before any reports requested with [`crate::ReportTwinStateHandle`].
+    /// Only the properties whose values differ from the hub's are reported, so nothing is reported if the hub already has them.
+    ///
+    /// This should be called before the client is first polled. Afterwards, it's the same as reporting a patch.
+    pub fn set_initial_reported_state(
+        &mut self,
+        properties: std::collections::HashMap<String, serde_json::Value>,
+    ) {
+        self.reported_properties
+            .set_initial_reported_state(properties);
+    }
+
+    /// Sets the largest payload of a report of the twin state, in bytes of JSON.
+    /// Defaults to [`crate::MAX_REPORTED_TWIN_STATE_LEN`]. `None` means no limit.
+    ///
+    /// Reports with larger payloads aren't sent to the Azure IoT Hub. The client yields a [`Message::ReportedTwinStateTooLarge`] instead.
+    pub fn set_max_reported_twin_state_len(&mut self, max_reported_twin_state_len: Option<usize>) {
+        self.reported_properties
+            .set_max_payload_len(max_reported_twin_state_len);
+    }
+
+    /// Sets whether reports of the twin state requested while an earlier report is waiting for its acknowledgement
+    /// are combined into a single report. Defaults to `true`.
+    ///
+    /// If `false`, each request that changes the reported properties is reported on its own, so the Azure IoT Hub sees
+    /// every intermediate state. This sends more reports, which makes it more likely that the hub throttles them.
+    /// See [`crate::ReportTwinStateHandle::report_twin_state`]
+    pub fn set_coalesce_reported_twin_state(&mut self, coalesce: bool) {
+        self.reported_properties.set_coalesce_reports(coalesce);
+    }
+
+    /// Sets the QoS of the client's subscriptions to twin responses, desired properties patches and direct method requests.
+    /// The client re-subscribes with the new QoS if it has already subscribed.
+    ///
+    /// Fails with [`crate::DefaultSubscriptionQosError::UnsupportedQoS`] if the Azure IoT Hub doesn't support one of the QoS.
+    /// See [`crate::DefaultSubscriptionQos`]
+    pub fn set_default_subscription_qos(
+        &mut self,
+        qos: crate::DefaultSubscriptionQos,
+    ) -> Result<(), crate::DefaultSubscriptionQosError> {
+        crate::set_default_subscription_qos(&mut self.inner, qos)
+    }
+
+    /// Sets the properties that the client appends to its username, such as `DeviceClientType`.
+    /// Defaults to [`crate::default_username_properties`].
+    ///
+    /// The properties are percent-encoded like the properties of a message. Set `DeviceClientType` to override the SDK type
+    /// reported to the Azure IoT Hub, or use an empty map to not send any properties.
+    ///
+    /// This takes effect on the next connection to the Azure IoT Hub.
+    pub fn set_username_properties(
+        &mut self,
+        properties: &std::collections::BTreeMap<String, String>,
+    ) {
+        self.inner.set_username(Some(crate::username(
+            &self.iothub_hostname,
+            &self.device_id,
+            Some(&self.module_id),
+            properties,
+        )));
+    }
+
+    /// Sets a callback that returns the SAS token to authenticate with every time the client connects to the Azure IoT Hub,
+    /// instead of the token the client was created with. See [`mqtt::Client::set_password_callback`]
+    ///
+    /// This lets the client reconnect after its token has expired, eg with a token that the [`mqtt::IoSource`] got while connecting.
+    pub fn set_sas_token_callback<F>(&mut self, sas_token_callback: F)
+    where
+        F: FnMut() -> Option<String> + Send + 'static,
+    {
+        self.inner
+            .set_password_callback(Some(Box::new(sas_token_callback)));
+    }
 }
 
-impl Stream for Client {
+impl<IoS> Stream for Client<IoS>
+where
+    IoS: mqtt::IoSource + 'static,
+    <<IoS as mqtt::IoSource>::Future as futures::Future>::Error: std::fmt::Display,
+{
     type Item = Message;
     type Error = mqtt::Error;
 
@@ -140,10 +420,8 @@ impl Stream for Client {
                     payload,
                     ack_sender,
                 } = direct_method_response;
-                let payload = serde_json::to_vec(&payload)
-                    .expect("cannot fail to serialize serde_json::Value");
                 let publication = mqtt::proto::Publication {
-                    topic_name: format!("$iothub/methods/res/{}/?$rid={}", status, request_id),
+                    topic_name: crate::direct_method_response_topic_name(status, &request_id),
                     qos: mqtt::proto::QoS::AtLeastOnce,
                     retain: false,
                     payload,
@@ -161,12 +439,16 @@ impl Stream for Client {
                 State::WaitingForSubscriptions { reset_session } => {
                     if *reset_session {
                         match self.inner.poll()? {
+							futures::Async::Ready(Some(mqtt::Event::Connecting { .. })) |
 							futures::Async::Ready(Some(mqtt::Event::NewConnection { .. })) => (),
 
 							futures::Async::Ready(Some(mqtt::Event::Publication(publication))) => match InternalMessage::parse(publication) {
 								Ok(InternalMessage::DirectMethod { name, payload, request_id }) =>
 									return Ok(futures::Async::Ready(Some(Message::DirectMethod { name, payload, request_id }))),
 
+								Ok(InternalMessage::Unrecognized(publication)) =>
+									return Ok(futures::Async::Ready(Some(Message::Unrecognized(publication)))),
+
 								Ok(message @ InternalMessage::TwinState(_)) =>
 									log::debug!("Discarding message {:?} because we haven't finished subscribing yet", message),
 
@@ -179,6 +461,12 @@ impl Stream for Client {
 								self.state = State::Idle;
 							},
 
+							// The subscriptions are tracked through the SubscriptionUpdates above
+							futures::Async::Ready(Some(mqtt::Event::SubscriptionsSynced)) => (),
+
+							// The client finished shutting down, so the next poll will end the stream
+							futures::Async::Ready(Some(mqtt::Event::Disconnected { .. })) => (),
+
 							futures::Async::Ready(None) => return Ok(futures::Async::Ready(None)),
 
 							futures::Async::NotReady => return Ok(futures::Async::NotReady),
@@ -192,8 +480,11 @@ impl Stream for Client {
                     let mut continue_loop = false;
 
                     let mut twin_state_message = match self.inner.poll()? {
+                        futures::Async::Ready(Some(mqtt::Event::Connecting { .. })) => continue,
+
                         futures::Async::Ready(Some(mqtt::Event::NewConnection {
                             reset_session,
+                            ..
                         })) => {
                             self.state = State::WaitingForSubscriptions { reset_session };
                             self.desired_properties.new_connection();
@@ -215,6 +506,12 @@ impl Stream for Client {
                                     })));
                                 }
 
+                                Ok(InternalMessage::Unrecognized(publication)) => {
+                                    return Ok(futures::Async::Ready(Some(Message::Unrecognized(
+                                        publication,
+                                    ))));
+                                }
+
                                 Ok(InternalMessage::TwinState(message)) => {
                                     // There may be more messages, so continue the loop
                                     continue_loop = true;
@@ -237,6 +534,12 @@ impl Stream for Client {
                             unreachable!()
                         }
 
+                        // Follows the SubscriptionUpdates that moved the client to this state
+                        futures::Async::Ready(Some(mqtt::Event::SubscriptionsSynced)) => continue,
+
+                        // The client finished shutting down, so the next poll will end the stream
+                        futures::Async::Ready(Some(mqtt::Event::Disconnected { .. })) => continue,
+
                         futures::Async::Ready(None) => return Ok(futures::Async::Ready(None)),
 
                         futures::Async::NotReady => None,
@@ -283,6 +586,16 @@ impl Stream for Client {
                                     version,
                                 ))));
                             }
+                            crate::twin_state::reported::Message::Rejected(status) => {
+                                return Ok(futures::Async::Ready(Some(
+                                    Message::ReportedTwinStateRejected(status),
+                                )));
+                            }
+                            crate::twin_state::reported::Message::TooLarge { len, max } => {
+                                return Ok(futures::Async::Ready(Some(
+                                    Message::ReportedTwinStateTooLarge { len, max },
+                                )));
+                            }
                         },
                         Ok(crate::twin_state::Response::Continue) => continue_loop = true,
                         Ok(crate::twin_state::Response::NotReady) => (),
@@ -319,15 +632,35 @@ pub enum Message {
     /// The server acknowledged a report of the twin state. Contains the version number of the updated section.
     ReportedTwinState(usize),
 
+    /// The server rejected a report of the twin state with a status that retrying wouldn't fix, such as 400 Bad Request,
+    /// or 412 Precondition Failed for a [`crate::ReportTwinStateRequest::ConditionalPatch`] whose expected version was outdated.
+    ///
+    /// The rejected changes are discarded, so later reports are applied on top of the reported properties the server still has.
+    /// Reports that fail with 429 Too Many Requests or a 5xx status are retried with back-off instead.
+    ///
+    /// The server rejects a report with 413 Payload Too Large if its reported properties would exceed their size limit,
+    /// even if the report itself is small.
+    ReportedTwinStateRejected(crate::Status),
+
+    /// A report of the twin state wasn't sent because its payload was larger than the maximum set with
+    /// [`Client::set_max_reported_twin_state_len`]. Contains the length of the payload and the maximum.
+    ///
+    /// The changes are discarded like those of a rejected report. Split them into smaller reports and report those instead.
+    ReportedTwinStateTooLarge { len: usize, max: usize },
+
     /// The full twin state, as currently stored in the Azure IoT Hub.
     TwinInitial(crate::TwinState),
 
     /// A patch to the twin state that should be applied to the current state to get the new state.
     TwinPatch(crate::TwinProperties),
+
+    /// A publication on a topic that this client doesn't recognize, such as from a newer Azure IoT Hub feature
+    /// or custom routing. It is passed through as it was received.
+    Unrecognized(mqtt::ReceivedPublication),
 }
 
 #[derive(Debug)]
-enum MessageParseError {
+pub(crate) enum MessageParseError {
     Json(serde_json::Error),
     UnrecognizedMessage(crate::twin_state::MessageParseError),
 }
@@ -356,7 +689,7 @@ impl std::error::Error for MessageParseError {
 }
 
 #[derive(Debug)]
-enum InternalMessage {
+pub(crate) enum InternalMessage {
     DirectMethod {
         name: String,
         payload: serde_json::Value,
@@ -364,15 +697,17 @@ enum InternalMessage {
     },
 
     TwinState(crate::twin_state::InternalTwinStateMessage),
+
+    Unrecognized(mqtt::ReceivedPublication),
 }
 
 impl InternalMessage {
-    fn parse(publication: mqtt::ReceivedPublication) -> Result<Self, MessageParseError> {
-        if let Some(captures) = crate::DIRECT_METHOD_REGEX.captures(&publication.topic_name) {
-            let name = captures[1].to_string();
+    pub(crate) fn parse(publication: mqtt::ReceivedPublication) -> Result<Self, MessageParseError> {
+        if let Some((name, request_id)) =
+            crate::parse_direct_method_topic_name(&publication.topic_name)
+        {
             let payload =
                 serde_json::from_slice(&publication.payload).map_err(MessageParseError::Json)?;
-            let request_id = captures[2].to_string();
 
             Ok(InternalMessage::DirectMethod {
                 name,
@@ -382,8 +717,75 @@ impl InternalMessage {
         } else {
             match crate::twin_state::InternalTwinStateMessage::parse(publication) {
                 Ok(message) => Ok(InternalMessage::TwinState(message)),
+                Err(crate::twin_state::MessageParseError::UnrecognizedMessage(publication)) => {
+                    Ok(InternalMessage::Unrecognized(publication))
+                }
                 Err(err) => Err(MessageParseError::UnrecognizedMessage(err)),
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use futures::{Future, Stream};
+
+    #[test]
+    fn twin_initial_over_io_source() {
+        let hub = crate::fake_hub::FakeHub::new(
+            serde_json::json!({ "desired": { "$version": 3 }, "reported": { "$version": 4, "status": "ok" } }),
+            vec![],
+        );
+
+        let client = super::Client::with_io_source(
+            "example.azure-devices.net",
+            "device1",
+            "module1",
+            None,
+            hub.io_source(),
+            None,
+            std::time::Duration::from_secs(0),
+            std::time::Duration::from_secs(60),
+            crate::DEFAULT_HANDLE_CHANNEL_CAPACITY,
+        )
+        .unwrap();
+
+        let mut runtime = tokio::runtime::current_thread::Runtime::new()
+            .expect("couldn't initialize tokio runtime");
+
+        let (message, _client) = runtime
+            .block_on(client.into_future().map_err(|(err, _)| err))
+            .unwrap();
+        match message {
+            Some(super::Message::TwinInitial(twin_state)) => {
+                assert_eq!(twin_state.desired.version, 3);
+                assert_eq!(twin_state.reported.version, 4);
+                assert_eq!(
+                    twin_state.reported.properties["status"],
+                    serde_json::json!("ok")
+                );
+            }
+            message => panic!("expected TwinInitial but got {:?}", message),
+        }
+
+        match &hub.received()[0] {
+            mqtt::proto::Packet::Connect {
+                username,
+                password,
+                client_id,
+                ..
+            } => {
+                assert!(username
+                    .as_ref()
+                    .unwrap()
+                    .starts_with("example.azure-devices.net/device1/module1/?"));
+                assert_eq!(password, &None);
+                assert_eq!(
+                    client_id,
+                    &mqtt::proto::ClientId::IdWithCleanSession("device1/module1".to_string())
+                );
+            }
+            packet => panic!("expected CONNECT but got {:?}", packet),
+        }
+    }
+}
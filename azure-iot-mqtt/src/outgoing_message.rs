@@ -0,0 +1,214 @@
+use futures::Future;
+
+/// A device-to-cloud message, such as telemetry or a will
+///
+/// The system and application properties are sent in the property bag of the topic name,
+/// so the Azure IoT Hub can route the message like any other event.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct OutgoingMessage {
+    pub payload: Vec<u8>,
+
+    /// Sent as the `$.mid` system property
+    pub message_id: Option<String>,
+
+    /// Sent as the `$.cid` system property
+    pub correlation_id: Option<String>,
+
+    /// Sent as the `$.ct` system property. Eg "application/json"
+    ///
+    /// The Azure IoT Hub's message routing can query the body of messages whose content type is "application/json"
+    /// and whose content encoding is "utf-8", "utf-16" or "utf-32".
+    pub content_type: Option<String>,
+
+    /// Sent as the `$.ce` system property. Eg "utf-8"
+    pub content_encoding: Option<String>,
+
+    /// Application properties
+    pub properties: std::collections::BTreeMap<String, String>,
+}
+
+impl OutgoingMessage {
+    /// The topic name to publish this message to, given the events topic of the device or module,
+    /// eg "devices/foo/messages/events/"
+    pub(crate) fn topic_name(&self, events_topic_name: &str) -> String {
+        let system_properties = [
+            ("$.mid", &self.message_id),
+            ("$.cid", &self.correlation_id),
+            ("$.ct", &self.content_type),
+            ("$.ce", &self.content_encoding),
+        ];
+        let system_properties = system_properties
+            .iter()
+            .filter_map(|(key, value)| value.as_ref().map(|value| (*key, &**value)));
+        let properties = self
+            .properties
+            .iter()
+            .map(|(key, value)| (&**key, &**value));
+
+        let mut topic_name = events_topic_name.to_string();
+        topic_name.push_str(&crate::property_bag::encode(
+            system_properties.chain(properties),
+        ));
+        topic_name
+    }
+}
+
+/// Used to send device-to-cloud messages, such as telemetry, to the Azure IoT Hub
+pub struct SendEventHandle {
+    publish_handle: mqtt::PublishHandle,
+    events_topic_name: String,
+}
+
+impl SendEventHandle {
+    pub(crate) fn new(publish_handle: mqtt::PublishHandle, events_topic_name: String) -> Self {
+        SendEventHandle {
+            publish_handle,
+            events_topic_name,
+        }
+    }
+
+    /// Sends the given message to the events endpoint of the device or module
+    ///
+    /// The message is published with QoS 1, so if the connection breaks before the Azure IoT Hub acks it, the client sends it again
+    /// once it has reconnected. The returned future resolves when the Azure IoT Hub has acked the message.
+    ///
+    /// Fails immediately with [`SendEventError::InvalidContentType`] if the message's content type is not a MIME type,
+    /// eg "application/json" or "text/plain; charset=utf-8".
+    pub fn send_event(
+        &mut self,
+        message: OutgoingMessage,
+    ) -> impl Future<Item = (), Error = SendEventError> {
+        if let Some(content_type) = &message.content_type {
+            if !is_valid_content_type(content_type) {
+                return futures::future::Either::A(futures::future::err(
+                    SendEventError::InvalidContentType(content_type.clone()),
+                ));
+            }
+        }
+
+        let publication = mqtt::proto::Publication {
+            topic_name: message.topic_name(&self.events_topic_name),
+            qos: mqtt::proto::QoS::AtLeastOnce,
+            retain: false,
+            payload: message.payload,
+        };
+        futures::future::Either::B(
+            self.publish_handle
+                .publish(publication)
+                .map_err(SendEventError::Publish),
+        )
+    }
+}
+
+#[derive(Debug)]
+pub enum SendEventError {
+    InvalidContentType(String),
+    Publish(mqtt::PublishError),
+}
+
+impl std::fmt::Display for SendEventError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SendEventError::InvalidContentType(content_type) => {
+                write!(f, "content type {:?} is not a MIME type", content_type)
+            }
+            SendEventError::Publish(err) => write!(f, "could not publish message: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for SendEventError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SendEventError::InvalidContentType(_) => None,
+            SendEventError::Publish(err) => Some(err),
+        }
+    }
+}
+
+/// Checks that the given content type has the form `type "/" subtype *(";" parameter)` from RFC 2045,
+/// where the parameter values are tokens or quoted strings
+fn is_valid_content_type(content_type: &str) -> bool {
+    fn is_token(s: &str) -> bool {
+        !s.is_empty()
+            && s.chars()
+                .all(|c| c.is_ascii_graphic() && !"()<>@,;:\\\"/[]?=".contains(c))
+    }
+
+    fn is_quoted_string(s: &str) -> bool {
+        s.len() >= 2
+            && s.starts_with('"')
+            && s.ends_with('"')
+            && s[1..s.len() - 1]
+                .chars()
+                .all(|c| c.is_ascii() && c != '"' && c != '\\' && !c.is_ascii_control())
+    }
+
+    let mut parts = content_type.split(';');
+
+    let media_type = parts.next().unwrap_or_default().trim();
+    let mut media_type = media_type.splitn(2, '/');
+    let (top_level_type, subtype) = match (media_type.next(), media_type.next()) {
+        (Some(top_level_type), Some(subtype)) => (top_level_type, subtype),
+        _ => return false,
+    };
+    if !is_token(top_level_type) || !is_token(subtype) {
+        return false;
+    }
+
+    parts.all(|parameter| {
+        let mut parameter = parameter.trim().splitn(2, '=');
+        match (parameter.next(), parameter.next()) {
+            (Some(attribute), Some(value)) => {
+                is_token(attribute) && (is_token(value) || is_quoted_string(value))
+            }
+            _ => false,
+        }
+    })
+}
+
+impl From<Vec<u8>> for OutgoingMessage {
+    fn from(payload: Vec<u8>) -> Self {
+        OutgoingMessage {
+            payload,
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn is_valid_content_type() {
+        for &content_type in &[
+            "application/json",
+            "application/vnd.foo+json",
+            "text/plain; charset=utf-8",
+            "text/plain;charset=\"utf-8\"",
+        ] {
+            assert!(
+                super::is_valid_content_type(content_type),
+                "{:?}",
+                content_type
+            );
+        }
+
+        for &content_type in &[
+            "",
+            "json",
+            "application/",
+            "/json",
+            "application/json/x",
+            "application json",
+            "application/json; charset",
+            "application/json; charset=",
+            "application/json; charset=utf 8",
+        ] {
+            assert!(
+                !super::is_valid_content_type(content_type),
+                "{:?}",
+                content_type
+            );
+        }
+    }
+}
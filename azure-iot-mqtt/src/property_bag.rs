@@ -0,0 +1,126 @@
+//! The Azure IoT Hub sends and receives message properties as a property bag in the topic name,
+//! ie a URL-encoded query string like `$.mid=1&key=some%20value`
+
+/// Parses a property bag into its properties.
+///
+/// Keys and values are percent-decoded, and `+` is decoded as a space. A key without `=` has an empty value.
+/// If a key is repeated, the last value wins.
+///
+/// Malformed input doesn't fail to parse. Invalid percent-encoded sequences are kept as they are,
+/// and bytes that don't decode to valid UTF-8 are replaced with U+FFFD.
+pub fn parse(property_bag: &str) -> std::collections::BTreeMap<String, String> {
+    url::form_urlencoded::parse(property_bag.as_bytes())
+        .into_owned()
+        .collect()
+}
+
+/// Encodes the given properties into a property bag, in the order they're given.
+///
+/// Keys and values are percent-encoded, except for unreserved characters and `$` which the Azure IoT Hub uses
+/// for the keys of system properties like `$.mid`. Spaces are encoded as `%20` rather than `+`
+/// since MQTT topic names must not contain the `+` wildcard.
+pub fn encode<I, K, V>(properties: I) -> String
+where
+    I: IntoIterator<Item = (K, V)>,
+    K: AsRef<str>,
+    V: AsRef<str>,
+{
+    let mut result = String::new();
+
+    for (key, value) in properties {
+        if !result.is_empty() {
+            result.push('&');
+        }
+
+        percent_encode(key.as_ref(), &mut result);
+        result.push('=');
+        percent_encode(value.as_ref(), &mut result);
+    }
+
+    result
+}
+
+fn percent_encode(s: &str, result: &mut String) {
+    for &b in s.as_bytes() {
+        match b {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'$' => {
+                result.push(char::from(b))
+            }
+            b => {
+                result.push('%');
+                result.push(char::from(HEX_DIGITS[usize::from(b >> 4)]));
+                result.push(char::from(HEX_DIGITS[usize::from(b & 0x0F)]));
+            }
+        }
+    }
+}
+
+const HEX_DIGITS: &[u8; 16] = b"0123456789ABCDEF";
+
+#[cfg(test)]
+mod tests {
+    fn properties(properties: &[(&str, &str)]) -> std::collections::BTreeMap<String, String> {
+        properties
+            .iter()
+            .map(|&(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn parse_percent_decodes() {
+        assert_eq!(
+            super::parse("%24.mid=1&some%20key=some%20value%26more"),
+            properties(&[("$.mid", "1"), ("some key", "some value&more")]),
+        );
+
+        // Invalid sequences are kept as they are
+        assert_eq!(super::parse("key=100%zz"), properties(&[("key", "100%zz")]));
+    }
+
+    #[test]
+    fn parse_decodes_plus_as_space() {
+        assert_eq!(
+            super::parse("some+key=some+value"),
+            properties(&[("some key", "some value")]),
+        );
+    }
+
+    #[test]
+    fn parse_empty_values() {
+        assert_eq!(
+            super::parse("key1=&key2&key3=value3"),
+            properties(&[("key1", ""), ("key2", ""), ("key3", "value3")]),
+        );
+        assert_eq!(super::parse(""), properties(&[]));
+    }
+
+    #[test]
+    fn parse_repeated_keys() {
+        assert_eq!(
+            super::parse("key=value1&other=value2&key=value3"),
+            properties(&[("key", "value3"), ("other", "value2")]),
+        );
+    }
+
+    #[test]
+    fn encode() {
+        assert_eq!(
+            super::encode(vec![
+                ("$.mid", "1"),
+                ("some key", "a+b&c=d/e#f?g%"),
+                ("unreserved", "aZ09-._~"),
+            ]),
+            "$.mid=1&some%20key=a%2Bb%26c%3Dd%2Fe%23f%3Fg%25&unreserved=aZ09-._~",
+        );
+        assert_eq!(super::encode(Vec::<(&str, &str)>::new()), "");
+    }
+
+    #[test]
+    fn encode_parse_round_trip() {
+        let original = properties(&[
+            ("$.ct", "application/json"),
+            ("key", "a value+with&symbols="),
+        ]);
+        assert_eq!(super::parse(&super::encode(&original)), original);
+    }
+}
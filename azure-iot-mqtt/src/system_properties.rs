@@ -7,12 +7,26 @@ pub struct SystemProperties {
     pub iothub_ack: IotHubAck,
 }
 
+/// The delivery feedback that the sender of a cloud-to-device message asked the Azure IoT Hub for
+///
+/// Over MQTT, the hub considers a message completed once the device acks it, so messages received by [`crate::device::Client`]
+/// are never reported as rejected. They can still be reported as expired if they expire before they're delivered.
+/// See [`crate::device::CloudToDeviceMessage`]
 #[derive(Debug)]
 pub enum IotHubAck {
+    /// Feedback for both completed and rejected or expired messages
     Full,
+
+    /// Feedback only for rejected or expired messages
     Negative,
+
+    /// No feedback
     None,
+
+    /// Feedback only for completed messages
     Positive,
+
+    /// A value this crate doesn't recognize
     Other(String),
 }
 
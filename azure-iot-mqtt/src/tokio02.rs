@@ -0,0 +1,323 @@
+//! This module contains `std::future` versions of the I/O types, for applications that run on tokio 0.2. It requires the `tokio02` feature.
+//!
+//! [`IoSource`] connects to the Azure IoT Hub over TCP and TLS, and optionally WebSockets, like [`crate::IoSource`] does,
+//! but with tokio 0.2's networking and `async fn`s. The resulting [`Io`] implements tokio 0.2's `AsyncRead` and `AsyncWrite`.
+//!
+//! [`mqtt::Client`] is still a futures 0.1 type, so these types are meant for applications that drive their own MQTT client
+//! with `async`/`await`.
+
+use futures_03::{Sink, Stream};
+
+/// Connects to the Azure IoT Hub with tokio 0.2
+pub struct IoSource {
+    iothub_hostname: String,
+    certificate: Option<(Vec<u8>, String)>,
+    trusted_certificates: Vec<native_tls::Certificate>,
+    timeout: std::time::Duration,
+    transport: crate::Transport,
+}
+
+impl IoSource {
+    /// Creates a source of connections to the Azure IoT Hub at `iothub_hostname`.
+    ///
+    /// * `certificate`
+    ///
+    ///     The DER-encoded PKCS#12 client certificate and its password, if the device authenticates with a certificate.
+    ///
+    /// * `timeout`
+    ///
+    ///     How long connecting to the Azure IoT Hub may take, including the TLS and WebSocket handshakes.
+    ///
+    /// * `transport`
+    ///
+    ///     The transport to use for the connection.
+    pub fn new(
+        iothub_hostname: String,
+        certificate: Option<(Vec<u8>, String)>,
+        timeout: std::time::Duration,
+        transport: crate::Transport,
+    ) -> Self {
+        IoSource {
+            iothub_hostname,
+            certificate,
+            trusted_certificates: vec![],
+            timeout,
+            transport,
+        }
+    }
+
+    /// Sets additional root certificates to trust when verifying the server's certificate, on top of the system's trusted roots.
+    ///
+    /// This is needed when the server's certificate is not issued by a publicly trusted CA, such as when connecting to
+    /// an IoT Edge gateway.
+    pub fn set_trusted_certificates(&mut self, trusted_certificates: Vec<native_tls::Certificate>) {
+        self.trusted_certificates = trusted_certificates;
+    }
+
+    /// Connects to the Azure IoT Hub.
+    ///
+    /// Fails with a [`std::io::ErrorKind::TimedOut`] error if connecting takes longer than the timeout.
+    /// If the WebSocket handshake fails, the error wraps a [`crate::WebSocketHandshakeError`].
+    pub async fn connect(
+        &self,
+    ) -> std::io::Result<Io<tokio_tls_03::TlsStream<tokio_02::net::TcpStream>>> {
+        match tokio_02::time::timeout(self.timeout, self.connect_inner()).await {
+            Ok(result) => result,
+            Err(_) => Err(std::io::ErrorKind::TimedOut.into()),
+        }
+    }
+
+    async fn connect_inner(
+        &self,
+    ) -> std::io::Result<Io<tokio_tls_03::TlsStream<tokio_02::net::TcpStream>>> {
+        let port = match self.transport {
+            crate::Transport::Tcp => 8883,
+            crate::Transport::WebSocket => 443,
+        };
+
+        let stream = tokio_02::net::TcpStream::connect((&*self.iothub_hostname, port)).await?;
+        stream.set_nodelay(true)?;
+
+        let connector =
+            crate::io::tls_connector(self.certificate.as_ref(), self.trusted_certificates.clone())?;
+        let connector: tokio_tls_03::TlsConnector = connector.into();
+        let stream = connector
+            .connect(&self.iothub_hostname, stream)
+            .await
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))?;
+
+        match self.transport {
+            crate::Transport::Tcp => Ok(Io::Raw(stream)),
+
+            crate::Transport::WebSocket => {
+                let mut request =
+                    tokio_tungstenite::tungstenite::client::IntoClientRequest::into_client_request(
+                        format!("ws://{}/$iothub/websocket", self.iothub_hostname),
+                    )
+                    .map_err(io_error_from_tungstenite_error)?;
+                request.headers_mut().insert(
+                    "sec-websocket-protocol",
+                    tokio_tungstenite::tungstenite::http::HeaderValue::from_static("mqtt"),
+                );
+
+                let (inner, _) = tokio_tungstenite::client_async(request, stream)
+                    .await
+                    .map_err(|err| match err {
+                        tokio_tungstenite::tungstenite::Error::Http(status) => std::io::Error::new(
+                            std::io::ErrorKind::Other,
+                            crate::WebSocketHandshakeError::from_status(status.as_u16()),
+                        ),
+
+                        tokio_tungstenite::tungstenite::Error::Protocol(message) => {
+                            std::io::Error::new(
+                                std::io::ErrorKind::Other,
+                                crate::WebSocketHandshakeError::Protocol(message.into_owned()),
+                            )
+                        }
+
+                        err => io_error_from_tungstenite_error(err),
+                    })?;
+
+                Ok(Io::WebSocket {
+                    inner,
+                    pending_read: std::io::Cursor::new(vec![]),
+                })
+            }
+        }
+    }
+}
+
+/// A wrapper around an inner I/O object
+pub enum Io<S> {
+    Raw(S),
+
+    WebSocket {
+        inner: tokio_tungstenite::WebSocketStream<S>,
+        pending_read: std::io::Cursor<Vec<u8>>,
+    },
+}
+
+impl<S> tokio_02::io::AsyncRead for Io<S>
+where
+    S: tokio_02::io::AsyncRead + tokio_02::io::AsyncWrite + Unpin,
+{
+    fn poll_read(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut [u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        use std::io::Read;
+
+        let (inner, pending_read) = match self.get_mut() {
+            Io::Raw(stream) => return std::pin::Pin::new(stream).poll_read(cx, buf),
+            Io::WebSocket {
+                inner,
+                pending_read,
+            } => (inner, pending_read),
+        };
+
+        if buf.is_empty() {
+            return std::task::Poll::Ready(Ok(0));
+        }
+
+        loop {
+            if pending_read.position() != pending_read.get_ref().len() as u64 {
+                return std::task::Poll::Ready(Ok(pending_read
+                    .read(buf)
+                    .expect("Cursor::read cannot fail")));
+            }
+
+            let message = match futures_03::ready!(std::pin::Pin::new(&mut *inner).poll_next(cx)) {
+                Some(Ok(tokio_tungstenite::tungstenite::Message::Binary(b))) => b,
+
+                Some(Ok(message)) => {
+                    log::warn!("ignoring unexpected message: {:?}", message);
+                    continue;
+                }
+
+                Some(Err(err)) => {
+                    return std::task::Poll::Ready(Err(io_error_from_tungstenite_error(err)))
+                }
+
+                None => return std::task::Poll::Ready(Ok(0)),
+            };
+
+            *pending_read = std::io::Cursor::new(message);
+        }
+    }
+}
+
+impl<S> tokio_02::io::AsyncWrite for Io<S>
+where
+    S: tokio_02::io::AsyncRead + tokio_02::io::AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        let inner = match self.get_mut() {
+            Io::Raw(stream) => return std::pin::Pin::new(stream).poll_write(cx, buf),
+            Io::WebSocket { inner, .. } => inner,
+        };
+
+        if buf.is_empty() {
+            return std::task::Poll::Ready(Ok(0));
+        }
+
+        futures_03::ready!(std::pin::Pin::new(&mut *inner).poll_ready(cx))
+            .map_err(io_error_from_tungstenite_error)?;
+
+        let message = tokio_tungstenite::tungstenite::Message::Binary(buf.to_owned());
+        std::pin::Pin::new(inner)
+            .start_send(message)
+            .map_err(io_error_from_tungstenite_error)?;
+
+        std::task::Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Io::Raw(stream) => std::pin::Pin::new(stream).poll_flush(cx),
+            Io::WebSocket { inner, .. } => std::pin::Pin::new(inner)
+                .poll_flush(cx)
+                .map_err(io_error_from_tungstenite_error),
+        }
+    }
+
+    fn poll_shutdown(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Io::Raw(stream) => std::pin::Pin::new(stream).poll_shutdown(cx),
+            Io::WebSocket { inner, .. } => std::pin::Pin::new(inner)
+                .poll_close(cx)
+                .map_err(io_error_from_tungstenite_error),
+        }
+    }
+}
+
+fn io_error_from_tungstenite_error(err: tokio_tungstenite::tungstenite::Error) -> std::io::Error {
+    match err {
+        tokio_tungstenite::tungstenite::Error::Io(err) => err,
+        err => std::io::Error::new(std::io::ErrorKind::Other, err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn websocket_io_reads_and_writes_binary_messages() {
+        use futures_03::{SinkExt, StreamExt};
+        use tokio_02::io::{AsyncReadExt, AsyncWriteExt};
+
+        let mut runtime = tokio_02::runtime::Builder::new()
+            .basic_scheduler()
+            .enable_all()
+            .build()
+            .unwrap();
+
+        runtime.block_on(async {
+            let mut listener = tokio_02::net::TcpListener::bind("127.0.0.1:0")
+                .await
+                .unwrap();
+            let server_addr = listener.local_addr().unwrap();
+
+            let server = tokio_02::spawn(async move {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut server = tokio_tungstenite::accept_async(stream).await.unwrap();
+
+                let message = server.next().await.unwrap().unwrap();
+                assert_eq!(
+                    message,
+                    tokio_tungstenite::tungstenite::Message::Binary(b"ping".to_vec())
+                );
+
+                // The client ignores text messages, and reads that span binary messages are stitched together
+                server
+                    .send(tokio_tungstenite::tungstenite::Message::Text(
+                        "ignored".to_owned(),
+                    ))
+                    .await
+                    .unwrap();
+                server
+                    .send(tokio_tungstenite::tungstenite::Message::Binary(
+                        b"po".to_vec(),
+                    ))
+                    .await
+                    .unwrap();
+                server
+                    .send(tokio_tungstenite::tungstenite::Message::Binary(
+                        b"ng".to_vec(),
+                    ))
+                    .await
+                    .unwrap();
+            });
+
+            let stream = tokio_02::net::TcpStream::connect(server_addr)
+                .await
+                .unwrap();
+            let (inner, _) =
+                tokio_tungstenite::client_async(format!("ws://{}/", server_addr), stream)
+                    .await
+                    .unwrap();
+            let mut io = super::Io::WebSocket {
+                inner,
+                pending_read: std::io::Cursor::new(vec![]),
+            };
+
+            io.write_all(b"ping").await.unwrap();
+            io.flush().await.unwrap();
+
+            let mut buf = [0; 4];
+            io.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"pong");
+
+            server.await.unwrap();
+        });
+    }
+}
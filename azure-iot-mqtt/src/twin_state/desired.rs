@@ -18,7 +18,7 @@ enum Inner {
     SendRequest,
 
     WaitingForResponse {
-        request_id: u8,
+        request_id: u64,
         timeout: tokio::timer::Delay,
     },
 
@@ -43,13 +43,16 @@ impl State {
 		clippy::unneeded_field_pattern, // Clippy wants wildcard pattern for the `if let Some(Response)` pattern below,
 		                                // which would silently allow fields to be added to the variant without adding them here
 	)]
-    pub(crate) fn poll(
+    pub(crate) fn poll<IoS>(
         &mut self,
-        client: &mut mqtt::Client<crate::IoSource>,
+        client: &mut mqtt::Client<IoS>,
 
         message: &mut Option<super::InternalTwinStateMessage>,
-        previous_request_id: &mut u8,
-    ) -> Result<super::Response<Message>, super::MessageParseError> {
+        previous_request_id: &mut u64,
+    ) -> Result<super::Response<Message>, super::MessageParseError>
+    where
+        IoS: mqtt::IoSource,
+    {
         loop {
             log::trace!("    {:?}", self.inner);
 
@@ -105,6 +108,17 @@ impl State {
                     request_id,
                     timeout,
                 } => {
+                    if let Some(super::InternalTwinStateMessage::TwinPatch(twin_properties)) =
+                        message
+                    {
+                        // The response includes this patch, since the server sent the patch before it, so don't send another GET for it
+                        log::debug!(
+                            "discarding PATCH with version {} while waiting for twin state",
+                            twin_properties.version
+                        );
+                        let _ = message.take();
+                    }
+
                     if let Some(super::InternalTwinStateMessage::Response {
                         status,
                         request_id: message_request_id,
@@ -129,8 +143,7 @@ impl State {
                                     )));
                                 }
 
-                                status @ crate::Status::TooManyRequests
-                                | status @ crate::Status::Error(_) => {
+                                status if status.is_retryable() => {
                                     log::warn!(
                                         "getting initial twin state failed with status {}",
                                         status
@@ -167,8 +180,21 @@ impl State {
                 Inner::HaveResponse { version } => {
                     match message.take() {
                         Some(super::InternalTwinStateMessage::TwinPatch(twin_properties)) => {
-                            if twin_properties.version != *version + 1 {
-                                log::warn!("expected PATCH response with version {} but received version {}", *version + 1, twin_properties.version);
+                            // Eg a patch that was redelivered, or that the server sent before the response to the last GET.
+                            // The twin state already includes it, so it isn't a gap that needs another GET.
+                            if twin_properties.version <= *version {
+                                log::debug!(
+                                    "discarding PATCH with version {} since twin state is already at version {}",
+                                    twin_properties.version,
+                                    version
+                                );
+                                return Ok(super::Response::NotReady);
+                            }
+
+                            // The version comes from the server, so don't trust it to not overflow
+                            let expected_version = version.wrapping_add(1);
+                            if twin_properties.version != expected_version {
+                                log::warn!("expected PATCH response with version {} but received version {}", expected_version, twin_properties.version);
                                 self.inner = Inner::SendRequest;
                                 continue;
                             }
@@ -234,3 +260,102 @@ pub(crate) enum Message {
 
     Patch(crate::TwinProperties),
 }
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn version_gap_sends_single_get() {
+        use super::{Message, State};
+        use crate::twin_state::{InternalTwinStateMessage, Response};
+
+        crate::twin_state::with_test_client(|client| {
+            let mut state = State::new(
+                std::time::Duration::from_secs(1),
+                std::time::Duration::from_secs(4),
+            );
+
+            let mut previous_request_id = 0;
+
+            let twin_state_response = |request_id: u64, version: usize| {
+                Some(InternalTwinStateMessage::Response {
+                    status: crate::Status::Ok,
+                    request_id,
+                    version: None,
+                    payload: serde_json::to_vec(&serde_json::json!({
+                        "desired": { "$version": version },
+                        "reported": { "$version": 1 },
+                    }))
+                    .unwrap(),
+                })
+            };
+
+            let patch = |version: usize| {
+                Some(InternalTwinStateMessage::TwinPatch(
+                    serde_json::from_value(serde_json::json!({ "$version": version })).unwrap(),
+                ))
+            };
+
+            match state.poll(client, &mut None, &mut previous_request_id) {
+                Ok(Response::Continue) => (),
+                _ => panic!("expected GET to be sent"),
+            }
+            assert_eq!(previous_request_id, 1);
+
+            match state.poll(
+                client,
+                &mut twin_state_response(1, 1),
+                &mut previous_request_id,
+            ) {
+                Ok(Response::Message(Message::Initial(twin_state))) => {
+                    assert_eq!(twin_state.desired.version, 1)
+                }
+                _ => panic!("expected initial twin state"),
+            }
+
+            // Version 2 was missed, so the twin state is fetched again
+            match state.poll(client, &mut patch(3), &mut previous_request_id) {
+                Ok(Response::Continue) => (),
+                _ => panic!("expected GET to be sent"),
+            }
+            assert_eq!(previous_request_id, 2);
+
+            // More patches while waiting for the response don't send more GETs
+            for version in 4..=5 {
+                let mut message = patch(version);
+                match state.poll(client, &mut message, &mut previous_request_id) {
+                    Ok(Response::NotReady) => (),
+                    _ => panic!("expected patch to be discarded"),
+                }
+                assert!(message.is_none());
+            }
+            assert_eq!(previous_request_id, 2);
+
+            match state.poll(
+                client,
+                &mut twin_state_response(2, 5),
+                &mut previous_request_id,
+            ) {
+                Ok(Response::Message(Message::Initial(twin_state))) => {
+                    assert_eq!(twin_state.desired.version, 5)
+                }
+                _ => panic!("expected initial twin state"),
+            }
+
+            // Patches that the twin state already includes aren't a gap
+            let mut message = patch(5);
+            match state.poll(client, &mut message, &mut previous_request_id) {
+                Ok(Response::NotReady) => (),
+                _ => panic!("expected patch to be discarded"),
+            }
+            assert!(message.is_none());
+            assert_eq!(previous_request_id, 2);
+
+            match state.poll(client, &mut patch(6), &mut previous_request_id) {
+                Ok(Response::Message(Message::Patch(twin_properties))) => {
+                    assert_eq!(twin_properties.version, 6)
+                }
+                _ => panic!("expected patch"),
+            }
+        });
+    }
+}
@@ -1,7 +1,7 @@
 pub(crate) mod desired;
 
 pub(crate) mod reported;
-pub use self::reported::{ReportTwinStateHandle, ReportTwinStateRequest};
+pub use self::reported::{ReportTwinStateAck, ReportTwinStateHandle, ReportTwinStateRequest};
 
 /// The full twin state stored in the Azure IoT Hub.
 #[derive(Debug, serde_derive::Deserialize)]
@@ -14,20 +14,51 @@ pub struct TwinState {
 }
 
 /// A collection of twin properties, including a version number
-#[derive(Debug, serde_derive::Deserialize)]
+#[derive(Debug)]
 pub struct TwinProperties {
-    #[serde(rename = "$version")]
     pub version: usize,
 
-    #[serde(flatten)]
     pub properties: std::collections::HashMap<String, serde_json::Value>,
+
+    /// The properties exactly as they were received, including `$version`.
+    ///
+    /// Applications with their own types for the properties can deserialize them from this with `serde_json::from_value`
+    /// instead of going through `properties`.
+    pub raw: serde_json::Value,
+}
+
+impl<'de> serde::Deserialize<'de> for TwinProperties {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        #[derive(serde_derive::Deserialize)]
+        struct Inner {
+            #[serde(rename = "$version")]
+            version: usize,
+
+            #[serde(flatten)]
+            properties: std::collections::HashMap<String, serde_json::Value>,
+        }
+
+        let raw: serde_json::Value = serde::Deserialize::deserialize(deserializer)?;
+        let Inner {
+            version,
+            properties,
+        } = serde_json::from_value(raw.clone()).map_err(serde::de::Error::custom)?;
+        Ok(TwinProperties {
+            version,
+            properties,
+            raw,
+        })
+    }
 }
 
 #[derive(Debug)]
 pub(crate) enum InternalTwinStateMessage {
     Response {
         status: crate::Status,
-        request_id: u8,
+        request_id: u64,
         version: Option<usize>,
         payload: Vec<u8>,
     },
@@ -45,29 +76,20 @@ impl InternalTwinStateMessage {
 
             let query_string = &captures[2];
 
-            let mut request_id = None;
-            let mut version = None;
-
-            for (key, value) in url::form_urlencoded::parse(query_string.as_bytes()) {
-                match &*key {
-                    "$rid" => {
-                        request_id = Some(value.parse().map_err(|err| {
-                            MessageParseError::ParseResponseRequestId(status.to_string(), err)
-                        })?)
-                    }
-                    "$version" => {
-                        version = Some(value.parse().map_err(|err| {
-                            MessageParseError::ParseResponseVersion(status.to_string(), err)
-                        })?)
-                    }
-                    _ => (),
-                }
-            }
+            let properties = crate::property_bag::parse(query_string);
 
-            let request_id = if let Some(request_id) = request_id {
-                request_id
-            } else {
-                return Err(MessageParseError::MissingResponseRequestId);
+            let request_id = match properties.get("$rid") {
+                Some(request_id) => request_id.parse().map_err(|err| {
+                    MessageParseError::ParseResponseRequestId(status.to_string(), err)
+                })?,
+                None => return Err(MessageParseError::MissingResponseRequestId),
+            };
+
+            let version = match properties.get("$version") {
+                Some(version) => Some(version.parse().map_err(|err| {
+                    MessageParseError::ParseResponseVersion(status.to_string(), err)
+                })?),
+                None => None,
             };
 
             Ok(InternalTwinStateMessage::Response {
@@ -162,3 +184,151 @@ pub(crate) enum Response<M> {
 lazy_static::lazy_static! {
     static ref RESPONSE_REGEX: regex::Regex = regex::Regex::new(r"^\$iothub/twin/res/(\d+)/\?(.+)$").expect("could not compile regex");
 }
+
+/// Runs `f` on a tokio runtime with a client that is never polled, so it never connects. Publications just stay queued in it.
+#[cfg(test)]
+pub(crate) fn with_test_client<F>(f: F)
+where
+    F: FnOnce(
+        &mut mqtt::Client<fn() -> futures::future::Empty<tokio::net::TcpStream, std::io::Error>>,
+    ),
+{
+    let mut runtime =
+        tokio::runtime::current_thread::Runtime::new().expect("couldn't initialize tokio runtime");
+
+    runtime
+        .block_on(futures::future::lazy(|| -> Result<(), ()> {
+            let mut client = mqtt::Client::new(
+                None,
+                None,
+                None,
+                None,
+                futures::future::empty as fn() -> _,
+                std::time::Duration::from_secs(0),
+                std::time::Duration::from_secs(4),
+            )
+            .unwrap();
+
+            f(&mut client);
+
+            Ok(())
+        }))
+        .unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn twin_properties_raw() {
+        #[derive(Debug, PartialEq, serde_derive::Deserialize)]
+        struct Desired {
+            #[serde(rename = "$version")]
+            version: usize,
+            interval: u64,
+            target: Option<String>,
+        }
+
+        let twin_state: super::TwinState = serde_json::from_str(
+            r#"{ "desired": { "$version": 3, "interval": 5, "target": "1.2.3" }, "reported": { "$version": 1 } }"#,
+        )
+        .unwrap();
+
+        assert_eq!(twin_state.desired.version, 3);
+        assert_eq!(twin_state.desired.properties.len(), 2);
+        assert_eq!(
+            serde_json::from_value::<Desired>(twin_state.desired.raw).unwrap(),
+            Desired {
+                version: 3,
+                interval: 5,
+                target: Some("1.2.3".to_string()),
+            }
+        );
+        assert_eq!(
+            twin_state.reported.raw,
+            serde_json::json!({ "$version": 1 })
+        );
+
+        let err =
+            serde_json::from_str::<super::TwinProperties>(r#"{ "interval": 5 }"#).unwrap_err();
+        assert!(err.to_string().contains("$version"), "{}", err);
+    }
+
+    #[test]
+    fn responses_do_not_cross_match() {
+        use super::{desired, reported, InternalTwinStateMessage, Response};
+
+        crate::twin_state::with_test_client(|client| {
+            let mut desired = desired::State::new(
+                std::time::Duration::from_secs(1),
+                std::time::Duration::from_secs(4),
+            );
+            let mut reported = reported::State::new(
+                std::time::Duration::from_secs(1),
+                std::time::Duration::from_secs(4),
+                16,
+            );
+
+            // Start where a u8 request ID would have wrapped around
+            let mut previous_request_id = u64::from(u8::max_value());
+
+            // Send a twin GET, then a reported state update while the GET is still outstanding
+            match desired.poll(client, &mut None, &mut previous_request_id) {
+                Ok(Response::Continue) => (),
+                _ => panic!("expected desired state to send its request"),
+            }
+            let get_request_id = previous_request_id;
+
+            reported.set_initial_state(Default::default());
+            reported.set_initial_reported_state(
+                vec![("key".to_string(), serde_json::json!("value"))]
+                    .into_iter()
+                    .collect(),
+            );
+            match reported.poll(client, &mut None, &mut previous_request_id) {
+                Ok(Response::Continue) => (),
+                _ => panic!("expected reported state to send its request"),
+            }
+            let patch_request_id = previous_request_id;
+
+            assert_ne!(get_request_id, patch_request_id);
+
+            // The response to the update is ignored by the desired state, and accepted by the reported state
+            let mut message = Some(InternalTwinStateMessage::Response {
+                status: crate::Status::NoContent,
+                request_id: patch_request_id,
+                version: Some(5),
+                payload: vec![],
+            });
+            match desired.poll(client, &mut message, &mut previous_request_id) {
+                Ok(Response::NotReady) => (),
+                _ => panic!("expected desired state to ignore the response to the update"),
+            }
+            assert!(message.is_some());
+            match reported.poll(client, &mut message, &mut previous_request_id) {
+                Ok(Response::Message(reported::Message::Reported(5))) => (),
+                _ => panic!("expected reported state to accept the response to the update"),
+            }
+            assert!(message.is_none());
+
+            // The response to the GET is ignored by the reported state, and accepted by the desired state
+            let mut message = Some(InternalTwinStateMessage::Response {
+                status: crate::Status::Ok,
+                request_id: get_request_id,
+                version: None,
+                payload: br#"{ "desired": { "$version": 3 }, "reported": { "$version": 5, "key": "value" } }"#.to_vec(),
+            });
+            match reported.poll(client, &mut message, &mut previous_request_id) {
+                Ok(Response::NotReady) => (),
+                _ => panic!("expected reported state to ignore the response to the GET"),
+            }
+            assert!(message.is_some());
+            match desired.poll(client, &mut message, &mut previous_request_id) {
+                Ok(Response::Message(desired::Message::Initial(twin_state))) => {
+                    assert_eq!(twin_state.desired.version, 3);
+                }
+                _ => panic!("expected desired state to accept the response to the GET"),
+            }
+            assert!(message.is_none());
+        });
+    }
+}
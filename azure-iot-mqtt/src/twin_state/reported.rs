@@ -7,11 +7,37 @@ pub(crate) struct State {
 
     keep_alive: std::time::Duration,
 
-    report_twin_state_send: futures::sync::mpsc::Sender<ReportTwinStateRequest>,
-    report_twin_state_recv: futures::sync::mpsc::Receiver<ReportTwinStateRequest>,
+    report_twin_state_send: futures::sync::mpsc::Sender<QueuedRequest>,
+    report_twin_state_recv: futures::sync::mpsc::Receiver<QueuedRequest>,
     previous_twin_state: Option<std::collections::HashMap<String, serde_json::Value>>,
     current_twin_state: std::collections::HashMap<String, serde_json::Value>,
-    pending_response: Option<(u8, tokio::timer::Delay)>,
+
+    /// Requests are left in `report_twin_state_recv` until the first initial twin state has been received,
+    /// so that they're applied on top of the reported properties the hub already has.
+    have_initial_state: bool,
+
+    /// Reported properties that the device already had when the client was created, applied in order on top of the first initial twin state,
+    /// or on top of the current state once no report is waiting for its response
+    initial_reported_state: Vec<std::collections::HashMap<String, serde_json::Value>>,
+
+    pending_response: Option<(u64, tokio::timer::Delay)>,
+
+    /// The senders of the acknowledgements of requests that have been applied to the current state, but not sent in a report yet
+    unsent_acks: Vec<futures::sync::oneshot::Sender<ReportTwinStateAck>>,
+
+    /// The senders of the acknowledgements of requests whose report has been sent, and is waiting for its response or being retried
+    sent_acks: Vec<futures::sync::oneshot::Sender<ReportTwinStateAck>>,
+
+    /// The version that the hub's reported properties must have for it to apply the next report,
+    /// as set by a [`ReportTwinStateRequest::ConditionalPatch`]
+    expected_version: Option<usize>,
+
+    /// Reports whose payloads are larger than this are discarded instead of being sent
+    max_payload_len: Option<usize>,
+
+    /// Whether all the requests queued while a report is waiting for its response are combined into the next report,
+    /// instead of being reported one at a time
+    coalesce_reports: bool,
 
     inner: Inner,
 }
@@ -27,8 +53,13 @@ enum Inner {
 }
 
 impl State {
-    pub(crate) fn new(max_back_off: std::time::Duration, keep_alive: std::time::Duration) -> Self {
-        let (report_twin_state_send, report_twin_state_recv) = futures::sync::mpsc::channel(0);
+    pub(crate) fn new(
+        max_back_off: std::time::Duration,
+        keep_alive: std::time::Duration,
+        handle_channel_capacity: usize,
+    ) -> Self {
+        let (report_twin_state_send, report_twin_state_recv) =
+            futures::sync::mpsc::channel(handle_channel_capacity);
 
         State {
             max_back_off,
@@ -40,7 +71,14 @@ impl State {
             report_twin_state_recv,
             previous_twin_state: None,
             current_twin_state: Default::default(),
+            have_initial_state: false,
+            initial_reported_state: Default::default(),
             pending_response: None,
+            unsent_acks: vec![],
+            sent_acks: vec![],
+            expected_version: None,
+            max_payload_len: Some(crate::MAX_REPORTED_TWIN_STATE_LEN),
+            coalesce_reports: true,
 
             inner: Default::default(),
         }
@@ -50,13 +88,16 @@ impl State {
 		clippy::unneeded_field_pattern, // Clippy wants wildcard pattern for the `if let Some(Response)` pattern below,
 		                                // which would silently allow fields to be added to the variant without adding them here
 	)]
-    pub(crate) fn poll(
+    pub(crate) fn poll<IoS>(
         &mut self,
-        client: &mut mqtt::Client<crate::IoSource>,
+        client: &mut mqtt::Client<IoS>,
 
         message: &mut Option<super::InternalTwinStateMessage>,
-        previous_request_id: &mut u8,
-    ) -> Result<super::Response<Message>, super::MessageParseError> {
+        previous_request_id: &mut u64,
+    ) -> Result<super::Response<Message>, super::MessageParseError>
+    where
+        IoS: mqtt::IoSource,
+    {
         loop {
             log::trace!("    {:?}", self.inner);
 
@@ -87,11 +128,27 @@ impl State {
                 Inner::Idle => {
                     let mut current_twin_state_changed = false;
 
-                    while let futures::Async::Ready(Some(report_twin_state_request)) = self
-                        .report_twin_state_recv
-                        .poll()
-                        .expect("Receiver::poll cannot fail")
-                    {
+                    if self.have_initial_state && self.pending_response.is_none() {
+                        for properties in
+                            std::mem::replace(&mut self.initial_reported_state, Default::default())
+                        {
+                            merge(&mut self.current_twin_state, properties);
+                            current_twin_state_changed = true;
+                        }
+                    }
+
+                    // Requests made while a report is waiting for its response are left in the channel until the response arrives,
+                    // so an application that reports faster than the hub responds is held back instead of sending a report per request.
+                    while let futures::Async::Ready(Some(QueuedRequest {
+                        request: report_twin_state_request,
+                        ack_sender,
+                    })) = if self.have_initial_state && self.pending_response.is_none() {
+                        self.report_twin_state_recv
+                            .poll()
+                            .expect("Receiver::poll cannot fail")
+                    } else {
+                        futures::Async::NotReady
+                    } {
                         match report_twin_state_request {
                             ReportTwinStateRequest::Replace(properties) => {
                                 self.current_twin_state = properties
@@ -99,9 +156,24 @@ impl State {
                             ReportTwinStateRequest::Patch(patch) => {
                                 merge(&mut self.current_twin_state, patch)
                             }
+                            ReportTwinStateRequest::ConditionalPatch {
+                                patch,
+                                expected_version,
+                            } => {
+                                merge(&mut self.current_twin_state, patch);
+                                self.expected_version = Some(expected_version);
+                            }
                         }
 
                         current_twin_state_changed = true;
+                        self.unsent_acks.extend(ack_sender);
+
+                        if !self.coalesce_reports
+                            && self.previous_twin_state.as_ref() != Some(&self.current_twin_state)
+                        {
+                            // Report this request on its own before taking the next one
+                            break;
+                        }
                     }
 
                     if current_twin_state_changed
@@ -111,6 +183,14 @@ impl State {
                         continue;
                     }
 
+                    if self.pending_response.is_none()
+                        && !self.unsent_acks.is_empty()
+                        && self.previous_twin_state.as_ref() == Some(&self.current_twin_state)
+                    {
+                        // The hub already has the requested properties, so there's nothing to report
+                        self.ack(&ReportTwinStateAck::Accepted(None));
+                    }
+
                     if let Some((request_id, timeout)) = &mut self.pending_response {
                         if let Some(super::InternalTwinStateMessage::Response {
                             status,
@@ -120,40 +200,56 @@ impl State {
                         }) = message
                         {
                             if *message_request_id == *request_id {
-                                match status {
-                                    crate::Status::Ok | crate::Status::NoContent => {
-                                        let version = (*version).ok_or(
-                                            super::MessageParseError::MissingResponseVersion,
-                                        )?;
-
-                                        let _ = message.take();
+                                let status = *status;
+                                let version = *version;
+                                let _ = message.take();
+                                self.pending_response = None;
 
+                                match ResponseAction::from_status(status) {
+                                    ResponseAction::Accepted => {
                                         self.previous_twin_state =
                                             Some(self.current_twin_state.clone());
-                                        self.pending_response = None;
+                                        self.current_back_off = std::time::Duration::from_secs(0);
+                                        self.expected_version = None;
 
+                                        let version = version.ok_or(
+                                            super::MessageParseError::MissingResponseVersion,
+                                        )?;
+                                        self.ack(&ReportTwinStateAck::Accepted(Some(version)));
                                         return Ok(super::Response::Message(Message::Reported(
                                             version,
                                         )));
                                     }
 
-                                    status @ crate::Status::TooManyRequests
-                                    | status @ crate::Status::Error(_) => {
+                                    ResponseAction::Retry => {
                                         log::warn!(
                                             "reporting twin state failed with status {}",
                                             status
                                         );
 
-                                        let _ = message.take();
-
                                         self.inner = Inner::BeginBackOff;
                                         continue;
                                     }
 
-                                    status => {
-                                        let status = *status;
-                                        let _ = message.take();
-                                        return Err(super::MessageParseError::IotHubStatus(status));
+                                    ResponseAction::Rejected => {
+                                        log::warn!(
+                                            "reporting twin state was rejected with status {}",
+                                            status
+                                        );
+
+                                        // Retrying would only be rejected again, so discard the rejected changes
+                                        // to keep them out of subsequent reports
+                                        if let Some(previous_twin_state) = &self.previous_twin_state
+                                        {
+                                            self.current_twin_state = previous_twin_state.clone();
+                                        }
+                                        self.current_back_off = std::time::Duration::from_secs(0);
+                                        self.expected_version = None;
+                                        self.ack(&ReportTwinStateAck::Rejected(status));
+
+                                        return Ok(super::Response::Message(Message::Rejected(
+                                            status,
+                                        )));
                                     }
                                 }
                             }
@@ -165,6 +261,7 @@ impl State {
                         {
                             futures::Async::Ready(()) => {
                                 log::warn!("timed out waiting for report twin state response");
+                                let _ = self.pending_response.take();
                                 self.inner = Inner::SendRequest;
                             }
 
@@ -182,9 +279,45 @@ impl State {
                         // Wait for desired_properties to provide the initial reported twin state
                         return Ok(super::Response::NotReady);
                     };
+                    if patch.is_empty() {
+                        // The hub already has the current state, including that of a report that's still waiting for its response,
+                        // so stop waiting for it
+                        self.pending_response = None;
+                        self.expected_version = None;
+                        self.ack(&ReportTwinStateAck::Accepted(None));
+                        self.inner = Inner::Idle;
+                        continue;
+                    }
+
                     let payload = serde_json::to_vec(&patch)
                         .expect("cannot fail to serialize HashMap<String, serde_json::Value>");
 
+                    if let Some(max_payload_len) = self.max_payload_len {
+                        if payload.len() > max_payload_len {
+                            log::warn!(
+                                "reported twin state of {} bytes is larger than the maximum of {} bytes",
+                                payload.len(),
+                                max_payload_len
+                            );
+
+                            // The hub would reject it anyway, so discard the changes like those of a rejected report
+                            if let Some(previous_twin_state) = &self.previous_twin_state {
+                                self.current_twin_state = previous_twin_state.clone();
+                            }
+                            self.expected_version = None;
+                            self.inner = Inner::Idle;
+                            self.ack(&ReportTwinStateAck::TooLarge {
+                                len: payload.len(),
+                                max: max_payload_len,
+                            });
+
+                            return Ok(super::Response::Message(Message::TooLarge {
+                                len: payload.len(),
+                                max: max_payload_len,
+                            }));
+                        }
+                    }
+
                     let request_id = previous_request_id.wrapping_add(1);
                     *previous_request_id = request_id;
 
@@ -193,10 +326,7 @@ impl State {
                     // But we do need to tell the caller client to poll the `mqtt::Client` at least once more so that it attempts to send the message,
                     // so return `Response::Continue`.
                     let _ = client.publish(mqtt::proto::Publication {
-                        topic_name: format!(
-                            "$iothub/twin/PATCH/properties/reported/?$rid={}",
-                            request_id
-                        ),
+                        topic_name: request_topic_name(request_id, self.expected_version),
                         qos: mqtt::proto::QoS::AtMostOnce,
                         retain: false,
                         payload,
@@ -206,6 +336,7 @@ impl State {
                     let timeout = tokio::timer::Delay::new(deadline);
 
                     self.pending_response = Some((request_id, timeout));
+                    self.sent_acks.append(&mut self.unsent_acks);
 
                     self.inner = Inner::Idle;
 
@@ -217,20 +348,63 @@ impl State {
 
     pub(crate) fn new_connection(&mut self) {
         self.previous_twin_state = None;
+        self.pending_response = None;
         self.inner = Inner::SendRequest;
     }
 
+    /// Sets the reported properties that the hub has, as received in the initial twin state.
+    ///
+    /// The first time, this also becomes the current state that queued and future requests are applied to.
+    /// After a reconnect, the current state is kept and whatever differs from the hub's is reported again.
     pub(crate) fn set_initial_state(
         &mut self,
         state: std::collections::HashMap<String, serde_json::Value>,
     ) {
+        if !self.have_initial_state {
+            self.current_twin_state = state.clone();
+            for properties in
+                std::mem::replace(&mut self.initial_reported_state, Default::default())
+            {
+                merge(&mut self.current_twin_state, properties);
+            }
+            self.have_initial_state = true;
+        }
+
         self.previous_twin_state = Some(state);
         self.inner = Inner::SendRequest;
     }
 
+    /// Sets reported properties that the device already has, such as from persistent storage.
+    ///
+    /// They're applied like a patch on top of the reported properties in the first initial twin state, before any queued requests,
+    /// so only the properties that differ from the hub's are reported. If the initial twin state has already been received,
+    /// they're applied like any other patch, once the report that's waiting for its response, if any, has been acknowledged.
+    pub(crate) fn set_initial_reported_state(
+        &mut self,
+        properties: std::collections::HashMap<String, serde_json::Value>,
+    ) {
+        self.initial_reported_state.push(properties);
+    }
+
+    pub(crate) fn set_max_payload_len(&mut self, max_payload_len: Option<usize>) {
+        self.max_payload_len = max_payload_len;
+    }
+
+    pub(crate) fn set_coalesce_reports(&mut self, coalesce_reports: bool) {
+        self.coalesce_reports = coalesce_reports;
+    }
+
     pub(crate) fn report_twin_state_handle(&self) -> ReportTwinStateHandle {
         ReportTwinStateHandle(self.report_twin_state_send.clone())
     }
+
+    /// Acknowledges the requests of the report that was just handled, including those that haven't been sent
+    fn ack(&mut self, ack: &ReportTwinStateAck) {
+        for ack_sender in self.sent_acks.drain(..).chain(self.unsent_acks.drain(..)) {
+            // The application may have stopped waiting for the acknowledgement
+            let _ = ack_sender.send(ack.clone());
+        }
+    }
 }
 
 impl Default for Inner {
@@ -255,26 +429,103 @@ impl std::fmt::Debug for Inner {
 
 /// Used to report twin state to the Azure IoT Hub
 #[derive(Debug)]
-pub struct ReportTwinStateHandle(futures::sync::mpsc::Sender<ReportTwinStateRequest>);
+pub struct ReportTwinStateHandle(futures::sync::mpsc::Sender<QueuedRequest>);
 
 impl ReportTwinStateHandle {
-    /// Send a direct method response with the given parameters
+    /// Report the given twin state to the Azure IoT Hub.
+    ///
+    /// Requests made before the client has received the initial twin state are queued, and applied on top of
+    /// the reported properties in the initial twin state once it arrives. So a [`ReportTwinStateRequest::Patch`]
+    /// only ever changes the properties it contains, regardless of whether it's made before or after the application
+    /// has processed the `TwinInitial` message.
+    ///
+    /// The client waits for the hub to acknowledge a report before it sends the next one. By default, all the requests made
+    /// in the meantime are combined into that next report, with the latest value of each property winning, so the hub only sees
+    /// the latest state. Use `set_coalesce_reported_twin_state` on the device or module client to report them one at a time instead.
+    ///
+    /// The returned future resolves when the request has been queued, not when the hub has acknowledged it.
+    /// Requests made while the client is waiting for an acknowledgement stay queued, so once the queue is full,
+    /// the future doesn't resolve until the acknowledgement arrives.
     pub fn report_twin_state(
         &self,
         request: ReportTwinStateRequest,
     ) -> impl Future<Item = (), Error = ReportTwinStateError> {
-        self.0.clone().send(request).then(|result| match result {
-            Ok(_) => Ok(()),
-            Err(_) => Err(ReportTwinStateError::ClientDoesNotExist),
-        })
+        self.send(request, None)
+    }
+
+    /// Report the given twin state to the Azure IoT Hub, like [`ReportTwinStateHandle::report_twin_state`], but the returned future
+    /// resolves when the hub has acknowledged the report that contains the request, with how the hub handled it.
+    ///
+    /// Requests that are combined into one report share its acknowledgement. If the connection breaks before the hub responds,
+    /// the report is sent again once the client has reconnected, and the future keeps waiting.
+    pub fn report_twin_state_acked(
+        &self,
+        request: ReportTwinStateRequest,
+    ) -> impl Future<Item = ReportTwinStateAck, Error = ReportTwinStateError> {
+        let (ack_sender, ack_receiver) = futures::sync::oneshot::channel();
+        let ack_receiver = ack_receiver.map_err(|_| ReportTwinStateError::ClientDoesNotExist);
+
+        self.send(request, Some(ack_sender))
+            .and_then(|()| ack_receiver)
+    }
+
+    fn send(
+        &self,
+        request: ReportTwinStateRequest,
+        ack_sender: Option<futures::sync::oneshot::Sender<ReportTwinStateAck>>,
+    ) -> impl Future<Item = (), Error = ReportTwinStateError> {
+        self.0
+            .clone()
+            .send(QueuedRequest {
+                request,
+                ack_sender,
+            })
+            .then(|result| match result {
+                Ok(_) => Ok(()),
+                Err(_) => Err(ReportTwinStateError::ClientDoesNotExist),
+            })
     }
 }
 
+/// A request queued by a [`ReportTwinStateHandle`], with the sender of its acknowledgement if the application waits for it
+#[derive(Debug)]
+struct QueuedRequest {
+    request: ReportTwinStateRequest,
+    ack_sender: Option<futures::sync::oneshot::Sender<ReportTwinStateAck>>,
+}
+
 /// The kind of twin state update
 #[derive(Debug)]
 pub enum ReportTwinStateRequest {
     Replace(std::collections::HashMap<String, serde_json::Value>),
     Patch(std::collections::HashMap<String, serde_json::Value>),
+
+    /// Like [`ReportTwinStateRequest::Patch`], but the hub only applies the report if its reported properties
+    /// still have the given version, ie if no other writer has updated them since.
+    ///
+    /// If they don't, the report is rejected with 412 Precondition Failed, its changes are discarded like those of any other
+    /// rejected report, and the application can re-read the twin state and try again. If it's accepted, the new version
+    /// is returned in the acknowledgement.
+    ///
+    /// The condition applies to the whole report that contains this patch, which includes any other requests queued alongside it.
+    ConditionalPatch {
+        patch: std::collections::HashMap<String, serde_json::Value>,
+        expected_version: usize,
+    },
+}
+
+/// How the Azure IoT Hub handled the report that contained a request made with [`ReportTwinStateHandle::report_twin_state_acked`]
+#[derive(Clone, Debug)]
+pub enum ReportTwinStateAck {
+    /// The hub accepted the report. Contains the new version of the reported properties,
+    /// or `None` if nothing was reported because the hub already had the requested properties.
+    Accepted(Option<usize>),
+
+    /// The hub rejected the report with the given status, and its changes were discarded
+    Rejected(crate::Status),
+
+    /// The report wasn't sent because its payload was larger than the maximum. Contains the length of the payload and the maximum.
+    TooLarge { len: usize, max: usize },
 }
 
 #[derive(Debug)]
@@ -295,6 +546,50 @@ impl std::error::Error for ReportTwinStateError {}
 #[derive(Debug)]
 pub(crate) enum Message {
     Reported(usize),
+    Rejected(crate::Status),
+    TooLarge { len: usize, max: usize },
+}
+
+/// What to do with the response to a report of the twin state, based on its status
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ResponseAction {
+    /// The hub has the reported properties, eg 204 No Content
+    Accepted,
+
+    /// The request failed for a reason that may go away, eg 429 Too Many Requests or 503 Service Unavailable,
+    /// so it's sent again after backing off
+    Retry,
+
+    /// The hub refused the request, eg 400 Bad Request for a malformed or too large patch,
+    /// so sending the same request again would fail the same way
+    Rejected,
+}
+
+impl ResponseAction {
+    fn from_status(status: crate::Status) -> Self {
+        if status.is_success() {
+            ResponseAction::Accepted
+        } else if status.is_retryable() {
+            ResponseAction::Retry
+        } else {
+            ResponseAction::Rejected
+        }
+    }
+}
+
+/// The topic name of a report of the twin state with the given request ID, which the hub only applies
+/// if its reported properties have `expected_version`
+fn request_topic_name(request_id: u64, expected_version: Option<usize>) -> String {
+    match expected_version {
+        Some(expected_version) => format!(
+            "$iothub/twin/PATCH/properties/reported/?$rid={}&$version={}",
+            request_id, expected_version
+        ),
+        None => format!(
+            "$iothub/twin/PATCH/properties/reported/?$rid={}",
+            request_id
+        ),
+    }
 }
 
 fn merge(
@@ -389,6 +684,473 @@ fn diff(
 
 #[cfg(test)]
 mod tests {
+    #[test]
+    fn response_action() {
+        use super::ResponseAction;
+        use crate::Status;
+
+        for &(status, expected) in &[
+            (Status::Ok, ResponseAction::Accepted),
+            (Status::NoContent, ResponseAction::Accepted),
+            (Status::BadRequest, ResponseAction::Rejected),
+            (Status::Other(401), ResponseAction::Rejected),
+            (Status::Other(413), ResponseAction::Rejected),
+            (Status::TooManyRequests, ResponseAction::Retry),
+            (Status::Error(500), ResponseAction::Retry),
+            (Status::Error(503), ResponseAction::Retry),
+            (Status::Error(504), ResponseAction::Retry),
+        ] {
+            assert_eq!(
+                ResponseAction::from_status(status),
+                expected,
+                "{:?}",
+                status
+            );
+        }
+    }
+
+    #[test]
+    fn request_topic_name() {
+        assert_eq!(
+            super::request_topic_name(5, None),
+            "$iothub/twin/PATCH/properties/reported/?$rid=5"
+        );
+        assert_eq!(
+            super::request_topic_name(5, Some(3)),
+            "$iothub/twin/PATCH/properties/reported/?$rid=5&$version=3"
+        );
+    }
+
+    #[test]
+    fn too_large_report_is_discarded() {
+        use super::{Message, State};
+        use crate::twin_state::Response;
+
+        crate::twin_state::with_test_client(|client| {
+            let mut state = State::new(
+                std::time::Duration::from_secs(1),
+                std::time::Duration::from_secs(4),
+                16,
+            );
+            state.set_max_payload_len(Some(16));
+            state.set_initial_state(Default::default());
+
+            let mut previous_request_id = 0;
+
+            // {"big":"a long value"} is 22 bytes
+            state.set_initial_reported_state(
+                vec![("big".to_string(), serde_json::json!("a long value"))]
+                    .into_iter()
+                    .collect(),
+            );
+            match state.poll(client, &mut None, &mut previous_request_id) {
+                Ok(Response::Message(Message::TooLarge { len: 22, max: 16 })) => (),
+                _ => panic!("expected report to be too large"),
+            }
+            assert_eq!(previous_request_id, 0);
+
+            // The discarded changes aren't part of the next report, so it fits
+            state.set_initial_reported_state(
+                vec![("s".to_string(), serde_json::json!("value"))]
+                    .into_iter()
+                    .collect(),
+            );
+            match state.poll(client, &mut None, &mut previous_request_id) {
+                Ok(Response::Continue) => (),
+                _ => panic!("expected report to be sent"),
+            }
+            assert_eq!(previous_request_id, 1);
+        });
+    }
+
+    #[test]
+    fn reports_are_coalesced_while_waiting_for_response() {
+        use futures::Future;
+
+        use super::{Message, ReportTwinStateRequest, State};
+        use crate::twin_state::{InternalTwinStateMessage, Response};
+
+        for &coalesce_reports in &[true, false] {
+            crate::twin_state::with_test_client(|client| {
+                let mut state = State::new(
+                    std::time::Duration::from_secs(1),
+                    std::time::Duration::from_secs(4),
+                    16,
+                );
+                state.set_coalesce_reports(coalesce_reports);
+                state.set_initial_state(Default::default());
+                let report_twin_state_handle = state.report_twin_state_handle();
+
+                let mut previous_request_id = 0;
+
+                let report = |key: &str, value: u32| {
+                    report_twin_state_handle
+                        .report_twin_state(ReportTwinStateRequest::Patch(
+                            vec![(key.to_string(), serde_json::json!(value))]
+                                .into_iter()
+                                .collect(),
+                        ))
+                        .wait()
+                        .unwrap();
+                };
+
+                let accept = |state: &mut State,
+                              client: &mut mqtt::Client<_>,
+                              previous_request_id: &mut u64| {
+                    let mut message = Some(InternalTwinStateMessage::Response {
+                        status: crate::Status::NoContent,
+                        request_id: *previous_request_id,
+                        version: Some(1),
+                        payload: vec![],
+                    });
+                    match state.poll(client, &mut message, previous_request_id) {
+                        Ok(Response::Message(Message::Reported(1))) => (),
+                        _ => panic!("expected report to be accepted"),
+                    }
+                };
+
+                // Nothing to report yet
+                match state.poll(client, &mut None, &mut previous_request_id) {
+                    Ok(Response::NotReady) => (),
+                    _ => panic!("expected nothing to be reported"),
+                }
+
+                report("key1", 1);
+                match state.poll(client, &mut None, &mut previous_request_id) {
+                    Ok(Response::Continue) => (),
+                    _ => panic!("expected first request to be reported"),
+                }
+                assert_eq!(previous_request_id, 1);
+
+                // Requests made while the first report is waiting for its response aren't reported yet
+                report("key1", 2);
+                report("key2", 3);
+                match state.poll(client, &mut None, &mut previous_request_id) {
+                    Ok(Response::NotReady) => (),
+                    _ => panic!("expected requests to wait for the response"),
+                }
+                assert_eq!(previous_request_id, 1);
+
+                accept(&mut state, client, &mut previous_request_id);
+
+                match state.poll(client, &mut None, &mut previous_request_id) {
+                    Ok(Response::Continue) => (),
+                    _ => panic!("expected queued requests to be reported"),
+                }
+                assert_eq!(previous_request_id, 2);
+
+                accept(&mut state, client, &mut previous_request_id);
+
+                // Without coalescing, the last request is reported on its own
+                match state.poll(client, &mut None, &mut previous_request_id) {
+                    Ok(Response::NotReady) if coalesce_reports => (),
+                    Ok(Response::Continue) if !coalesce_reports => (),
+                    _ => panic!(
+                        "unexpected response, coalesce_reports = {}",
+                        coalesce_reports
+                    ),
+                }
+                assert_eq!(previous_request_id, if coalesce_reports { 2 } else { 3 });
+            });
+        }
+    }
+
+    #[test]
+    fn acked_report_resolves_with_response() {
+        use futures::Future;
+
+        use super::{Message, ReportTwinStateAck, ReportTwinStateRequest, State};
+        use crate::twin_state::{InternalTwinStateMessage, Response};
+
+        crate::twin_state::with_test_client(|client| {
+            let mut state = State::new(
+                std::time::Duration::from_secs(1),
+                std::time::Duration::from_secs(4),
+                16,
+            );
+            state.set_initial_state(Default::default());
+            let report_twin_state_handle = state.report_twin_state_handle();
+
+            let mut previous_request_id = 0;
+
+            // Polling the returned future queues the request
+            let report = |key: &str, value: u32| {
+                let mut ack = report_twin_state_handle.report_twin_state_acked(
+                    ReportTwinStateRequest::Patch(
+                        vec![(key.to_string(), serde_json::json!(value))]
+                            .into_iter()
+                            .collect(),
+                    ),
+                );
+                match ack.poll() {
+                    Ok(futures::Async::NotReady) => (),
+                    _ => panic!("expected the request to wait for its acknowledgement"),
+                }
+                ack
+            };
+
+            let respond = |state: &mut State,
+                           client: &mut mqtt::Client<_>,
+                           previous_request_id: &mut u64,
+                           status: crate::Status| {
+                let mut message = Some(InternalTwinStateMessage::Response {
+                    status,
+                    request_id: *previous_request_id,
+                    version: Some(5),
+                    payload: vec![],
+                });
+                state.poll(client, &mut message, previous_request_id)
+            };
+
+            let mut ack1 = report("key1", 1);
+            match state.poll(client, &mut None, &mut previous_request_id) {
+                Ok(Response::Continue) => (),
+                _ => panic!("expected request to be reported"),
+            }
+            match ack1.poll() {
+                Ok(futures::Async::NotReady) => (),
+                _ => panic!("expected the request to wait for the response"),
+            }
+
+            // A request made while the report is waiting for its response isn't acknowledged by that response
+            let mut ack2 = report("key1", 1);
+
+            match respond(
+                &mut state,
+                client,
+                &mut previous_request_id,
+                crate::Status::NoContent,
+            ) {
+                Ok(Response::Message(Message::Reported(5))) => (),
+                _ => panic!("expected report to be accepted"),
+            }
+            match ack1.poll() {
+                Ok(futures::Async::Ready(ReportTwinStateAck::Accepted(Some(5)))) => (),
+                _ => panic!("expected the request to be acknowledged with the new version"),
+            }
+            match ack2.poll() {
+                Ok(futures::Async::NotReady) => (),
+                _ => panic!("expected the request to still be queued"),
+            }
+
+            // The hub already has the second request's properties, so it's acknowledged without being reported
+            match state.poll(client, &mut None, &mut previous_request_id) {
+                Ok(Response::NotReady) => (),
+                _ => panic!("expected nothing to be reported"),
+            }
+            assert_eq!(previous_request_id, 1);
+            match ack2.poll() {
+                Ok(futures::Async::Ready(ReportTwinStateAck::Accepted(None))) => (),
+                _ => panic!("expected the request to be acknowledged without a version"),
+            }
+
+            let mut ack3 = report("key2", 2);
+            match state.poll(client, &mut None, &mut previous_request_id) {
+                Ok(Response::Continue) => (),
+                _ => panic!("expected request to be reported"),
+            }
+            match respond(
+                &mut state,
+                client,
+                &mut previous_request_id,
+                crate::Status::BadRequest,
+            ) {
+                Ok(Response::Message(Message::Rejected(crate::Status::BadRequest))) => (),
+                _ => panic!("expected report to be rejected"),
+            }
+            match ack3.poll() {
+                Ok(futures::Async::Ready(ReportTwinStateAck::Rejected(
+                    crate::Status::BadRequest,
+                ))) => (),
+                _ => panic!("expected the request to be rejected"),
+            }
+
+            // A request that's never reported fails once the client is gone
+            let mut ack4 = report("key3", 3);
+            drop(state);
+            match ack4.poll() {
+                Err(super::ReportTwinStateError::ClientDoesNotExist) => (),
+                _ => panic!("expected the request to fail"),
+            }
+        });
+    }
+
+    #[test]
+    fn report_queued_before_initial_state_is_applied_on_top_of_it() {
+        use futures::Future;
+
+        use super::{ReportTwinStateRequest, State};
+        use crate::twin_state::Response;
+
+        crate::twin_state::with_test_client(|client| {
+            let mut state = State::new(
+                std::time::Duration::from_secs(1),
+                std::time::Duration::from_secs(4),
+                16,
+            );
+
+            let mut previous_request_id = 0;
+
+            state
+                .report_twin_state_handle()
+                .report_twin_state(ReportTwinStateRequest::Patch(
+                    vec![("key2".to_string(), serde_json::json!(2))]
+                        .into_iter()
+                        .collect(),
+                ))
+                .wait()
+                .unwrap();
+
+            // The request waits for the initial twin state
+            match state.poll(client, &mut None, &mut previous_request_id) {
+                Ok(Response::NotReady) => (),
+                _ => panic!("expected request to wait for the initial twin state"),
+            }
+            assert_eq!(previous_request_id, 0);
+
+            let initial_state: std::collections::HashMap<_, _> =
+                vec![("key1".to_string(), serde_json::json!(1))]
+                    .into_iter()
+                    .collect();
+            state.set_initial_state(initial_state.clone());
+
+            match state.poll(client, &mut None, &mut previous_request_id) {
+                Ok(Response::Continue) => (),
+                _ => panic!("expected request to be reported"),
+            }
+            assert_eq!(previous_request_id, 1);
+
+            // Only the patch is reported, not the properties the hub already has
+            assert_eq!(state.previous_twin_state, Some(initial_state));
+            assert_eq!(
+                super::diff(
+                    state.previous_twin_state.as_ref().unwrap(),
+                    &state.current_twin_state
+                ),
+                vec![("key2".to_string(), serde_json::json!(2))]
+                    .into_iter()
+                    .collect(),
+            );
+        });
+    }
+
+    #[test]
+    fn timed_out_report_is_not_sent_again_if_hub_has_it() {
+        use futures::Future;
+
+        use super::{ReportTwinStateRequest, State};
+        use crate::twin_state::Response;
+
+        crate::twin_state::with_test_client(|client| {
+            let mut state = State::new(
+                std::time::Duration::from_secs(1),
+                std::time::Duration::from_secs(4),
+                16,
+            );
+            state.set_initial_state(Default::default());
+
+            let mut previous_request_id = 0;
+
+            let properties: std::collections::HashMap<_, _> =
+                vec![("key1".to_string(), serde_json::json!(1))]
+                    .into_iter()
+                    .collect();
+
+            state
+                .report_twin_state_handle()
+                .report_twin_state(ReportTwinStateRequest::Patch(properties.clone()))
+                .wait()
+                .unwrap();
+            match state.poll(client, &mut None, &mut previous_request_id) {
+                Ok(Response::Continue) => (),
+                _ => panic!("expected request to be reported"),
+            }
+            assert_eq!(previous_request_id, 1);
+
+            // The response is lost, and the initial twin state is fetched again, eg because of a version gap in the desired properties.
+            // The hub applied the report, so there's nothing left to report, and the report stops waiting for its response
+            // without waiting for it to time out.
+            state.set_initial_state(properties);
+
+            match state.poll(client, &mut None, &mut previous_request_id) {
+                Ok(Response::NotReady) => (),
+                _ => panic!("expected nothing to be reported"),
+            }
+            assert_eq!(previous_request_id, 1);
+            assert!(state.pending_response.is_none());
+        });
+    }
+
+    #[test]
+    fn initial_reported_state_waits_for_pending_report() {
+        use super::{Message, State};
+        use crate::twin_state::{InternalTwinStateMessage, Response};
+
+        crate::twin_state::with_test_client(|client| {
+            let mut state = State::new(
+                std::time::Duration::from_secs(1),
+                std::time::Duration::from_secs(4),
+                16,
+            );
+
+            let mut previous_request_id = 0;
+
+            let properties = |key: &str, value: u32| -> std::collections::HashMap<_, _> {
+                vec![(key.to_string(), serde_json::json!(value))]
+                    .into_iter()
+                    .collect()
+            };
+
+            // Set before the initial twin state, so it's applied on top of it
+            state.set_initial_reported_state(properties("key1", 1));
+            state.set_initial_state(properties("key2", 2));
+            match state.poll(client, &mut None, &mut previous_request_id) {
+                Ok(Response::Continue) => (),
+                _ => panic!("expected initial reported state to be reported"),
+            }
+            assert_eq!(previous_request_id, 1);
+            assert_eq!(
+                super::diff(
+                    state.previous_twin_state.as_ref().unwrap(),
+                    &state.current_twin_state
+                ),
+                properties("key1", 1),
+            );
+
+            // Set after the initial twin state while a report is waiting for its response, so it waits too
+            state.set_initial_reported_state(properties("key3", 3));
+            match state.poll(client, &mut None, &mut previous_request_id) {
+                Ok(Response::NotReady) => (),
+                _ => panic!("expected initial reported state to wait for the response"),
+            }
+            assert_eq!(previous_request_id, 1);
+
+            let mut message = Some(InternalTwinStateMessage::Response {
+                status: crate::Status::NoContent,
+                request_id: 1,
+                version: Some(1),
+                payload: vec![],
+            });
+            match state.poll(client, &mut message, &mut previous_request_id) {
+                Ok(Response::Message(Message::Reported(1))) => (),
+                _ => panic!("expected report to be accepted"),
+            }
+
+            match state.poll(client, &mut None, &mut previous_request_id) {
+                Ok(Response::Continue) => (),
+                _ => panic!("expected initial reported state to be reported"),
+            }
+            assert_eq!(previous_request_id, 2);
+            assert_eq!(
+                super::diff(
+                    state.previous_twin_state.as_ref().unwrap(),
+                    &state.current_twin_state
+                ),
+                properties("key3", 3),
+            );
+        });
+    }
+
     #[test]
     fn diff_merge() {
         verify_diff_merge(
@@ -0,0 +1,44 @@
+#[macro_use]
+extern crate criterion;
+
+use tokio::codec::Encoder;
+
+fn remaining_length(c: &mut criterion::Criterion) {
+    c.bench_function_over_inputs(
+        "remaining_length",
+        |b, &&value| {
+            let mut bytes = bytes::BytesMut::with_capacity(4);
+            b.iter(|| {
+                bytes.clear();
+                mqtt::proto::RemainingLengthCodec::default()
+                    .encode(criterion::black_box(value), &mut bytes)
+                    .unwrap();
+            })
+        },
+        &[0x00_usize, 0x7F, 0x80, 0x3FFF, 0x0FFF_FFFF],
+    );
+}
+
+fn publish(c: &mut criterion::Criterion) {
+    c.bench_function("publish", |b| {
+        let mut codec = mqtt::proto::PacketCodec::default();
+        let mut bytes = bytes::BytesMut::new();
+        b.iter(|| {
+            bytes.clear();
+            codec
+                .encode(
+                    mqtt::proto::Packet::Publish {
+                        packet_identifier_dup_qos: mqtt::proto::PacketIdentifierDupQoS::AtMostOnce,
+                        retain: false,
+                        topic_name: "devices/device/messages/events/".to_string(),
+                        payload: b"{\"temperature\":21.5}".to_vec(),
+                    },
+                    &mut bytes,
+                )
+                .unwrap();
+        })
+    });
+}
+
+criterion_group!(benches, remaining_length, publish);
+criterion_main!(benches);
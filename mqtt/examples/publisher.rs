@@ -98,7 +98,8 @@ fn main() {
         move || tokio::net::TcpStream::connect(&server),
         max_reconnect_back_off,
         keep_alive,
-    );
+    )
+    .expect("could not create client");
 
     let shutdown_handle = client
         .shutdown_handle()
@@ -127,12 +128,12 @@ fn main() {
 
                 executor.spawn(
                     publish_handle
-                        .publish(mqtt::proto::Publication {
-                            topic_name: topic.clone(),
-                            qos,
-                            retain: false,
-                            payload: payload.clone().into_bytes(),
-                        })
+                        .publish(
+                            mqtt::proto::Publication::builder(topic.clone())
+                                .qos(qos)
+                                .payload(payload.clone())
+                                .build(),
+                        )
                         .then(move |result| {
                             let () = result.expect("couldn't publish");
                             log::info!("Published to {}", topic);
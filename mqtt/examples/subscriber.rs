@@ -84,7 +84,8 @@ fn main() {
         move || tokio::net::TcpStream::connect(&server),
         max_reconnect_back_off,
         keep_alive,
-    );
+    )
+    .expect("could not create client");
 
     let shutdown_handle = client
         .shutdown_handle()
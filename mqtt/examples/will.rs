@@ -89,12 +89,10 @@ fn main() {
 
     let mut runtime = tokio::runtime::Runtime::new().expect("couldn't initialize tokio runtime");
 
-    let will = mqtt::proto::Publication {
-        topic_name: topic.clone(),
-        qos,
-        retain: false,
-        payload: payload.into_bytes(),
-    };
+    let will = mqtt::proto::Publication::builder(topic.clone())
+        .qos(qos)
+        .payload(payload)
+        .build();
 
     let client = mqtt::Client::new(
         client_id,
@@ -104,7 +102,8 @@ fn main() {
         move || tokio::net::TcpStream::connect(&server),
         max_reconnect_back_off,
         keep_alive,
-    );
+    )
+    .expect("could not create client");
 
     let mut update_subscription_handle = client
         .update_subscription_handle()
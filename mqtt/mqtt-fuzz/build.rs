@@ -26,6 +26,16 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 			}),
 			client_id: mqtt::proto::ClientId::IdWithExistingSession("id".to_string()),
 			keep_alive: std::time::Duration::from_secs(5),
+			protocol_version: mqtt::proto::ProtocolVersion::V3_1_1,
+		}),
+
+		("connect-v3_1", mqtt::proto::Packet::Connect {
+			username: Some("username".to_string()),
+			password: Some("password".to_string()),
+			will: None,
+			client_id: mqtt::proto::ClientId::IdWithCleanSession("id".to_string()),
+			keep_alive: std::time::Duration::from_secs(5),
+			protocol_version: mqtt::proto::ProtocolVersion::V3_1,
 		}),
 
 		("disconnect", mqtt::proto::Packet::Disconnect),
@@ -19,12 +19,31 @@ fn main() {
 
 			let mut bytes = bytes::BytesMut::new();
 			codec.encode(packet.clone(), &mut bytes).unwrap();
+			let encoded = bytes.clone();
 
 			let packet2 = codec.decode(&mut bytes).unwrap().unwrap();
 
 			assert_eq!(packet, packet2);
 
 			assert!(bytes.is_empty());
+
+			// Decode the re-encoded packet once more, split into two reads at a point chosen by the input,
+			// and assert that the split doesn't make the codec reject it or decode it differently
+
+			let split = usize::from(data[0]) % (encoded.len() + 1);
+
+			let mut codec: mqtt::proto::PacketCodec = Default::default();
+
+			let mut bytes: bytes::BytesMut = encoded[..split].into();
+			let mut packet3 = codec.decode(&mut bytes).unwrap();
+			if packet3.is_none() {
+				bytes.extend_from_slice(&encoded[split..]);
+				packet3 = codec.decode(&mut bytes).unwrap();
+			}
+
+			assert_eq!(Some(packet), packet3);
+
+			assert!(bytes.is_empty());
 		}
 	})
 }
@@ -0,0 +1,297 @@
+/// A builder for a [`crate::Client`]
+///
+/// Only the [`crate::IoSource`] is required. Every other parameter has a default, documented on its setter.
+#[derive(Debug)]
+pub struct ClientBuilder<IoS> {
+    io_source: IoS,
+    client_id: Option<String>,
+    clean_session: bool,
+    username: Option<String>,
+    password: Option<String>,
+    password_callback: super::connect::PasswordCallback,
+    will: Option<crate::proto::Publication>,
+    max_reconnect_back_off: std::time::Duration,
+    reconnect_policy: Option<Box<dyn super::ReconnectPolicy + Send>>,
+    keep_alive: std::time::Duration,
+    ping_interval_fraction: f64,
+    protocol_version: crate::proto::ProtocolVersion,
+    max_publish_payload_len: Option<usize>,
+    at_least_once_dedup_capacity: Option<usize>,
+    max_received_topic_name_len: Option<usize>,
+    reset_io_stats_on_reconnect: bool,
+    report_connection_attempts: bool,
+    reset_session_on_server_close: bool,
+    subscription_ack_timeout: Option<std::time::Duration>,
+    connect_timeout: Option<std::time::Duration>,
+    slow_consumer_threshold: Option<std::time::Duration>,
+    handle_channel_capacity: usize,
+    start_paused: bool,
+}
+
+impl<IoS> ClientBuilder<IoS>
+where
+    IoS: super::IoSource,
+{
+    /// The default value of [`ClientBuilder::max_reconnect_back_off`]
+    pub const DEFAULT_MAX_RECONNECT_BACK_OFF: std::time::Duration =
+        std::time::Duration::from_secs(30);
+
+    /// The default value of [`ClientBuilder::keep_alive`]
+    pub const DEFAULT_KEEP_ALIVE: std::time::Duration = std::time::Duration::from_secs(60);
+
+    /// The default value of [`ClientBuilder::ping_interval_fraction`]
+    pub const DEFAULT_PING_INTERVAL_FRACTION: f64 = 0.5;
+
+    /// The default value of [`ClientBuilder::handle_channel_capacity`]
+    pub const DEFAULT_HANDLE_CHANNEL_CAPACITY: usize = 16;
+
+    /// Creates a builder for a client that connects to the server using the given [`crate::IoSource`]
+    pub fn new(io_source: IoS) -> Self {
+        ClientBuilder {
+            io_source,
+            client_id: None,
+            clean_session: true,
+            username: None,
+            password: None,
+            password_callback: Default::default(),
+            will: None,
+            max_reconnect_back_off: Self::DEFAULT_MAX_RECONNECT_BACK_OFF,
+            reconnect_policy: None,
+            keep_alive: Self::DEFAULT_KEEP_ALIVE,
+            ping_interval_fraction: Self::DEFAULT_PING_INTERVAL_FRACTION,
+            protocol_version: Default::default(),
+            max_publish_payload_len: None,
+            at_least_once_dedup_capacity: None,
+            max_received_topic_name_len: None,
+            reset_io_stats_on_reconnect: false,
+            report_connection_attempts: false,
+            reset_session_on_server_close: false,
+            subscription_ack_timeout: None,
+            connect_timeout: None,
+            slow_consumer_threshold: None,
+            handle_channel_capacity: Self::DEFAULT_HANDLE_CHANNEL_CAPACITY,
+            start_paused: false,
+        }
+    }
+
+    /// Sets the client ID. On subsequent re-connects, the ID will be re-used.
+    /// Defaults to none, ie the client will use a server-generated ID for each new connection.
+    ///
+    /// The ID is checked with [`crate::proto::ClientIdValidation::Basic`] when the client is built.
+    pub fn client_id(mut self, client_id: String) -> Self {
+        self.client_id = Some(client_id);
+        self
+    }
+
+    /// Sets whether the first connection starts a new clean session with the server.
+    /// Defaults to `true`.
+    ///
+    /// If `false`, the first connection resumes the session the server already has for the client ID, if any.
+    /// This has no effect if no client ID is set, since sessions with server-generated IDs can't be resumed.
+    pub fn clean_session(mut self, clean_session: bool) -> Self {
+        self.clean_session = clean_session;
+        self
+    }
+
+    /// Sets the username for the server. Defaults to none.
+    pub fn username(mut self, username: String) -> Self {
+        self.username = Some(username);
+        self
+    }
+
+    /// Sets the password for the server. Defaults to none.
+    pub fn password(mut self, password: String) -> Self {
+        self.password = Some(password);
+        self
+    }
+
+    /// See [`crate::Client::set_password_callback`]
+    pub fn password_callback<F>(mut self, password_callback: F) -> Self
+    where
+        F: FnMut() -> Option<String> + Send + 'static,
+    {
+        self.password_callback =
+            super::connect::PasswordCallback::new(Some(Box::new(password_callback)));
+        self
+    }
+
+    /// Sets the will that the server publishes if the client disconnects unexpectedly. Defaults to none.
+    pub fn will(mut self, will: crate::proto::Publication) -> Self {
+        self.will = Some(will);
+        self
+    }
+
+    /// Sets the maximum back-off between reconnect attempts. Every connection failure will double the back-off period,
+    /// to a maximum of this value. Defaults to [`ClientBuilder::DEFAULT_MAX_RECONNECT_BACK_OFF`].
+    /// Values smaller than [`crate::ExponentialBackoff::MIN_MAX_BACK_OFF`] are raised to it.
+    ///
+    /// This has no effect if a policy is set with [`ClientBuilder::reconnect_policy`].
+    pub fn max_reconnect_back_off(mut self, max_reconnect_back_off: std::time::Duration) -> Self {
+        self.max_reconnect_back_off = max_reconnect_back_off;
+        self
+    }
+
+    /// See [`crate::Client::set_reconnect_policy`]. Defaults to a [`crate::ExponentialBackoff`]
+    /// with the maximum back-off set with [`ClientBuilder::max_reconnect_back_off`].
+    pub fn reconnect_policy<P>(mut self, reconnect_policy: P) -> Self
+    where
+        P: super::ReconnectPolicy + Send + 'static,
+    {
+        self.reconnect_policy = Some(Box::new(reconnect_policy));
+        self
+    }
+
+    /// Sets the keep-alive time advertised to the server. The client will ping the server at the fraction of this interval
+    /// set with [`ClientBuilder::ping_interval_fraction`]. Defaults to [`ClientBuilder::DEFAULT_KEEP_ALIVE`].
+    pub fn keep_alive(mut self, keep_alive: std::time::Duration) -> Self {
+        self.keep_alive = keep_alive;
+        self
+    }
+
+    /// Sets how often the client pings the server, as a fraction of the keep-alive time. It must be greater than 0 and at most 1,
+    /// otherwise building the client fails with [`crate::CreateClientError::InvalidPingIntervalFraction`].
+    /// Defaults to [`ClientBuilder::DEFAULT_PING_INTERVAL_FRACTION`].
+    ///
+    /// Larger fractions ping less often, which saves power and bandwidth. But the server disconnects the client if it doesn't
+    /// receive anything for one and a half times the keep-alive, so fractions close to 1 leave little room for the PINGREQ
+    /// being delayed by a slow network or a busy client.
+    pub fn ping_interval_fraction(mut self, ping_interval_fraction: f64) -> Self {
+        self.ping_interval_fraction = ping_interval_fraction;
+        self
+    }
+
+    /// Sets the version of the MQTT protocol to speak to the server.
+    /// Defaults to [`crate::proto::ProtocolVersion::V3_1_1`].
+    pub fn protocol_version(mut self, protocol_version: crate::proto::ProtocolVersion) -> Self {
+        self.protocol_version = protocol_version;
+        self
+    }
+
+    /// See [`crate::Client::set_max_publish_payload_len`]
+    pub fn max_publish_payload_len(mut self, max_publish_payload_len: Option<usize>) -> Self {
+        self.max_publish_payload_len = max_publish_payload_len;
+        self
+    }
+
+    /// See [`crate::Client::set_at_least_once_dedup_capacity`]
+    pub fn at_least_once_dedup_capacity(mut self, capacity: Option<usize>) -> Self {
+        self.at_least_once_dedup_capacity = capacity;
+        self
+    }
+
+    /// See [`crate::Client::set_max_received_topic_name_len`]
+    pub fn max_received_topic_name_len(mut self, max_topic_name_len: Option<usize>) -> Self {
+        self.max_received_topic_name_len = max_topic_name_len;
+        self
+    }
+
+    /// See [`crate::Client::set_reset_io_stats_on_reconnect`]
+    pub fn reset_io_stats_on_reconnect(mut self, reset_io_stats_on_reconnect: bool) -> Self {
+        self.reset_io_stats_on_reconnect = reset_io_stats_on_reconnect;
+        self
+    }
+
+    /// See [`crate::Client::set_reset_session_on_server_close`]
+    pub fn reset_session_on_server_close(mut self, reset_session_on_server_close: bool) -> Self {
+        self.reset_session_on_server_close = reset_session_on_server_close;
+        self
+    }
+
+    /// See [`crate::Client::set_report_connection_attempts`]
+    pub fn report_connection_attempts(mut self, report_connection_attempts: bool) -> Self {
+        self.report_connection_attempts = report_connection_attempts;
+        self
+    }
+
+    /// See [`crate::Client::set_subscription_ack_timeout`]
+    pub fn subscription_ack_timeout(
+        mut self,
+        subscription_ack_timeout: Option<std::time::Duration>,
+    ) -> Self {
+        self.subscription_ack_timeout = subscription_ack_timeout;
+        self
+    }
+
+    /// See [`crate::Client::set_connect_timeout`]
+    pub fn connect_timeout(mut self, connect_timeout: Option<std::time::Duration>) -> Self {
+        self.connect_timeout = connect_timeout;
+        self
+    }
+
+    /// See [`crate::Client::set_slow_consumer_threshold`]
+    pub fn slow_consumer_threshold(
+        mut self,
+        slow_consumer_threshold: Option<std::time::Duration>,
+    ) -> Self {
+        self.slow_consumer_threshold = slow_consumer_threshold;
+        self
+    }
+
+    /// Sets the capacity of the channels behind the client's handles, such as [`crate::PublishHandle`], ie how many requests
+    /// each channel buffers in addition to the one slot that every sender of the channel has.
+    /// Defaults to [`ClientBuilder::DEFAULT_HANDLE_CHANNEL_CAPACITY`].
+    ///
+    /// The handles send each request through its own sender, so sending a request doesn't wait for the client to pick up
+    /// earlier ones regardless of this capacity. Requests stay queued in memory, payloads included, until the client is polled,
+    /// so producers that can outpace the client should wait for the futures returned by the handles before sending more.
+    pub fn handle_channel_capacity(mut self, handle_channel_capacity: usize) -> Self {
+        self.handle_channel_capacity = handle_channel_capacity;
+        self
+    }
+
+    /// Sets whether the client waits to be told to connect before it connects to the server for the first time.
+    /// Defaults to `false`, ie the client connects as soon as it's polled.
+    ///
+    /// A paused client doesn't touch the network and its stream stays not ready until [`crate::ConnectHandle::connect`] is called
+    /// on a handle from [`crate::Client::connect_handle`]. Requests made in the meantime, such as publications and subscriptions,
+    /// are queued and sent once the client has connected. Shutting down a paused client doesn't connect it.
+    pub fn start_paused(mut self, start_paused: bool) -> Self {
+        self.start_paused = start_paused;
+        self
+    }
+
+    /// Creates the client
+    pub fn build(self) -> Result<super::Client<IoS>, super::CreateClientError> {
+        if !(self.ping_interval_fraction > 0.0 && self.ping_interval_fraction <= 1.0) {
+            return Err(super::CreateClientError::InvalidPingIntervalFraction(
+                self.ping_interval_fraction,
+            ));
+        }
+
+        let client_id = match (self.client_id, self.clean_session) {
+            (Some(id), true) => crate::proto::ClientId::IdWithCleanSession(id),
+            (Some(id), false) => crate::proto::ClientId::IdWithExistingSession(id),
+            (None, _) => crate::proto::ClientId::ServerGenerated,
+        };
+
+        let mut client = super::Client::with_client_id(
+            client_id,
+            self.username,
+            self.password,
+            self.will,
+            self.io_source,
+            self.max_reconnect_back_off,
+            self.keep_alive,
+            self.handle_channel_capacity,
+        )?;
+        client.set_ping_interval(self.keep_alive.mul_f64(self.ping_interval_fraction));
+        client.set_protocol_version(self.protocol_version);
+        client.set_max_publish_payload_len(self.max_publish_payload_len);
+        client.set_at_least_once_dedup_capacity(self.at_least_once_dedup_capacity);
+        client.set_max_received_topic_name_len(self.max_received_topic_name_len);
+        client.set_reset_io_stats_on_reconnect(self.reset_io_stats_on_reconnect);
+        client.set_report_connection_attempts(self.report_connection_attempts);
+        client.set_reset_session_on_server_close(self.reset_session_on_server_close);
+        client.set_subscription_ack_timeout(self.subscription_ack_timeout);
+        client.set_connect_timeout(self.connect_timeout);
+        client.set_slow_consumer_threshold(self.slow_consumer_threshold);
+        client.set_wrapped_password_callback(self.password_callback);
+        if let Some(reconnect_policy) = self.reconnect_policy {
+            client.set_boxed_reconnect_policy(reconnect_policy);
+        }
+        if self.start_paused {
+            client.start_paused();
+        }
+        Ok(client)
+    }
+}
@@ -1,13 +1,121 @@
 use futures::{Future, Sink, Stream};
 
+/// Decides how long a [`crate::Client`] backs off before each attempt to reconnect to the server
+pub trait ReconnectPolicy: std::fmt::Debug {
+    /// Returns how long to back off before the next reconnect attempt, or `None` to stop reconnecting.
+    ///
+    /// `attempt` is the number of this reconnect attempt since the last successful connection, starting at 1.
+    /// `last` is the back-off that was returned for the previous reconnect attempt, or zero for the first one.
+    ///
+    /// The client asks for the back-off as soon as the current attempt starts, so that it can report it in [`crate::Event::Connecting`],
+    /// and uses it if the attempt fails. Once a connection is lost, it asks for the back-off of the first reconnect attempt.
+    ///
+    /// If this returns `None`, the client fails with [`crate::Error::ReconnectPolicyGaveUp`] instead of backing off.
+    fn next_backoff(
+        &mut self,
+        attempt: u32,
+        last: std::time::Duration,
+    ) -> Option<std::time::Duration>;
+}
+
+/// The default [`ReconnectPolicy`]. The first reconnect attempt is made immediately, then every connection failure
+/// doubles the back-off period, starting at one second, to a maximum of `max_back_off`.
+///
+/// The client's first attempt to connect is not a reconnect attempt, so if it fails, the first retry is also made immediately,
+/// the same as after a lost connection. The retry after that backs off for one second.
+///
+/// `max_back_off` is at least [`ExponentialBackoff::MIN_MAX_BACK_OFF`], so that a client whose server is down doesn't retry
+/// in a tight loop. A client that must retry faster than that, such as in tests, can use its own [`ReconnectPolicy`] instead.
+#[derive(Clone, Copy, Debug)]
+pub struct ExponentialBackoff {
+    max_back_off: std::time::Duration,
+}
+
+impl ExponentialBackoff {
+    /// The smallest maximum back-off. Smaller values given to [`ExponentialBackoff::new`] are raised to this.
+    pub const MIN_MAX_BACK_OFF: std::time::Duration = std::time::Duration::from_secs(1);
+
+    /// Creates a policy that backs off for at most `max_back_off`, or [`ExponentialBackoff::MIN_MAX_BACK_OFF`] if that's larger
+    pub fn new(max_back_off: std::time::Duration) -> Self {
+        if max_back_off < Self::MIN_MAX_BACK_OFF {
+            log::warn!(
+                "Maximum reconnect back-off {:?} is too small, using {:?} instead",
+                max_back_off,
+                Self::MIN_MAX_BACK_OFF
+            );
+        }
+
+        ExponentialBackoff {
+            max_back_off: std::cmp::max(max_back_off, Self::MIN_MAX_BACK_OFF),
+        }
+    }
+}
+
+impl ReconnectPolicy for ExponentialBackoff {
+    fn next_backoff(
+        &mut self,
+        attempt: u32,
+        last: std::time::Duration,
+    ) -> Option<std::time::Duration> {
+        Some(match attempt {
+            0 | 1 => std::time::Duration::from_secs(0),
+            _ if last.as_secs() == 0 => std::time::Duration::from_secs(1),
+            _ => std::cmp::min(self.max_back_off, last * 2),
+        })
+    }
+}
+
+/// The callback set with [`crate::Client::set_password_callback`], if any
+#[derive(Default)]
+pub(super) struct PasswordCallback(Option<Box<dyn FnMut() -> Option<String> + Send>>);
+
+impl PasswordCallback {
+    pub(super) fn new(callback: Option<Box<dyn FnMut() -> Option<String> + Send>>) -> Self {
+        PasswordCallback(callback)
+    }
+
+    /// The password for the next CONNECT packet. This is the callback's, if it's set, otherwise `password`.
+    fn password(&mut self, password: Option<&str>) -> Option<String> {
+        match &mut self.0 {
+            Some(callback) => callback(),
+            None => password.map(ToOwned::to_owned),
+        }
+    }
+}
+
+impl std::fmt::Debug for PasswordCallback {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("PasswordCallback")
+            .field(&self.0.is_some())
+            .finish()
+    }
+}
+
 #[derive(Debug)]
 pub(super) struct Connect<IoS>
 where
     IoS: super::IoSource,
 {
     io_source: IoS,
-    max_back_off: std::time::Duration,
-    current_back_off: std::time::Duration,
+    reconnect_policy: Box<dyn ReconnectPolicy + Send>,
+    /// The number of reconnect attempts since the last successful connection
+    reconnect_attempt: u32,
+    /// The back-off before the last reconnect attempt
+    last_back_off: std::time::Duration,
+    /// The back-off before the next reconnect attempt, if the reconnect policy has been asked for it already.
+    /// `Some(None)` if the policy gave up.
+    next_back_off: Option<Option<std::time::Duration>>,
+    io_counters: std::sync::Arc<crate::logging_framed::IoCounters>,
+    packet_observer: crate::logging_framed::PacketObserver,
+    password_callback: PasswordCallback,
+    reset_io_stats_on_reconnect: bool,
+    max_topic_name_len: Option<usize>,
+    protocol_version: crate::proto::ProtocolVersion,
+    report_attempts: bool,
+    connect_timeout: Option<std::time::Duration>,
+    attempt: u32,
+    /// The number of times the client has connected to the server
+    connections: u64,
     state: State<IoS>,
 }
 
@@ -17,8 +125,12 @@ where
 {
     BeginBackOff,
     EndBackOff(tokio::timer::Delay),
+    GaveUp,
     BeginConnecting,
-    WaitingForIoToConnect(<IoS as super::IoSource>::Future),
+    WaitingForIoToConnect(
+        <IoS as super::IoSource>::Future,
+        Option<tokio::timer::Delay>,
+    ),
     Framed(
         crate::logging_framed::LoggingFramed<<IoS as super::IoSource>::Io>,
         FramedState,
@@ -48,8 +160,9 @@ where
         match self {
             State::BeginBackOff => f.write_str("BeginBackOff"),
             State::EndBackOff(_) => f.write_str("EndBackOff"),
+            State::GaveUp => f.write_str("GaveUp"),
             State::BeginConnecting => f.write_str("BeginConnecting"),
-            State::WaitingForIoToConnect(_) => f.write_str("WaitingForIoToConnect"),
+            State::WaitingForIoToConnect(_, _) => f.write_str("WaitingForIoToConnect"),
             State::Framed(_, framed_state) => f.debug_tuple("Framed").field(framed_state).finish(),
         }
     }
@@ -59,18 +172,125 @@ impl<IoS> Connect<IoS>
 where
     IoS: super::IoSource,
 {
-    pub(super) fn new(io_source: IoS, max_back_off: std::time::Duration) -> Self {
+    pub(super) fn new(io_source: IoS, reconnect_policy: Box<dyn ReconnectPolicy + Send>) -> Self {
         Connect {
             io_source,
-            max_back_off,
-            current_back_off: std::time::Duration::from_secs(0),
+            reconnect_policy,
+            reconnect_attempt: 0,
+            last_back_off: std::time::Duration::from_secs(0),
+            next_back_off: None,
+            io_counters: Default::default(),
+            packet_observer: Default::default(),
+            password_callback: Default::default(),
+            reset_io_stats_on_reconnect: false,
+            max_topic_name_len: None,
+            protocol_version: Default::default(),
+            report_attempts: false,
+            connect_timeout: None,
+            attempt: 0,
+            connections: 0,
             state: State::BeginConnecting,
         }
     }
 
+    pub(super) fn io_source(&self) -> &IoS {
+        &self.io_source
+    }
+
+    pub(super) fn io_stats(&self) -> super::IoStats {
+        self.io_counters.get()
+    }
+
+    pub(super) fn connections(&self) -> u64 {
+        self.connections
+    }
+
+    pub(super) fn set_packet_observer(
+        &mut self,
+        packet_observer: Option<Box<dyn FnMut(super::Direction, &[u8]) + Send>>,
+    ) {
+        self.packet_observer.set(packet_observer);
+    }
+
+    pub(super) fn set_password_callback(&mut self, password_callback: PasswordCallback) {
+        self.password_callback = password_callback;
+    }
+
+    pub(super) fn set_reset_io_stats_on_reconnect(&mut self, reset_io_stats_on_reconnect: bool) {
+        self.reset_io_stats_on_reconnect = reset_io_stats_on_reconnect;
+    }
+
+    pub(super) fn set_max_topic_name_len(&mut self, max_topic_name_len: Option<usize>) {
+        self.max_topic_name_len = max_topic_name_len;
+    }
+
+    pub(super) fn set_protocol_version(&mut self, protocol_version: crate::proto::ProtocolVersion) {
+        self.protocol_version = protocol_version;
+    }
+
+    pub(super) fn set_report_attempts(&mut self, report_attempts: bool) {
+        self.report_attempts = report_attempts;
+    }
+
+    pub(super) fn set_connect_timeout(&mut self, connect_timeout: Option<std::time::Duration>) {
+        self.connect_timeout = connect_timeout;
+    }
+
+    pub(super) fn set_reconnect_policy(
+        &mut self,
+        reconnect_policy: Box<dyn ReconnectPolicy + Send>,
+    ) {
+        self.reconnect_policy = reconnect_policy;
+        self.next_back_off = None;
+    }
+
     pub(super) fn reconnect(&mut self) {
         self.state = State::BeginBackOff;
     }
+
+    /// Drops the current connection, if any, so that the client connects to the server again the next time it's polled.
+    /// A DISCONNECT packet is sent first on a best-effort basis so that the server discards the will.
+    pub(super) fn force_reconnect(&mut self) {
+        if let State::Framed(_, FramedState::Connected { .. }) = &self.state {
+            self.disconnect_best_effort();
+            self.reconnect();
+        }
+    }
+
+    /// Makes a best-effort attempt to send a DISCONNECT packet on the current connection, if any, without blocking.
+    ///
+    /// This can be called outside of a task, such as when the client is dropped. If the packet can't be written immediately,
+    /// it is not sent.
+    pub(super) fn disconnect_best_effort(&mut self) {
+        struct NoopNotify;
+
+        impl futures::executor::Notify for NoopNotify {
+            fn notify(&self, _id: usize) {}
+        }
+
+        let framed = match &mut self.state {
+            State::Framed(framed, FramedState::Connected { .. }) => framed,
+            _ => return,
+        };
+
+        let send_disconnect = futures::future::poll_fn(|| {
+            if let futures::AsyncSink::NotReady(_) =
+                framed.start_send(crate::proto::Packet::Disconnect)?
+            {
+                return Ok(futures::Async::NotReady);
+            }
+
+            framed.poll_complete()
+        });
+
+        match futures::executor::spawn(send_disconnect).poll_future_notify(&&NoopNotify, 0) {
+            Ok(futures::Async::Ready(())) => log::debug!("Sent DISCONNECT"),
+            Ok(futures::Async::NotReady) => {
+                log::warn!("could not send DISCONNECT: connection is not ready for writing")
+            }
+            Err(err) => log::warn!("could not send DISCONNECT: {}", err),
+        }
+    }
 }
 
 impl<IoS> Connect<IoS>
@@ -85,27 +305,42 @@ where
         will: Option<&crate::proto::Publication>,
         client_id: &mut crate::proto::ClientId,
         keep_alive: std::time::Duration,
-    ) -> futures::Poll<Connected<'a, IoS>, ()> {
+    ) -> futures::Poll<Progress<'a, IoS>, ()> {
         let state = &mut self.state;
 
         loop {
             log::trace!("    {:?}", state);
 
             match state {
-                State::BeginBackOff => match self.current_back_off {
-                    back_off if back_off.as_secs() == 0 => {
-                        self.current_back_off = std::time::Duration::from_secs(1);
-                        *state = State::BeginConnecting;
-                    }
+                State::BeginBackOff => {
+                    let next_back_off = match self.next_back_off.take() {
+                        Some(next_back_off) => next_back_off,
+                        None => self
+                            .reconnect_policy
+                            .next_backoff(self.reconnect_attempt + 1, self.last_back_off),
+                    };
+
+                    match next_back_off {
+                        Some(back_off) => {
+                            self.reconnect_attempt = self.reconnect_attempt.saturating_add(1);
+                            self.last_back_off = back_off;
+
+                            if back_off == std::time::Duration::from_secs(0) {
+                                *state = State::BeginConnecting;
+                            } else {
+                                log::debug!("Backing off for {:?}", back_off);
+                                let back_off_deadline = std::time::Instant::now() + back_off;
+                                *state =
+                                    State::EndBackOff(tokio::timer::Delay::new(back_off_deadline));
+                            }
+                        }
 
-                    back_off => {
-                        log::debug!("Backing off for {:?}", back_off);
-                        let back_off_deadline = std::time::Instant::now() + back_off;
-                        self.current_back_off =
-                            std::cmp::min(self.max_back_off, self.current_back_off * 2);
-                        *state = State::EndBackOff(tokio::timer::Delay::new(back_off_deadline));
+                        None => {
+                            log::warn!("reconnect policy gave up reconnecting to the server");
+                            *state = State::GaveUp;
+                        }
                     }
-                },
+                }
 
                 State::EndBackOff(back_off_timer) => match back_off_timer
                     .poll()
@@ -115,18 +350,60 @@ where
                     futures::Async::NotReady => return Ok(futures::Async::NotReady),
                 },
 
+                State::GaveUp => return Err(()),
+
                 State::BeginConnecting => {
+                    self.attempt = self.attempt.saturating_add(1);
+                    log::debug!("Connecting to server, attempt {}", self.attempt);
+
                     let io = self.io_source.connect();
-                    *state = State::WaitingForIoToConnect(io);
+                    let connect_timer = self.connect_timeout.map(|connect_timeout| {
+                        tokio::timer::Delay::new(std::time::Instant::now() + connect_timeout)
+                    });
+                    *state = State::WaitingForIoToConnect(io, connect_timer);
+
+                    // Ask for the back-off now so that it can be reported
+                    let next_back_off = self
+                        .reconnect_policy
+                        .next_backoff(self.reconnect_attempt + 1, self.last_back_off);
+                    self.next_back_off = Some(next_back_off);
+
+                    if self.report_attempts {
+                        return Ok(futures::Async::Ready(Progress::Attempt {
+                            attempt: self.attempt,
+                            back_off: next_back_off.unwrap_or_default(),
+                        }));
+                    }
                 }
 
-                State::WaitingForIoToConnect(io) => match io.poll() {
+                State::WaitingForIoToConnect(io, connect_timer) => match io.poll() {
                     Ok(futures::Async::Ready(io)) => {
-                        let framed = crate::logging_framed::LoggingFramed::new(io);
+                        if self.reset_io_stats_on_reconnect {
+                            self.io_counters.reset();
+                        }
+                        let mut codec = crate::proto::PacketCodec::default();
+                        codec.set_max_topic_name_len(self.max_topic_name_len);
+                        let framed = crate::logging_framed::LoggingFramed::new(
+                            io,
+                            codec,
+                            self.io_counters.clone(),
+                            self.packet_observer.clone(),
+                        );
                         *state = State::Framed(framed, FramedState::BeginSendingConnect);
                     }
 
-                    Ok(futures::Async::NotReady) => return Ok(futures::Async::NotReady),
+                    Ok(futures::Async::NotReady) => match connect_timer {
+                        Some(connect_timer) => {
+                            match connect_timer.poll().expect("could not poll connect timer") {
+                                futures::Async::Ready(()) => {
+                                    log::warn!("could not connect to server: timed out");
+                                    *state = State::BeginBackOff;
+                                }
+                                futures::Async::NotReady => return Ok(futures::Async::NotReady),
+                            }
+                        }
+                        None => return Ok(futures::Async::NotReady),
+                    },
 
                     Err(err) => {
                         log::warn!("could not connect to server: {}", err);
@@ -137,10 +414,11 @@ where
                 State::Framed(framed, framed_state @ FramedState::BeginSendingConnect) => {
                     let packet = crate::proto::Packet::Connect {
                         username: username.map(ToOwned::to_owned),
-                        password: password.map(ToOwned::to_owned),
+                        password: self.password_callback.password(password),
                         will: will.cloned(),
                         client_id: client_id.clone(),
                         keep_alive,
+                        protocol_version: self.protocol_version,
                     };
 
                     match framed.start_send(packet) {
@@ -171,11 +449,24 @@ where
                 State::Framed(framed, framed_state @ FramedState::WaitingForConnAck) => {
                     match framed.poll() {
                         Ok(futures::Async::Ready(Some(packet))) => match packet {
+                            crate::proto::Packet::ConnAck {
+                                session_present: true,
+                                return_code: crate::proto::ConnectReturnCode::Accepted,
+                            } if client_id.is_clean_session() => {
+                                // Ref: 3.2.2.2 Session Present
+                                log::warn!("could not connect to server: server claims to have resumed a session even though a clean session was requested");
+                                *state = State::BeginBackOff;
+                            }
+
                             crate::proto::Packet::ConnAck {
                                 session_present,
                                 return_code: crate::proto::ConnectReturnCode::Accepted,
                             } => {
-                                self.current_back_off = std::time::Duration::from_secs(0);
+                                self.reconnect_attempt = 0;
+                                self.last_back_off = std::time::Duration::from_secs(0);
+                                self.next_back_off = None;
+                                self.attempt = 0;
+                                self.connections = self.connections.saturating_add(1);
 
                                 let reset_session = match client_id {
                                     crate::proto::ClientId::ServerGenerated => true,
@@ -211,7 +502,7 @@ where
                             }
 
                             packet => {
-                                log::warn!("could not connect to server: expected to receive ConnAck but received {:?}", packet);
+                                log::warn!("could not connect to server: expected to receive ConnAck but received {}", packet.summary());
                                 *state = State::BeginBackOff;
                             }
                         },
@@ -244,13 +535,26 @@ where
                     };
                     *new_connection = false;
                     *reset_session = false;
-                    return Ok(futures::Async::Ready(result));
+                    return Ok(futures::Async::Ready(Progress::Connected(result)));
                 }
             }
         }
     }
 }
 
+pub(super) enum Progress<'a, IoS>
+where
+    IoS: super::IoSource,
+{
+    /// A new connection attempt has started. Only returned if attempts are being reported.
+    Attempt {
+        attempt: u32,
+        back_off: std::time::Duration,
+    },
+
+    Connected(Connected<'a, IoS>),
+}
+
 pub(super) struct Connected<'a, IoS>
 where
     IoS: super::IoSource,
@@ -1,10 +1,16 @@
 use futures::{Future, Sink, Stream};
 
+mod builder;
+pub use self::builder::ClientBuilder;
+
 mod connect;
 mod ping;
+#[cfg(feature = "prometheus")]
+mod prometheus;
 mod publish;
 mod subscriptions;
 
+pub use self::connect::{ExponentialBackoff, ReconnectPolicy};
 pub use self::publish::{PublishError, PublishHandle};
 pub use self::subscriptions::{
     SubscriptionUpdate, UpdateSubscriptionError, UpdateSubscriptionHandle,
@@ -22,6 +28,10 @@ pub use self::subscriptions::{
 /// The [`Stream`] only ends (returns `Ready(None)`) when the client is told to shut down gracefully using the handle
 /// returned by [`Client::shutdown_handle`]. The `Client` becomes unusable after it has returned `None`
 /// and should be dropped.
+///
+/// Dropping a `Client` that hasn't shut down makes a best-effort attempt to send a DISCONNECT packet to the server,
+/// without waiting for the connection to become writable. If that fails, the connection is closed abruptly
+/// and the server publishes the will. Use the handle returned by [`Client::shutdown_handle`] to shut down reliably.
 #[derive(Debug)]
 pub struct Client<IoS>(ClientState<IoS>)
 where
@@ -33,11 +43,17 @@ where
 {
     /// Create a new client with the given parameters
     ///
+    /// See also [`Client::builder`], which names each parameter and provides defaults for the optional ones.
+    /// The channels behind the client's handles have a capacity of [`ClientBuilder::DEFAULT_HANDLE_CHANNEL_CAPACITY`].
+    ///
     /// * `client_id`
     ///
     ///     If set, this ID will be used to start a new clean session with the server. On subsequent re-connects, the ID will be re-used.
     ///     Otherwise, the client will use a server-generated ID for each new connection.
     ///
+    ///     The ID is checked with [`crate::proto::ClientIdValidation::Basic`]. Use [`crate::proto::ClientId::validate`]
+    ///     with [`crate::proto::ClientIdValidation::Strict`] beforehand if the server only accepts the minimal set of IDs required by the spec.
+    ///
     /// * `username`, `password`
     ///
     ///     Optional credentials for the server.
@@ -49,6 +65,8 @@ where
     /// * `max_reconnect_back_off`
     ///
     ///     Every connection failure will double the back-off period, to a maximum of this value.
+    ///     Values smaller than [`ExponentialBackoff::MIN_MAX_BACK_OFF`] are raised to it.
+    ///     Use [`Client::set_reconnect_policy`] to reconnect faster, eg in tests.
     ///
     /// * `keep_alive`
     ///
@@ -61,33 +79,115 @@ where
         io_source: IoS,
         max_reconnect_back_off: std::time::Duration,
         keep_alive: std::time::Duration,
-    ) -> Self {
+    ) -> Result<Self, CreateClientError> {
         let client_id = match client_id {
             Some(id) => crate::proto::ClientId::IdWithCleanSession(id),
             None => crate::proto::ClientId::ServerGenerated,
         };
+        Client::with_client_id(
+            client_id,
+            username,
+            password,
+            will,
+            io_source,
+            max_reconnect_back_off,
+            keep_alive,
+            ClientBuilder::<IoS>::DEFAULT_HANDLE_CHANNEL_CAPACITY,
+        )
+    }
+
+    /// Create a new client that resumes the session the server has for the given client ID, such as a session that the process
+    /// established before it restarted. The other parameters are the same as for [`Client::new`].
+    ///
+    /// The first CONNECT asks the server to resume the session instead of starting a clean one, so the server keeps the session's
+    /// subscriptions and delivers the QoS 1 and 2 publications it queued for the client while it was disconnected.
+    /// If the server doesn't have a session for the ID, it starts a new one, and the client's first [`Event::NewConnection`]
+    /// has `reset_session` set. The client then re-sends the subscriptions made through it, but any subscriptions that only
+    /// the previous process made are gone.
+    ///
+    /// [`ClientBuilder::clean_session`] does the same for a client created with [`Client::builder`].
+    pub fn with_existing_session(
+        client_id: String,
+        username: Option<String>,
+        password: Option<String>,
+        will: Option<crate::proto::Publication>,
+        io_source: IoS,
+        max_reconnect_back_off: std::time::Duration,
+        keep_alive: std::time::Duration,
+    ) -> Result<Self, CreateClientError> {
+        Client::with_client_id(
+            crate::proto::ClientId::IdWithExistingSession(client_id),
+            username,
+            password,
+            will,
+            io_source,
+            max_reconnect_back_off,
+            keep_alive,
+            ClientBuilder::<IoS>::DEFAULT_HANDLE_CHANNEL_CAPACITY,
+        )
+    }
+
+    /// Returns a [`ClientBuilder`] that creates a client using the given [`IoSource`]
+    pub fn builder(io_source: IoS) -> ClientBuilder<IoS> {
+        ClientBuilder::new(io_source)
+    }
+
+    fn with_client_id(
+        client_id: crate::proto::ClientId,
+        username: Option<String>,
+        password: Option<String>,
+        will: Option<crate::proto::Publication>,
+        io_source: IoS,
+        max_reconnect_back_off: std::time::Duration,
+        keep_alive: std::time::Duration,
+        handle_channel_capacity: usize,
+    ) -> Result<Self, CreateClientError> {
+        client_id
+            .validate(crate::proto::ClientIdValidation::Basic)
+            .map_err(CreateClientError::InvalidClientId)?;
 
-        let (shutdown_send, shutdown_recv) = futures::sync::mpsc::channel(0);
+        let (shutdown_send, shutdown_recv) = futures::sync::mpsc::channel(handle_channel_capacity);
+        let (flush_send, flush_recv) = futures::sync::mpsc::channel(handle_channel_capacity);
+        let (connect_send, connect_recv) = futures::sync::mpsc::channel(handle_channel_capacity);
 
-        Client(ClientState::Up {
+        Ok(Client(ClientState::Up {
             client_id,
             username,
             password,
             will,
             keep_alive,
+            ping_interval: keep_alive / 2,
 
             shutdown_send,
             shutdown_recv,
+            drain_timeout: None,
+
+            flush_send,
+            flush_recv,
+            flush_waiters: vec![],
+
+            paused: false,
+            connect_send,
+            connect_recv,
 
             packet_identifiers: Default::default(),
 
-            connect: self::connect::Connect::new(io_source, max_reconnect_back_off),
-            ping: self::ping::State::BeginWaitingForNextPing,
-            publish: Default::default(),
-            subscriptions: Default::default(),
+            connect: self::connect::Connect::new(
+                io_source,
+                Box::new(ExponentialBackoff::new(max_reconnect_back_off)),
+            ),
+            ping: self::ping::State::new(),
+            publish: self::publish::State::new(handle_channel_capacity),
+            subscriptions: self::subscriptions::State::new(handle_channel_capacity),
+            topic_streams: vec![],
 
             packets_waiting_to_be_sent: Default::default(),
-        })
+
+            slow_consumer_threshold: None,
+            ack_pending_since: None,
+
+            reset_session_on_server_close: false,
+        }))
     }
 
     /// Queues a message to be published to the server
@@ -105,6 +205,271 @@ where
         }
     }
 
+    /// Publishes a received message again to `new_topic_name`, such as when bridging it to another topic.
+    ///
+    /// The message is published with the lower of the QoS it was received with, and the highest QoS the server granted
+    /// for any of this client's subscriptions that match `new_topic_name`. If no subscriptions match `new_topic_name`,
+    /// it is published with the QoS it was received with.
+    pub fn republish(
+        &mut self,
+        received: &ReceivedPublication,
+        new_topic_name: String,
+    ) -> impl Future<Item = (), Error = PublishError> {
+        let granted_qos = match &self.0 {
+            ClientState::Up { subscriptions, .. } => subscriptions.granted_qos(&new_topic_name),
+            ClientState::ShuttingDown { .. } | ClientState::ShutDown { .. } => None,
+        };
+        let qos = match granted_qos {
+            Some(granted_qos) => std::cmp::min(received.qos, granted_qos),
+            None => received.qos,
+        };
+
+        self.publish(crate::proto::Publication {
+            topic_name: new_topic_name,
+            qos,
+            retain: received.retain,
+            payload: received.payload.clone(),
+        })
+    }
+
+    /// Sets a callback that transforms the topic name of every publication the client publishes, such as to add a prefix
+    /// that namespaces the topics of a tenant. Defaults to `None`, ie publications are published to the topic names they were given.
+    ///
+    /// The callback applies to publications made through the client and its [`PublishHandle`]s, including handles created before it was set,
+    /// once they're requested. It runs before the payload length of the publication is checked, since the largest payload
+    /// that fits in a PUBLISH packet depends on the length of the topic name. It doesn't apply to the will or to the topic filters of subscriptions.
+    pub fn set_outbound_topic_transform(
+        &mut self,
+        transform: Option<Box<dyn for<'a> FnMut(&'a str) -> std::borrow::Cow<'a, str> + Send>>,
+    ) {
+        if let ClientState::Up { publish, .. } = &mut self.0 {
+            publish.set_outbound_topic_transform(transform);
+        }
+    }
+
+    /// Sets a callback that transforms the topic name of every publication received from the server, such as to strip
+    /// the prefix added by [`Client::set_outbound_topic_transform`]. Defaults to `None`, ie topic names are left as they were received.
+    ///
+    /// [`Event::Publication`] and the streams returned by [`Client::subscribe_stream`] have the transformed topic name,
+    /// so the topic filters given to `subscribe_stream` are matched against it.
+    pub fn set_inbound_topic_transform(
+        &mut self,
+        transform: Option<Box<dyn for<'a> FnMut(&'a str) -> std::borrow::Cow<'a, str> + Send>>,
+    ) {
+        if let ClientState::Up { publish, .. } = &mut self.0 {
+            publish.set_inbound_topic_transform(transform);
+        }
+    }
+
+    /// Sets the largest payload, in bytes, that the client will accept for publishing.
+    ///
+    /// Publications with larger payloads fail immediately with [`PublishError::PayloadTooLarge`] instead of being sent to the server.
+    /// Defaults to `None`, ie only limited by what fits in a single PUBLISH packet. See [`crate::proto::Publication::max_payload_len`].
+    ///
+    /// This also applies to [`PublishHandle`]s that were created before it was called.
+    pub fn set_max_publish_payload_len(&mut self, max_publish_payload_len: Option<usize>) {
+        if let ClientState::Up { publish, .. } = &mut self.0 {
+            publish.set_max_payload_len(max_publish_payload_len);
+        }
+    }
+
+    /// Enables suppressing redeliveries of AtLeastOnce publications received from the server, remembering up to `capacity` publications.
+    /// Defaults to `None`, ie every publication received from the server is yielded from the client.
+    ///
+    /// If the server doesn't receive the PUBACK for an AtLeastOnce publication, such as because the connection broke,
+    /// it redelivers the publication after the client reconnects. With this enabled, the client still acks the redelivered publication
+    /// but doesn't yield it as an [`Event::Publication`] a second time.
+    ///
+    /// This is best-effort. Only the `capacity` most recently received publications of the current session are remembered,
+    /// so redeliveries of older publications are still yielded.
+    pub fn set_at_least_once_dedup_capacity(&mut self, capacity: Option<usize>) {
+        if let ClientState::Up { publish, .. } = &mut self.0 {
+            publish.set_dedup_capacity(capacity);
+        }
+    }
+
+    /// Sets the longest topic name, in bytes, that the client will accept in publications from the server.
+    /// Defaults to `None`, ie only limited by the two-byte length prefix of MQTT strings.
+    ///
+    /// A PUBLISH packet with a longer topic name is treated as a protocol error, and the client reconnects.
+    /// The packet is rejected as soon as the topic name's length has been received, without buffering the rest of it.
+    ///
+    /// This takes effect on the next connection to the server.
+    pub fn set_max_received_topic_name_len(&mut self, max_topic_name_len: Option<usize>) {
+        if let ClientState::Up { connect, .. } = &mut self.0 {
+            connect.set_max_topic_name_len(max_topic_name_len);
+        }
+    }
+
+    /// Sets the username that the client sends to the server.
+    ///
+    /// This takes effect on the next connection to the server.
+    pub fn set_username(&mut self, new_username: Option<String>) {
+        if let ClientState::Up { username, .. } = &mut self.0 {
+            *username = new_username;
+        }
+    }
+
+    /// Sets whether the client emits an [`Event::Connecting`] every time it starts a new attempt to connect to the server.
+    /// Defaults to `false`.
+    ///
+    /// This can be used to report the connection status while the client is backing off during a prolonged outage.
+    /// The events don't affect when the client reconnects.
+    pub fn set_report_connection_attempts(&mut self, report_connection_attempts: bool) {
+        if let ClientState::Up { connect, .. } = &mut self.0 {
+            connect.set_report_attempts(report_connection_attempts);
+        }
+    }
+
+    /// Sets a callback that is given the raw bytes of every packet the client sends to or receives from the server.
+    /// Defaults to `None`.
+    ///
+    /// This is meant for debugging, such as comparing the traffic against a reference capture. The callback is invoked
+    /// from the task polling the client, so it should return quickly. It takes effect immediately, including on the current connection.
+    pub fn set_packet_observer(
+        &mut self,
+        packet_observer: Option<Box<dyn FnMut(Direction, &[u8]) + Send>>,
+    ) {
+        if let ClientState::Up { connect, .. } = &mut self.0 {
+            connect.set_packet_observer(packet_observer);
+        }
+    }
+
+    /// Sets a callback that returns the password for every CONNECT packet the client sends, instead of the password
+    /// the client was created with. Defaults to `None`, ie the client always sends the password it was created with.
+    ///
+    /// This is for credentials that expire, such as tokens, so that the client can reconnect with fresh ones.
+    /// The callback is invoked from the task polling the client once a new connection has been established, so it should
+    /// return quickly. A callback that has to fetch the credentials asynchronously can do so in the [`IoSource`]'s connection future.
+    pub fn set_password_callback(
+        &mut self,
+        password_callback: Option<Box<dyn FnMut() -> Option<String> + Send>>,
+    ) {
+        self.set_wrapped_password_callback(self::connect::PasswordCallback::new(password_callback));
+    }
+
+    fn set_wrapped_password_callback(&mut self, password_callback: self::connect::PasswordCallback) {
+        if let ClientState::Up { connect, .. } = &mut self.0 {
+            connect.set_password_callback(password_callback);
+        }
+    }
+
+    /// Sets how long the client waits for the [`IoSource`] to connect to the server before it abandons the attempt
+    /// and backs off as if the connection failed. Defaults to `None`, ie the client waits forever.
+    ///
+    /// This doesn't include sending the CONNECT packet and waiting for the CONNACK.
+    pub fn set_connect_timeout(&mut self, connect_timeout: Option<std::time::Duration>) {
+        if let ClientState::Up { connect, .. } = &mut self.0 {
+            connect.set_connect_timeout(connect_timeout);
+        }
+    }
+
+    /// Sets the policy that decides how long the client backs off before reconnecting to the server.
+    /// Defaults to an [`ExponentialBackoff`] with the `max_reconnect_back_off` the client was created with.
+    ///
+    /// If the policy gives up, the client fails with [`Error::ReconnectPolicyGaveUp`].
+    pub fn set_reconnect_policy<P>(&mut self, reconnect_policy: P)
+    where
+        P: ReconnectPolicy + Send + 'static,
+    {
+        self.set_boxed_reconnect_policy(Box::new(reconnect_policy));
+    }
+
+    fn set_boxed_reconnect_policy(&mut self, reconnect_policy: Box<dyn ReconnectPolicy + Send>) {
+        if let ClientState::Up { connect, .. } = &mut self.0 {
+            connect.set_reconnect_policy(reconnect_policy);
+        }
+    }
+
+    /// Sets how long the client waits for the server to ack a SUBSCRIBE or UNSUBSCRIBE packet.
+    /// Defaults to `None`, ie the client waits forever.
+    ///
+    /// If the server doesn't ack an update in time, the client emits an [`Event::SubscriptionUpdates`] containing
+    /// a [`SubscriptionUpdate::AckTimeout`] with the topic filters of the update, then reconnects and sends the update again.
+    pub fn set_subscription_ack_timeout(&mut self, ack_timeout: Option<std::time::Duration>) {
+        if let ClientState::Up { subscriptions, .. } = &mut self.0 {
+            subscriptions.set_ack_timeout(ack_timeout);
+        }
+    }
+
+    /// Sets how long the client may go without being polled after it yields a QoS 1 or 2 publication before it logs a warning
+    /// that the application is consuming publications too slowly. Defaults to `None`, ie no warning.
+    ///
+    /// The client only sends the PUBACK or PUBREC for a publication the next time it's polled, so an application that's slow
+    /// to poll the client, such as one whose handler for the previous publication blocks, delays the acks. A server that
+    /// waits for those acks before delivering more publications will see the client as overwhelmed.
+    pub fn set_slow_consumer_threshold(
+        &mut self,
+        slow_consumer_threshold: Option<std::time::Duration>,
+    ) {
+        if let ClientState::Up {
+            slow_consumer_threshold: current_slow_consumer_threshold,
+            ..
+        } = &mut self.0
+        {
+            *current_slow_consumer_threshold = slow_consumer_threshold;
+        }
+    }
+
+    /// Sets whether the client starts a clean session when it reconnects after the server closed the connection.
+    /// Defaults to `false`, ie the client asks the server to resume the session.
+    ///
+    /// Either way, whether the server actually resumed the session is decided by the session present flag of its CONNACK.
+    /// If the server didn't resume it, the [`Event::NewConnection`] has `reset_session` set, the client re-sends its subscriptions,
+    /// and it restarts the QoS 1 and 2 flows of its unacked publications instead of continuing them.
+    ///
+    /// Set this to `true` for a server that closes connections because it discarded the session, such as when the session expired,
+    /// so that the client doesn't rely on a session the server no longer has.
+    pub fn set_reset_session_on_server_close(&mut self, reset_session_on_server_close: bool) {
+        if let ClientState::Up {
+            reset_session_on_server_close: current_reset_session_on_server_close,
+            ..
+        } = &mut self.0
+        {
+            *current_reset_session_on_server_close = reset_session_on_server_close;
+        }
+    }
+
+    /// Sets the priority of the subscription to the given topic filter. Defaults to 0 for every topic filter.
+    ///
+    /// When the client resubscribes after the server has reset the session, it sends a separate SUBSCRIBE packet for each priority,
+    /// highest priority first, so that the server acks the subscriptions with higher priorities first. Subscriptions with the same priority
+    /// are sent in the same packet, so by default all subscriptions are resubscribed with a single SUBSCRIBE packet.
+    ///
+    /// This doesn't affect subscription updates requested while the client is connected, which are sent in the order they were requested.
+    pub fn set_subscription_priority(&mut self, topic_filter: String, priority: u8) {
+        if let ClientState::Up { subscriptions, .. } = &mut self.0 {
+            subscriptions.set_priority(topic_filter, priority);
+        }
+    }
+
+    /// The subscription updates that have been sent to the server but not acked yet, in the order they were sent.
+    /// Updates that haven't been sent yet aren't included.
+    ///
+    /// This is meant for debugging subscriptions that never seem to take effect.
+    pub fn unacked_subscription_updates(&self) -> Vec<SubscriptionUpdate> {
+        match &self.0 {
+            ClientState::Up { subscriptions, .. } => subscriptions.unacked_subscription_updates(),
+            ClientState::ShuttingDown { .. } | ClientState::ShutDown { .. } => vec![],
+        }
+    }
+
+    fn set_ping_interval(&mut self, ping_interval: std::time::Duration) {
+        if let ClientState::Up {
+            ping_interval: current_ping_interval,
+            ..
+        } = &mut self.0
+        {
+            *current_ping_interval = ping_interval;
+        }
+    }
+
+    fn set_protocol_version(&mut self, protocol_version: crate::proto::ProtocolVersion) {
+        if let ClientState::Up { connect, .. } = &mut self.0 {
+            connect.set_protocol_version(protocol_version);
+        }
+    }
+
     /// Returns a handle that can be used to publish messages to the server
     pub fn publish_handle(&self) -> Result<PublishHandle, PublishError> {
         match &self.0 {
@@ -133,6 +498,58 @@ where
         }
     }
 
+    /// Subscribes to a topic with the given parameters, and returns a stream of the publications received on topics that match its topic filter.
+    ///
+    /// The client must still be polled for the stream to receive publications. The publications are also yielded by the client
+    /// as [`Event::Publication`] as usual, and they're buffered in the stream until it's polled.
+    ///
+    /// The stream ends when the server acks an unsubscription from the same topic filter, or when the client shuts down.
+    /// Dropping the stream doesn't unsubscribe from the topic filter.
+    pub fn subscribe_stream(
+        &mut self,
+        subscribe_to: crate::proto::SubscribeTo,
+    ) -> Result<impl Stream<Item = ReceivedPublication, Error = ()>, UpdateSubscriptionError> {
+        match &mut self.0 {
+            ClientState::Up {
+                subscriptions,
+                topic_streams,
+                ..
+            } => {
+                let (sender, receiver) = futures::sync::mpsc::unbounded();
+                topic_streams.push((subscribe_to.topic_filter.clone(), sender));
+                subscriptions
+                    .update_subscription(crate::SubscriptionUpdate::Subscribe(subscribe_to));
+                Ok(receiver)
+            }
+
+            ClientState::ShuttingDown { .. } | ClientState::ShutDown { .. } => {
+                Err(UpdateSubscriptionError::ClientDoesNotExist)
+            }
+        }
+    }
+
+    /// Subscribes to several topics at once
+    ///
+    /// The subscriptions that aren't already in effect are sent to the server in a single SUBSCRIBE packet with a single packet identifier.
+    pub fn subscribe_many(
+        &mut self,
+        subscribe_to: Vec<crate::proto::SubscribeTo>,
+    ) -> Result<(), UpdateSubscriptionError> {
+        match &mut self.0 {
+            ClientState::Up { subscriptions, .. } => {
+                for subscribe_to in subscribe_to {
+                    subscriptions
+                        .update_subscription(crate::SubscriptionUpdate::Subscribe(subscribe_to));
+                }
+                Ok(())
+            }
+
+            ClientState::ShuttingDown { .. } | ClientState::ShutDown { .. } => {
+                Err(UpdateSubscriptionError::ClientDoesNotExist)
+            }
+        }
+    }
+
     /// Unsubscribes from the given topic
     pub fn unsubscribe(&mut self, unsubscribe_from: String) -> Result<(), UpdateSubscriptionError> {
         match &mut self.0 {
@@ -160,6 +577,140 @@ where
         }
     }
 
+    /// Gets a reference to the [`IoSource`] this client uses to connect to the server.
+    ///
+    /// Returns `None` if the client has shut down.
+    pub fn io_source(&self) -> Option<&IoS> {
+        match &self.0 {
+            ClientState::Up { connect, .. } | ClientState::ShuttingDown { connect, .. } => {
+                Some(connect.io_source())
+            }
+            ClientState::ShutDown { .. } => None,
+        }
+    }
+
+    /// Gets the number of bytes the client has read from and written to the server.
+    ///
+    /// By default the counts are cumulative across reconnects. Use [`Client::set_reset_io_stats_on_reconnect`]
+    /// to only count the current connection instead.
+    ///
+    /// Returns `None` if the client has shut down.
+    pub fn io_stats(&self) -> Option<IoStats> {
+        match &self.0 {
+            ClientState::Up { connect, .. } | ClientState::ShuttingDown { connect, .. } => {
+                Some(connect.io_stats())
+            }
+            ClientState::ShutDown { .. } => None,
+        }
+    }
+
+    /// Sets whether the counts returned by [`Client::io_stats`] are reset to zero every time the client connects to the server.
+    /// Defaults to `false`, ie the counts are cumulative across reconnects.
+    pub fn set_reset_io_stats_on_reconnect(&mut self, reset_io_stats_on_reconnect: bool) {
+        if let ClientState::Up { connect, .. } = &mut self.0 {
+            connect.set_reset_io_stats_on_reconnect(reset_io_stats_on_reconnect);
+        }
+    }
+
+    /// Gets the round-trip time between the last PINGREQ the client sent and the server's PINGRESP to it,
+    /// on the current connection or a previous one.
+    ///
+    /// The client logs a warning when a round-trip takes at least half of the ping interval, since pings that slow risk arriving
+    /// after the server has already given up on the client. Raise the keep-alive if that happens regularly.
+    ///
+    /// Returns `None` if no PINGREQ has been answered yet, or if the client is shutting down or has shut down.
+    pub fn last_ping_rtt(&self) -> Option<std::time::Duration> {
+        match &self.0 {
+            ClientState::Up { ping, .. } => ping.last_rtt(),
+            ClientState::ShuttingDown { .. } | ClientState::ShutDown { .. } => None,
+        }
+    }
+
+    /// Gets a snapshot of the client's counters, such as how many times it reconnected and how many publications it sent.
+    ///
+    /// Returns `None` if the client is shutting down or has shut down.
+    pub fn metrics(&self) -> Option<Metrics> {
+        match &self.0 {
+            ClientState::Up {
+                connect,
+                publish,
+                subscriptions,
+                ..
+            } => Some(Metrics {
+                reconnects: connect.connections().saturating_sub(1),
+                io_stats: connect.io_stats(),
+                publications_sent: publish.publications_sent(),
+                inflight_publications: publish.inflight_len(),
+                inflight_subscription_updates: subscriptions.inflight_len(),
+            }),
+            ClientState::ShuttingDown { .. } | ClientState::ShutDown { .. } => None,
+        }
+    }
+
+    /// Renders the client's [`Client::metrics`] in the Prometheus text exposition format. See [`Metrics::to_prometheus`].
+    ///
+    /// Returns `None` if the client is shutting down or has shut down.
+    #[cfg(feature = "prometheus")]
+    pub fn metrics_prometheus(&self) -> Option<String> {
+        self.metrics().map(|metrics| metrics.to_prometheus())
+    }
+
+    /// Drops the current connection to the server and connects again, without resetting the session.
+    ///
+    /// This is useful when the application learns that the connection is bad before the client would notice it through
+    /// unanswered pings, such as when the device's network interface changes. The client sends a DISCONNECT packet
+    /// on a best-effort basis so that the server discards the will, then reconnects like it does after any other broken connection.
+    /// Subscriptions and unacked QoS 1 and QoS 2 publications carry over to the new connection, which is reported with
+    /// an [`Event::NewConnection`] as usual. Clients that use a server-generated ID start a new session, as on every reconnect.
+    ///
+    /// This has no effect if the client isn't connected to the server. The client must be polled for the reconnect to happen.
+    pub fn force_reconnect(&mut self) {
+        if let ClientState::Up { connect, .. } = &mut self.0 {
+            connect.force_reconnect();
+        }
+    }
+
+    /// Returns a future that resolves once every packet the client has queued so far has been written to the server.
+    ///
+    /// This only means the packets have been handed to the underlying I/O object, not that the server has received or acked them.
+    /// If the client is not connected to the server, the future resolves after the client has reconnected and written the packets.
+    ///
+    /// The client must be polled for the future to resolve.
+    pub fn flush(&self) -> impl Future<Item = (), Error = FlushError> {
+        match self.flush_handle() {
+            Ok(flush_handle) => futures::future::Either::A(flush_handle.flush()),
+            Err(err) => futures::future::Either::B(futures::future::err(err)),
+        }
+    }
+
+    /// Returns a handle that can be used to wait for the client's queued packets to be written to the server.
+    /// See [`Client::flush`] for details.
+    pub fn flush_handle(&self) -> Result<FlushHandle, FlushError> {
+        match &self.0 {
+            ClientState::Up { flush_send, .. } => Ok(FlushHandle(flush_send.clone())),
+            ClientState::ShuttingDown { .. } | ClientState::ShutDown { .. } => {
+                Err(FlushError::ClientDoesNotExist)
+            }
+        }
+    }
+
+    /// Returns a handle that can be used to let a paused client connect to the server. See [`ClientBuilder::start_paused`]
+    pub fn connect_handle(&self) -> Result<ConnectHandle, ConnectError> {
+        match &self.0 {
+            ClientState::Up { connect_send, .. } => Ok(ConnectHandle(connect_send.clone())),
+            ClientState::ShuttingDown { .. } | ClientState::ShutDown { .. } => {
+                Err(ConnectError::ClientDoesNotExist)
+            }
+        }
+    }
+
+    /// Makes the client wait for a [`ConnectHandle`] before it connects to the server for the first time
+    fn start_paused(&mut self) {
+        if let ClientState::Up { paused, .. } = &mut self.0 {
+            *paused = true;
+        }
+    }
+
     /// Returns a handle that can be used to signal the client to shut down
     pub fn shutdown_handle(&self) -> Result<ShutdownHandle, ShutdownError> {
         match &self.0 {
@@ -171,6 +722,19 @@ where
     }
 }
 
+impl<IoS> Drop for Client<IoS>
+where
+    IoS: IoSource,
+{
+    fn drop(&mut self) {
+        // Dropping the client is not a graceful shutdown, but if it's connected, try to tell the server that it's going away
+        // so that the server discards the will instead of publishing it.
+        if let ClientState::Up { connect, .. } = &mut self.0 {
+            connect.disconnect_best_effort();
+        }
+    }
+}
+
 impl<IoS> Stream for Client<IoS>
 where
     IoS: IoSource,
@@ -188,8 +752,16 @@ where
                     password,
                     will,
                     keep_alive,
+                    ping_interval,
 
                     shutdown_recv,
+                    drain_timeout,
+
+                    flush_recv,
+                    flush_waiters,
+
+                    paused,
+                    connect_recv,
 
                     packet_identifiers,
 
@@ -197,16 +769,65 @@ where
                     ping,
                     publish,
                     subscriptions,
+                    topic_streams,
 
                     packets_waiting_to_be_sent,
+
+                    slow_consumer_threshold,
+                    ack_pending_since,
+
+                    reset_session_on_server_close,
                     ..
                 } => {
+                    check_slow_consumer(*slow_consumer_threshold, ack_pending_since);
+
+                    while let futures::Async::Ready(Some(flush_waiter)) =
+                        flush_recv.poll().expect("Receiver::poll cannot fail")
+                    {
+                        flush_waiters.push(flush_waiter);
+                    }
+
                     match shutdown_recv.poll().expect("Receiver::poll cannot fail") {
-                        futures::Async::Ready(Some(())) => break None,
+                        futures::Async::Ready(Some(ShutdownRequest::Immediate)) => break None,
+
+                        futures::Async::Ready(Some(ShutdownRequest::Graceful(timeout))) => {
+                            log::info!("Draining publications before shutting down...");
+                            *drain_timeout = Some(tokio::timer::Delay::new(
+                                std::time::Instant::now() + timeout,
+                            ));
+                            continue;
+                        }
 
                         futures::Async::Ready(None) | futures::Async::NotReady => (),
                     }
 
+                    if let Some(drain_timeout_timer) = drain_timeout {
+                        if publish.is_drained() && packets_waiting_to_be_sent.is_empty() {
+                            break None;
+                        }
+
+                        match drain_timeout_timer
+                            .poll()
+                            .expect("could not poll drain timeout timer")
+                        {
+                            futures::Async::Ready(()) => {
+                                log::warn!("timed out waiting for publications to drain");
+                                break None;
+                            }
+                            futures::Async::NotReady => (),
+                        }
+                    }
+
+                    while let futures::Async::Ready(Some(())) =
+                        connect_recv.poll().expect("Receiver::poll cannot fail")
+                    {
+                        *paused = false;
+                    }
+
+                    if *paused {
+                        return Ok(futures::Async::NotReady);
+                    }
+
                     let self::connect::Connected {
                         framed,
                         new_connection,
@@ -218,9 +839,20 @@ where
                         client_id,
                         *keep_alive,
                     ) {
-                        Ok(futures::Async::Ready(framed)) => framed,
+                        Ok(futures::Async::Ready(self::connect::Progress::Connected(
+                            connected,
+                        ))) => connected,
+                        Ok(futures::Async::Ready(self::connect::Progress::Attempt {
+                            attempt,
+                            back_off,
+                        })) => {
+                            return Ok(futures::Async::Ready(Some(Event::Connecting {
+                                attempt,
+                                back_off,
+                            })));
+                        }
                         Ok(futures::Async::NotReady) => return Ok(futures::Async::NotReady),
-                        Err(()) => unreachable!(),
+                        Err(()) => break Some(Error::ReconnectPolicyGaveUp),
                     };
 
                     if new_connection {
@@ -230,8 +862,9 @@ where
 
                         ping.new_connection();
 
-                        packets_waiting_to_be_sent
-                            .extend(publish.new_connection(reset_session, packet_identifiers));
+                        let (retransmitted_publications, publish_packets) =
+                            publish.new_connection(reset_session, packet_identifiers);
+                        packets_waiting_to_be_sent.extend(publish_packets);
 
                         packets_waiting_to_be_sent.extend(
                             subscriptions.new_connection(reset_session, packet_identifiers),
@@ -239,29 +872,51 @@ where
 
                         return Ok(futures::Async::Ready(Some(Event::NewConnection {
                             reset_session,
+                            retransmitted_publications,
                         })));
                     }
 
+                    let mut all_packets_written = false;
+
                     match client_poll(
                         framed,
-                        *keep_alive,
+                        *ping_interval,
                         packets_waiting_to_be_sent,
+                        &mut all_packets_written,
                         packet_identifiers,
                         ping,
                         publish,
                         subscriptions,
                     ) {
                         Ok(futures::Async::Ready(event)) => {
+                            record_ack_pending(*slow_consumer_threshold, ack_pending_since, &event);
+
+                            update_topic_streams(topic_streams, &event);
                             return Ok(futures::Async::Ready(Some(event)));
                         }
-                        Ok(futures::Async::NotReady) => return Ok(futures::Async::NotReady),
+                        Ok(futures::Async::NotReady) => {
+                            if all_packets_written {
+                                for flush_waiter in flush_waiters.drain(..) {
+                                    let _ = flush_waiter.send(());
+                                }
+                            }
+
+                            if drain_timeout.is_some()
+                                && publish.is_drained()
+                                && packets_waiting_to_be_sent.is_empty()
+                            {
+                                break None;
+                            }
+
+                            return Ok(futures::Async::NotReady);
+                        }
                         Err(err) => {
                             if err.is_user_error() {
                                 break Some(err);
                             } else {
                                 log::warn!("client will reconnect because of error: {}", err);
 
-                                if !err.session_is_resumable() {
+                                if !err.session_is_resumable(*reset_session_on_server_close) {
                                     // Ensure clean session if the error is such that the session is not resumable.
                                     //
                                     // DEVNOTE: subscriptions::State relies on the fact that the session is reset here.
@@ -304,24 +959,23 @@ where
                         client_id,
                         *keep_alive,
                     ) {
-                        Ok(futures::Async::Ready(framed)) => framed,
-                        Ok(futures::Async::NotReady) => {
+                        Ok(futures::Async::Ready(self::connect::Progress::Connected(
+                            connected,
+                        ))) => connected,
+                        Ok(futures::Async::Ready(self::connect::Progress::Attempt { .. }))
+                        | Ok(futures::Async::NotReady)
+                        | Err(()) => {
                             // Already disconnected
-                            self.0 = ClientState::ShutDown {
-                                reason: reason.take(),
-                            };
+                            self.0 = ClientState::shut_down(reason.take(), false);
                             continue;
                         }
-                        Err(()) => unreachable!(),
                     };
 
                     loop {
                         if *sent_disconnect {
                             match framed.poll_complete().map_err(Error::EncodePacket) {
                                 Ok(futures::Async::Ready(())) => {
-                                    self.0 = ClientState::ShutDown {
-                                        reason: reason.take(),
-                                    };
+                                    self.0 = ClientState::shut_down(reason.take(), true);
                                     break;
                                 }
 
@@ -329,9 +983,7 @@ where
 
                                 Err(err) => {
                                     log::warn!("couldn't send DISCONNECT: {}", err);
-                                    self.0 = ClientState::ShutDown {
-                                        reason: reason.take(),
-                                    };
+                                    self.0 = ClientState::shut_down(reason.take(), false);
                                     break;
                                 }
                             }
@@ -345,9 +997,7 @@ where
 
                                 Err(err) => {
                                     log::warn!("couldn't send DISCONNECT: {}", err);
-                                    self.0 = ClientState::ShutDown {
-                                        reason: reason.take(),
-                                    };
+                                    self.0 = ClientState::shut_down(reason.take(), false);
                                     break;
                                 }
                             }
@@ -355,16 +1005,39 @@ where
                     }
                 }
 
-                ClientState::ShutDown { reason } => match reason.take() {
-                    Some(err) => return Err(err),
-                    None => return Ok(futures::Async::Ready(None)),
-                },
+                ClientState::ShutDown {
+                    reason,
+                    disconnected,
+                } => {
+                    if let Some(graceful) = disconnected.take() {
+                        return Ok(futures::Async::Ready(Some(Event::Disconnected {
+                            graceful,
+                        })));
+                    }
+
+                    match reason.take() {
+                        Some(err) => return Err(err),
+                        None => return Ok(futures::Async::Ready(None)),
+                    }
+                }
             }
         };
 
         // If we're here, then we're transitioning from Up to ShuttingDown
 
-        match std::mem::replace(&mut self.0, ClientState::ShutDown { reason: None }) {
+        match std::mem::replace(
+            &mut self.0,
+            ClientState::ShutDown {
+                reason: None,
+                disconnected: None,
+            },
+        ) {
+            // The client never started connecting, so there's no connection to disconnect
+            ClientState::Up { paused: true, .. } => {
+                self.0 = ClientState::shut_down(reason, false);
+                self.poll()
+            }
+
             ClientState::Up {
                 client_id,
                 username,
@@ -427,12 +1100,27 @@ where
 }
 
 /// An event generated by the [`Client`]
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum Event {
+    /// The [`Client`] started a new attempt to connect to the server.
+    ///
+    /// Only emitted if enabled with [`Client::set_report_connection_attempts`].
+    Connecting {
+        /// The number of consecutive attempts since the last successful connection, including this one. Starts at 1.
+        attempt: u32,
+
+        /// How long the client will back off before the next attempt if this one fails
+        back_off: std::time::Duration,
+    },
+
     /// The [`Client`] established a new connection to the server.
     NewConnection {
         /// Whether the session was reset as part of this new connection or not
         reset_session: bool,
+
+        /// The number of publications sent on previous connections that had not been acked by the server,
+        /// and so were sent again on this connection
+        retransmitted_publications: usize,
     },
 
     /// A publication received from the server
@@ -440,19 +1128,91 @@ pub enum Event {
 
     /// Subscription updates acked by the server
     SubscriptionUpdates(Vec<crate::SubscriptionUpdate>),
+
+    /// Every subscription update pending on the current connection has been acked by the server, ie the client's full set
+    /// of subscriptions is active. Emitted once per connection, after the [`Event::SubscriptionUpdates`] for the last such update.
+    ///
+    /// Pending updates include the resubscriptions sent after the session was reset, the updates that were unacked on
+    /// the previous connection, and any updates requested before this event. If the session was resumed with nothing pending,
+    /// this event follows [`Event::NewConnection`] immediately. It isn't emitted while the client has no subscriptions.
+    ///
+    /// Publications that the server sent right after acking the last update may be emitted before this event.
+    SubscriptionsSynced,
+
+    /// The [`Client`] finished shutting down after being asked to with a [`ShutdownHandle`]. This is the last event before the client ends.
+    ///
+    /// MQTT has no ack for the DISCONNECT packet, so this is the only confirmation that the server was told about the clean disconnect.
+    /// The client isn't shut down this way, and doesn't emit this event, if it fails with an error.
+    Disconnected {
+        /// Whether the DISCONNECT packet was completely written to the server. `false` if the client wasn't connected
+        /// when it shut down, or if the DISCONNECT packet couldn't be written.
+        graceful: bool,
+    },
+}
+
+/// Whether a packet given to the observer set with [`Client::set_packet_observer`] was sent to or received from the server
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Received,
+    Sent,
 }
 
 /// A message that was received from the server
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct ReceivedPublication {
     pub topic_name: String,
     pub dup: bool,
     pub qos: crate::proto::QoS,
+
+    /// Whether the server sent this publication because it is the retained message of the topic.
+    ///
+    /// The server sets this for a retained message that it delivers because a new subscription matched it,
+    /// and clears it for publications delivered to existing subscriptions as they're published. So an application
+    /// that bootstraps its state from retained topics can apply the publications with `retain` set as the initial snapshot,
+    /// and treat the others as live updates.
+    ///
+    /// MQTT 3.1.1 has no way to tell a retained message apart from a live one that was also published with the retain flag
+    /// if it arrives while the subscription is already in place, so the server sends the latter with `retain` cleared.
     pub retain: bool,
+
     pub payload: Vec<u8>,
 }
 
-pub struct ShutdownHandle(futures::sync::mpsc::Sender<()>);
+/// The number of bytes a [`Client`] has read from and written to the server, as returned by [`Client::io_stats`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct IoStats {
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+}
+
+/// A snapshot of a [`Client`]'s counters, as returned by [`Client::metrics`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Metrics {
+    /// The number of times the client connected to the server after its first connection
+    pub reconnects: u64,
+
+    /// The same as [`Client::io_stats`]
+    pub io_stats: IoStats,
+
+    /// The number of publications the client has sent to the server. Publications retransmitted on a new connection are only counted once.
+    pub publications_sent: PublicationCounts,
+
+    /// The number of QoS 1 and QoS 2 publications that have been sent to the server but not completely acked yet
+    pub inflight_publications: usize,
+
+    /// The number of SUBSCRIBE and UNSUBSCRIBE packets that have been sent to the server but not acked yet
+    pub inflight_subscription_updates: usize,
+}
+
+/// A number of publications for each [`crate::proto::QoS`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PublicationCounts {
+    pub at_most_once: u64,
+    pub at_least_once: u64,
+    pub exactly_once: u64,
+}
+
+pub struct ShutdownHandle(futures::sync::mpsc::Sender<ShutdownRequest>);
 
 impl ShutdownHandle {
     /// Signals the [`Client`] to shut down.
@@ -460,13 +1220,72 @@ impl ShutdownHandle {
     /// The returned `Future` resolves when the `Client` is guaranteed the notification,
     /// not necessarily when the `Client` has completed shutting down.
     pub fn shutdown(&self) -> impl Future<Item = (), Error = ShutdownError> {
-        self.0.clone().send(()).then(|result| match result {
+        self.send(ShutdownRequest::Immediate)
+    }
+
+    /// Signals the [`Client`] to shut down after it has finished sending its queued publications.
+    ///
+    /// The `Client` keeps running until all queued publications have been sent, and all QoS 1 and QoS 2 publications
+    /// have been acked by the server, or until `timeout` elapses, whichever comes first. It then shuts down
+    /// the same way as for [`ShutdownHandle::shutdown`].
+    ///
+    /// The returned `Future` resolves when the `Client` is guaranteed the notification,
+    /// not necessarily when the `Client` has completed shutting down.
+    pub fn shutdown_graceful(
+        &self,
+        timeout: std::time::Duration,
+    ) -> impl Future<Item = (), Error = ShutdownError> {
+        self.send(ShutdownRequest::Graceful(timeout))
+    }
+
+    fn send(&self, request: ShutdownRequest) -> impl Future<Item = (), Error = ShutdownError> {
+        self.0.clone().send(request).then(|result| match result {
             Ok(_) => Ok(()),
             Err(_) => Err(ShutdownError::ClientDoesNotExist),
         })
     }
 }
 
+/// Used to let a [`Client`] created with [`ClientBuilder::start_paused`] connect to the server
+#[derive(Clone)]
+pub struct ConnectHandle(futures::sync::mpsc::Sender<()>);
+
+impl ConnectHandle {
+    /// Signals the [`Client`] to start connecting to the server. This has no effect if the `Client` isn't paused.
+    ///
+    /// The returned `Future` resolves when the `Client` is guaranteed the notification,
+    /// not necessarily when the `Client` has connected.
+    pub fn connect(&self) -> impl Future<Item = (), Error = ConnectError> {
+        self.0.clone().send(()).then(|result| match result {
+            Ok(_) => Ok(()),
+            Err(_) => Err(ConnectError::ClientDoesNotExist),
+        })
+    }
+}
+
+/// Used to wait for a [`Client`]'s queued packets to be written to the server
+#[derive(Clone)]
+pub struct FlushHandle(futures::sync::mpsc::Sender<futures::sync::oneshot::Sender<()>>);
+
+impl FlushHandle {
+    /// Returns a future that resolves once every packet the [`Client`] has queued so far has been written to the server.
+    /// See [`Client::flush`] for details.
+    pub fn flush(&self) -> impl Future<Item = (), Error = FlushError> {
+        let (flush_waiter, flushed) = futures::sync::oneshot::channel();
+        self.0
+            .clone()
+            .send(flush_waiter)
+            .map_err(|_| FlushError::ClientDoesNotExist)
+            .and_then(|_| flushed.map_err(|_| FlushError::ClientDoesNotExist))
+    }
+}
+
+#[derive(Debug)]
+enum ShutdownRequest {
+    Immediate,
+    Graceful(std::time::Duration),
+}
+
 #[derive(Debug)]
 enum ClientState<IoS>
 where
@@ -479,8 +1298,25 @@ where
         will: Option<crate::proto::Publication>,
         keep_alive: std::time::Duration,
 
-        shutdown_send: futures::sync::mpsc::Sender<()>,
-        shutdown_recv: futures::sync::mpsc::Receiver<()>,
+        /// How often the client pings the server. Half of `keep_alive` unless configured otherwise.
+        ping_interval: std::time::Duration,
+
+        shutdown_send: futures::sync::mpsc::Sender<ShutdownRequest>,
+        shutdown_recv: futures::sync::mpsc::Receiver<ShutdownRequest>,
+
+        /// Set if the client is draining its publications before shutting down
+        drain_timeout: Option<tokio::timer::Delay>,
+
+        flush_send: futures::sync::mpsc::Sender<futures::sync::oneshot::Sender<()>>,
+        flush_recv: futures::sync::mpsc::Receiver<futures::sync::oneshot::Sender<()>>,
+
+        /// Waiting for all packets to be written. Dropped without being completed if the client shuts down.
+        flush_waiters: Vec<futures::sync::oneshot::Sender<()>>,
+
+        /// Set until a [`ConnectHandle`] lets the client connect, if the client was created with [`ClientBuilder::start_paused`]
+        paused: bool,
+        connect_send: futures::sync::mpsc::Sender<()>,
+        connect_recv: futures::sync::mpsc::Receiver<()>,
 
         packet_identifiers: PacketIdentifiers,
 
@@ -489,8 +1325,24 @@ where
         publish: self::publish::State,
         subscriptions: self::subscriptions::State,
 
+        /// The topic filters of the streams returned by [`Client::subscribe_stream`], and the senders of those streams
+        topic_streams: Vec<(
+            String,
+            futures::sync::mpsc::UnboundedSender<ReceivedPublication>,
+        )>,
+
         /// Packets waiting to be written to the underlying `Framed`
         packets_waiting_to_be_sent: std::collections::VecDeque<crate::proto::Packet>,
+
+        /// See [`Client::set_slow_consumer_threshold`]
+        slow_consumer_threshold: Option<std::time::Duration>,
+
+        /// When the client last yielded a QoS 1 or 2 publication whose ack is waiting for the client to be polled again.
+        /// Only tracked if `slow_consumer_threshold` is set.
+        ack_pending_since: Option<std::time::Instant>,
+
+        /// See [`Client::set_reset_session_on_server_close`]
+        reset_session_on_server_close: bool,
     },
 
     ShuttingDown {
@@ -512,13 +1364,103 @@ where
     ShutDown {
         /// The Error that caused the Client to transition away from Up, if any
         reason: Option<Error>,
+
+        /// Whether the DISCONNECT packet was sent, if the client was shut down on request and hasn't yielded
+        /// the corresponding [`Event::Disconnected`] yet
+        disconnected: Option<bool>,
     },
 }
 
+impl<IoS> ClientState<IoS>
+where
+    IoS: IoSource,
+{
+    /// The state of a client that has finished shutting down, after sending a DISCONNECT packet to the server if `graceful` is set
+    fn shut_down(reason: Option<Error>, graceful: bool) -> Self {
+        // Event::Disconnected is only emitted if the shutdown was requested, rather than caused by an error
+        let disconnected = if reason.is_none() {
+            Some(graceful)
+        } else {
+            None
+        };
+        ClientState::ShutDown {
+            reason,
+            disconnected,
+        }
+    }
+}
+
+/// Sends a received publication to the streams whose topic filters match it,
+/// and ends the streams whose topic filters have been unsubscribed from.
+///
+/// Streams that have been dropped are removed.
+fn update_topic_streams(
+    topic_streams: &mut Vec<(
+        String,
+        futures::sync::mpsc::UnboundedSender<ReceivedPublication>,
+    )>,
+    event: &Event,
+) {
+    match event {
+        Event::Publication(publication) => topic_streams.retain(|(topic_filter, sender)| {
+            !crate::proto::topic_filter_matches(topic_filter, &publication.topic_name)
+                || sender.unbounded_send(publication.clone()).is_ok()
+        }),
+
+        Event::SubscriptionUpdates(subscription_updates) => {
+            for subscription_update in subscription_updates {
+                if let SubscriptionUpdate::Unsubscribe(unsubscribed_from) = subscription_update {
+                    topic_streams.retain(|(topic_filter, _)| topic_filter != unsubscribed_from);
+                }
+            }
+        }
+
+        _ => (),
+    }
+}
+
+/// Records when the client yields a QoS 1 or 2 publication, if `slow_consumer_threshold` is set, since the ack for it was queued
+/// but will only be written when the client is polled again.
+fn record_ack_pending(
+    slow_consumer_threshold: Option<std::time::Duration>,
+    ack_pending_since: &mut Option<std::time::Instant>,
+    event: &Event,
+) {
+    if let Event::Publication(publication) = event {
+        if slow_consumer_threshold.is_some() && publication.qos != crate::proto::QoS::AtMostOnce {
+            *ack_pending_since = Some(std::time::Instant::now());
+        }
+    }
+}
+
+/// Logs a warning if the client has been polled more than `slow_consumer_threshold` after it yielded a publication whose ack
+/// is waiting for it to be polled. Returns how long the ack was waiting if it warned.
+fn check_slow_consumer(
+    slow_consumer_threshold: Option<std::time::Duration>,
+    ack_pending_since: &mut Option<std::time::Instant>,
+) -> Option<std::time::Duration> {
+    if let (Some(slow_consumer_threshold), Some(ack_pending_since)) =
+        (slow_consumer_threshold, ack_pending_since.take())
+    {
+        let elapsed = ack_pending_since.elapsed();
+        if elapsed > slow_consumer_threshold {
+            log::warn!(
+                "consumer is slow: client was polled {:?} after it yielded a publication that needs to be acked",
+                elapsed,
+            );
+            return Some(elapsed);
+        }
+    }
+
+    None
+}
+
+/// Sets `all_packets_written` if it returns `NotReady` after every packet waiting to be sent has been completely written to `framed`
 fn client_poll<S>(
     framed: &mut crate::logging_framed::LoggingFramed<S>,
-    keep_alive: std::time::Duration,
+    ping_interval: std::time::Duration,
     packets_waiting_to_be_sent: &mut std::collections::VecDeque<crate::proto::Packet>,
+    all_packets_written: &mut bool,
     packet_identifiers: &mut PacketIdentifiers,
     ping: &mut self::ping::State,
     publish: &mut self::publish::State,
@@ -542,8 +1484,9 @@ where
 
         // Finish sending any packets waiting to be sent.
         //
-        // We don't care whether this returns Async::NotReady or Ready.
-        let _ = framed.poll_complete().map_err(Error::EncodePacket)?;
+        // Whether this returns Async::NotReady or Ready only matters to flush waiters.
+        let poll_complete = framed.poll_complete().map_err(Error::EncodePacket)?;
+        *all_packets_written = poll_complete.is_ready() && packets_waiting_to_be_sent.is_empty();
 
         let mut continue_loop = false;
 
@@ -560,7 +1503,7 @@ where
         let mut new_packets_to_be_sent = vec![];
 
         // Ping
-        match ping.poll(&mut packet, keep_alive)? {
+        match ping.poll(&mut packet, ping_interval)? {
             futures::Async::Ready(packet) => new_packets_to_be_sent.push(packet),
             futures::Async::NotReady => (),
         }
@@ -580,7 +1523,11 @@ where
             subscription_updates
         };
 
-        assert!(packet.is_none(), "unconsumed packet");
+        // None of the handlers consumed the packet, so the server sent a packet that only a client is supposed to send,
+        // like SUBSCRIBE, or one that isn't valid once connected, like CONNACK
+        if let Some(packet) = packet {
+            return Err(Error::UnexpectedPacket(packet.packet_type_name()));
+        }
 
         if !new_packets_to_be_sent.is_empty() {
             // Have new packets to send, so keep looping
@@ -600,6 +1547,10 @@ where
             )));
         }
 
+        if subscriptions.poll_synced() {
+            return Ok(futures::Async::Ready(Event::SubscriptionsSynced));
+        }
+
         if !continue_loop {
             return Ok(futures::Async::NotReady);
         }
@@ -622,20 +1573,20 @@ impl PacketIdentifiers {
     /// We use a bitshift instead of usize::pow because the latter is not a const fn
     const SIZE: usize = (1 << 16) / (std::mem::size_of::<usize>() * 8);
 
+    /// Reserves the first free packet identifier after the previously reserved one, wrapping around if necessary.
+    ///
+    /// Fails only if every packet identifier is in use.
     fn reserve(&mut self) -> Result<crate::proto::PacketIdentifier, Error> {
-        let start = self.previous;
-        let mut current = start;
-
-        current += 1;
-
-        let (block, mask) = self.entry(current);
-        if (*block & mask) != 0 {
-            return Err(Error::PacketIdentifiersExhausted);
+        for current in self.previous.successors() {
+            let (block, mask) = self.entry(current);
+            if (*block & mask) == 0 {
+                *block |= mask;
+                self.previous = current;
+                return Ok(current);
+            }
         }
 
-        *block |= mask;
-        self.previous = current;
-        Ok(current)
+        Err(Error::PacketIdentifiersExhausted)
     }
 
     fn discard(&mut self, packet_identifier: crate::proto::PacketIdentifier) {
@@ -677,10 +1628,14 @@ pub enum Error {
     EncodePacket(crate::proto::EncodeError),
     PacketIdentifiersExhausted,
     PingTimer(tokio::timer::Error),
+    ReconnectPolicyGaveUp,
     ServerClosedConnection,
     SubAckDoesNotContainEnoughQoS(crate::proto::PacketIdentifier, usize, usize),
+    SubscriptionAckTimedOut,
+    SubscriptionAckTimer(tokio::timer::Error),
     SubscriptionDowngraded(String, crate::proto::QoS, crate::proto::QoS),
     SubscriptionRejectedByServer,
+    UnexpectedPacket(&'static str),
     UnexpectedSubAck(crate::proto::PacketIdentifier, UnexpectedSubUnsubAckReason),
     UnexpectedUnsubAck(crate::proto::PacketIdentifier, UnexpectedSubUnsubAckReason),
 }
@@ -701,12 +1656,15 @@ impl Error {
         }
     }
 
-    fn session_is_resumable(&self) -> bool {
+    /// Whether the client should ask the server to resume the session when it reconnects after this error.
+    /// The server may still not have the session, which the client finds out from the CONNACK.
+    fn session_is_resumable(&self, reset_session_on_server_close: bool) -> bool {
         match self {
             Error::DecodePacket(crate::proto::DecodeError::Io(err)) => {
                 err.kind() == std::io::ErrorKind::TimedOut
             }
-            Error::ServerClosedConnection => true,
+            Error::ServerClosedConnection => !reset_session_on_server_close,
+            Error::SubscriptionAckTimedOut => true,
             _ => false,
         }
     }
@@ -734,18 +1692,30 @@ impl std::fmt::Display for Error {
 			Error::PingTimer(err) =>
 				write!(f, "ping timer failed: {}", err),
 
+			Error::ReconnectPolicyGaveUp =>
+				write!(f, "reconnect policy gave up reconnecting to the server"),
+
 			Error::ServerClosedConnection =>
 				write!(f, "connection closed by server"),
 
 			Error::SubAckDoesNotContainEnoughQoS(packet_identifier, expected, actual) =>
 				write!(f, "Expected SUBACK {} to contain {} QoS's but it actually contained {}", packet_identifier, expected, actual),
 
+			Error::SubscriptionAckTimedOut =>
+				write!(f, "timed out waiting for the server to ack a subscription update"),
+
+			Error::SubscriptionAckTimer(err) =>
+				write!(f, "subscription ack timer failed: {}", err),
+
 			Error::SubscriptionDowngraded(topic_name, expected, actual) =>
 				write!(f, "Server downgraded subscription for topic filter {:?} with QoS {:?} to {:?}", topic_name, expected, actual),
 
 			Error::SubscriptionRejectedByServer =>
 				write!(f, "Server rejected one or more subscriptions"),
 
+			Error::UnexpectedPacket(packet_type_name) =>
+				write!(f, "received unexpected {} packet", packet_type_name),
+
 			Error::UnexpectedSubAck(packet_identifier, reason) =>
 				write!(f, "received SUBACK {} but {}", packet_identifier, reason),
 
@@ -764,10 +1734,14 @@ impl std::error::Error for Error {
             Error::EncodePacket(err) => Some(err),
             Error::PacketIdentifiersExhausted => None,
             Error::PingTimer(err) => Some(err),
+            Error::ReconnectPolicyGaveUp => None,
             Error::ServerClosedConnection => None,
             Error::SubAckDoesNotContainEnoughQoS(_, _, _) => None,
+            Error::SubscriptionAckTimedOut => None,
+            Error::SubscriptionAckTimer(err) => Some(err),
             Error::SubscriptionDowngraded(_, _, _) => None,
             Error::SubscriptionRejectedByServer => None,
+            Error::UnexpectedPacket(_) => None,
             Error::UnexpectedSubAck(_, _) => None,
             Error::UnexpectedUnsubAck(_, _) => None,
         }
@@ -791,6 +1765,35 @@ impl std::fmt::Display for UnexpectedSubUnsubAckReason {
     }
 }
 
+/// Errors from creating a [`Client`]
+#[derive(Debug)]
+pub enum CreateClientError {
+    InvalidClientId(crate::proto::ClientIdError),
+    InvalidPingIntervalFraction(f64),
+}
+
+impl std::fmt::Display for CreateClientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CreateClientError::InvalidClientId(err) => write!(f, "invalid client ID: {}", err),
+            CreateClientError::InvalidPingIntervalFraction(fraction) => write!(
+                f,
+                "invalid ping interval fraction {}: must be greater than 0 and at most 1",
+                fraction
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CreateClientError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CreateClientError::InvalidClientId(err) => Some(err),
+            CreateClientError::InvalidPingIntervalFraction(_) => None,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum ShutdownError {
     ClientDoesNotExist,
@@ -806,10 +1809,77 @@ impl std::fmt::Display for ShutdownError {
 
 impl std::error::Error for ShutdownError {}
 
+#[derive(Debug)]
+pub enum ConnectError {
+    ClientDoesNotExist,
+}
+
+impl std::fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConnectError::ClientDoesNotExist => write!(f, "client does not exist"),
+        }
+    }
+}
+
+impl std::error::Error for ConnectError {}
+
+#[derive(Debug)]
+pub enum FlushError {
+    ClientDoesNotExist,
+}
+
+impl std::fmt::Display for FlushError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FlushError::ClientDoesNotExist => write!(f, "client does not exist"),
+        }
+    }
+}
+
+impl std::error::Error for FlushError {}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn exponential_backoff() {
+        let mut policy = ExponentialBackoff::new(std::time::Duration::from_secs(5));
+
+        let mut last = std::time::Duration::from_secs(0);
+        let mut back_offs = vec![];
+        for attempt in 1..=6 {
+            last = policy.next_backoff(attempt, last).unwrap();
+            back_offs.push(last.as_secs());
+        }
+        assert_eq!(back_offs, vec![0, 1, 2, 4, 5, 5]);
+    }
+
+    #[test]
+    fn exponential_backoff_has_minimum_max_back_off() {
+        let mut policy = ExponentialBackoff::new(std::time::Duration::from_secs(0));
+
+        let mut last = std::time::Duration::from_secs(0);
+        let mut back_offs = vec![];
+        for attempt in 1..=4 {
+            last = policy.next_backoff(attempt, last).unwrap();
+            back_offs.push(last.as_secs());
+        }
+        assert_eq!(back_offs, vec![0, 1, 1, 1]);
+    }
+
+    #[test]
+    fn handles_are_send_and_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+
+        assert_send_sync::<ConnectHandle>();
+        assert_send_sync::<FlushHandle>();
+        assert_send_sync::<PublishHandle>();
+        assert_send_sync::<ShutdownHandle>();
+        assert_send_sync::<UpdateSubscriptionHandle>();
+    }
+
     #[test]
     fn packet_identifiers() {
         #[cfg(target_pointer_width = "32")]
@@ -893,4 +1963,119 @@ mod tests {
         }
         assert_eq!(packet_identifiers.in_use[..], expected[..]);
     }
+
+    #[test]
+    fn packet_identifiers_reserve_never_returns_an_identifier_in_use() {
+        // xorshift32, so that the sequence of operations is random but reproducible
+        let mut rng_state: u32 = 0x1234_5678;
+        let mut next_random = move || {
+            rng_state ^= rng_state << 13;
+            rng_state ^= rng_state >> 17;
+            rng_state ^= rng_state << 5;
+            rng_state
+        };
+
+        let mut packet_identifiers: PacketIdentifiers = Default::default();
+        let mut in_use = std::collections::BTreeSet::new();
+        let mut in_use_list = vec![];
+
+        // Enough reservations to wrap around the identifier space a few times,
+        // while randomly chosen identifiers stay in use across the wraparounds
+        for _ in 0..500_000 {
+            if in_use_list.is_empty() || next_random() % 2 == 0 {
+                let packet_identifier = packet_identifiers.reserve().unwrap();
+                assert_ne!(packet_identifier.get(), 0);
+                assert!(
+                    in_use.insert(packet_identifier),
+                    "{} was reserved while in use",
+                    packet_identifier
+                );
+                in_use_list.push(packet_identifier);
+            } else {
+                let index = next_random() as usize % in_use_list.len();
+                let packet_identifier = in_use_list.swap_remove(index);
+                assert!(in_use.remove(&packet_identifier));
+                packet_identifiers.discard(packet_identifier);
+            }
+        }
+
+        // Fill up every remaining identifier, then check that exhaustion is reported
+        // and that discarding any one identifier makes it available again
+        while in_use.len() < usize::from(u16::max_value()) {
+            assert!(in_use.insert(packet_identifiers.reserve().unwrap()));
+        }
+        assert!(crate::proto::PacketIdentifier::all().eq(in_use.iter().cloned()));
+        match packet_identifiers.reserve() {
+            Err(Error::PacketIdentifiersExhausted) => (),
+            result => panic!("{:?}", result),
+        }
+
+        for &packet_identifier in &[1, 2, 0x7FFF, u16::max_value()] {
+            let packet_identifier = crate::proto::PacketIdentifier::new(packet_identifier).unwrap();
+            packet_identifiers.discard(packet_identifier);
+            assert_eq!(packet_identifiers.reserve().unwrap(), packet_identifier);
+        }
+    }
+
+    fn received_publication(qos: crate::proto::QoS) -> Event {
+        Event::Publication(ReceivedPublication {
+            topic_name: "topic".to_string(),
+            dup: false,
+            qos,
+            retain: false,
+            payload: b"payload".to_vec(),
+        })
+    }
+
+    #[test]
+    fn slow_consumer_is_detected() {
+        let threshold = Some(std::time::Duration::from_millis(10));
+        let mut ack_pending_since = None;
+
+        record_ack_pending(
+            threshold,
+            &mut ack_pending_since,
+            &received_publication(crate::proto::QoS::AtLeastOnce),
+        );
+        assert!(ack_pending_since.is_some());
+
+        std::thread::sleep(std::time::Duration::from_millis(20));
+
+        let elapsed = check_slow_consumer(threshold, &mut ack_pending_since)
+            .expect("slow consumer was not detected");
+        assert!(elapsed > std::time::Duration::from_millis(10));
+        assert!(ack_pending_since.is_none());
+
+        // A prompt poll isn't reported
+        record_ack_pending(
+            threshold,
+            &mut ack_pending_since,
+            &received_publication(crate::proto::QoS::ExactlyOnce),
+        );
+        assert!(check_slow_consumer(
+            Some(std::time::Duration::from_secs(60)),
+            &mut ack_pending_since
+        )
+        .is_none());
+        assert!(ack_pending_since.is_none());
+    }
+
+    #[test]
+    fn slow_consumer_is_only_tracked_for_acked_publications_with_threshold() {
+        let mut ack_pending_since = None;
+
+        record_ack_pending(
+            Some(std::time::Duration::from_millis(10)),
+            &mut ack_pending_since,
+            &received_publication(crate::proto::QoS::AtMostOnce),
+        );
+        assert!(ack_pending_since.is_none());
+
+        record_ack_pending(
+            None,
+            &mut ack_pending_since,
+            &received_publication(crate::proto::QoS::AtLeastOnce),
+        );
+        assert!(ack_pending_since.is_none());
+    }
 }
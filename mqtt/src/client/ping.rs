@@ -1,23 +1,56 @@
 use futures::Future;
 
-pub(super) enum State {
+pub(super) struct State {
+    timer: TimerState,
+
+    /// When the client queued the PINGREQ that hasn't been answered with a PINGRESP yet, if any
+    ping_req_sent: Option<std::time::Instant>,
+
+    /// The round-trip time of the last PINGREQ that was answered, on this connection or a previous one
+    last_rtt: Option<std::time::Duration>,
+}
+
+enum TimerState {
     BeginWaitingForNextPing,
     WaitingForNextPing(tokio::timer::Delay),
 }
 
 impl State {
+    pub(super) fn new() -> Self {
+        State {
+            timer: TimerState::BeginWaitingForNextPing,
+            ping_req_sent: None,
+            last_rtt: None,
+        }
+    }
+
     pub(super) fn poll(
         &mut self,
         packet: &mut Option<crate::proto::Packet>,
-        keep_alive: std::time::Duration,
+        ping_interval: std::time::Duration,
     ) -> futures::Poll<crate::proto::Packet, super::Error> {
         if let Some(crate::proto::Packet::PingResp) = packet {
             let _ = packet.take();
 
-            match self {
-                State::BeginWaitingForNextPing => (),
-                State::WaitingForNextPing(ping_timer) => {
-                    ping_timer.reset(deadline(std::time::Instant::now(), keep_alive))
+            if let Some(ping_req_sent) = self.ping_req_sent.take() {
+                let rtt = ping_req_sent.elapsed();
+                self.last_rtt = Some(rtt);
+
+                // The server disconnects the client if it doesn't hear from it within one and a half times the keep-alive,
+                // so a round-trip this slow leaves little room before pings start arriving too late
+                if rtt >= ping_interval / 2 {
+                    log::warn!(
+                        "ping round-trip time {:?} is close to the ping interval {:?}; consider raising the keep-alive",
+                        rtt,
+                        ping_interval,
+                    );
+                }
+            }
+
+            match &mut self.timer {
+                TimerState::BeginWaitingForNextPing => (),
+                TimerState::WaitingForNextPing(ping_timer) => {
+                    ping_timer.reset(std::time::Instant::now() + ping_interval)
                 }
             }
         }
@@ -25,17 +58,23 @@ impl State {
         loop {
             log::trace!("    {:?}", self);
 
-            match self {
-                State::BeginWaitingForNextPing => {
+            match &mut self.timer {
+                TimerState::BeginWaitingForNextPing => {
                     let ping_timer =
-                        tokio::timer::Delay::new(deadline(std::time::Instant::now(), keep_alive));
-                    *self = State::WaitingForNextPing(ping_timer);
+                        tokio::timer::Delay::new(std::time::Instant::now() + ping_interval);
+                    self.timer = TimerState::WaitingForNextPing(ping_timer);
                 }
 
-                State::WaitingForNextPing(ping_timer) => {
+                TimerState::WaitingForNextPing(ping_timer) => {
                     match ping_timer.poll().map_err(super::Error::PingTimer)? {
                         futures::Async::Ready(()) => {
-                            ping_timer.reset(deadline(ping_timer.deadline(), keep_alive));
+                            ping_timer.reset(ping_timer.deadline() + ping_interval);
+
+                            // If the previous PINGREQ is still unanswered, keep measuring from that one
+                            if self.ping_req_sent.is_none() {
+                                self.ping_req_sent = Some(std::time::Instant::now());
+                            }
+
                             return Ok(futures::Async::Ready(crate::proto::Packet::PingReq));
                         }
 
@@ -47,19 +86,20 @@ impl State {
     }
 
     pub(super) fn new_connection(&mut self) {
-        *self = State::BeginWaitingForNextPing;
+        self.timer = TimerState::BeginWaitingForNextPing;
+        self.ping_req_sent = None;
+    }
+
+    pub(super) fn last_rtt(&self) -> Option<std::time::Duration> {
+        self.last_rtt
     }
 }
 
 impl std::fmt::Debug for State {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            State::BeginWaitingForNextPing => f.write_str("BeginWaitingForNextPing"),
-            State::WaitingForNextPing { .. } => f.write_str("WaitingForNextPing"),
+        match self.timer {
+            TimerState::BeginWaitingForNextPing => f.write_str("BeginWaitingForNextPing"),
+            TimerState::WaitingForNextPing { .. } => f.write_str("WaitingForNextPing"),
         }
     }
 }
-
-fn deadline(now: std::time::Instant, keep_alive: std::time::Duration) -> std::time::Instant {
-    now + keep_alive / 2
-}
@@ -0,0 +1,133 @@
+impl super::Metrics {
+    /// Renders the metrics in the Prometheus text exposition format, eg to serve them from a `/metrics` endpoint
+    /// next to the application's own metrics.
+    ///
+    /// The metric names are stable:
+    ///
+    /// - `mqtt_reconnects_total`
+    /// - `mqtt_read_bytes_total` and `mqtt_written_bytes_total`. These are reset on every reconnect if
+    ///   [`crate::Client::set_reset_io_stats_on_reconnect`] is enabled, which Prometheus treats as a counter reset.
+    /// - `mqtt_publish_total`, with a `qos` label of `0`, `1` or `2`
+    /// - `mqtt_inflight_publications` and `mqtt_inflight_subscription_updates`
+    pub fn to_prometheus(&self) -> String {
+        let mut result = String::new();
+
+        write_metric(
+            &mut result,
+            "mqtt_reconnects_total",
+            "counter",
+            "The number of times the client reconnected to the server.",
+            &[("", self.reconnects)],
+        );
+        write_metric(
+            &mut result,
+            "mqtt_read_bytes_total",
+            "counter",
+            "The number of bytes read from the server.",
+            &[("", self.io_stats.bytes_read)],
+        );
+        write_metric(
+            &mut result,
+            "mqtt_written_bytes_total",
+            "counter",
+            "The number of bytes written to the server.",
+            &[("", self.io_stats.bytes_written)],
+        );
+        write_metric(
+            &mut result,
+            "mqtt_publish_total",
+            "counter",
+            "The number of publications sent to the server.",
+            &[
+                ("qos=\"0\"", self.publications_sent.at_most_once),
+                ("qos=\"1\"", self.publications_sent.at_least_once),
+                ("qos=\"2\"", self.publications_sent.exactly_once),
+            ],
+        );
+        write_metric(
+            &mut result,
+            "mqtt_inflight_publications",
+            "gauge",
+            "The number of QoS 1 and QoS 2 publications sent to the server and not acked yet.",
+            &[("", self.inflight_publications as u64)],
+        );
+        write_metric(
+            &mut result,
+            "mqtt_inflight_subscription_updates",
+            "gauge",
+            "The number of SUBSCRIBE and UNSUBSCRIBE packets sent to the server and not acked yet.",
+            &[("", self.inflight_subscription_updates as u64)],
+        );
+
+        result
+    }
+}
+
+/// Appends a metric with its HELP and TYPE lines, and one sample for each set of labels
+fn write_metric(
+    result: &mut String,
+    name: &str,
+    metric_type: &str,
+    help: &str,
+    samples: &[(&str, u64)],
+) {
+    use std::fmt::Write;
+
+    // Writing to a String can't fail
+    let _ = writeln!(result, "# HELP {} {}", name, help);
+    let _ = writeln!(result, "# TYPE {} {}", name, metric_type);
+    for (labels, value) in samples {
+        if labels.is_empty() {
+            let _ = writeln!(result, "{} {}", name, value);
+        } else {
+            let _ = writeln!(result, "{}{{{}}} {}", name, labels, value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn to_prometheus() {
+        let metrics = crate::Metrics {
+            reconnects: 2,
+            io_stats: crate::IoStats {
+                bytes_read: 100,
+                bytes_written: 200,
+            },
+            publications_sent: crate::PublicationCounts {
+                at_most_once: 3,
+                at_least_once: 4,
+                exactly_once: 5,
+            },
+            inflight_publications: 6,
+            inflight_subscription_updates: 7,
+        };
+
+        assert_eq!(
+            metrics.to_prometheus(),
+            "\
+# HELP mqtt_reconnects_total The number of times the client reconnected to the server.
+# TYPE mqtt_reconnects_total counter
+mqtt_reconnects_total 2
+# HELP mqtt_read_bytes_total The number of bytes read from the server.
+# TYPE mqtt_read_bytes_total counter
+mqtt_read_bytes_total 100
+# HELP mqtt_written_bytes_total The number of bytes written to the server.
+# TYPE mqtt_written_bytes_total counter
+mqtt_written_bytes_total 200
+# HELP mqtt_publish_total The number of publications sent to the server.
+# TYPE mqtt_publish_total counter
+mqtt_publish_total{qos=\"0\"} 3
+mqtt_publish_total{qos=\"1\"} 4
+mqtt_publish_total{qos=\"2\"} 5
+# HELP mqtt_inflight_publications The number of QoS 1 and QoS 2 publications sent to the server and not acked yet.
+# TYPE mqtt_inflight_publications gauge
+mqtt_inflight_publications 6
+# HELP mqtt_inflight_subscription_updates The number of SUBSCRIBE and UNSUBSCRIBE packets sent to the server and not acked yet.
+# TYPE mqtt_inflight_subscription_updates gauge
+mqtt_inflight_subscription_updates 7
+"
+        );
+    }
+}
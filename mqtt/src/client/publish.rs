@@ -10,7 +10,7 @@ pub(super) struct State {
     /// Holds PUBLISH packets sent by us, waiting for a corresponding PUBACK or PUBREC
     waiting_to_be_acked: std::collections::BTreeMap<
         crate::proto::PacketIdentifier,
-        (futures::sync::oneshot::Sender<()>, crate::proto::Packet),
+        (AckSender, crate::proto::Packet),
     >,
 
     /// Holds the identifiers of PUBREC packets sent by us, waiting for a corresponding PUBREL,
@@ -21,8 +21,26 @@ pub(super) struct State {
     /// Holds PUBLISH packets sent by us, waiting for a corresponding PUBCOMP
     waiting_to_be_completed: std::collections::BTreeMap<
         crate::proto::PacketIdentifier,
-        (futures::sync::oneshot::Sender<()>, crate::proto::Packet),
+        (AckSender, crate::proto::Packet),
     >,
+
+    /// The largest payload that will be accepted for publishing, shared with every [`PublishHandle`]
+    max_payload_len: MaxPayloadLen,
+
+    /// Remembers recently received AtLeastOnce PUBLISH packets so that redeliveries of them can be suppressed, if enabled
+    recently_received: Option<RecentlyReceived>,
+
+    /// Tracks keyed publish requests that have not been sent yet, so that they can be cancelled
+    keyed: std::sync::Arc<std::sync::Mutex<KeyedPublishRequests>>,
+
+    /// The number of publish requests that have been turned into PUBLISH packets
+    publications_sent: super::PublicationCounts,
+
+    /// Applied to the topic names of publish requests, shared with every [`PublishHandle`]
+    outbound_topic_transform: TopicTransform,
+
+    /// Applied to the topic names of received publications
+    inbound_topic_transform: TopicTransform,
 }
 
 impl State {
@@ -46,9 +64,9 @@ impl State {
                     Some((ack_sender, _)) => {
                         packet_identifiers.discard(packet_identifier);
 
-                        match ack_sender.send(()) {
+                        match ack_sender.send(Ok(())) {
 						Ok(()) => (),
-						Err(()) => log::debug!("could not send ack for publish request because ack receiver has been dropped"),
+						Err(_) => log::debug!("could not send ack for publish request because ack receiver has been dropped"),
 					}
                     }
                     None => log::warn!("ignoring PUBACK for a PUBLISH we never sent"),
@@ -60,9 +78,9 @@ impl State {
                     Some((ack_sender, _)) => {
                         packet_identifiers.discard(packet_identifier);
 
-                        match ack_sender.send(()) {
+                        match ack_sender.send(Ok(())) {
 						Ok(()) => (),
-						Err(()) => log::debug!("could not send ack for publish request because ack receiver has been dropped"),
+						Err(_) => log::debug!("could not send ack for publish request because ack receiver has been dropped"),
 					}
                     }
                     None => log::warn!("ignoring PUBCOMP for a PUBREL we never sent"),
@@ -86,13 +104,28 @@ impl State {
                 }
 
                 crate::proto::PacketIdentifierDupQoS::AtLeastOnce(packet_identifier, dup) => {
-                    publication_received = Some(crate::ReceivedPublication {
-                        topic_name,
-                        dup,
-                        qos: crate::proto::QoS::AtLeastOnce,
-                        retain,
-                        payload,
-                    });
+                    let is_redelivery = match &mut self.recently_received {
+                        Some(recently_received) => {
+                            recently_received.insert(packet_identifier, dup, &topic_name, &payload)
+                        }
+                        None => false,
+                    };
+
+                    if is_redelivery {
+                        // The server didn't receive our PUBACK for this PUBLISH. Ack it again but don't surface it to the application a second time.
+                        log::debug!(
+                            "suppressing redelivered PUBLISH with packet identifier {:?}",
+                            packet_identifier
+                        );
+                    } else {
+                        publication_received = Some(crate::ReceivedPublication {
+                            topic_name,
+                            dup,
+                            qos: crate::proto::QoS::AtLeastOnce,
+                            retain,
+                            payload,
+                        });
+                    }
 
                     packets_waiting_to_be_sent
                         .push(crate::proto::Packet::PubAck { packet_identifier });
@@ -154,20 +187,34 @@ impl State {
             other => *packet = other,
         }
 
-        while let futures::Async::Ready(Some(publish_request)) = self
-            .publish_request_recv
-            .poll()
-            .expect("Receiver::poll cannot fail")
-        {
-            self.publish_requests_waiting_to_be_sent
-                .push_back(publish_request);
+        if let Some(publication_received) = &mut publication_received {
+            publication_received.topic_name = self.inbound_topic_transform.apply(
+                std::mem::replace(&mut publication_received.topic_name, Default::default()),
+            );
         }
 
+        self.receive_publish_requests();
+
+        // Held while requests are turned into packets, so that a request can't be cancelled after it has been sent.
+        let mut keyed = self
+            .keyed
+            .lock()
+            .expect("keyed publish requests mutex poisoned");
+
         while let Some(PublishRequest {
             publication,
             ack_sender,
+            keyed_id,
+            packet_identifier_sender,
         }) = self.publish_requests_waiting_to_be_sent.pop_front()
         {
+            if let Some(keyed_id) = keyed_id {
+                if keyed.cancelled.remove(&keyed_id) {
+                    let _ = ack_sender.send(Err(PublishError::Cancelled));
+                    continue;
+                }
+            }
+
             match publication.qos {
                 crate::proto::QoS::AtMostOnce => {
                     packets_waiting_to_be_sent.push(crate::proto::Packet::Publish {
@@ -177,9 +224,15 @@ impl State {
                         payload: publication.payload,
                     });
 
-                    match ack_sender.send(()) {
+                    if let Some(packet_identifier_sender) = packet_identifier_sender {
+                        let _ = packet_identifier_sender.send(None);
+                    }
+
+                    self.publications_sent.at_most_once += 1;
+
+                    match ack_sender.send(Ok(())) {
 						Ok(()) => (),
-						Err(()) => log::debug!("could not send ack for publish request because ack receiver has been dropped"),
+						Err(_) => log::debug!("could not send ack for publish request because ack receiver has been dropped"),
 					}
                 }
 
@@ -191,11 +244,17 @@ impl State {
                                 .push_front(PublishRequest {
                                     publication,
                                     ack_sender,
+                                    keyed_id,
+                                    packet_identifier_sender,
                                 });
                             return Err(err);
                         }
                     };
 
+                    if let Some(packet_identifier_sender) = packet_identifier_sender {
+                        let _ = packet_identifier_sender.send(Some(packet_identifier));
+                    }
+
                     let packet = crate::proto::Packet::Publish {
                         packet_identifier_dup_qos:
                             crate::proto::PacketIdentifierDupQoS::AtLeastOnce(
@@ -225,6 +284,7 @@ impl State {
                     );
 
                     packets_waiting_to_be_sent.push(packet);
+                    self.publications_sent.at_least_once += 1;
                 }
 
                 crate::proto::QoS::ExactlyOnce => {
@@ -235,11 +295,17 @@ impl State {
                                 .push_front(PublishRequest {
                                     publication,
                                     ack_sender,
+                                    keyed_id,
+                                    packet_identifier_sender,
                                 });
                             return Err(err);
                         }
                     };
 
+                    if let Some(packet_identifier_sender) = packet_identifier_sender {
+                        let _ = packet_identifier_sender.send(Some(packet_identifier));
+                    }
+
                     let packet = crate::proto::Packet::Publish {
                         packet_identifier_dup_qos:
                             crate::proto::PacketIdentifierDupQoS::ExactlyOnce(
@@ -269,32 +335,49 @@ impl State {
                     );
 
                     packets_waiting_to_be_sent.push(packet);
+                    self.publications_sent.exactly_once += 1;
                 }
             }
+
+            if let Some(keyed_id) = keyed_id {
+                keyed.pending.remove(&keyed_id);
+            }
         }
 
         Ok((packets_waiting_to_be_sent, publication_received))
     }
 
+    /// Returns the packets to send on the new connection,
+    /// and how many of the publications sent on previous connections are being retransmitted in them.
     pub(super) fn new_connection<'a>(
         &'a mut self,
         reset_session: bool,
         packet_identifiers: &mut super::PacketIdentifiers,
-    ) -> impl Iterator<Item = crate::proto::Packet> + 'a {
+    ) -> (usize, impl Iterator<Item = crate::proto::Packet> + 'a) {
         if reset_session {
             // Move all waiting_to_be_completed back to waiting_to_be_acked since we must restart the ExactlyOnce protocol flow
             self.waiting_to_be_acked
                 .append(&mut self.waiting_to_be_completed);
 
-            // Clear waiting_to_be_released
+            // Clear waiting_to_be_released. None of these have been given to the application yet since that only happens on PUBREL,
+            // so if the server redelivers one of them anyway, it's still only surfaced once.
             for (packet_identifier, _) in
                 std::mem::replace(&mut self.waiting_to_be_released, Default::default())
             {
                 packet_identifiers.discard(packet_identifier);
             }
+
+            // The server will not redeliver publications from the previous session
+            if let Some(recently_received) = &mut self.recently_received {
+                recently_received.clear();
+            }
         }
 
-        self.waiting_to_be_acked
+        let retransmitted_publications =
+            self.waiting_to_be_acked.len() + self.waiting_to_be_completed.len();
+
+        let packets = self
+            .waiting_to_be_acked
             .values()
             .map(|(_, packet)| packet.clone())
             .chain(
@@ -306,30 +389,94 @@ impl State {
                 self.waiting_to_be_completed
                     .values()
                     .map(|(_, packet)| packet.clone()),
-            )
+            );
+
+        (retransmitted_publications, packets)
     }
 
     pub(super) fn publish(
         &mut self,
-        publication: crate::proto::Publication,
+        mut publication: crate::proto::Publication,
     ) -> impl Future<Item = (), Error = PublishError> {
+        publication.topic_name = self.outbound_topic_transform.apply(publication.topic_name);
+
+        if let Err(err) = check_payload_len(&publication, &self.max_payload_len) {
+            return futures::future::Either::A(futures::future::err(err));
+        }
+
         let (ack_sender, ack_receiver) = futures::sync::oneshot::channel();
         self.publish_requests_waiting_to_be_sent
             .push_back(PublishRequest {
                 publication,
                 ack_sender,
+                keyed_id: None,
+                packet_identifier_sender: None,
             });
-        ack_receiver.map_err(|_| PublishError::ClientDoesNotExist)
+        futures::future::Either::B(ack(ack_receiver))
     }
 
     pub(super) fn publish_handle(&self) -> PublishHandle {
-        PublishHandle(self.publish_request_send.clone())
+        PublishHandle {
+            sender: self.publish_request_send.clone(),
+            max_payload_len: self.max_payload_len.clone(),
+            keyed: self.keyed.clone(),
+            outbound_topic_transform: self.outbound_topic_transform.clone(),
+        }
+    }
+
+    pub(super) fn set_outbound_topic_transform(&mut self, transform: Option<TopicTransformFn>) {
+        self.outbound_topic_transform.set(transform);
+    }
+
+    pub(super) fn set_inbound_topic_transform(&mut self, transform: Option<TopicTransformFn>) {
+        self.inbound_topic_transform.set(transform);
+    }
+
+    pub(super) fn publications_sent(&self) -> super::PublicationCounts {
+        self.publications_sent
+    }
+
+    /// The number of QoS 1 and QoS 2 publications that have been sent but not completely acked by the server
+    pub(super) fn inflight_len(&self) -> usize {
+        self.waiting_to_be_acked.len() + self.waiting_to_be_completed.len()
+    }
+
+    /// Returns true if there are no publications waiting to be sent or waiting to be acked by the server.
+    ///
+    /// Publish requests that the handles have sent but that haven't been picked up yet count as waiting to be sent.
+    pub(super) fn is_drained(&mut self) -> bool {
+        self.receive_publish_requests();
+
+        self.publish_requests_waiting_to_be_sent.is_empty()
+            && self.waiting_to_be_acked.is_empty()
+            && self.waiting_to_be_completed.is_empty()
+    }
+
+    /// Moves the publish requests sent by the handles into the queue of requests waiting to be sent
+    fn receive_publish_requests(&mut self) {
+        while let futures::Async::Ready(Some(publish_request)) = self
+            .publish_request_recv
+            .poll()
+            .expect("Receiver::poll cannot fail")
+        {
+            self.publish_requests_waiting_to_be_sent
+                .push_back(publish_request);
+        }
+    }
+
+    pub(super) fn set_max_payload_len(&mut self, max_payload_len: Option<usize>) {
+        self.max_payload_len.set(max_payload_len);
+    }
+
+    pub(super) fn set_dedup_capacity(&mut self, capacity: Option<usize>) {
+        self.recently_received = capacity.map(RecentlyReceived::new);
     }
 }
 
-impl Default for State {
-    fn default() -> Self {
-        let (publish_request_send, publish_request_recv) = futures::sync::mpsc::channel(0);
+impl State {
+    pub(super) fn new(handle_channel_capacity: usize) -> Self {
+        let (publish_request_send, publish_request_recv) =
+            futures::sync::mpsc::channel(handle_channel_capacity);
 
         State {
             publish_request_send,
@@ -339,52 +486,408 @@ impl Default for State {
             waiting_to_be_acked: Default::default(),
             waiting_to_be_released: Default::default(),
             waiting_to_be_completed: Default::default(),
+
+            max_payload_len: Default::default(),
+
+            recently_received: None,
+
+            keyed: Default::default(),
+
+            publications_sent: Default::default(),
+
+            outbound_topic_transform: Default::default(),
+            inbound_topic_transform: Default::default(),
         }
     }
 }
 
+impl Drop for State {
+    fn drop(&mut self) {
+        // Keyed requests that are still queued will never be sent, so forget their keys.
+        // The handles may outlive the client, so they would otherwise keep them forever.
+        if let Ok(mut keyed) = self.keyed.lock() {
+            keyed.pending.clear();
+            keyed.cancelled.clear();
+        }
+    }
+}
+
+/// A bounded record of recently received AtLeastOnce PUBLISH packets, used to detect redeliveries.
+///
+/// Packets are identified by their packet identifier and a hash of their topic and payload,
+/// since the server is free to reuse a packet identifier for a different publication once it has received our PUBACK.
+#[derive(Debug)]
+struct RecentlyReceived {
+    capacity: usize,
+    entries: std::collections::VecDeque<(crate::proto::PacketIdentifier, u64)>,
+}
+
+impl RecentlyReceived {
+    fn new(capacity: usize) -> Self {
+        RecentlyReceived {
+            capacity,
+            entries: std::collections::VecDeque::with_capacity(capacity),
+        }
+    }
+
+    /// Records the given PUBLISH packet. Returns true if it is a redelivery of a recently received packet.
+    fn insert(
+        &mut self,
+        packet_identifier: crate::proto::PacketIdentifier,
+        dup: bool,
+        topic_name: &str,
+        payload: &[u8],
+    ) -> bool {
+        use std::hash::{Hash, Hasher};
+
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        topic_name.hash(&mut hasher);
+        payload.hash(&mut hasher);
+        let hash = hasher.finish();
+
+        let existing = self
+            .entries
+            .iter()
+            .position(|&(existing_packet_identifier, _)| {
+                existing_packet_identifier == packet_identifier
+            })
+            .and_then(|index| self.entries.remove(index));
+
+        // Only packets marked as duplicates can be redeliveries. A packet that isn't marked as a duplicate
+        // reuses the packet identifier for a new publication.
+        let is_redelivery = dup && existing == Some((packet_identifier, hash));
+
+        if self.capacity > 0 {
+            if self.entries.len() == self.capacity {
+                let _ = self.entries.pop_front();
+            }
+            self.entries.push_back((packet_identifier, hash));
+        }
+
+        is_redelivery
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
 /// Used to publish messages to the server
-pub struct PublishHandle(futures::sync::mpsc::Sender<PublishRequest>);
+pub struct PublishHandle {
+    sender: futures::sync::mpsc::Sender<PublishRequest>,
+    max_payload_len: MaxPayloadLen,
+    keyed: std::sync::Arc<std::sync::Mutex<KeyedPublishRequests>>,
+    outbound_topic_transform: TopicTransform,
+}
 
 impl PublishHandle {
     /// Publish the given message to the server
+    ///
+    /// Fails immediately with [`PublishError::PayloadTooLarge`] if the payload is larger than the client's maximum payload length,
+    /// or too large to fit in a single PUBLISH packet. See [`crate::proto::Publication::max_payload_len`].
     pub fn publish(
         &mut self,
         publication: crate::proto::Publication,
     ) -> impl Future<Item = (), Error = PublishError> {
-        let (ack_sender, ack_receiver) = futures::sync::oneshot::channel();
+        self.publish_inner(publication, None)
+    }
 
-        self.0
-            .clone()
-            .send(PublishRequest {
-                publication,
-                ack_sender,
-            })
-            .then(|result| match result {
-                Ok(_) => Ok(ack_receiver.map_err(|_| PublishError::ClientDoesNotExist)),
-                Err(_) => Err(PublishError::ClientDoesNotExist),
+    /// Publish the given message to the server, and learn the packet identifier that the client assigns to its PUBLISH packet,
+    /// eg to correlate the publication with the server's logs.
+    ///
+    /// The returned future resolves once the client has turned the publication into a PUBLISH packet, to the packet identifier
+    /// and a future that resolves like the one returned by [`PublishHandle::publish`]. The packet identifier is `None`
+    /// for AtMostOnce publications, since their PUBLISH packets don't have one. AtLeastOnce and ExactlyOnce publications keep
+    /// their identifier if they're retransmitted on a new connection, but the client reuses it for other packets once the publication
+    /// has been acked.
+    pub fn publish_with_packet_identifier(
+        &mut self,
+        publication: crate::proto::Publication,
+    ) -> impl Future<
+        Item = (
+            Option<crate::proto::PacketIdentifier>,
+            impl Future<Item = (), Error = PublishError>,
+        ),
+        Error = PublishError,
+    > {
+        let (packet_identifier_sender, packet_identifier_receiver) =
+            futures::sync::oneshot::channel();
+
+        self.send_request(publication, None, Some(packet_identifier_sender))
+            .and_then(move |ack_receiver| {
+                packet_identifier_receiver.then(move |result| match result {
+                    Ok(packet_identifier) => futures::future::Either::A(futures::future::ok((
+                        packet_identifier,
+                        ack(ack_receiver),
+                    ))),
+
+                    // The request was dropped without being sent, so its ack has the reason why
+                    Err(_) => futures::future::Either::B(ack(ack_receiver).and_then(|()| {
+                        Err::<(Option<crate::proto::PacketIdentifier>, _), _>(
+                            PublishError::ClientDoesNotExist,
+                        )
+                    })),
+                })
             })
-            .flatten()
+    }
+
+    /// Publish the given message to the server, and associate it with the given key so that it can be cancelled
+    /// with [`PublishHandle::cancel`] until it is sent.
+    ///
+    /// The returned future fails with [`PublishError::Cancelled`] if the publication is cancelled.
+    pub fn publish_keyed(
+        &mut self,
+        key: String,
+        publication: crate::proto::Publication,
+    ) -> impl Future<Item = (), Error = PublishError> {
+        let keyed_id = {
+            let mut keyed = self
+                .keyed
+                .lock()
+                .expect("keyed publish requests mutex poisoned");
+            let keyed_id = keyed.next_id;
+            keyed.next_id += 1;
+            keyed.pending.insert(keyed_id, key);
+            keyed_id
+        };
+
+        self.publish_inner(publication, Some(keyed_id))
+    }
+
+    /// Cancels all publications queued with [`PublishHandle::publish_keyed`] with the given key that have not been sent yet.
+    /// Returns the number of publications that were cancelled.
+    ///
+    /// A publication counts as sent, and can no longer be cancelled, once the client has turned it into a PUBLISH packet
+    /// to be written to the server. For AtLeastOnce and ExactlyOnce publications this is also when a packet identifier is reserved for it,
+    /// after which the client is committed to delivering it, including retransmitting it on a new connection.
+    /// Publications are only turned into packets while the client is connected, so publications queued while the client is disconnected
+    /// can be cancelled until it reconnects.
+    ///
+    /// The futures of cancelled publications fail with [`PublishError::Cancelled`] the next time the client processes its queue of publications.
+    pub fn cancel(&self, key: &str) -> usize {
+        let mut keyed = self
+            .keyed
+            .lock()
+            .expect("keyed publish requests mutex poisoned");
+        let keyed = &mut *keyed;
+
+        let cancelled: Vec<_> = keyed
+            .pending
+            .iter()
+            .filter(|(_, pending_key)| *pending_key == key)
+            .map(|(&keyed_id, _)| keyed_id)
+            .collect();
+        for keyed_id in &cancelled {
+            keyed.pending.remove(keyed_id);
+            keyed.cancelled.insert(*keyed_id);
+        }
+
+        cancelled.len()
+    }
+
+    fn publish_inner(
+        &mut self,
+        publication: crate::proto::Publication,
+        keyed_id: Option<u64>,
+    ) -> impl Future<Item = (), Error = PublishError> {
+        self.send_request(publication, keyed_id, None).and_then(ack)
+    }
+
+    /// Sends the request to the client, and resolves to the receiver of its ack
+    fn send_request(
+        &mut self,
+        mut publication: crate::proto::Publication,
+        keyed_id: Option<u64>,
+        packet_identifier_sender: Option<PacketIdentifierSender>,
+    ) -> impl Future<Item = AckReceiver, Error = PublishError> {
+        publication.topic_name = self.outbound_topic_transform.apply(publication.topic_name);
+
+        // Forgets the key if the request doesn't reach the client, since the client won't do it then
+        let keyed_guard = KeyedGuard(keyed_id.map(|keyed_id| (self.keyed.clone(), keyed_id)));
+
+        if let Err(err) = check_payload_len(&publication, &self.max_payload_len) {
+            return futures::future::Either::A(futures::future::err(err));
+        }
+
+        let (ack_sender, ack_receiver) = futures::sync::oneshot::channel();
+
+        futures::future::Either::B(
+            self.sender
+                .clone()
+                .send(PublishRequest {
+                    publication,
+                    ack_sender,
+                    keyed_id,
+                    packet_identifier_sender,
+                })
+                .then(move |result| match result {
+                    Ok(_) => {
+                        keyed_guard.disarm();
+                        Ok(ack_receiver)
+                    }
+                    Err(_) => Err(PublishError::ClientDoesNotExist),
+                }),
+        )
     }
 }
 
+/// A callback set with [`crate::Client::set_outbound_topic_transform`] or [`crate::Client::set_inbound_topic_transform`]
+pub(super) type TopicTransformFn =
+    Box<dyn for<'a> FnMut(&'a str) -> std::borrow::Cow<'a, str> + Send>;
+
+/// A [`TopicTransformFn`], if any, shared by the client and its handles so that it can be changed after the handles were created
+#[derive(Clone, Default)]
+struct TopicTransform(std::sync::Arc<std::sync::Mutex<Option<TopicTransformFn>>>);
+
+impl TopicTransform {
+    fn set(&self, transform: Option<TopicTransformFn>) {
+        if let Ok(mut current) = self.0.lock() {
+            *current = transform;
+        }
+    }
+
+    fn apply(&self, topic_name: String) -> String {
+        if let Ok(mut current) = self.0.lock() {
+            if let Some(transform) = &mut *current {
+                return transform(&topic_name).into_owned();
+            }
+        }
+
+        topic_name
+    }
+}
+
+impl std::fmt::Debug for TopicTransform {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let is_set = self
+            .0
+            .lock()
+            .map(|current| current.is_some())
+            .unwrap_or(false);
+        f.debug_tuple("TopicTransform").field(&is_set).finish()
+    }
+}
+
+/// The largest payload that will be accepted for publishing, shared by the client and its handles so that it can be changed
+/// after the handles were created. `usize::MAX` means there's no limit other than what fits in a single PUBLISH packet.
+#[derive(Clone, Debug)]
+struct MaxPayloadLen(std::sync::Arc<std::sync::atomic::AtomicUsize>);
+
+impl MaxPayloadLen {
+    fn get(&self) -> usize {
+        self.0.load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    fn set(&self, max_payload_len: Option<usize>) {
+        self.0.store(
+            max_payload_len.unwrap_or(usize::MAX),
+            std::sync::atomic::Ordering::Relaxed,
+        );
+    }
+}
+
+impl Default for MaxPayloadLen {
+    fn default() -> Self {
+        MaxPayloadLen(std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(
+            usize::MAX,
+        )))
+    }
+}
+
+/// Removes the key of a request queued with [`PublishHandle::publish_keyed`] when it's dropped, unless it's disarmed
+/// because the request was handed to the client, which then removes the key itself.
+struct KeyedGuard(Option<(std::sync::Arc<std::sync::Mutex<KeyedPublishRequests>>, u64)>);
+
+impl KeyedGuard {
+    fn disarm(mut self) {
+        self.0 = None;
+    }
+}
+
+impl Drop for KeyedGuard {
+    fn drop(&mut self) {
+        if let Some((keyed, keyed_id)) = self.0.take() {
+            if let Ok(mut keyed) = keyed.lock() {
+                keyed.pending.remove(&keyed_id);
+                keyed.cancelled.remove(&keyed_id);
+            }
+        }
+    }
+}
+
+/// Publish requests queued with [`PublishHandle::publish_keyed`], identified by a unique ID
+#[derive(Debug, Default)]
+struct KeyedPublishRequests {
+    next_id: u64,
+
+    /// The keys of requests that have not been sent or cancelled yet
+    pending: std::collections::BTreeMap<u64, String>,
+
+    /// Requests that were cancelled but are still queued, to be dropped instead of sent
+    cancelled: std::collections::BTreeSet<u64>,
+}
+
 #[derive(Debug)]
 pub enum PublishError {
+    Cancelled,
     ClientDoesNotExist,
+    PayloadTooLarge { len: usize, max: usize },
 }
 
 impl std::fmt::Display for PublishError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
+            PublishError::Cancelled => write!(f, "publication was cancelled"),
             PublishError::ClientDoesNotExist => write!(f, "client does not exist"),
+            PublishError::PayloadTooLarge { len, max } => write!(
+                f,
+                "payload of length {} is larger than the maximum of {}",
+                len, max
+            ),
         }
     }
 }
 
 impl std::error::Error for PublishError {}
 
+fn check_payload_len(
+    publication: &crate::proto::Publication,
+    max_payload_len: &MaxPayloadLen,
+) -> Result<(), PublishError> {
+    // The payload must also fit in a single PUBLISH packet, otherwise encoding it would fail deep inside the client
+    let max = std::cmp::min(max_payload_len.get(), publication.max_payload_len());
+
+    if publication.payload.len() > max {
+        Err(PublishError::PayloadTooLarge {
+            len: publication.payload.len(),
+            max,
+        })
+    } else {
+        Ok(())
+    }
+}
+
+type AckSender = futures::sync::oneshot::Sender<Result<(), PublishError>>;
+
+type AckReceiver = futures::sync::oneshot::Receiver<Result<(), PublishError>>;
+
+type PacketIdentifierSender =
+    futures::sync::oneshot::Sender<Option<crate::proto::PacketIdentifier>>;
+
+fn ack(ack_receiver: AckReceiver) -> impl Future<Item = (), Error = PublishError> {
+    ack_receiver.then(|result| match result {
+        Ok(result) => result,
+        Err(_) => Err(PublishError::ClientDoesNotExist),
+    })
+}
+
 #[derive(Debug)]
 struct PublishRequest {
     publication: crate::proto::Publication,
-    ack_sender: futures::sync::oneshot::Sender<()>,
+    ack_sender: AckSender,
+    keyed_id: Option<u64>,
+
+    /// Told the packet identifier of the publication once it's turned into a PUBLISH packet,
+    /// if requested with [`PublishHandle::publish_with_packet_identifier`]
+    packet_identifier_sender: Option<PacketIdentifierSender>,
 }
@@ -4,12 +4,28 @@ use futures::{Future, Sink, Stream};
 pub(super) struct State {
     subscriptions: std::collections::HashMap<String, crate::proto::QoS>,
 
-    subscriptions_updated_send: futures::sync::mpsc::Sender<SubscriptionUpdate>,
-    subscriptions_updated_recv: futures::sync::mpsc::Receiver<SubscriptionUpdate>,
+    /// Topic filters that are resubscribed before others when the session is reset. Topic filters not in this map have priority 0.
+    priorities: std::collections::HashMap<String, u8>,
+
+    /// Each item is a group of updates sent from an [`UpdateSubscriptionHandle`] that must be batched together
+    subscriptions_updated_send: futures::sync::mpsc::Sender<Vec<SubscriptionUpdate>>,
+    subscriptions_updated_recv: futures::sync::mpsc::Receiver<Vec<SubscriptionUpdate>>,
 
     subscription_updates_waiting_to_be_sent: std::collections::VecDeque<SubscriptionUpdate>,
     subscription_updates_waiting_to_be_acked:
         std::collections::VecDeque<(crate::proto::PacketIdentifier, BatchedSubscriptionUpdate)>,
+
+    ack_timeout: Option<std::time::Duration>,
+
+    /// Times out the ack of the update at the front of `subscription_updates_waiting_to_be_acked`
+    ack_timer: Option<(crate::proto::PacketIdentifier, tokio::timer::Delay)>,
+
+    /// Set when the ack timer has fired, so that the next poll fails and the client reconnects
+    /// after the [`SubscriptionUpdate::AckTimeout`] has been returned to the user.
+    ack_timed_out: bool,
+
+    /// Set by a new connection, and cleared once every subscription update pending on that connection has been acked
+    syncing: bool,
 }
 
 impl State {
@@ -18,6 +34,11 @@ impl State {
         packet: &mut Option<crate::proto::Packet>,
         packet_identifiers: &mut super::PacketIdentifiers,
     ) -> Result<(Vec<crate::proto::Packet>, Vec<SubscriptionUpdate>), super::Error> {
+        if self.ack_timed_out {
+            self.ack_timed_out = false;
+            return Err(super::Error::SubscriptionAckTimedOut);
+        }
+
         let mut subscription_updates = vec![];
 
         match packet.take() {
@@ -191,13 +212,13 @@ impl State {
             other => *packet = other,
         }
 
-        while let futures::Async::Ready(Some(subscription_to_update)) = self
+        while let futures::Async::Ready(Some(subscriptions_to_update)) = self
             .subscriptions_updated_recv
             .poll()
             .expect("Receiver::poll cannot fail")
         {
             self.subscription_updates_waiting_to_be_sent
-                .push_back(subscription_to_update);
+                .extend(subscriptions_to_update);
         }
 
         let mut packets_waiting_to_be_sent = vec![];
@@ -227,6 +248,8 @@ impl State {
                     SubscriptionUpdate::Unsubscribe(unsubscribe_from) => {
                         target_subscriptions.remove(&unsubscribe_from)
                     }
+                    // Only ever emitted by the client, so there's nothing to send to the server
+                    SubscriptionUpdate::AckTimeout(_) => None,
                 };
             }
 
@@ -314,14 +337,87 @@ impl State {
             }
         }
 
+        if let Some(topic_filters) = self.poll_ack_timer()? {
+            log::warn!(
+                "Timed out waiting for the server to ack subscription updates for {:?}",
+                topic_filters
+            );
+            subscription_updates.push(SubscriptionUpdate::AckTimeout(topic_filters));
+            self.ack_timed_out = true;
+        }
+
         Ok((packets_waiting_to_be_sent, subscription_updates))
     }
 
+    /// Returns `true` once per connection, after every subscription update pending on the connection has been acked
+    /// and the client has at least one subscription.
+    ///
+    /// If the client has no subscriptions yet, this keeps waiting, so that subscriptions made later in the connection are still synced.
+    ///
+    /// Must be called after [`State::poll`], so that updates requested through an [`UpdateSubscriptionHandle`] have been queued.
+    pub(super) fn poll_synced(&mut self) -> bool {
+        if !self.syncing
+            || !self.subscription_updates_waiting_to_be_sent.is_empty()
+            || !self.subscription_updates_waiting_to_be_acked.is_empty()
+            || self.subscriptions.is_empty()
+        {
+            return false;
+        }
+
+        self.syncing = false;
+        true
+    }
+
+    /// Returns the topic filters of the update at the front of `subscription_updates_waiting_to_be_acked`
+    /// if it has been waiting for its ack for longer than the ack timeout.
+    fn poll_ack_timer(&mut self) -> Result<Option<Vec<String>>, super::Error> {
+        let (ack_timeout, (packet_identifier, subscription_update)) = match (
+            self.ack_timeout,
+            self.subscription_updates_waiting_to_be_acked.front(),
+        ) {
+            (Some(ack_timeout), Some(front)) => (ack_timeout, front),
+            _ => {
+                self.ack_timer = None;
+                return Ok(None);
+            }
+        };
+
+        // Start the timer when an update reaches the front of the queue, ie when the server should ack it next
+        let is_waiting_for_front = match &self.ack_timer {
+            Some((timer_packet_identifier, _)) => timer_packet_identifier == packet_identifier,
+            None => false,
+        };
+        if !is_waiting_for_front {
+            self.ack_timer = Some((
+                *packet_identifier,
+                tokio::timer::Delay::new(std::time::Instant::now() + ack_timeout),
+            ));
+        }
+        let (_, ack_timer) = self.ack_timer.as_mut().expect("ack timer was just set");
+
+        match ack_timer
+            .poll()
+            .map_err(super::Error::SubscriptionAckTimer)?
+        {
+            futures::Async::Ready(()) => {
+                let topic_filters = subscription_update.topic_filters();
+                self.ack_timer = None;
+                Ok(Some(topic_filters))
+            }
+
+            futures::Async::NotReady => Ok(None),
+        }
+    }
+
     pub(super) fn new_connection(
         &mut self,
         reset_session: bool,
         packet_identifiers: &mut super::PacketIdentifiers,
     ) -> impl Iterator<Item = crate::proto::Packet> {
+        self.ack_timer = None;
+        self.ack_timed_out = false;
+        self.syncing = true;
+
         if reset_session {
             let mut subscriptions = std::mem::replace(&mut self.subscriptions, Default::default());
             let subscription_updates_waiting_to_be_acked = std::mem::replace(
@@ -350,18 +446,30 @@ impl State {
                 }
             }
 
-            // Generate a SUBSCRIBE packet for the final set of subscriptions
-            let mut subscriptions_waiting_to_be_acked: Vec<_> = subscriptions
-                .into_iter()
-                .map(|(topic_filter, qos)| crate::proto::SubscribeTo { topic_filter, qos })
-                .collect();
-            subscriptions_waiting_to_be_acked.sort_by(|subscribe_to1, subscribe_to2| {
-                subscribe_to1.topic_filter.cmp(&subscribe_to2.topic_filter)
-            });
+            // Generate a SUBSCRIBE packet for the final set of subscriptions. Subscriptions with different priorities go in separate packets, highest priority first
+            let mut subscriptions_by_priority: std::collections::BTreeMap<
+                std::cmp::Reverse<u8>,
+                Vec<crate::proto::SubscribeTo>,
+            > = Default::default();
+            for (topic_filter, qos) in subscriptions {
+                let priority = self
+                    .priorities
+                    .get(&topic_filter)
+                    .cloned()
+                    .unwrap_or_default();
+                subscriptions_by_priority
+                    .entry(std::cmp::Reverse(priority))
+                    .or_default()
+                    .push(crate::proto::SubscribeTo { topic_filter, qos });
+            }
+
+            let mut packets = vec![];
+
+            for (_, mut subscriptions_waiting_to_be_acked) in subscriptions_by_priority {
+                subscriptions_waiting_to_be_acked.sort_by(|subscribe_to1, subscribe_to2| {
+                    subscribe_to1.topic_filter.cmp(&subscribe_to2.topic_filter)
+                });
 
-            if subscriptions_waiting_to_be_acked.is_empty() {
-                NewConnectionIter::Empty
-            } else {
                 let packet_identifier = packet_identifiers
                     .reserve()
                     .expect("reset session should have available packet identifiers");
@@ -370,11 +478,13 @@ impl State {
                     BatchedSubscriptionUpdate::Subscribe(subscriptions_waiting_to_be_acked.clone()),
                 ));
 
-                NewConnectionIter::Single(std::iter::once(crate::proto::Packet::Subscribe {
+                packets.push(crate::proto::Packet::Subscribe {
                     packet_identifier,
                     subscribe_to: subscriptions_waiting_to_be_acked,
-                }))
+                });
             }
+
+            packets.into_iter()
         } else {
             // Re-create all pending (ie unacked) changes to the set of subscriptions
             let unacked_packets: Vec<_> = self
@@ -399,10 +509,64 @@ impl State {
                 )
                 .collect();
 
-            NewConnectionIter::Multiple(unacked_packets.into_iter())
+            unacked_packets.into_iter()
+        }
+    }
+
+    /// The number of SUBSCRIBE and UNSUBSCRIBE packets that have been sent but not acked by the server
+    pub(super) fn inflight_len(&self) -> usize {
+        self.subscription_updates_waiting_to_be_acked.len()
+    }
+
+    /// The highest QoS granted for any subscription whose topic filter matches the given topic name,
+    /// or `None` if there are no such subscriptions.
+    pub(super) fn granted_qos(&self, topic_name: &str) -> Option<crate::proto::QoS> {
+        self.subscriptions
+            .iter()
+            .filter(|(topic_filter, _)| {
+                crate::proto::topic_filter_matches(topic_filter, topic_name)
+            })
+            .map(|(_, qos)| *qos)
+            .max()
+    }
+
+    /// The subscription updates that have been sent to the server but not acked yet, in the order they were sent
+    pub(super) fn unacked_subscription_updates(&self) -> Vec<SubscriptionUpdate> {
+        let mut result = vec![];
+
+        for (_, subscription_update) in &self.subscription_updates_waiting_to_be_acked {
+            match subscription_update {
+                BatchedSubscriptionUpdate::Subscribe(subscribe_to) => result.extend(
+                    subscribe_to
+                        .iter()
+                        .cloned()
+                        .map(SubscriptionUpdate::Subscribe),
+                ),
+                BatchedSubscriptionUpdate::Unsubscribe(unsubscribe_from) => result.extend(
+                    unsubscribe_from
+                        .iter()
+                        .cloned()
+                        .map(SubscriptionUpdate::Unsubscribe),
+                ),
+            }
+        }
+
+        result
+    }
+
+    pub(super) fn set_priority(&mut self, topic_filter: String, priority: u8) {
+        if priority == 0 {
+            self.priorities.remove(&topic_filter);
+        } else {
+            self.priorities.insert(topic_filter, priority);
         }
     }
 
+    pub(super) fn set_ack_timeout(&mut self, ack_timeout: Option<std::time::Duration>) {
+        self.ack_timeout = ack_timeout;
+        self.ack_timer = None;
+    }
+
     pub(super) fn update_subscription(&mut self, subscription_update: SubscriptionUpdate) {
         self.subscription_updates_waiting_to_be_sent
             .push_back(subscription_update);
@@ -413,19 +577,26 @@ impl State {
     }
 }
 
-impl Default for State {
-    fn default() -> Self {
+impl State {
+    pub(super) fn new(handle_channel_capacity: usize) -> Self {
         let (subscriptions_updated_send, subscriptions_updated_recv) =
-            futures::sync::mpsc::channel(0);
+            futures::sync::mpsc::channel(handle_channel_capacity);
 
         State {
             subscriptions: Default::default(),
 
+            priorities: Default::default(),
+
             subscriptions_updated_send,
             subscriptions_updated_recv,
 
             subscription_updates_waiting_to_be_sent: Default::default(),
             subscription_updates_waiting_to_be_acked: Default::default(),
+
+            ack_timeout: None,
+            ack_timer: None,
+            ack_timed_out: false,
+            syncing: false,
         }
     }
 }
@@ -435,6 +606,12 @@ impl Default for State {
 pub enum SubscriptionUpdate {
     Subscribe(crate::proto::SubscribeTo),
     Unsubscribe(String),
+
+    /// The server didn't ack the SUBSCRIBE or UNSUBSCRIBE for these topic filters within the timeout set with
+    /// [`crate::Client::set_subscription_ack_timeout`]. The client reconnects and sends the update again.
+    ///
+    /// This is only ever emitted by the client. It's ignored if requested through an [`UpdateSubscriptionHandle`].
+    AckTimeout(Vec<String>),
 }
 
 #[derive(Debug)]
@@ -443,27 +620,21 @@ enum BatchedSubscriptionUpdate {
     Unsubscribe(Vec<String>),
 }
 
-#[derive(Debug)]
-enum NewConnectionIter {
-    Empty,
-    Single(std::iter::Once<crate::proto::Packet>),
-    Multiple(std::vec::IntoIter<crate::proto::Packet>),
-}
-
-impl Iterator for NewConnectionIter {
-    type Item = crate::proto::Packet;
-
-    fn next(&mut self) -> Option<Self::Item> {
+impl BatchedSubscriptionUpdate {
+    fn topic_filters(&self) -> Vec<String> {
         match self {
-            NewConnectionIter::Empty => None,
-            NewConnectionIter::Single(packet) => packet.next(),
-            NewConnectionIter::Multiple(packets) => packets.next(),
+            BatchedSubscriptionUpdate::Subscribe(subscribe_to) => subscribe_to
+                .iter()
+                .map(|subscribe_to| subscribe_to.topic_filter.clone())
+                .collect(),
+            BatchedSubscriptionUpdate::Unsubscribe(unsubscribe_from) => unsubscribe_from.clone(),
         }
     }
 }
 
+#[derive(Debug)]
 /// Used to update subscriptions
-pub struct UpdateSubscriptionHandle(futures::sync::mpsc::Sender<SubscriptionUpdate>);
+pub struct UpdateSubscriptionHandle(futures::sync::mpsc::Sender<Vec<SubscriptionUpdate>>);
 
 impl UpdateSubscriptionHandle {
     /// Subscribe to a topic with the given parameters.
@@ -486,13 +657,25 @@ impl UpdateSubscriptionHandle {
         &mut self,
         subscribe_to: crate::proto::SubscribeTo,
     ) -> impl Future<Item = (), Error = UpdateSubscriptionError> {
-        self.0
-            .clone()
-            .send(SubscriptionUpdate::Subscribe(subscribe_to))
-            .then(|result| match result {
-                Ok(_) => Ok(()),
-                Err(_) => Err(UpdateSubscriptionError::ClientDoesNotExist),
-            })
+        self.update_subscriptions(vec![SubscriptionUpdate::Subscribe(subscribe_to)])
+    }
+
+    /// Subscribe to several topics at once.
+    ///
+    /// Unlike calling [`UpdateSubscriptionHandle::subscribe`] for each topic, this guarantees that the subscriptions are received by the client together,
+    /// so that those that aren't already in effect are sent to the server in a single SUBSCRIBE packet with a single packet identifier.
+    ///
+    /// Otherwise this behaves the same as [`UpdateSubscriptionHandle::subscribe`].
+    pub fn subscribe_many(
+        &mut self,
+        subscribe_to: Vec<crate::proto::SubscribeTo>,
+    ) -> impl Future<Item = (), Error = UpdateSubscriptionError> {
+        self.update_subscriptions(
+            subscribe_to
+                .into_iter()
+                .map(SubscriptionUpdate::Subscribe)
+                .collect(),
+        )
     }
 
     /// Unsubscribe from the given topic.
@@ -513,10 +696,17 @@ impl UpdateSubscriptionHandle {
     pub fn unsubscribe(
         &mut self,
         unsubscribe_from: String,
+    ) -> impl Future<Item = (), Error = UpdateSubscriptionError> {
+        self.update_subscriptions(vec![SubscriptionUpdate::Unsubscribe(unsubscribe_from)])
+    }
+
+    fn update_subscriptions(
+        &mut self,
+        subscription_updates: Vec<SubscriptionUpdate>,
     ) -> impl Future<Item = (), Error = UpdateSubscriptionError> {
         self.0
             .clone()
-            .send(SubscriptionUpdate::Unsubscribe(unsubscribe_from))
+            .send(subscription_updates)
             .then(|result| match result {
                 Ok(_) => Ok(()),
                 Err(_) => Err(UpdateSubscriptionError::ClientDoesNotExist),
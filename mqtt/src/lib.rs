@@ -1,5 +1,10 @@
 /*!
  * This crate contains an implementation of an MQTT client.
+ *
+ * The [`Client`] is a `Stream` that has to be polled by a single task, but the handles it hands out, like [`PublishHandle`]
+ * and [`ShutdownHandle`], are `Send` and `Sync`. They can be moved to and used from other threads and tasks, and each of
+ * them can request as many handles as it needs from the client, or clone the handles that implement `Clone`.
+ * Every handle talks to the client over its own channel sender, so handles used from different tasks don't wait on each other.
  */
 
 #![deny(rust_2018_idioms, warnings)]
@@ -17,9 +22,10 @@
 
 mod client;
 pub use self::client::{
-    Client, Error, Event, IoSource, PublishError, PublishHandle, ReceivedPublication,
-    ShutdownError, ShutdownHandle, SubscriptionUpdate, UpdateSubscriptionError,
-    UpdateSubscriptionHandle,
+    Client, ClientBuilder, ConnectError, ConnectHandle, CreateClientError, Direction, Error, Event,
+    ExponentialBackoff, FlushError, FlushHandle, IoSource, IoStats, Metrics, PublicationCounts,
+    PublishError, PublishHandle, ReceivedPublication, ReconnectPolicy, ShutdownError,
+    ShutdownHandle, SubscriptionUpdate, UpdateSubscriptionError, UpdateSubscriptionHandle,
 };
 
 mod logging_framed;
@@ -1,5 +1,5 @@
 #[derive(Debug)]
-pub(crate) struct LoggingFramed<T>(tokio::codec::Framed<T, crate::proto::PacketCodec>)
+pub(crate) struct LoggingFramed<T>(tokio::codec::Framed<CountingIo<T>, ObservedCodec>)
 where
     T: tokio::io::AsyncRead + tokio::io::AsyncWrite;
 
@@ -7,8 +7,22 @@ impl<T> LoggingFramed<T>
 where
     T: tokio::io::AsyncRead + tokio::io::AsyncWrite,
 {
-    pub(crate) fn new(io: T) -> Self {
-        LoggingFramed(tokio::codec::Framed::new(io, Default::default()))
+    /// Every byte read from and written to `io` is added to `counters`,
+    /// and the raw bytes of every packet are given to `packet_observer`, if it has an observer
+    pub(crate) fn new(
+        io: T,
+        codec: crate::proto::PacketCodec,
+        counters: std::sync::Arc<IoCounters>,
+        packet_observer: PacketObserver,
+    ) -> Self {
+        LoggingFramed(tokio::codec::Framed::new(
+            CountingIo { io, counters },
+            ObservedCodec {
+                codec,
+                packet_observer,
+                received: vec![],
+            },
+        ))
     }
 }
 
@@ -16,15 +30,15 @@ impl<T> futures::Sink for LoggingFramed<T>
 where
     T: tokio::io::AsyncRead + tokio::io::AsyncWrite,
 {
-    type SinkItem = <tokio::codec::Framed<T, crate::proto::PacketCodec> as futures::Sink>::SinkItem;
+    type SinkItem = <tokio::codec::Framed<CountingIo<T>, ObservedCodec> as futures::Sink>::SinkItem;
     type SinkError =
-        <tokio::codec::Framed<T, crate::proto::PacketCodec> as futures::Sink>::SinkError;
+        <tokio::codec::Framed<CountingIo<T>, ObservedCodec> as futures::Sink>::SinkError;
 
     fn start_send(
         &mut self,
         item: Self::SinkItem,
     ) -> futures::StartSend<Self::SinkItem, Self::SinkError> {
-        log::trace!(">>> {:?}", item);
+        log::trace!(">>> {}", item.summary());
         self.0.start_send(item)
     }
 
@@ -37,14 +51,372 @@ impl<T> futures::Stream for LoggingFramed<T>
 where
     T: tokio::io::AsyncRead + tokio::io::AsyncWrite,
 {
-    type Item = <tokio::codec::Framed<T, crate::proto::PacketCodec> as futures::Stream>::Item;
-    type Error = <tokio::codec::Framed<T, crate::proto::PacketCodec> as futures::Stream>::Error;
+    type Item = <tokio::codec::Framed<CountingIo<T>, ObservedCodec> as futures::Stream>::Item;
+    type Error = <tokio::codec::Framed<CountingIo<T>, ObservedCodec> as futures::Stream>::Error;
 
     fn poll(&mut self) -> futures::Poll<Option<Self::Item>, Self::Error> {
         let result = self.0.poll()?;
         if let futures::Async::Ready(Some(item)) = &result {
-            log::trace!("<<< {:?}", item);
+            log::trace!("<<< {}", item.summary());
         }
         Ok(result)
     }
 }
+
+/// The callback set with [`crate::Client::set_packet_observer`], shared by the client and its current connection
+/// so that it can be changed while connected
+#[derive(Clone, Default)]
+pub(crate) struct PacketObserver(
+    std::sync::Arc<std::sync::Mutex<Option<Box<dyn FnMut(crate::Direction, &[u8]) + Send>>>>,
+);
+
+impl PacketObserver {
+    pub(crate) fn set(&self, observer: Option<Box<dyn FnMut(crate::Direction, &[u8]) + Send>>) {
+        if let Ok(mut current) = self.0.lock() {
+            *current = observer;
+        }
+    }
+
+    fn is_set(&self) -> bool {
+        self.0
+            .lock()
+            .map(|current| current.is_some())
+            .unwrap_or(false)
+    }
+
+    fn observe(&self, direction: crate::Direction, packet: &[u8]) {
+        if let Ok(mut current) = self.0.lock() {
+            if let Some(observer) = &mut *current {
+                observer(direction, packet);
+            }
+        }
+    }
+}
+
+impl std::fmt::Debug for PacketObserver {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("PacketObserver")
+            .field(&self.is_set())
+            .finish()
+    }
+}
+
+/// Wraps a [`crate::proto::PacketCodec`] to give the raw bytes of every packet it encodes and decodes to a [`PacketObserver`]
+#[derive(Debug)]
+pub(crate) struct ObservedCodec {
+    codec: crate::proto::PacketCodec,
+    packet_observer: PacketObserver,
+
+    /// The bytes of the packet being decoded. The codec consumes the fixed header of a packet before the rest of it has arrived,
+    /// so a packet can span several calls to `decode`.
+    received: Vec<u8>,
+}
+
+impl tokio::codec::Decoder for ObservedCodec {
+    type Item = crate::proto::Packet;
+    type Error = crate::proto::DecodeError;
+
+    fn decode(&mut self, src: &mut bytes::BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if !self.packet_observer.is_set() {
+            self.received.clear();
+            return self.codec.decode(src);
+        }
+
+        // The codec only consumes bytes from the front of src, so the consumed bytes are a prefix of the original src.
+        // Only the part of that prefix that the codec can consume in this call is kept, rather than all of src.
+        let len_before = src.len();
+        let received_before = self.received.len();
+        let max_consumed = std::cmp::min(len_before, max_decode_len(&self.received, src));
+        self.received.extend_from_slice(&src[..max_consumed]);
+
+        let packet = self.codec.decode(src);
+        self.received
+            .truncate(received_before + (len_before - src.len()));
+        let packet = packet?;
+
+        if packet.is_some() {
+            self.packet_observer
+                .observe(crate::Direction::Received, &self.received);
+            self.received.clear();
+        }
+
+        Ok(packet)
+    }
+}
+
+/// The most bytes that the packet codec can consume from `src` in one call to `decode`,
+/// given the bytes of the current packet that it has already consumed in `received`.
+///
+/// The codec consumes the fixed header as it arrives, but the rest of the packet only once all of it has arrived.
+fn max_decode_len(received: &[u8], src: &[u8]) -> usize {
+    // The first byte, and at most four bytes of remaining length
+    const MAX_FIXED_HEADER_LEN: usize = 5;
+
+    let mut remaining_length = 0;
+    for (i, &encoded_byte) in received
+        .iter()
+        .chain(src)
+        .enumerate()
+        .take(MAX_FIXED_HEADER_LEN)
+        .skip(1)
+    {
+        remaining_length |= usize::from(encoded_byte & 0x7F) << ((i - 1) * 7);
+        if encoded_byte & 0x80 == 0 {
+            let fixed_header_len = i + 1;
+            let packet_len = fixed_header_len + remaining_length;
+            let available_len = received.len() + src.len();
+            let max_len = if available_len >= packet_len {
+                packet_len
+            } else {
+                fixed_header_len
+            };
+            return max_len.saturating_sub(received.len());
+        }
+    }
+
+    MAX_FIXED_HEADER_LEN.saturating_sub(received.len())
+}
+
+impl tokio::codec::Encoder for ObservedCodec {
+    type Item = crate::proto::Packet;
+    type Error = crate::proto::EncodeError;
+
+    fn encode(&mut self, item: Self::Item, dst: &mut bytes::BytesMut) -> Result<(), Self::Error> {
+        let start = dst.len();
+        self.codec.encode(item, dst)?;
+        self.packet_observer
+            .observe(crate::Direction::Sent, &dst[start..]);
+        Ok(())
+    }
+}
+
+/// Running totals of the bytes read from and written to a connection.
+///
+/// They're only updated with relaxed atomic adds, so they cost next to nothing if they're never read.
+#[derive(Debug, Default)]
+pub(crate) struct IoCounters {
+    bytes_read: std::sync::atomic::AtomicU64,
+    bytes_written: std::sync::atomic::AtomicU64,
+}
+
+impl IoCounters {
+    pub(crate) fn get(&self) -> crate::IoStats {
+        crate::IoStats {
+            bytes_read: self.bytes_read.load(std::sync::atomic::Ordering::Relaxed),
+            bytes_written: self
+                .bytes_written
+                .load(std::sync::atomic::Ordering::Relaxed),
+        }
+    }
+
+    pub(crate) fn reset(&self) {
+        self.bytes_read
+            .store(0, std::sync::atomic::Ordering::Relaxed);
+        self.bytes_written
+            .store(0, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    fn add_read(&self, len: usize) {
+        self.bytes_read
+            .fetch_add(len as u64, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    fn add_written(&self, len: usize) {
+        self.bytes_written
+            .fetch_add(len as u64, std::sync::atomic::Ordering::Relaxed);
+    }
+}
+
+/// Wraps an I/O object to count the bytes read from and written to it
+#[derive(Debug)]
+pub(crate) struct CountingIo<T> {
+    io: T,
+    counters: std::sync::Arc<IoCounters>,
+}
+
+impl<T> std::io::Read for CountingIo<T>
+where
+    T: std::io::Read,
+{
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.io.read(buf)?;
+        self.counters.add_read(read);
+        Ok(read)
+    }
+}
+
+impl<T> tokio::io::AsyncRead for CountingIo<T>
+where
+    T: tokio::io::AsyncRead,
+{
+    unsafe fn prepare_uninitialized_buffer(&self, buf: &mut [u8]) -> bool {
+        self.io.prepare_uninitialized_buffer(buf)
+    }
+
+    fn poll_read(&mut self, buf: &mut [u8]) -> futures::Poll<usize, std::io::Error> {
+        let result = self.io.poll_read(buf)?;
+        if let futures::Async::Ready(read) = result {
+            self.counters.add_read(read);
+        }
+        Ok(result)
+    }
+}
+
+impl<T> std::io::Write for CountingIo<T>
+where
+    T: std::io::Write,
+{
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.io.write(buf)?;
+        self.counters.add_written(written);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.io.flush()
+    }
+}
+
+impl<T> tokio::io::AsyncWrite for CountingIo<T>
+where
+    T: tokio::io::AsyncWrite,
+{
+    fn poll_write(&mut self, buf: &[u8]) -> futures::Poll<usize, std::io::Error> {
+        let result = self.io.poll_write(buf)?;
+        if let futures::Async::Ready(written) = result {
+            self.counters.add_written(written);
+        }
+        Ok(result)
+    }
+
+    fn poll_flush(&mut self) -> futures::Poll<(), std::io::Error> {
+        self.io.poll_flush()
+    }
+
+    fn shutdown(&mut self) -> futures::Poll<(), std::io::Error> {
+        self.io.shutdown()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn counting_io_counts_bytes() {
+        use futures::{Sink, Stream};
+
+        let counters: std::sync::Arc<super::IoCounters> = Default::default();
+
+        let mut framed = super::LoggingFramed::new(
+            std::io::Cursor::new(vec![]),
+            Default::default(),
+            counters.clone(),
+            Default::default(),
+        );
+        framed.start_send(crate::proto::Packet::PingReq).unwrap();
+        framed.poll_complete().unwrap();
+        framed.start_send(crate::proto::Packet::PingResp).unwrap();
+        framed.poll_complete().unwrap();
+        assert_eq!(
+            counters.get(),
+            crate::IoStats {
+                bytes_read: 0,
+                bytes_written: 4,
+            }
+        );
+
+        let mut io = (framed.0).into_inner().io;
+        io.set_position(0);
+        let mut framed =
+            super::LoggingFramed::new(io, Default::default(), counters.clone(), Default::default());
+        assert_eq!(
+            framed.poll().unwrap(),
+            futures::Async::Ready(Some(crate::proto::Packet::PingReq))
+        );
+        assert_eq!(
+            counters.get(),
+            crate::IoStats {
+                bytes_read: 4,
+                bytes_written: 4,
+            }
+        );
+
+        counters.reset();
+        assert_eq!(counters.get(), Default::default());
+    }
+
+    #[test]
+    fn packet_observer_gets_raw_packets() {
+        use tokio::codec::{Decoder, Encoder};
+
+        let observed: std::sync::Arc<std::sync::Mutex<Vec<(crate::Direction, Vec<u8>)>>> =
+            Default::default();
+
+        let packet_observer: super::PacketObserver = Default::default();
+        packet_observer.set(Some(Box::new({
+            let observed = observed.clone();
+            move |direction, packet| {
+                observed
+                    .lock()
+                    .unwrap()
+                    .push((direction, packet.to_owned()))
+            }
+        })));
+
+        let mut codec = super::ObservedCodec {
+            codec: Default::default(),
+            packet_observer,
+            received: vec![],
+        };
+
+        let mut bytes = bytes::BytesMut::new();
+        codec
+            .encode(crate::proto::Packet::PingReq, &mut bytes)
+            .unwrap();
+
+        // The fixed header of the PUBACK arrives before the rest of it, and is followed by the start of a PINGRESP
+        let mut src = bytes::BytesMut::from(&[0x40, 0x02][..]);
+        assert_eq!(codec.decode(&mut src).unwrap(), None);
+        src.extend_from_slice(&[0x00, 0x05, 0xD0]);
+        assert_eq!(
+            codec.decode(&mut src).unwrap(),
+            Some(crate::proto::Packet::PubAck {
+                packet_identifier: crate::proto::PacketIdentifier::new(5).unwrap(),
+            })
+        );
+
+        // The PINGRESP's first byte arrives on its own, and the PUBLISH's body arrives in two parts
+        assert_eq!(codec.decode(&mut src).unwrap(), None);
+        assert!(src.is_empty());
+        src.extend_from_slice(&[0x00, 0x30, 0x05, 0x00, 0x01]);
+        assert_eq!(
+            codec.decode(&mut src).unwrap(),
+            Some(crate::proto::Packet::PingResp)
+        );
+        assert_eq!(codec.decode(&mut src).unwrap(), None);
+        assert_eq!(&src[..], &[0x00, 0x01][..]);
+        src.extend_from_slice(&[b't', b'a', b'b']);
+        assert_eq!(
+            codec.decode(&mut src).unwrap(),
+            Some(crate::proto::Packet::Publish {
+                packet_identifier_dup_qos: crate::proto::PacketIdentifierDupQoS::AtMostOnce,
+                retain: false,
+                topic_name: "t".to_string(),
+                payload: b"ab".to_vec(),
+            })
+        );
+        assert!(src.is_empty());
+
+        assert_eq!(
+            *observed.lock().unwrap(),
+            vec![
+                (crate::Direction::Sent, vec![0xC0, 0x00]),
+                (crate::Direction::Received, vec![0x40, 0x02, 0x00, 0x05]),
+                (crate::Direction::Received, vec![0xD0, 0x00]),
+                (
+                    crate::Direction::Received,
+                    vec![0x30, 0x05, 0x00, 0x01, b't', b'a', b'b'],
+                ),
+            ]
+        );
+    }
+}
@@ -7,9 +7,48 @@ use bytes::{Buf, BufMut, IntoBuf};
 mod packet;
 
 pub use self::packet::{
-    Packet, PacketCodec, PacketIdentifierDupQoS, Publication, QoS, SubAckQos, SubscribeTo,
+    Packet, PacketCodec, PacketIdentifierDupQoS, Publication, PublicationBuilder, QoS, SubAckQos,
+    SubscribeTo,
 };
 
+/// The version of the MQTT protocol spoken by the client and server
+///
+/// Ref: 3.1.2.1 Protocol Name, 3.1.2.2 Protocol Level
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ProtocolVersion {
+    /// MQTT 3.1, spoken by some legacy servers
+    ///
+    /// Servers speaking this version may reject server-generated client IDs and client IDs longer than 23 characters.
+    V3_1,
+
+    /// MQTT 3.1.1
+    V3_1_1,
+}
+
+impl ProtocolVersion {
+    /// The protocol name sent in the CONNECT packet
+    pub fn protocol_name(self) -> &'static str {
+        match self {
+            ProtocolVersion::V3_1 => "MQIsdp",
+            ProtocolVersion::V3_1_1 => "MQTT",
+        }
+    }
+
+    /// The protocol level sent in the CONNECT packet
+    pub fn protocol_level(self) -> u8 {
+        match self {
+            ProtocolVersion::V3_1 => 0x03,
+            ProtocolVersion::V3_1_1 => 0x04,
+        }
+    }
+}
+
+impl Default for ProtocolVersion {
+    fn default() -> Self {
+        ProtocolVersion::V3_1_1
+    }
+}
+
 /// The client ID
 ///
 /// Refs:
@@ -22,6 +61,99 @@ pub enum ClientId {
     IdWithExistingSession(String),
 }
 
+impl ClientId {
+    /// The longest client ID that all servers are required to accept.
+    ///
+    /// Ref: 3.1.3.1 Client Identifier
+    pub const STRICT_MAX_LEN: usize = 23;
+
+    /// Returns the ID, or `None` if the ID is server-generated.
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            ClientId::ServerGenerated => None,
+            ClientId::IdWithCleanSession(id) | ClientId::IdWithExistingSession(id) => Some(id),
+        }
+    }
+
+    /// Returns whether a CONNECT packet with this ID asks the server to start a new session,
+    /// ie whether it has the Clean Session flag set.
+    pub fn is_clean_session(&self) -> bool {
+        match self {
+            ClientId::ServerGenerated | ClientId::IdWithCleanSession(_) => true,
+            ClientId::IdWithExistingSession(_) => false,
+        }
+    }
+
+    /// Checks that this ID can be sent to the server.
+    ///
+    /// With [`ClientIdValidation::Basic`], the ID must be non-empty, must fit in an MQTT string and must not contain U+0000.
+    /// Servers are allowed to accept any such ID.
+    ///
+    /// With [`ClientIdValidation::Strict`], the ID must additionally be at most [`ClientId::STRICT_MAX_LEN`] bytes long
+    /// and only contain the characters `0-9`, `a-z` and `A-Z`. All servers are required to accept such IDs.
+    pub fn validate(&self, validation: ClientIdValidation) -> Result<(), ClientIdError> {
+        let id = match self.as_str() {
+            Some(id) => id,
+            None => return Ok(()),
+        };
+
+        if id.is_empty() {
+            return Err(ClientIdError::Empty);
+        }
+
+        let max = match validation {
+            ClientIdValidation::Basic => u16::max_value() as usize,
+            ClientIdValidation::Strict => ClientId::STRICT_MAX_LEN,
+        };
+        if id.len() > max {
+            return Err(ClientIdError::TooLong { len: id.len(), max });
+        }
+
+        let is_valid_char = |c: char| match validation {
+            ClientIdValidation::Basic => c != '\u{0000}',
+            ClientIdValidation::Strict => c.is_ascii_alphanumeric(),
+        };
+        if let Some(c) = id.chars().find(|&c| !is_valid_char(c)) {
+            return Err(ClientIdError::InvalidCharacter(c));
+        }
+
+        Ok(())
+    }
+}
+
+/// How strictly [`ClientId::validate`] checks a client ID
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ClientIdValidation {
+    Basic,
+    Strict,
+}
+
+/// The reason a client ID failed validation
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ClientIdError {
+    Empty,
+    InvalidCharacter(char),
+    TooLong { len: usize, max: usize },
+}
+
+impl std::fmt::Display for ClientIdError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClientIdError::Empty => write!(f, "client ID is empty"),
+            ClientIdError::InvalidCharacter(c) => {
+                write!(f, "client ID contains invalid character {:?}", c)
+            }
+            ClientIdError::TooLong { len, max } => write!(
+                f,
+                "client ID of length {} is longer than the maximum of {}",
+                len, max
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ClientIdError {}
+
 /// The return code for a connection attempt
 ///
 /// Ref: 3.2.2.3 Connect Return code
@@ -76,6 +208,40 @@ impl From<ConnectReturnCode> for u8 {
     }
 }
 
+/// Returns whether a publication with the given topic name matches the given topic filter.
+///
+/// `+` matches exactly one topic level and `#` matches any number of trailing levels, including their parent level.
+/// Topic names starting with `$` are not matched by filters starting with a wildcard.
+///
+/// Ref: 4.7 Topic Names and Topic Filters
+pub fn topic_filter_matches(topic_filter: &str, topic_name: &str) -> bool {
+    if topic_name.starts_with('$')
+        && (topic_filter.starts_with('+') || topic_filter.starts_with('#'))
+    {
+        return false;
+    }
+
+    let mut topic_filter = topic_filter.split('/');
+    let mut topic_name = topic_name.split('/');
+
+    loop {
+        match (topic_filter.next(), topic_name.next()) {
+            (Some("#"), _) => return true,
+            (Some("+"), Some(_)) => (),
+            (Some(filter_level), Some(name_level)) if filter_level == name_level => (),
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+/// Returns whether the server accepted every subscription of a SUBACK packet.
+///
+/// A subscription that the server accepted with a lower QoS than requested still counts as accepted.
+pub fn all_succeeded(qos: &[SubAckQos]) -> bool {
+    qos.iter().all(|qos| qos.is_success())
+}
+
 /// A tokio codec that encodes and decodes MQTT-format strings.
 ///
 /// Strings are prefixed with a two-byte big-endian length and are encoded as utf-8.
@@ -84,6 +250,19 @@ impl From<ConnectReturnCode> for u8 {
 #[derive(Debug, Default)]
 pub struct Utf8StringCodec {
     decoder_state: Utf8StringDecoderState,
+    max_len: Option<usize>,
+}
+
+impl Utf8StringCodec {
+    /// Creates a codec that fails to decode strings longer than `max_len` bytes with [`DecodeError::StringTooLarge`].
+    ///
+    /// The error is returned as soon as the length prefix has been read, without waiting for the rest of the string.
+    pub fn with_max_len(max_len: usize) -> Self {
+        Utf8StringCodec {
+            decoder_state: Default::default(),
+            max_len: Some(max_len),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -110,6 +289,11 @@ impl tokio::codec::Decoder for Utf8StringCodec {
                         Ok(len) => len as usize,
                         Err(_) => return Ok(None),
                     };
+                    if let Some(max) = self.max_len {
+                        if len > max {
+                            return Err(DecodeError::StringTooLarge { len, max });
+                        }
+                    }
                     self.decoder_state = Utf8StringDecoderState::HaveLength(len);
                 }
 
@@ -149,6 +333,12 @@ impl tokio::codec::Encoder for Utf8StringCodec {
     }
 }
 
+/// The largest remaining length that can be encoded in the fixed header of a packet, ie the largest size of a packet
+/// after its fixed header.
+///
+/// Ref: 2.2.3 Remaining Length
+pub const MAX_REMAINING_LENGTH: usize = 0x0FFF_FFFF;
+
 /// A tokio codec that encodes and decodes MQTT-format "remaining length" numbers.
 ///
 /// These numbers are encoded with a variable-length scheme that uses the MSB of each byte as a continuation bit.
@@ -196,6 +386,8 @@ impl tokio::codec::Decoder for RemainingLengthCodec {
             }
 
             if self.decoder_state.num_bytes_read == 4 {
+                // Reset so that the codec starts afresh if it's used to decode another remaining length
+                self.decoder_state = Default::default();
                 return Err(DecodeError::RemainingLengthTooHigh);
             }
         }
@@ -211,9 +403,17 @@ impl tokio::codec::Encoder for RemainingLengthCodec {
         mut item: Self::Item,
         dst: &mut bytes::BytesMut,
     ) -> Result<(), Self::Error> {
+        // Most packets are shorter than 128 bytes, so their remaining length is a single byte
+        if item < 0x80 {
+            #[allow(clippy::cast_possible_truncation)]
+            dst.append_u8(item as u8);
+            return Ok(());
+        }
+
         dst.reserve(4 * std::mem::size_of::<u8>());
 
         let original = item;
+        let original_len = dst.len();
 
         loop {
             #[allow(clippy::cast_possible_truncation)]
@@ -231,7 +431,8 @@ impl tokio::codec::Encoder for RemainingLengthCodec {
                 break;
             }
 
-            if dst.len() == 4 {
+            // `dst` may already contain other bytes, such as the packet type or earlier packets
+            if dst.len() - original_len == 4 {
                 return Err(EncodeError::RemainingLengthTooHigh(original));
             }
         }
@@ -263,6 +464,31 @@ impl PacketIdentifier {
     pub fn get(self) -> u16 {
         self.0
     }
+
+    /// Returns every valid packet identifier in ascending order, ie 1 to [`PacketIdentifier::max_value`]
+    pub fn all() -> impl Iterator<Item = Self> {
+        (1..=u16::max_value()).map(PacketIdentifier)
+    }
+
+    /// Returns every valid packet identifier in the order that repeatedly adding 1 to this one produces them,
+    /// ie starting from the one after this one and wrapping around from [`PacketIdentifier::max_value`] to 1.
+    /// The last identifier returned is this one.
+    ///
+    /// Note that this differs from adding each of `1..=u16::max_value()` to this identifier,
+    /// since addition skips zero when it wraps around.
+    pub fn successors(self) -> impl Iterator<Item = Self> {
+        let mut current = self;
+        let mut done = false;
+        std::iter::from_fn(move || {
+            if done {
+                return None;
+            }
+
+            current += 1;
+            done = current == self;
+            Some(current)
+        })
+    }
 }
 
 impl std::fmt::Display for PacketIdentifier {
@@ -291,12 +517,17 @@ impl std::ops::AddAssign<u16> for PacketIdentifier {
 #[derive(Debug)]
 pub enum DecodeError {
     ConnectReservedSet,
+    EmptyTopicName,
     IncompletePacket,
     Io(std::io::Error),
     PublishDupAtMostOnce,
     NoTopics,
     RemainingLengthTooHigh,
     StringNotUtf8(std::str::Utf8Error),
+    StringTooLarge {
+        len: usize,
+        max: usize,
+    },
     UnrecognizedConnAckFlags(u8),
     UnrecognizedPacket {
         packet_type: u8,
@@ -306,6 +537,11 @@ pub enum DecodeError {
     UnrecognizedProtocolLevel(u8),
     UnrecognizedProtocolName(String),
     UnrecognizedQoS(u8),
+    UnsupportedMqtt5Packet {
+        packet_type: u8,
+        flags: u8,
+        remaining_length: usize,
+    },
     ZeroPacketIdentifier,
 }
 
@@ -315,6 +551,7 @@ impl std::fmt::Display for DecodeError {
             DecodeError::ConnectReservedSet => {
                 write!(f, "the reserved byte of the CONNECT flags is set")
             }
+            DecodeError::EmptyTopicName => write!(f, "PUBLISH packet has an empty topic name"),
             DecodeError::IncompletePacket => write!(f, "packet is truncated"),
             DecodeError::Io(err) => write!(f, "I/O error: {}", err),
             DecodeError::NoTopics => write!(f, "expected at least one topic but there were none"),
@@ -325,6 +562,11 @@ impl std::fmt::Display for DecodeError {
                 write!(f, "remaining length is too high to be decoded")
             }
             DecodeError::StringNotUtf8(err) => err.fmt(f),
+            DecodeError::StringTooLarge { len, max } => write!(
+                f,
+                "string of length {} is longer than the maximum of {}",
+                len, max
+            ),
             DecodeError::UnrecognizedConnAckFlags(flags) => {
                 write!(f, "could not parse CONNACK flags 0x{:02X}", flags)
             }
@@ -346,6 +588,16 @@ impl std::fmt::Display for DecodeError {
                 write!(f, "unexpected protocol name {:?}", name)
             }
             DecodeError::UnrecognizedQoS(qos) => write!(f, "could not parse QoS 0x{:02X}", qos),
+            DecodeError::UnsupportedMqtt5Packet {
+                packet_type,
+                flags,
+                remaining_length,
+            } => write!(
+                f,
+                "received MQTT 5 packet with type 0x{:1X}, flags 0x{:1X} and remaining length {}; \
+                 the server appears to speak MQTT 5, but this client only supports MQTT 3.1 and 3.1.1",
+                packet_type, flags, remaining_length,
+            ),
             DecodeError::ZeroPacketIdentifier => write!(f, "packet identifier is 0"),
         }
     }
@@ -356,17 +608,20 @@ impl std::error::Error for DecodeError {
         #[allow(clippy::match_same_arms)]
         match self {
             DecodeError::ConnectReservedSet => None,
+            DecodeError::EmptyTopicName => None,
             DecodeError::IncompletePacket => None,
             DecodeError::Io(err) => Some(err),
             DecodeError::NoTopics => None,
             DecodeError::PublishDupAtMostOnce => None,
             DecodeError::RemainingLengthTooHigh => None,
             DecodeError::StringNotUtf8(err) => Some(err),
+            DecodeError::StringTooLarge { .. } => None,
             DecodeError::UnrecognizedConnAckFlags(_) => None,
             DecodeError::UnrecognizedPacket { .. } => None,
             DecodeError::UnrecognizedProtocolLevel(_) => None,
             DecodeError::UnrecognizedProtocolName(_) => None,
             DecodeError::UnrecognizedQoS(_) => None,
+            DecodeError::UnsupportedMqtt5Packet { .. } => None,
             DecodeError::ZeroPacketIdentifier => None,
         }
     }
@@ -490,6 +745,55 @@ impl BufMutExt for bytes::BytesMut {
 
 #[cfg(test)]
 mod tests {
+    #[test]
+    fn client_id_validate() {
+        use super::{ClientId, ClientIdError, ClientIdValidation};
+
+        let id = |s: &str| ClientId::IdWithCleanSession(s.to_string());
+
+        assert_eq!(
+            ClientId::ServerGenerated.validate(ClientIdValidation::Strict),
+            Ok(())
+        );
+        assert_eq!(id("abc123").validate(ClientIdValidation::Strict), Ok(()));
+        assert_eq!(
+            id("").validate(ClientIdValidation::Basic),
+            Err(ClientIdError::Empty)
+        );
+        assert_eq!(
+            id("a\u{0000}b").validate(ClientIdValidation::Basic),
+            Err(ClientIdError::InvalidCharacter('\u{0000}'))
+        );
+
+        // Longer IDs and other characters are only rejected by the strict check
+        let long_id = id(&"a".repeat(ClientId::STRICT_MAX_LEN + 1));
+        assert_eq!(long_id.validate(ClientIdValidation::Basic), Ok(()));
+        assert_eq!(
+            long_id.validate(ClientIdValidation::Strict),
+            Err(ClientIdError::TooLong {
+                len: ClientId::STRICT_MAX_LEN + 1,
+                max: ClientId::STRICT_MAX_LEN,
+            })
+        );
+        assert_eq!(
+            id("device/module").validate(ClientIdValidation::Basic),
+            Ok(())
+        );
+        assert_eq!(
+            id("device/module").validate(ClientIdValidation::Strict),
+            Err(ClientIdError::InvalidCharacter('/'))
+        );
+
+        let too_long_id = id(&"a".repeat(u16::max_value() as usize + 1));
+        assert_eq!(
+            too_long_id.validate(ClientIdValidation::Basic),
+            Err(ClientIdError::TooLong {
+                len: u16::max_value() as usize + 1,
+                max: u16::max_value() as usize,
+            })
+        );
+    }
+
     #[test]
     fn remaining_length_encode() {
         remaining_length_encode_inner_ok(0x00, &[0x00]);
@@ -508,6 +812,33 @@ mod tests {
         remaining_length_encode_inner_too_high(0xFFFF_FFFF_FFFF_FFFF);
     }
 
+    #[test]
+    fn remaining_length_encode_after_other_bytes() {
+        use tokio::codec::Encoder;
+
+        // The codec writes after whatever is already in the buffer, such as the packet type and earlier packets
+        for &(value, expected) in &[
+            (0x7F, &b"\x30\x00\x00\x7F"[..]),
+            (0x80, &b"\x30\x00\x00\x80\x01"[..]),
+            (0x0FFF_FFFF, &b"\x30\x00\x00\xFF\xFF\xFF\x7F"[..]),
+        ] {
+            let mut bytes = bytes::BytesMut::from(&b"\x30\x00\x00"[..]);
+            super::RemainingLengthCodec::default()
+                .encode(value, &mut bytes)
+                .unwrap();
+            assert_eq!(&*bytes, expected);
+        }
+
+        let mut bytes = bytes::BytesMut::from(&b"\x30\x00\x00"[..]);
+        let err = super::RemainingLengthCodec::default()
+            .encode(0x1000_0000, &mut bytes)
+            .unwrap_err();
+        if let super::EncodeError::RemainingLengthTooHigh(0x1000_0000) = err {
+        } else {
+            panic!("{:?}", err);
+        }
+    }
+
     fn remaining_length_encode_inner_ok(value: usize, expected: &[u8]) {
         use tokio::codec::Encoder;
 
@@ -583,6 +914,560 @@ mod tests {
         }
     }
 
+    #[test]
+    fn remaining_length_decode_after_too_high() {
+        use tokio::codec::Decoder;
+
+        let mut codec = super::RemainingLengthCodec::default();
+
+        let mut bytes = bytes::BytesMut::from(&[0xFF, 0xFF, 0xFF, 0xFF][..]);
+        let err = codec.decode(&mut bytes).unwrap_err();
+        if let super::DecodeError::RemainingLengthTooHigh = err {
+        } else {
+            panic!("{:?}", err);
+        }
+
+        // The bytes of the too-high remaining length don't leak into the next one
+        let mut bytes = bytes::BytesMut::from(&[0x01][..]);
+        assert_eq!(codec.decode(&mut bytes).unwrap(), Some(0x01));
+
+        let mut bytes = bytes::BytesMut::from(&[0x80, 0x01][..]);
+        assert_eq!(codec.decode(&mut bytes).unwrap(), Some(0x80));
+    }
+
+    #[test]
+    fn utf8_string_decode_max_len() {
+        use tokio::codec::Decoder;
+
+        let mut bytes = bytes::BytesMut::from(&b"\x00\x03abc"[..]);
+        assert_eq!(
+            super::Utf8StringCodec::with_max_len(3)
+                .decode(&mut bytes)
+                .unwrap(),
+            Some("abc".to_string())
+        );
+
+        // Rejected as soon as the length is known, even though the string itself hasn't been received yet
+        let mut bytes = bytes::BytesMut::from(&b"\xFF\xFFabc"[..]);
+        let err = super::Utf8StringCodec::with_max_len(3)
+            .decode(&mut bytes)
+            .unwrap_err();
+        if let super::DecodeError::StringTooLarge {
+            len: 0xFFFF,
+            max: 3,
+        } = err
+        {
+        } else {
+            panic!("{:?}", err);
+        }
+    }
+
+    #[test]
+    fn publish_decode_max_topic_name_len() {
+        use tokio::codec::Decoder;
+
+        let mut codec = super::PacketCodec::default();
+        codec.set_max_topic_name_len(Some(3));
+
+        // A PUBLISH with a remaining length of 0x0F_FFFF whose topic name is 0xFFFF bytes long
+        let mut bytes = bytes::BytesMut::from(&b"\x30\xFF\xFF\x3F\xFF\xFF"[..]);
+        let err = codec.decode(&mut bytes).unwrap_err();
+        if let super::DecodeError::StringTooLarge {
+            len: 0xFFFF,
+            max: 3,
+        } = err
+        {
+        } else {
+            panic!("{:?}", err);
+        }
+    }
+
+    #[test]
+    fn publish_decode_empty_topic_name() {
+        use tokio::codec::Decoder;
+
+        let mut codec = super::PacketCodec::default();
+
+        // A QoS 0 PUBLISH with an empty topic name and a payload of "a"
+        let mut bytes = bytes::BytesMut::from(&b"\x30\x03\x00\x00a"[..]);
+        let err = codec.decode(&mut bytes).unwrap_err();
+        if let super::DecodeError::EmptyTopicName = err {
+        } else {
+            panic!("{:?}", err);
+        }
+    }
+
+    #[test]
+    fn packet_identifier_iterators() {
+        use super::PacketIdentifier;
+
+        let all: Vec<_> = PacketIdentifier::all().map(PacketIdentifier::get).collect();
+        assert_eq!(all.len(), usize::from(u16::max_value()));
+        assert_eq!(all[0], 1);
+        assert_eq!(all[all.len() - 1], u16::max_value());
+
+        for &start in &[1, 2, 0x7FFF, u16::max_value() - 1, u16::max_value()] {
+            let start = PacketIdentifier::new(start).unwrap();
+            let successors: Vec<_> = start.successors().collect();
+
+            assert_eq!(successors.len(), usize::from(u16::max_value()));
+            assert_eq!(successors[0], start + 1);
+            assert_eq!(successors[successors.len() - 1], start);
+            for window in successors.windows(2) {
+                assert_eq!(window[1], window[0] + 1);
+            }
+
+            let mut sorted = successors.clone();
+            sorted.sort();
+            assert!(sorted.into_iter().eq(PacketIdentifier::all()));
+        }
+    }
+
+    #[test]
+    fn packet_summary() {
+        use super::{Packet, PacketIdentifier, PacketIdentifierDupQoS, QoS, SubscribeTo};
+
+        let packet_identifier = PacketIdentifier::new(5).unwrap();
+
+        let publish = Packet::Publish {
+            packet_identifier_dup_qos: PacketIdentifierDupQoS::AtLeastOnce(packet_identifier, true),
+            retain: false,
+            topic_name: "foo/bar".to_string(),
+            payload: b"secret".to_vec(),
+        };
+        assert_eq!(publish.packet_type_name(), "PUBLISH");
+        assert_eq!(
+            publish.summary(),
+            r#"PUBLISH 5 qos=AtLeastOnce dup=true retain=false topic_name="foo/bar" payload_len=6"#
+        );
+
+        let connect = Packet::Connect {
+            username: Some("username".to_string()),
+            password: Some("password".to_string()),
+            will: None,
+            client_id: super::ClientId::ServerGenerated,
+            keep_alive: std::time::Duration::from_secs(4),
+            protocol_version: super::ProtocolVersion::V3_1_1,
+        };
+        assert!(!connect.summary().contains("password"));
+
+        assert_eq!(
+            Packet::Subscribe {
+                packet_identifier,
+                subscribe_to: vec![SubscribeTo {
+                    topic_filter: "foo/#".to_string(),
+                    qos: QoS::ExactlyOnce,
+                }],
+            }
+            .summary(),
+            "SUBSCRIBE 5 subscribe_to=[foo/#@ExactlyOnce]"
+        );
+        assert_eq!(Packet::PubAck { packet_identifier }.summary(), "PUBACK 5");
+        assert_eq!(Packet::PingReq.summary(), "PINGREQ");
+    }
+
+    #[test]
+    fn publication_builder() {
+        use super::{Publication, QoS};
+
+        assert_eq!(
+            Publication::builder("foo/bar").build(),
+            Publication {
+                topic_name: "foo/bar".to_string(),
+                qos: QoS::AtMostOnce,
+                retain: false,
+                payload: vec![],
+            }
+        );
+
+        assert_eq!(
+            Publication::builder("foo/bar".to_string())
+                .qos(QoS::AtLeastOnce)
+                .retain(true)
+                .payload("payload")
+                .build(),
+            Publication {
+                topic_name: "foo/bar".to_string(),
+                qos: QoS::AtLeastOnce,
+                retain: true,
+                payload: b"payload".to_vec(),
+            }
+        );
+    }
+
+    #[test]
+    fn topic_filter_matches() {
+        use super::topic_filter_matches;
+
+        assert!(topic_filter_matches(
+            "sport/tennis/player1",
+            "sport/tennis/player1"
+        ));
+        assert!(!topic_filter_matches(
+            "sport/tennis/player1",
+            "sport/tennis/player2"
+        ));
+        assert!(!topic_filter_matches(
+            "sport/tennis",
+            "sport/tennis/player1"
+        ));
+
+        assert!(topic_filter_matches(
+            "sport/tennis/player1/#",
+            "sport/tennis/player1"
+        ));
+        assert!(topic_filter_matches(
+            "sport/tennis/player1/#",
+            "sport/tennis/player1/ranking"
+        ));
+        assert!(topic_filter_matches("sport/#", "sport"));
+        assert!(topic_filter_matches("#", "sport/tennis"));
+
+        assert!(topic_filter_matches(
+            "sport/+/player1",
+            "sport/tennis/player1"
+        ));
+        assert!(topic_filter_matches("sport/+", "sport/"));
+        assert!(!topic_filter_matches("sport/+", "sport"));
+        assert!(!topic_filter_matches("sport/+", "sport/tennis/player1"));
+        assert!(topic_filter_matches("+/+", "/finance"));
+
+        assert!(!topic_filter_matches("#", "$SYS/monitor"));
+        assert!(!topic_filter_matches("+/monitor", "$SYS/monitor"));
+        assert!(topic_filter_matches("$SYS/#", "$SYS/monitor"));
+    }
+
+    #[test]
+    fn sub_ack_qos() {
+        use super::{all_succeeded, QoS, SubAckQos};
+
+        assert!(SubAckQos::Success(QoS::AtMostOnce).is_success());
+        assert_eq!(
+            SubAckQos::Success(QoS::ExactlyOnce).qos(),
+            Some(QoS::ExactlyOnce)
+        );
+        assert!(!SubAckQos::Failure.is_success());
+        assert_eq!(SubAckQos::Failure.qos(), None);
+
+        assert!(all_succeeded(&[]));
+        assert!(all_succeeded(&[
+            SubAckQos::Success(QoS::AtMostOnce),
+            SubAckQos::Success(QoS::AtLeastOnce),
+        ]));
+        assert!(!all_succeeded(&[
+            SubAckQos::Success(QoS::AtMostOnce),
+            SubAckQos::Failure,
+        ]));
+    }
+
+    #[test]
+    fn connect_protocol_version() {
+        use tokio::codec::{Decoder, Encoder};
+
+        use super::{ClientId, Packet, PacketCodec, ProtocolVersion};
+
+        let connect = |protocol_version| Packet::Connect {
+            username: None,
+            password: None,
+            will: None,
+            client_id: ClientId::IdWithCleanSession("a".to_string()),
+            keep_alive: std::time::Duration::from_secs(4),
+            protocol_version,
+        };
+
+        let mut bytes = bytes::BytesMut::new();
+        PacketCodec::default()
+            .encode(connect(ProtocolVersion::V3_1), &mut bytes)
+            .unwrap();
+        assert_eq!(
+            &bytes[..],
+            &b"\x10\x0F\x00\x06MQIsdp\x03\x02\x00\x04\x00\x01a"[..]
+        );
+        assert_eq!(
+            PacketCodec::default().decode(&mut bytes).unwrap(),
+            Some(connect(ProtocolVersion::V3_1))
+        );
+
+        let mut bytes = bytes::BytesMut::new();
+        PacketCodec::default()
+            .encode(connect(ProtocolVersion::V3_1_1), &mut bytes)
+            .unwrap();
+        assert_eq!(
+            &bytes[..],
+            &b"\x10\x0D\x00\x04MQTT\x04\x02\x00\x04\x00\x01a"[..]
+        );
+        assert_eq!(
+            PacketCodec::default().decode(&mut bytes).unwrap(),
+            Some(connect(ProtocolVersion::V3_1_1))
+        );
+
+        // The protocol level must match the protocol name
+        let mut bytes =
+            bytes::BytesMut::from(&b"\x10\x0D\x00\x04MQTT\x03\x02\x00\x04\x00\x01a"[..]);
+        let err = PacketCodec::default().decode(&mut bytes).unwrap_err();
+        if let super::DecodeError::UnrecognizedProtocolLevel(0x03) = err {
+        } else {
+            panic!("{:?}", err);
+        }
+    }
+
+    #[test]
+    fn connect_will() {
+        use tokio::codec::{Decoder, Encoder};
+
+        use super::{ClientId, Packet, PacketCodec, ProtocolVersion, Publication, QoS};
+
+        // Long enough that the payload length prefix needs both of its bytes
+        let will_payload: Vec<_> = (0..0x0102_u16).map(|i| i as u8).collect();
+
+        for &qos in &[QoS::AtMostOnce, QoS::AtLeastOnce, QoS::ExactlyOnce] {
+            for &retain in &[false, true] {
+                let connect = Packet::Connect {
+                    username: None,
+                    password: None,
+                    will: Some(Publication {
+                        topic_name: "w".to_string(),
+                        qos,
+                        retain,
+                        payload: will_payload.clone(),
+                    }),
+                    client_id: ClientId::IdWithCleanSession("a".to_string()),
+                    keep_alive: std::time::Duration::from_secs(4),
+                    protocol_version: ProtocolVersion::V3_1_1,
+                };
+
+                let mut bytes = bytes::BytesMut::new();
+                PacketCodec::default()
+                    .encode(connect.clone(), &mut bytes)
+                    .unwrap();
+
+                // Packet type, two bytes of remaining length, protocol name and protocol level, then the connect flags
+                let expected_flags = 0x02 // clean session
+                    | 0x04 // will
+                    | (u8::from(qos) << 3)
+                    | if retain { 0x20 } else { 0x00 };
+                assert_eq!(bytes[10], expected_flags, "{:?} retain={}", qos, retain);
+
+                // The payload ends with the will topic, the will payload length and the will payload
+                let will_start = bytes.len() - will_payload.len() - 5;
+                assert_eq!(
+                    &bytes[will_start..will_start + 5],
+                    &b"\x00\x01w\x01\x02"[..]
+                );
+                assert_eq!(&bytes[will_start + 5..], &will_payload[..]);
+
+                assert_eq!(
+                    PacketCodec::default().decode(&mut bytes).unwrap(),
+                    Some(connect)
+                );
+                assert!(bytes.is_empty());
+            }
+        }
+    }
+
+    #[test]
+    fn mqtt5_packet_decode() {
+        use tokio::codec::Decoder;
+
+        // An MQTT 5 AUTH packet with reason code 0x18 (continue authentication) and no properties
+        let mut bytes = bytes::BytesMut::from(&b"\xF0\x02\x18\x00"[..]);
+        let err = super::PacketCodec::default()
+            .decode(&mut bytes)
+            .unwrap_err();
+        if let super::DecodeError::UnsupportedMqtt5Packet {
+            packet_type: 0xF0,
+            flags: 0x00,
+            remaining_length: 2,
+        } = err
+        {
+        } else {
+            panic!("{:?}", err);
+        }
+        assert!(bytes.is_empty());
+
+        // Packet type 0x00 is reserved in every version of the protocol
+        let mut bytes = bytes::BytesMut::from(&b"\x00\x00"[..]);
+        let err = super::PacketCodec::default()
+            .decode(&mut bytes)
+            .unwrap_err();
+        if let super::DecodeError::UnrecognizedPacket {
+            packet_type: 0x00, ..
+        } = err
+        {
+        } else {
+            panic!("{:?}", err);
+        }
+    }
+
+    /// A packet of every type, for tests that round-trip packets through the codec
+    fn sample_packets() -> Vec<super::Packet> {
+        use super::{
+            ClientId, ConnectReturnCode, Packet, PacketIdentifier, PacketIdentifierDupQoS,
+            ProtocolVersion, Publication, QoS, SubAckQos, SubscribeTo,
+        };
+
+        let packet_identifier = PacketIdentifier::new(5).unwrap();
+
+        vec![
+            Packet::ConnAck {
+                session_present: true,
+                return_code: ConnectReturnCode::Accepted,
+            },
+            Packet::Connect {
+                username: Some("username".to_string()),
+                password: Some("password".to_string()),
+                will: Some(Publication {
+                    topic_name: "will".to_string(),
+                    qos: QoS::AtLeastOnce,
+                    retain: true,
+                    payload: b"bye".to_vec(),
+                }),
+                client_id: ClientId::IdWithCleanSession("a".to_string()),
+                keep_alive: std::time::Duration::from_secs(4),
+                protocol_version: ProtocolVersion::V3_1,
+            },
+            Packet::Connect {
+                username: None,
+                password: None,
+                will: None,
+                client_id: ClientId::ServerGenerated,
+                keep_alive: std::time::Duration::from_secs(4),
+                protocol_version: ProtocolVersion::V3_1_1,
+            },
+            Packet::Disconnect,
+            Packet::PingReq,
+            Packet::PubAck { packet_identifier },
+            Packet::PubRel { packet_identifier },
+            Packet::Publish {
+                packet_identifier_dup_qos: PacketIdentifierDupQoS::AtMostOnce,
+                retain: false,
+                topic_name: "foo/bar".to_string(),
+                payload: vec![],
+            },
+            // Long enough that the remaining length needs two bytes
+            Packet::Publish {
+                packet_identifier_dup_qos: PacketIdentifierDupQoS::ExactlyOnce(
+                    packet_identifier,
+                    false,
+                ),
+                retain: true,
+                topic_name: "foo/bar".to_string(),
+                payload: vec![0; 0x0100],
+            },
+            Packet::SubAck {
+                packet_identifier,
+                qos: vec![SubAckQos::Success(QoS::AtLeastOnce), SubAckQos::Failure],
+            },
+            Packet::Subscribe {
+                packet_identifier,
+                subscribe_to: vec![
+                    SubscribeTo {
+                        topic_filter: "foo/#".to_string(),
+                        qos: QoS::ExactlyOnce,
+                    },
+                    SubscribeTo {
+                        topic_filter: "bar/+".to_string(),
+                        qos: QoS::AtMostOnce,
+                    },
+                ],
+            },
+            Packet::UnsubAck { packet_identifier },
+            Packet::Unsubscribe {
+                packet_identifier,
+                unsubscribe_from: vec!["foo/#".to_string()],
+            },
+        ]
+    }
+
+    #[test]
+    fn packet_decode_fragmented() {
+        use tokio::codec::{Decoder, Encoder};
+
+        use super::PacketCodec;
+
+        /// Feeds the fragments to a new codec one at a time, like successive reads from the connection,
+        /// and returns the packets decoded along the way
+        fn decode_fragments<'a>(
+            fragments: impl IntoIterator<Item = &'a [u8]>,
+        ) -> Vec<super::Packet> {
+            let mut codec = PacketCodec::default();
+            let mut bytes = bytes::BytesMut::new();
+            let mut packets = vec![];
+            for fragment in fragments {
+                bytes.extend_from_slice(fragment);
+                while let Some(packet) = codec.decode(&mut bytes).unwrap() {
+                    packets.push(packet);
+                }
+            }
+            assert!(bytes.is_empty());
+            packets
+        }
+
+        let packets = sample_packets();
+
+        let mut stream = bytes::BytesMut::new();
+        for packet in &packets {
+            let mut bytes = bytes::BytesMut::new();
+            PacketCodec::default()
+                .encode(packet.clone(), &mut bytes)
+                .unwrap();
+
+            // Split within the fixed header, the variable header and the payload
+            for split in 0..=bytes.len() {
+                assert_eq!(
+                    decode_fragments(vec![&bytes[..split], &bytes[split..]]),
+                    vec![packet.clone()],
+                    "split at {}",
+                    split,
+                );
+            }
+
+            stream.extend_from_slice(&bytes);
+        }
+
+        // One byte at a time, across packet boundaries
+        assert_eq!(decode_fragments(stream.chunks(1)), packets);
+    }
+
+    #[test]
+    fn packet_encoded_len() {
+        use tokio::codec::Encoder;
+
+        use super::{PacketCodec, Publication, QoS};
+
+        let packets = sample_packets();
+
+        for packet in packets {
+            let encoded_len = packet.encoded_len();
+
+            let mut bytes = bytes::BytesMut::new();
+            PacketCodec::default()
+                .encode(packet.clone(), &mut bytes)
+                .unwrap();
+            assert_eq!(encoded_len, bytes.len(), "{:?}", packet);
+        }
+
+        // The largest payload that fits is the largest remaining length minus the topic name and packet identifier
+        let publication = Publication {
+            topic_name: "foo/bar".to_string(),
+            qos: QoS::AtMostOnce,
+            retain: false,
+            payload: vec![],
+        };
+        assert_eq!(
+            publication.max_payload_len(),
+            super::MAX_REMAINING_LENGTH - 9
+        );
+        let publication = Publication {
+            qos: QoS::AtLeastOnce,
+            ..publication
+        };
+        assert_eq!(
+            publication.max_payload_len(),
+            super::MAX_REMAINING_LENGTH - 11
+        );
+    }
+
     fn remaining_length_decode_inner_incomplete_packet(bytes: &[u8]) {
         use tokio::codec::Decoder;
 
@@ -16,6 +16,7 @@ pub enum Packet {
         will: Option<Publication>,
         client_id: super::ClientId,
         keep_alive: std::time::Duration,
+        protocol_version: super::ProtocolVersion,
     },
 
     Disconnect,
@@ -79,6 +80,10 @@ pub enum Packet {
 }
 
 impl Packet {
+    /// The type of an MQTT 5 AUTH packet. This crate only speaks MQTT 3.1 and 3.1.1, so AUTH packets are never decoded,
+    /// but they fail to decode with [`super::DecodeError::UnsupportedMqtt5Packet`] rather than as an unrecognized packet.
+    pub const AUTH: u8 = 0xF0;
+
     /// The type of a [`Packet::ConnAck`]
     pub const CONNACK: u8 = 0x20;
 
@@ -120,6 +125,225 @@ impl Packet {
 
     /// The type of a [`Packet::Unsubscribe`]
     pub const UNSUBSCRIBE: u8 = 0xA0;
+
+    /// The name of this packet's type, as used in the spec, eg `"PUBLISH"`
+    pub fn packet_type_name(&self) -> &'static str {
+        match self {
+            Packet::ConnAck { .. } => "CONNACK",
+            Packet::Connect { .. } => "CONNECT",
+            Packet::Disconnect => "DISCONNECT",
+            Packet::PingReq => "PINGREQ",
+            Packet::PingResp => "PINGRESP",
+            Packet::PubAck { .. } => "PUBACK",
+            Packet::PubComp { .. } => "PUBCOMP",
+            Packet::Publish { .. } => "PUBLISH",
+            Packet::PubRec { .. } => "PUBREC",
+            Packet::PubRel { .. } => "PUBREL",
+            Packet::SubAck { .. } => "SUBACK",
+            Packet::Subscribe { .. } => "SUBSCRIBE",
+            Packet::UnsubAck { .. } => "UNSUBACK",
+            Packet::Unsubscribe { .. } => "UNSUBSCRIBE",
+        }
+    }
+
+    /// A compact one-line description of this packet for logging.
+    ///
+    /// Unlike the `Debug` representation, this never contains the payload of a PUBLISH packet,
+    /// nor the credentials or will of a CONNECT packet.
+    pub fn summary(&self) -> String {
+        let packet_type_name = self.packet_type_name();
+
+        match self {
+            Packet::ConnAck {
+                session_present,
+                return_code,
+            } => format!(
+                "{} session_present={} return_code={:?}",
+                packet_type_name, session_present, return_code
+            ),
+
+            Packet::Connect {
+                client_id,
+                keep_alive,
+                ..
+            } => format!(
+                "{} client_id={:?} keep_alive={:?}",
+                packet_type_name, client_id, keep_alive
+            ),
+
+            Packet::Disconnect | Packet::PingReq | Packet::PingResp => packet_type_name.to_string(),
+
+            Packet::PubAck { packet_identifier }
+            | Packet::PubComp { packet_identifier }
+            | Packet::PubRec { packet_identifier }
+            | Packet::PubRel { packet_identifier }
+            | Packet::UnsubAck { packet_identifier } => {
+                format!("{} {}", packet_type_name, packet_identifier)
+            }
+
+            Packet::Publish {
+                packet_identifier_dup_qos,
+                retain,
+                topic_name,
+                payload,
+            } => {
+                let (packet_identifier, qos, dup) = match packet_identifier_dup_qos {
+                    PacketIdentifierDupQoS::AtMostOnce => (None, QoS::AtMostOnce, false),
+                    PacketIdentifierDupQoS::AtLeastOnce(packet_identifier, dup) => {
+                        (Some(packet_identifier), QoS::AtLeastOnce, *dup)
+                    }
+                    PacketIdentifierDupQoS::ExactlyOnce(packet_identifier, dup) => {
+                        (Some(packet_identifier), QoS::ExactlyOnce, *dup)
+                    }
+                };
+                let packet_identifier = match packet_identifier {
+                    Some(packet_identifier) => format!(" {}", packet_identifier),
+                    None => String::new(),
+                };
+                format!(
+                    "{}{} qos={:?} dup={} retain={} topic_name={:?} payload_len={}",
+                    packet_type_name,
+                    packet_identifier,
+                    qos,
+                    dup,
+                    retain,
+                    topic_name,
+                    payload.len()
+                )
+            }
+
+            Packet::SubAck {
+                packet_identifier,
+                qos,
+            } => format!("{} {} qos={:?}", packet_type_name, packet_identifier, qos),
+
+            Packet::Subscribe {
+                packet_identifier,
+                subscribe_to,
+            } => {
+                let subscribe_to: Vec<_> = subscribe_to
+                    .iter()
+                    .map(|subscribe_to| {
+                        format!("{}@{:?}", subscribe_to.topic_filter, subscribe_to.qos)
+                    })
+                    .collect();
+                format!(
+                    "{} {} subscribe_to=[{}]",
+                    packet_type_name,
+                    packet_identifier,
+                    subscribe_to.join(", ")
+                )
+            }
+
+            Packet::Unsubscribe {
+                packet_identifier,
+                unsubscribe_from,
+            } => format!(
+                "{} {} unsubscribe_from={:?}",
+                packet_type_name, packet_identifier, unsubscribe_from
+            ),
+        }
+    }
+
+    /// The number of bytes this packet takes up when encoded, including its fixed header.
+    ///
+    /// This is computed without encoding the packet. If the packet is too large to be encoded, ie its remaining length
+    /// is larger than [`super::MAX_REMAINING_LENGTH`], this is the length it would have had if it could be.
+    pub fn encoded_len(&self) -> usize {
+        // Two-byte length prefix followed by the string
+        fn string_len(s: &str) -> usize {
+            2 + s.len()
+        }
+
+        let remaining_len = match self {
+            Packet::ConnAck { .. } => 2,
+
+            Packet::Connect {
+                username,
+                password,
+                will,
+                client_id,
+                keep_alive: _,
+                protocol_version,
+            } => {
+                let client_id = match client_id {
+                    super::ClientId::ServerGenerated => "",
+                    super::ClientId::IdWithCleanSession(id)
+                    | super::ClientId::IdWithExistingSession(id) => id,
+                };
+
+                // Protocol name, protocol level, connect flags, keep-alive and client ID
+                let mut len = string_len(protocol_version.protocol_name())
+                    + 1
+                    + 1
+                    + 2
+                    + string_len(client_id);
+                if let Some(will) = will {
+                    len += string_len(&will.topic_name) + 2 + will.payload.len();
+                }
+                if let Some(username) = username {
+                    len += string_len(username);
+                }
+                if let Some(password) = password {
+                    len += string_len(password);
+                }
+                len
+            }
+
+            Packet::Disconnect | Packet::PingReq | Packet::PingResp => 0,
+
+            Packet::PubAck { .. }
+            | Packet::PubComp { .. }
+            | Packet::PubRec { .. }
+            | Packet::PubRel { .. }
+            | Packet::UnsubAck { .. } => 2,
+
+            Packet::Publish {
+                packet_identifier_dup_qos,
+                retain: _,
+                topic_name,
+                payload,
+            } => {
+                publish_remaining_len(
+                    topic_name,
+                    *packet_identifier_dup_qos != PacketIdentifierDupQoS::AtMostOnce,
+                ) + payload.len()
+            }
+
+            Packet::SubAck { qos, .. } => 2 + qos.len(),
+
+            Packet::Subscribe { subscribe_to, .. } => {
+                2 + subscribe_to
+                    .iter()
+                    .map(|subscribe_to| string_len(&subscribe_to.topic_filter) + 1)
+                    .sum::<usize>()
+            }
+
+            Packet::Unsubscribe {
+                unsubscribe_from, ..
+            } => {
+                2 + unsubscribe_from
+                    .iter()
+                    .map(|topic_filter| string_len(topic_filter))
+                    .sum::<usize>()
+            }
+        };
+
+        // Packet type, then the remaining length in groups of seven bits
+        let mut remaining_length_len = 1;
+        let mut rest = remaining_len >> 7;
+        while rest > 0 {
+            remaining_length_len += 1;
+            rest >>= 7;
+        }
+
+        1 + remaining_length_len + remaining_len
+    }
+}
+
+/// The remaining length of a PUBLISH packet with the given topic name, without its payload
+fn publish_remaining_len(topic_name: &str, has_packet_identifier: bool) -> usize {
+    2 + topic_name.len() + if has_packet_identifier { 2 } else { 0 }
 }
 
 #[allow(clippy::doc_markdown)]
@@ -167,6 +391,21 @@ pub enum SubAckQos {
     Failure,
 }
 
+impl SubAckQos {
+    /// Whether the server accepted the subscription
+    pub fn is_success(self) -> bool {
+        self.qos().is_some()
+    }
+
+    /// The QoS that the server granted the subscription, or `None` if it rejected the subscription
+    pub fn qos(self) -> Option<QoS> {
+        match self {
+            SubAckQos::Success(qos) => Some(qos),
+            SubAckQos::Failure => None,
+        }
+    }
+}
+
 impl From<SubAckQos> for u8 {
     fn from(qos: SubAckQos) -> Self {
         match qos {
@@ -185,12 +424,89 @@ pub struct Publication {
     pub payload: Vec<u8>,
 }
 
+impl Publication {
+    /// Returns a [`PublicationBuilder`] for a publication to the given topic.
+    /// The publication defaults to QoS 0, not retained, with an empty payload.
+    pub fn builder<T>(topic_name: T) -> PublicationBuilder
+    where
+        T: Into<String>,
+    {
+        PublicationBuilder(Publication {
+            topic_name: topic_name.into(),
+            qos: QoS::AtMostOnce,
+            retain: false,
+            payload: vec![],
+        })
+    }
+
+    /// The largest payload that a PUBLISH packet with this publication's topic name and QoS can hold.
+    ///
+    /// MQTT has no fragmentation, so a publication with a larger payload can't be sent.
+    pub fn max_payload_len(&self) -> usize {
+        super::MAX_REMAINING_LENGTH.saturating_sub(publish_remaining_len(
+            &self.topic_name,
+            self.qos != QoS::AtMostOnce,
+        ))
+    }
+}
+
+/// A builder for a [`Publication`]. See [`Publication::builder`]
+#[derive(Clone, Debug)]
+pub struct PublicationBuilder(Publication);
+
+impl PublicationBuilder {
+    /// Sets the QoS of the publication. Defaults to [`QoS::AtMostOnce`].
+    pub fn qos(mut self, qos: QoS) -> Self {
+        self.0.qos = qos;
+        self
+    }
+
+    /// Sets whether the server retains the publication as the last message of its topic. Defaults to `false`.
+    pub fn retain(mut self, retain: bool) -> Self {
+        self.0.retain = retain;
+        self
+    }
+
+    /// Sets the payload of the publication. Defaults to an empty payload.
+    pub fn payload<T>(mut self, payload: T) -> Self
+    where
+        T: Into<Vec<u8>>,
+    {
+        self.0.payload = payload.into();
+        self
+    }
+
+    /// Creates the publication
+    pub fn build(self) -> Publication {
+        self.0
+    }
+}
+
 /// A tokio codec that encodes and decodes MQTT packets.
 ///
 /// Ref: 2 MQTT Control Packet format
 #[derive(Debug, Default)]
 pub struct PacketCodec {
     decoder_state: PacketDecoderState,
+    max_topic_name_len: Option<usize>,
+}
+
+impl PacketCodec {
+    /// Sets the longest topic name or topic filter, in bytes, that the codec will decode.
+    /// Defaults to `None`, ie only limited by the two-byte length prefix.
+    ///
+    /// Longer strings fail to decode with [`super::DecodeError::StringTooLarge`]. For PUBLISH packets, the topic name's length
+    /// is checked as soon as its length prefix has been received, so the rest of the packet is not buffered.
+    pub fn set_max_topic_name_len(&mut self, max_topic_name_len: Option<usize>) {
+        self.max_topic_name_len = max_topic_name_len;
+    }
+
+    fn topic_name_codec(&self) -> super::Utf8StringCodec {
+        match self.max_topic_name_len {
+            Some(max_len) => super::Utf8StringCodec::with_max_len(max_len),
+            None => super::Utf8StringCodec::default(),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -247,6 +563,17 @@ impl tokio::codec::Decoder for PacketCodec {
                     first_byte,
                     remaining_length,
                 } => {
+                    if let (Some(max), Packet::PUBLISH) =
+                        (self.max_topic_name_len, *first_byte & 0xF0)
+                    {
+                        if src.len() >= 2 {
+                            let len = usize::from(src[0]) << 8 | usize::from(src[1]);
+                            if len > max {
+                                return Err(super::DecodeError::StringTooLarge { len, max });
+                            }
+                        }
+                    }
+
                     if src.len() < *remaining_length {
                         return Ok(None);
                     }
@@ -280,12 +607,14 @@ impl tokio::codec::Decoder for PacketCodec {
                 let protocol_name = super::Utf8StringCodec::default()
                     .decode(&mut src)?
                     .ok_or(super::DecodeError::IncompletePacket)?;
-                if protocol_name != "MQTT" {
-                    return Err(super::DecodeError::UnrecognizedProtocolName(protocol_name));
-                }
+                let protocol_version = match &*protocol_name {
+                    "MQIsdp" => super::ProtocolVersion::V3_1,
+                    "MQTT" => super::ProtocolVersion::V3_1_1,
+                    _ => return Err(super::DecodeError::UnrecognizedProtocolName(protocol_name)),
+                };
 
                 let protocol_level = src.try_get_u8()?;
-                if protocol_level != 0x04 {
+                if protocol_level != protocol_version.protocol_level() {
                     return Err(super::DecodeError::UnrecognizedProtocolLevel(
                         protocol_level,
                     ));
@@ -312,7 +641,8 @@ impl tokio::codec::Decoder for PacketCodec {
                 let will = if connect_flags & 0x04 == 0 {
                     None
                 } else {
-                    let topic_name = super::Utf8StringCodec::default()
+                    let topic_name = self
+                        .topic_name_codec()
                         .decode(&mut src)?
                         .ok_or(super::DecodeError::IncompletePacket)?;
 
@@ -365,6 +695,7 @@ impl tokio::codec::Decoder for PacketCodec {
                     will,
                     client_id,
                     keep_alive,
+                    protocol_version,
                 }))
             }
 
@@ -398,10 +729,16 @@ impl tokio::codec::Decoder for PacketCodec {
                 let dup = (flags & 0x08) != 0;
                 let retain = (flags & 0x01) != 0;
 
-                let topic_name = super::Utf8StringCodec::default()
+                let topic_name = self
+                    .topic_name_codec()
                     .decode(&mut src)?
                     .ok_or(super::DecodeError::IncompletePacket)?;
 
+                // Ref: 3.3.2.1 Topic Name. Only MQTT 5 allows it to be empty, when the PUBLISH uses a topic alias instead.
+                if topic_name.is_empty() {
+                    return Err(super::DecodeError::EmptyTopicName);
+                }
+
                 let packet_identifier_dup_qos = match (flags & 0x06) >> 1 {
                     0x00 if dup => return Err(super::DecodeError::PublishDupAtMostOnce),
 
@@ -495,7 +832,8 @@ impl tokio::codec::Decoder for PacketCodec {
                 let mut subscribe_to = vec![];
 
                 while !src.is_empty() {
-                    let topic_filter = super::Utf8StringCodec::default()
+                    let topic_filter = self
+                        .topic_name_codec()
                         .decode(&mut src)?
                         .ok_or(super::DecodeError::IncompletePacket)?;
                     let qos = match src.try_get_u8()? {
@@ -538,7 +876,7 @@ impl tokio::codec::Decoder for PacketCodec {
 
                 while !src.is_empty() {
                     unsubscribe_from.push(
-                        super::Utf8StringCodec::default()
+                        self.topic_name_codec()
                             .decode(&mut src)?
                             .ok_or(super::DecodeError::IncompletePacket)?,
                     );
@@ -554,6 +892,14 @@ impl tokio::codec::Decoder for PacketCodec {
                 }))
             }
 
+            (Packet::AUTH, flags, remaining_length) => {
+                Err(super::DecodeError::UnsupportedMqtt5Packet {
+                    packet_type: Packet::AUTH,
+                    flags,
+                    remaining_length,
+                })
+            }
+
             (packet_type, flags, remaining_length) => Err(super::DecodeError::UnrecognizedPacket {
                 packet_type,
                 flags,
@@ -592,10 +938,12 @@ impl tokio::codec::Encoder for PacketCodec {
                 will,
                 client_id,
                 keep_alive,
+                protocol_version,
             } => encode_packet(dst, Packet::CONNECT, |dst| {
-                super::Utf8StringCodec::default().encode("MQTT".to_string(), dst)?;
+                super::Utf8StringCodec::default()
+                    .encode(protocol_version.protocol_name().to_string(), dst)?;
 
-                dst.append_u8(0x04_u8);
+                dst.append_u8(protocol_version.protocol_level());
 
                 {
                     let mut connect_flags = 0x00_u8;
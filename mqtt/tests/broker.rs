@@ -0,0 +1,257 @@
+mod common;
+
+#[test]
+fn publication_is_routed_to_subscriber() {
+    use futures::{Future, Stream};
+
+    let mut runtime =
+        tokio::runtime::current_thread::Runtime::new().expect("couldn't initialize tokio runtime");
+
+    let broker = common::broker::Broker::new();
+
+    let mut subscriber = mqtt::Client::builder(broker.io_source())
+        .client_id("subscriber".to_string())
+        .max_reconnect_back_off(std::time::Duration::from_secs(0))
+        .build()
+        .unwrap();
+    subscriber
+        .subscribe(mqtt::proto::SubscribeTo {
+            topic_filter: "foo/+".to_string(),
+            qos: mqtt::proto::QoS::AtLeastOnce,
+        })
+        .unwrap();
+
+    let publisher = mqtt::Client::builder(broker.io_source())
+        .client_id("publisher".to_string())
+        .max_reconnect_back_off(std::time::Duration::from_secs(0))
+        .build()
+        .unwrap();
+    let mut publish_handle = publisher.publish_handle().unwrap();
+    runtime.spawn(
+        publisher
+            .for_each(|_| Ok(()))
+            .map_err(|err| panic!("{:?}", err)),
+    );
+
+    // Only publish once the subscription is in place, since the broker doesn't retain publications
+    let received = subscriber
+        .filter_map(move |event| match event {
+            mqtt::Event::SubscriptionUpdates(_) => {
+                tokio::runtime::current_thread::spawn(
+                    publish_handle
+                        .publish(mqtt::proto::Publication {
+                            topic_name: "foo/bar".to_string(),
+                            qos: mqtt::proto::QoS::ExactlyOnce,
+                            retain: false,
+                            payload: b"payload".to_vec(),
+                        })
+                        .map_err(|err| panic!("{:?}", err)),
+                );
+                None
+            }
+            mqtt::Event::Publication(publication) => Some(publication),
+            mqtt::Event::Connecting { .. }
+            | mqtt::Event::NewConnection { .. }
+            | mqtt::Event::SubscriptionsSynced
+            | mqtt::Event::Disconnected { .. } => None,
+        })
+        .into_future()
+        .map_err(|(err, _)| panic!("{:?}", err));
+    let received = tokio::timer::Timeout::new(received, std::time::Duration::from_secs(5));
+    let (received, _subscriber) = runtime
+        .block_on(received)
+        .expect("subscriber did not receive publication");
+
+    // The publication was sent with ExactlyOnce but the subscription was only granted AtLeastOnce
+    assert_eq!(
+        received,
+        Some(mqtt::ReceivedPublication {
+            topic_name: "foo/bar".to_string(),
+            dup: false,
+            qos: mqtt::proto::QoS::AtLeastOnce,
+            retain: false,
+            payload: b"payload".to_vec(),
+        })
+    );
+    assert_eq!(
+        broker.subscriptions("subscriber"),
+        vec![("foo/+".to_string(), mqtt::proto::QoS::AtLeastOnce)]
+    );
+}
+
+#[test]
+fn republish_uses_granted_qos() {
+    use futures::{Future, Stream};
+
+    let mut runtime =
+        tokio::runtime::current_thread::Runtime::new().expect("couldn't initialize tokio runtime");
+
+    let broker = common::broker::Broker::new();
+
+    // The bridge receives on in/# and republishes to out/#, but the server only granted it AtMostOnce for out/#
+    let mut bridge = mqtt::Client::builder(broker.io_source())
+        .client_id("bridge".to_string())
+        .max_reconnect_back_off(std::time::Duration::from_secs(0))
+        .build()
+        .unwrap();
+    for (topic_filter, qos) in vec![
+        ("in/#", mqtt::proto::QoS::AtLeastOnce),
+        ("out/#", mqtt::proto::QoS::AtMostOnce),
+    ] {
+        bridge
+            .subscribe(mqtt::proto::SubscribeTo {
+                topic_filter: topic_filter.to_string(),
+                qos,
+            })
+            .unwrap();
+    }
+
+    let mut listener = mqtt::Client::builder(broker.io_source())
+        .client_id("listener".to_string())
+        .max_reconnect_back_off(std::time::Duration::from_secs(0))
+        .build()
+        .unwrap();
+    listener
+        .subscribe(mqtt::proto::SubscribeTo {
+            topic_filter: "out/#".to_string(),
+            qos: mqtt::proto::QoS::ExactlyOnce,
+        })
+        .unwrap();
+
+    let mut published = false;
+    let received = futures::future::poll_fn(|| -> futures::Poll<_, mqtt::Error> {
+        while let futures::Async::Ready(event) = bridge.poll()? {
+            match event.expect("bridge shut down") {
+                mqtt::Event::Publication(publication) => {
+                    if publication.topic_name == "in/foo" {
+                        tokio::runtime::current_thread::spawn(
+                            bridge
+                                .republish(&publication, "out/foo".to_string())
+                                .map_err(|err| panic!("{:?}", err)),
+                        );
+                    }
+                }
+                mqtt::Event::Connecting { .. }
+                | mqtt::Event::NewConnection { .. }
+                | mqtt::Event::SubscriptionUpdates(_)
+                | mqtt::Event::SubscriptionsSynced
+                | mqtt::Event::Disconnected { .. } => (),
+            }
+        }
+
+        while let futures::Async::Ready(event) = listener.poll()? {
+            if let mqtt::Event::Publication(publication) = event.expect("listener shut down") {
+                return Ok(futures::Async::Ready(publication));
+            }
+        }
+
+        if !published
+            && broker.subscriptions("bridge").len() == 2
+            && broker.subscriptions("listener").len() == 1
+        {
+            broker.publish(mqtt::proto::Publication {
+                topic_name: "in/foo".to_string(),
+                qos: mqtt::proto::QoS::AtLeastOnce,
+                retain: false,
+                payload: b"payload".to_vec(),
+            });
+            published = true;
+        }
+
+        Ok(futures::Async::NotReady)
+    });
+    let received = tokio::timer::Timeout::new(received, std::time::Duration::from_secs(5));
+    let received = runtime
+        .block_on(received)
+        .expect("listener did not receive republished publication");
+
+    assert_eq!(
+        received,
+        mqtt::ReceivedPublication {
+            topic_name: "out/foo".to_string(),
+            dup: false,
+            qos: mqtt::proto::QoS::AtMostOnce,
+            retain: false,
+            payload: b"payload".to_vec(),
+        }
+    );
+}
+
+#[test]
+fn retained_publication_is_received_with_retain_flag() {
+    use futures::Stream;
+
+    let mut runtime =
+        tokio::runtime::current_thread::Runtime::new().expect("couldn't initialize tokio runtime");
+
+    let broker = common::broker::Broker::new();
+
+    // Retained before the subscriber exists, so it can only be delivered as the retained message of the topic
+    broker.publish(mqtt::proto::Publication {
+        topic_name: "state/foo".to_string(),
+        qos: mqtt::proto::QoS::AtLeastOnce,
+        retain: true,
+        payload: b"snapshot".to_vec(),
+    });
+
+    let mut subscriber = mqtt::Client::builder(broker.io_source())
+        .client_id("subscriber".to_string())
+        .max_reconnect_back_off(std::time::Duration::from_secs(0))
+        .build()
+        .unwrap();
+    subscriber
+        .subscribe(mqtt::proto::SubscribeTo {
+            topic_filter: "state/#".to_string(),
+            qos: mqtt::proto::QoS::ExactlyOnce,
+        })
+        .unwrap();
+
+    // Once the snapshot has been received, publish a live update. It's delivered without the retain flag
+    // even though it was published with it, since the subscription already exists.
+    let live_broker = broker.clone();
+    let received = subscriber
+        .filter_map(move |event| match event {
+            mqtt::Event::Publication(publication) => {
+                if publication.retain {
+                    live_broker.publish(mqtt::proto::Publication {
+                        topic_name: "state/foo".to_string(),
+                        qos: mqtt::proto::QoS::ExactlyOnce,
+                        retain: true,
+                        payload: b"update".to_vec(),
+                    });
+                }
+                Some(publication)
+            }
+            mqtt::Event::Connecting { .. }
+            | mqtt::Event::NewConnection { .. }
+            | mqtt::Event::SubscriptionUpdates(_)
+            | mqtt::Event::SubscriptionsSynced
+            | mqtt::Event::Disconnected { .. } => None,
+        })
+        .take(2)
+        .collect();
+    let received = tokio::timer::Timeout::new(received, std::time::Duration::from_secs(5));
+    let received = runtime
+        .block_on(received)
+        .expect("subscriber did not receive publications");
+
+    assert_eq!(
+        received,
+        vec![
+            mqtt::ReceivedPublication {
+                topic_name: "state/foo".to_string(),
+                dup: false,
+                qos: mqtt::proto::QoS::AtLeastOnce,
+                retain: true,
+                payload: b"snapshot".to_vec(),
+            },
+            mqtt::ReceivedPublication {
+                topic_name: "state/foo".to_string(),
+                dup: false,
+                qos: mqtt::proto::QoS::ExactlyOnce,
+                retain: false,
+                payload: b"update".to_vec(),
+            },
+        ]
+    );
+}
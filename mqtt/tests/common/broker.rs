@@ -0,0 +1,486 @@
+/// A minimal in-memory MQTT server that `mqtt::Client`s can connect to through [`Broker::io_source`].
+///
+/// Unlike [`super::IoSource`], the broker doesn't need to be told which packets to expect. It implements the subset of MQTT
+/// that the client uses - CONNECT, SUBSCRIBE, UNSUBSCRIBE, PUBLISH in both directions with all QoS levels, PINGREQ and DISCONNECT -
+/// and routes publications between the clients connected to it.
+///
+/// Publications with the retain flag are stored as the retained message of their topic, and delivered with the retain flag
+/// to new matching subscriptions. It doesn't retransmit unacked publications, nor does it queue publications for disconnected clients.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Broker(std::sync::Arc<std::sync::Mutex<State>>);
+
+#[derive(Debug, Default)]
+struct State {
+    sessions: std::collections::HashMap<String, Session>,
+    connections: std::collections::HashMap<usize, ConnectionState>,
+    retained: std::collections::BTreeMap<String, mqtt::proto::Publication>,
+    next_connection_id: usize,
+    next_server_generated_id: usize,
+}
+
+#[derive(Debug)]
+struct Session {
+    clean_session: bool,
+    subscriptions: std::collections::BTreeMap<String, mqtt::proto::QoS>,
+    connection_id: Option<usize>,
+}
+
+#[derive(Debug, Default)]
+struct ConnectionState {
+    client_id: Option<String>,
+    will: Option<mqtt::proto::Publication>,
+    outgoing: bytes::BytesMut,
+    read_task: Option<futures::task::Task>,
+    closed: bool,
+    next_packet_identifier: u16,
+}
+
+impl Broker {
+    pub(crate) fn new() -> Self {
+        Default::default()
+    }
+
+    /// Returns an `mqtt::IoSource` whose connections are served by this broker
+    pub(crate) fn io_source(&self) -> BrokerIoSource {
+        BrokerIoSource(self.clone())
+    }
+
+    /// Publishes a message to all connected clients with a matching subscription, as if another client had published it
+    pub(crate) fn publish(&self, publication: mqtt::proto::Publication) {
+        let mut state = self.0.lock().unwrap();
+        state.route(&publication);
+    }
+
+    /// Returns the topic filters the given client is subscribed to, and their granted QoS
+    pub(crate) fn subscriptions(&self, client_id: &str) -> Vec<(String, mqtt::proto::QoS)> {
+        let state = self.0.lock().unwrap();
+        state
+            .sessions
+            .get(client_id)
+            .map(|session| {
+                session
+                    .subscriptions
+                    .iter()
+                    .map(|(topic_filter, qos)| (topic_filter.clone(), *qos))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Breaks the connection of the given client, as if the network had failed
+    pub(crate) fn disconnect(&self, client_id: &str) {
+        let mut state = self.0.lock().unwrap();
+        if let Some(connection_id) = state
+            .sessions
+            .get(client_id)
+            .and_then(|session| session.connection_id)
+        {
+            state.close(connection_id);
+        }
+    }
+}
+
+impl State {
+    fn handle(&mut self, connection_id: usize, packet: mqtt::proto::Packet) {
+        match packet {
+            mqtt::proto::Packet::Connect {
+                will, client_id, ..
+            } => {
+                let clean_session = client_id.is_clean_session();
+                let client_id = match client_id {
+                    mqtt::proto::ClientId::ServerGenerated => {
+                        self.next_server_generated_id += 1;
+                        format!("server-generated-{}", self.next_server_generated_id)
+                    }
+                    mqtt::proto::ClientId::IdWithCleanSession(id)
+                    | mqtt::proto::ClientId::IdWithExistingSession(id) => id,
+                };
+
+                // Ref: 3.1.4 Response - if the client is already connected, the existing connection is disconnected
+                if let Some(existing_connection_id) = self
+                    .sessions
+                    .get(&client_id)
+                    .and_then(|session| session.connection_id)
+                {
+                    self.close(existing_connection_id);
+                }
+
+                if clean_session {
+                    self.sessions.remove(&client_id);
+                }
+                let session_present = self.sessions.contains_key(&client_id);
+                let session = self
+                    .sessions
+                    .entry(client_id.clone())
+                    .or_insert_with(|| Session {
+                        clean_session,
+                        subscriptions: Default::default(),
+                        connection_id: None,
+                    });
+                session.clean_session = clean_session;
+                session.connection_id = Some(connection_id);
+
+                let connection = self.connection(connection_id);
+                connection.client_id = Some(client_id);
+                connection.will = will;
+                connection.send(mqtt::proto::Packet::ConnAck {
+                    session_present,
+                    return_code: mqtt::proto::ConnectReturnCode::Accepted,
+                });
+            }
+
+            mqtt::proto::Packet::Disconnect => {
+                self.connection(connection_id).will = None;
+                self.close(connection_id);
+            }
+
+            mqtt::proto::Packet::PingReq => {
+                self.connection(connection_id)
+                    .send(mqtt::proto::Packet::PingResp);
+            }
+
+            mqtt::proto::Packet::Publish {
+                packet_identifier_dup_qos,
+                retain,
+                topic_name,
+                payload,
+            } => {
+                let (qos, response) = match packet_identifier_dup_qos {
+                    mqtt::proto::PacketIdentifierDupQoS::AtMostOnce => {
+                        (mqtt::proto::QoS::AtMostOnce, None)
+                    }
+                    mqtt::proto::PacketIdentifierDupQoS::AtLeastOnce(packet_identifier, _) => (
+                        mqtt::proto::QoS::AtLeastOnce,
+                        Some(mqtt::proto::Packet::PubAck { packet_identifier }),
+                    ),
+                    mqtt::proto::PacketIdentifierDupQoS::ExactlyOnce(packet_identifier, _) => (
+                        mqtt::proto::QoS::ExactlyOnce,
+                        Some(mqtt::proto::Packet::PubRec { packet_identifier }),
+                    ),
+                };
+
+                self.route(&mqtt::proto::Publication {
+                    topic_name,
+                    qos,
+                    retain,
+                    payload,
+                });
+
+                if let Some(response) = response {
+                    self.connection(connection_id).send(response);
+                }
+            }
+
+            mqtt::proto::Packet::PubRec { packet_identifier } => {
+                self.connection(connection_id)
+                    .send(mqtt::proto::Packet::PubRel { packet_identifier });
+            }
+
+            mqtt::proto::Packet::PubRel { packet_identifier } => {
+                self.connection(connection_id)
+                    .send(mqtt::proto::Packet::PubComp { packet_identifier });
+            }
+
+            // Publications to clients are not retransmitted, so there's nothing to do when they're acked.
+            mqtt::proto::Packet::PubAck { .. } | mqtt::proto::Packet::PubComp { .. } => (),
+
+            mqtt::proto::Packet::Subscribe {
+                packet_identifier,
+                subscribe_to,
+            } => {
+                // Ref: 3.3.1.3 RETAIN - the retained messages matching a new subscription are sent with the retain flag set
+                let mut retained = vec![];
+                for subscribe_to in &subscribe_to {
+                    for publication in self.retained.values() {
+                        if mqtt::proto::topic_filter_matches(
+                            &subscribe_to.topic_filter,
+                            &publication.topic_name,
+                        ) {
+                            retained.push((
+                                std::cmp::min(subscribe_to.qos, publication.qos),
+                                publication.clone(),
+                            ));
+                        }
+                    }
+                }
+
+                let session = self.session(connection_id);
+                let qos = subscribe_to
+                    .into_iter()
+                    .map(|subscribe_to| {
+                        session
+                            .subscriptions
+                            .insert(subscribe_to.topic_filter, subscribe_to.qos);
+                        mqtt::proto::SubAckQos::Success(subscribe_to.qos)
+                    })
+                    .collect();
+
+                let connection = self.connection(connection_id);
+                connection.send(mqtt::proto::Packet::SubAck {
+                    packet_identifier,
+                    qos,
+                });
+                for (qos, publication) in retained {
+                    connection.deliver(qos, true, &publication);
+                }
+            }
+
+            mqtt::proto::Packet::Unsubscribe {
+                packet_identifier,
+                unsubscribe_from,
+            } => {
+                let session = self.session(connection_id);
+                for topic_filter in unsubscribe_from {
+                    session.subscriptions.remove(&topic_filter);
+                }
+
+                self.connection(connection_id)
+                    .send(mqtt::proto::Packet::UnsubAck { packet_identifier });
+            }
+
+            packet @ mqtt::proto::Packet::ConnAck { .. }
+            | packet @ mqtt::proto::Packet::PingResp
+            | packet @ mqtt::proto::Packet::SubAck { .. }
+            | packet @ mqtt::proto::Packet::UnsubAck { .. } => {
+                panic!("broker received unexpected packet {:?}", packet)
+            }
+        }
+    }
+
+    /// Sends the publication to every connected client with a matching subscription,
+    /// at the lower of the publication's QoS and the subscription's QoS
+    ///
+    /// If the publication has the retain flag, it also replaces the retained message of its topic,
+    /// or clears it if the payload is empty.
+    fn route(&mut self, publication: &mqtt::proto::Publication) {
+        if publication.retain {
+            if publication.payload.is_empty() {
+                self.retained.remove(&publication.topic_name);
+            } else {
+                self.retained
+                    .insert(publication.topic_name.clone(), publication.clone());
+            }
+        }
+
+        let mut deliveries = vec![];
+        for session in self.sessions.values() {
+            let connection_id = match session.connection_id {
+                Some(connection_id) => connection_id,
+                None => continue,
+            };
+
+            let qos = session
+                .subscriptions
+                .iter()
+                .filter(|(topic_filter, _)| {
+                    mqtt::proto::topic_filter_matches(topic_filter, &publication.topic_name)
+                })
+                .map(|(_, qos)| *qos)
+                .max();
+            if let Some(qos) = qos {
+                deliveries.push((connection_id, std::cmp::min(qos, publication.qos)));
+            }
+        }
+
+        // Ref: 3.3.1.3 RETAIN - publications sent to existing subscriptions don't have the retain flag set
+        for (connection_id, qos) in deliveries {
+            self.connection(connection_id)
+                .deliver(qos, false, publication);
+        }
+    }
+
+    /// Closes the connection. The client reads EOF once it has read everything that was sent before the connection was closed.
+    fn close(&mut self, connection_id: usize) {
+        let connection = self.connection(connection_id);
+        connection.closed = true;
+        if let Some(read_task) = connection.read_task.take() {
+            read_task.notify();
+        }
+
+        let client_id = connection.client_id.clone();
+        let will = connection.will.take();
+
+        if let Some(client_id) = client_id {
+            let remove_session = match self.sessions.get_mut(&client_id) {
+                Some(session) if session.connection_id == Some(connection_id) => {
+                    session.connection_id = None;
+                    session.clean_session
+                }
+                _ => false,
+            };
+
+            // Ref: 3.1.2.4 Clean Session - a clean session lasts as long as the network connection
+            if remove_session {
+                self.sessions.remove(&client_id);
+            }
+        }
+
+        if let Some(will) = will {
+            self.route(&will);
+        }
+    }
+
+    fn connection(&mut self, connection_id: usize) -> &mut ConnectionState {
+        self.connections
+            .get_mut(&connection_id)
+            .expect("connection does not exist")
+    }
+
+    fn session(&mut self, connection_id: usize) -> &mut Session {
+        let client_id = self
+            .connection(connection_id)
+            .client_id
+            .clone()
+            .expect("client sent a packet before CONNECT");
+        self.sessions
+            .get_mut(&client_id)
+            .expect("session does not exist")
+    }
+}
+
+impl ConnectionState {
+    fn send(&mut self, packet: mqtt::proto::Packet) {
+        use tokio::codec::Encoder;
+
+        if self.closed {
+            return;
+        }
+
+        let mut packet_codec: mqtt::proto::PacketCodec = Default::default();
+        packet_codec.encode(packet, &mut self.outgoing).unwrap();
+
+        if let Some(read_task) = self.read_task.take() {
+            read_task.notify();
+        }
+    }
+
+    /// Sends the publication to the client at the given QoS
+    fn deliver(
+        &mut self,
+        qos: mqtt::proto::QoS,
+        retain: bool,
+        publication: &mqtt::proto::Publication,
+    ) {
+        let packet_identifier_dup_qos = match qos {
+            mqtt::proto::QoS::AtMostOnce => mqtt::proto::PacketIdentifierDupQoS::AtMostOnce,
+            mqtt::proto::QoS::AtLeastOnce => mqtt::proto::PacketIdentifierDupQoS::AtLeastOnce(
+                self.next_packet_identifier(),
+                false,
+            ),
+            mqtt::proto::QoS::ExactlyOnce => mqtt::proto::PacketIdentifierDupQoS::ExactlyOnce(
+                self.next_packet_identifier(),
+                false,
+            ),
+        };
+        self.send(mqtt::proto::Packet::Publish {
+            packet_identifier_dup_qos,
+            retain,
+            topic_name: publication.topic_name.clone(),
+            payload: publication.payload.clone(),
+        });
+    }
+
+    fn next_packet_identifier(&mut self) -> mqtt::proto::PacketIdentifier {
+        self.next_packet_identifier = self.next_packet_identifier.wrapping_add(1);
+        if self.next_packet_identifier == 0 {
+            self.next_packet_identifier = 1;
+        }
+        mqtt::proto::PacketIdentifier::new(self.next_packet_identifier).unwrap()
+    }
+}
+
+/// The `mqtt::IoSource` returned by [`Broker::io_source`]
+#[derive(Debug)]
+pub(crate) struct BrokerIoSource(Broker);
+
+impl mqtt::IoSource for BrokerIoSource {
+    type Io = BrokerConnection;
+    type Future = futures::future::FutureResult<Self::Io, std::io::Error>;
+
+    fn connect(&mut self) -> Self::Future {
+        let mut state = (self.0).0.lock().unwrap();
+        let connection_id = state.next_connection_id;
+        state.next_connection_id += 1;
+        state.connections.insert(connection_id, Default::default());
+
+        futures::future::ok(BrokerConnection {
+            broker: self.0.clone(),
+            connection_id,
+            incoming: bytes::BytesMut::new(),
+            packet_codec: Default::default(),
+        })
+    }
+}
+
+/// A connection between a client and a [`Broker`]
+#[derive(Debug)]
+pub(crate) struct BrokerConnection {
+    broker: Broker,
+    connection_id: usize,
+    incoming: bytes::BytesMut,
+    packet_codec: mqtt::proto::PacketCodec,
+}
+
+impl Drop for BrokerConnection {
+    fn drop(&mut self) {
+        let mut state = self.broker.0.lock().unwrap();
+        if !state.connection(self.connection_id).closed {
+            state.close(self.connection_id);
+        }
+        state.connections.remove(&self.connection_id);
+    }
+}
+
+impl std::io::Read for BrokerConnection {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut state = self.broker.0.lock().unwrap();
+        let connection = state.connection(self.connection_id);
+
+        if connection.outgoing.is_empty() {
+            if connection.closed {
+                return Ok(0);
+            }
+
+            connection.read_task = Some(futures::task::current());
+            return Err(std::io::ErrorKind::WouldBlock.into());
+        }
+
+        let read = std::cmp::min(buf.len(), connection.outgoing.len());
+        buf[..read].copy_from_slice(&connection.outgoing.split_to(read));
+        Ok(read)
+    }
+}
+
+impl tokio::io::AsyncRead for BrokerConnection {}
+
+impl std::io::Write for BrokerConnection {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        use tokio::codec::Decoder;
+
+        let mut state = self.broker.0.lock().unwrap();
+        if state.connection(self.connection_id).closed {
+            return Err(std::io::ErrorKind::BrokenPipe.into());
+        }
+
+        self.incoming.extend_from_slice(buf);
+        while let Some(packet) = self
+            .packet_codec
+            .decode(&mut self.incoming)
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?
+        {
+            state.handle(self.connection_id, packet);
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl tokio::io::AsyncWrite for BrokerConnection {
+    fn shutdown(&mut self) -> futures::Poll<(), std::io::Error> {
+        Ok(futures::Async::Ready(()))
+    }
+}
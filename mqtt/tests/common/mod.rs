@@ -1,5 +1,10 @@
+// Each test crate that includes `common` only uses some of its helpers.
+#![allow(dead_code)]
+
 use futures::{Future, Stream};
 
+pub(crate) mod broker;
+
 pub(crate) fn verify_client_events(
     runtime: &mut tokio::runtime::current_thread::Runtime,
     client: mqtt::Client<IoSource>,
@@ -57,6 +62,8 @@ impl IoSource {
                         packet_codec.encode(packet.clone(), &mut bytes).unwrap();
                         TestConnectionStep::Sends((packet, std::io::Cursor::new(bytes)))
                     }
+
+                    TestConnectionStep::StaysIdle => TestConnectionStep::StaysIdle,
                 })
                 .collect();
 
@@ -118,11 +125,34 @@ pub(crate) struct TestConnection {
     done_send: Option<futures::sync::oneshot::Sender<()>>,
 }
 
+impl Drop for TestConnection {
+    fn drop(&mut self) {
+        // The client may drop the connection without any further reads or writes after the last step, such as when it shuts down.
+        // This still counts as the connection's steps having been used up completely.
+        //
+        // Similarly, a connection that's idling at its last step is expected to be dropped by the client.
+        let steps_used_up = match self.steps.front() {
+            None => true,
+            Some(TestConnectionStep::StaysIdle) => self.steps.len() == 1,
+            Some(_) => false,
+        };
+        if steps_used_up {
+            if let Some(done_send) = self.done_send.take() {
+                let _ = done_send.send(());
+            }
+        }
+    }
+}
+
 /// A single step in the connection between a client and a server
 #[derive(Debug)]
 pub(crate) enum TestConnectionStep<TReceives, TSends> {
     Receives(TReceives),
     Sends(TSends),
+
+    /// The server neither sends nor expects anything more, and waits for the client to drop the connection.
+    /// This must be the last step of the connection.
+    StaysIdle,
 }
 
 impl std::io::Read for TestConnection {
@@ -137,6 +167,11 @@ impl std::io::Read for TestConnection {
                 return Err(std::io::ErrorKind::WouldBlock.into());
             }
 
+            Some(TestConnectionStep::StaysIdle) => {
+                println!("client is reading from server but server is idle");
+                return Err(std::io::ErrorKind::WouldBlock.into());
+            }
+
             Some(TestConnectionStep::Sends((packet, cursor))) => {
                 println!("server sends {:?}", packet);
                 let read = cursor.read(buf)?;
@@ -201,6 +236,10 @@ impl std::io::Write for TestConnection {
                 return Err(std::io::ErrorKind::WouldBlock.into());
             }
 
+            Some(TestConnectionStep::StaysIdle) => {
+                panic!("client wrote to server but server expected the connection to stay idle")
+            }
+
             None => {
                 if let Some(done_send) = self.done_send.take() {
                     done_send.send(()).unwrap();
@@ -13,6 +13,7 @@ fn server_generated_id_can_connect_and_idle() {
                 will: None,
                 client_id: mqtt::proto::ClientId::ServerGenerated,
                 keep_alive: std::time::Duration::from_secs(4),
+                protocol_version: mqtt::proto::ProtocolVersion::V3_1_1,
             }),
             common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck {
                 session_present: false,
@@ -30,6 +31,7 @@ fn server_generated_id_can_connect_and_idle() {
                 will: None,
                 client_id: mqtt::proto::ClientId::ServerGenerated,
                 keep_alive: std::time::Duration::from_secs(4),
+                protocol_version: mqtt::proto::ProtocolVersion::V3_1_1,
             }),
             common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck {
                 session_present: false,
@@ -50,7 +52,8 @@ fn server_generated_id_can_connect_and_idle() {
         io_source,
         std::time::Duration::from_secs(0),
         std::time::Duration::from_secs(4),
-    );
+    )
+    .unwrap();
 
     common::verify_client_events(
         &mut runtime,
@@ -58,9 +61,11 @@ fn server_generated_id_can_connect_and_idle() {
         vec![
             mqtt::Event::NewConnection {
                 reset_session: true,
+                retransmitted_publications: 0,
             },
             mqtt::Event::NewConnection {
                 reset_session: true,
+                retransmitted_publications: 0,
             },
         ],
     );
@@ -83,6 +88,7 @@ fn client_id_can_connect_and_idle() {
                 will: None,
                 client_id: mqtt::proto::ClientId::IdWithCleanSession("idle_client_id".to_string()),
                 keep_alive: std::time::Duration::from_secs(4),
+                protocol_version: mqtt::proto::ProtocolVersion::V3_1_1,
             }),
             common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck {
                 session_present: false,
@@ -102,6 +108,7 @@ fn client_id_can_connect_and_idle() {
                     "idle_client_id".to_string(),
                 ),
                 keep_alive: std::time::Duration::from_secs(4),
+                protocol_version: mqtt::proto::ProtocolVersion::V3_1_1,
             }),
             common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck {
                 // The clean session bit also determines if the *current* session should be persisted.
@@ -124,6 +131,7 @@ fn client_id_can_connect_and_idle() {
                     "idle_client_id".to_string(),
                 ),
                 keep_alive: std::time::Duration::from_secs(4),
+                protocol_version: mqtt::proto::ProtocolVersion::V3_1_1,
             }),
             common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck {
                 session_present: true,
@@ -144,7 +152,8 @@ fn client_id_can_connect_and_idle() {
         io_source,
         std::time::Duration::from_secs(0),
         std::time::Duration::from_secs(4),
-    );
+    )
+    .unwrap();
 
     common::verify_client_events(
         &mut runtime,
@@ -152,12 +161,257 @@ fn client_id_can_connect_and_idle() {
         vec![
             mqtt::Event::NewConnection {
                 reset_session: true,
+                retransmitted_publications: 0,
             },
             mqtt::Event::NewConnection {
                 reset_session: true,
+                retransmitted_publications: 0,
             },
             mqtt::Event::NewConnection {
                 reset_session: false,
+                retransmitted_publications: 0,
+            },
+        ],
+    );
+
+    runtime
+        .block_on(done)
+        .expect("connection broken while there were still steps remaining on the server");
+}
+
+#[test]
+fn builder_can_resume_existing_session() {
+    let mut runtime =
+        tokio::runtime::current_thread::Runtime::new().expect("couldn't initialize tokio runtime");
+
+    let (io_source, done) = common::IoSource::new(vec![vec![
+        common::TestConnectionStep::Receives(mqtt::proto::Packet::Connect {
+            username: Some("username".to_string()),
+            password: Some("password".to_string()),
+            will: None,
+            client_id: mqtt::proto::ClientId::IdWithExistingSession("idle_client_id".to_string()),
+            keep_alive: std::time::Duration::from_secs(4),
+            protocol_version: mqtt::proto::ProtocolVersion::V3_1_1,
+        }),
+        common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck {
+            session_present: true,
+            return_code: mqtt::proto::ConnectReturnCode::Accepted,
+        }),
+        common::TestConnectionStep::Receives(mqtt::proto::Packet::PingReq),
+        common::TestConnectionStep::Sends(mqtt::proto::Packet::PingResp),
+    ]]);
+
+    let client = mqtt::Client::builder(io_source)
+        .client_id("idle_client_id".to_string())
+        .clean_session(false)
+        .username("username".to_string())
+        .password("password".to_string())
+        .max_reconnect_back_off(std::time::Duration::from_secs(0))
+        .keep_alive(std::time::Duration::from_secs(4))
+        .build()
+        .unwrap();
+
+    common::verify_client_events(
+        &mut runtime,
+        client,
+        vec![mqtt::Event::NewConnection {
+            reset_session: false,
+            retransmitted_publications: 0,
+        }],
+    );
+
+    runtime
+        .block_on(done)
+        .expect("connection broken while there were still steps remaining on the server");
+}
+
+#[test]
+fn password_callback_is_called_for_every_connection() {
+    let mut runtime =
+        tokio::runtime::current_thread::Runtime::new().expect("couldn't initialize tokio runtime");
+
+    let (io_source, done) = common::IoSource::new(vec![
+        vec![
+            common::TestConnectionStep::Receives(mqtt::proto::Packet::Connect {
+                username: Some("username".to_string()),
+                password: Some("token1".to_string()),
+                will: None,
+                client_id: mqtt::proto::ClientId::ServerGenerated,
+                keep_alive: std::time::Duration::from_secs(4),
+                protocol_version: mqtt::proto::ProtocolVersion::V3_1_1,
+            }),
+            common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck {
+                session_present: false,
+                return_code: mqtt::proto::ConnectReturnCode::Accepted,
+            }),
+            common::TestConnectionStep::Receives(mqtt::proto::Packet::PingReq),
+            common::TestConnectionStep::Sends(mqtt::proto::Packet::PingResp),
+        ],
+        vec![
+            common::TestConnectionStep::Receives(mqtt::proto::Packet::Connect {
+                username: Some("username".to_string()),
+                password: Some("token2".to_string()),
+                will: None,
+                client_id: mqtt::proto::ClientId::ServerGenerated,
+                keep_alive: std::time::Duration::from_secs(4),
+                protocol_version: mqtt::proto::ProtocolVersion::V3_1_1,
+            }),
+            common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck {
+                session_present: false,
+                return_code: mqtt::proto::ConnectReturnCode::Accepted,
+            }),
+            common::TestConnectionStep::Receives(mqtt::proto::Packet::PingReq),
+            common::TestConnectionStep::Sends(mqtt::proto::Packet::PingResp),
+        ],
+    ]);
+
+    let mut tokens = 0;
+    let client = mqtt::Client::builder(io_source)
+        .username("username".to_string())
+        .password("password".to_string())
+        .password_callback(move || {
+            tokens += 1;
+            Some(format!("token{}", tokens))
+        })
+        .max_reconnect_back_off(std::time::Duration::from_secs(0))
+        .keep_alive(std::time::Duration::from_secs(4))
+        .build()
+        .unwrap();
+
+    common::verify_client_events(
+        &mut runtime,
+        client,
+        vec![
+            mqtt::Event::NewConnection {
+                reset_session: true,
+                retransmitted_publications: 0,
+            },
+            mqtt::Event::NewConnection {
+                reset_session: true,
+                retransmitted_publications: 0,
+            },
+        ],
+    );
+
+    runtime
+        .block_on(done)
+        .expect("connection broken while there were still steps remaining on the server");
+}
+
+#[test]
+fn existing_session_is_reset_if_server_does_not_have_it() {
+    let mut runtime =
+        tokio::runtime::current_thread::Runtime::new().expect("couldn't initialize tokio runtime");
+
+    let (io_source, done) = common::IoSource::new(vec![vec![
+        common::TestConnectionStep::Receives(mqtt::proto::Packet::Connect {
+            username: None,
+            password: None,
+            will: None,
+            client_id: mqtt::proto::ClientId::IdWithExistingSession("idle_client_id".to_string()),
+            keep_alive: std::time::Duration::from_secs(4),
+            protocol_version: mqtt::proto::ProtocolVersion::V3_1_1,
+        }),
+        common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck {
+            session_present: false,
+            return_code: mqtt::proto::ConnectReturnCode::Accepted,
+        }),
+        common::TestConnectionStep::Receives(mqtt::proto::Packet::PingReq),
+        common::TestConnectionStep::Sends(mqtt::proto::Packet::PingResp),
+    ]]);
+
+    let client = mqtt::Client::with_existing_session(
+        "idle_client_id".to_string(),
+        None,
+        None,
+        None,
+        io_source,
+        std::time::Duration::from_secs(0),
+        std::time::Duration::from_secs(4),
+    )
+    .unwrap();
+
+    common::verify_client_events(
+        &mut runtime,
+        client,
+        vec![mqtt::Event::NewConnection {
+            reset_session: true,
+            retransmitted_publications: 0,
+        }],
+    );
+
+    runtime
+        .block_on(done)
+        .expect("connection broken while there were still steps remaining on the server");
+}
+
+#[test]
+fn connection_attempts_are_reported() {
+    let mut runtime =
+        tokio::runtime::current_thread::Runtime::new().expect("couldn't initialize tokio runtime");
+
+    let (io_source, done) = common::IoSource::new(vec![
+        vec![
+            common::TestConnectionStep::Receives(mqtt::proto::Packet::Connect {
+                username: None,
+                password: None,
+                will: None,
+                client_id: mqtt::proto::ClientId::ServerGenerated,
+                keep_alive: std::time::Duration::from_secs(4),
+                protocol_version: mqtt::proto::ProtocolVersion::V3_1_1,
+            }),
+            common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck {
+                session_present: false,
+                return_code: mqtt::proto::ConnectReturnCode::Refused(
+                    mqtt::proto::ConnectionRefusedReason::ServerUnavailable,
+                ),
+            }),
+        ],
+        vec![
+            common::TestConnectionStep::Receives(mqtt::proto::Packet::Connect {
+                username: None,
+                password: None,
+                will: None,
+                client_id: mqtt::proto::ClientId::ServerGenerated,
+                keep_alive: std::time::Duration::from_secs(4),
+                protocol_version: mqtt::proto::ProtocolVersion::V3_1_1,
+            }),
+            common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck {
+                session_present: false,
+                return_code: mqtt::proto::ConnectReturnCode::Accepted,
+            }),
+            common::TestConnectionStep::Receives(mqtt::proto::Packet::PingReq),
+            common::TestConnectionStep::Sends(mqtt::proto::Packet::PingResp),
+        ],
+    ]);
+
+    let client = mqtt::Client::builder(io_source)
+        .max_reconnect_back_off(std::time::Duration::from_secs(0))
+        .keep_alive(std::time::Duration::from_secs(4))
+        .report_connection_attempts(true)
+        .build()
+        .unwrap();
+
+    common::verify_client_events(
+        &mut runtime,
+        client,
+        vec![
+            mqtt::Event::Connecting {
+                attempt: 1,
+                back_off: std::time::Duration::from_secs(0),
+            },
+            mqtt::Event::Connecting {
+                attempt: 2,
+                back_off: std::time::Duration::from_secs(1),
+            },
+            mqtt::Event::NewConnection {
+                reset_session: true,
+                retransmitted_publications: 0,
+            },
+            // The count starts over once the server drops the successful connection
+            mqtt::Event::Connecting {
+                attempt: 1,
+                back_off: std::time::Duration::from_secs(1),
             },
         ],
     );
@@ -166,3 +420,2088 @@ fn client_id_can_connect_and_idle() {
         .block_on(done)
         .expect("connection broken while there were still steps remaining on the server");
 }
+
+#[test]
+fn failed_first_connection_is_retried_immediately_then_backs_off() {
+    use futures::Stream;
+
+    let mut runtime =
+        tokio::runtime::current_thread::Runtime::new().expect("couldn't initialize tokio runtime");
+
+    let refused_connection = || {
+        vec![
+            common::TestConnectionStep::Receives(mqtt::proto::Packet::Connect {
+                username: None,
+                password: None,
+                will: None,
+                client_id: mqtt::proto::ClientId::ServerGenerated,
+                keep_alive: std::time::Duration::from_secs(4),
+                protocol_version: mqtt::proto::ProtocolVersion::V3_1_1,
+            }),
+            common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck {
+                session_present: false,
+                return_code: mqtt::proto::ConnectReturnCode::Refused(
+                    mqtt::proto::ConnectionRefusedReason::ServerUnavailable,
+                ),
+            }),
+        ]
+    };
+
+    let (io_source, done) = common::IoSource::new(vec![
+        refused_connection(),
+        refused_connection(),
+        vec![
+            common::TestConnectionStep::Receives(mqtt::proto::Packet::Connect {
+                username: None,
+                password: None,
+                will: None,
+                client_id: mqtt::proto::ClientId::ServerGenerated,
+                keep_alive: std::time::Duration::from_secs(4),
+                protocol_version: mqtt::proto::ProtocolVersion::V3_1_1,
+            }),
+            common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck {
+                session_present: false,
+                return_code: mqtt::proto::ConnectReturnCode::Accepted,
+            }),
+            common::TestConnectionStep::Receives(mqtt::proto::Packet::Disconnect),
+        ],
+    ]);
+
+    let mut client = mqtt::Client::builder(io_source)
+        .max_reconnect_back_off(std::time::Duration::from_secs(30))
+        .keep_alive(std::time::Duration::from_secs(4))
+        .report_connection_attempts(true)
+        .build()
+        .unwrap();
+
+    let mut attempts = vec![];
+    let connected = futures::future::poll_fn(|| -> futures::Poll<_, mqtt::Error> {
+        loop {
+            match client.poll()? {
+                futures::Async::Ready(Some(mqtt::Event::Connecting { .. })) => {
+                    attempts.push(std::time::Instant::now())
+                }
+                futures::Async::Ready(Some(mqtt::Event::NewConnection { .. })) => {
+                    return Ok(futures::Async::Ready(()));
+                }
+                futures::Async::Ready(event) => panic!("{:?}", event),
+                futures::Async::NotReady => return Ok(futures::Async::NotReady),
+            }
+        }
+    });
+    let connected = tokio::timer::Timeout::new(connected, std::time::Duration::from_secs(5));
+    runtime
+        .block_on(connected)
+        .unwrap_or_else(|err| panic!("client did not connect: {:?}", err));
+
+    assert_eq!(attempts.len(), 3);
+
+    // The failed first attempt is retried immediately, like a lost connection is
+    assert!(attempts[1] - attempts[0] < std::time::Duration::from_millis(500));
+
+    // The second failure backs off for a second
+    assert!(attempts[2] - attempts[1] >= std::time::Duration::from_secs(1));
+
+    drop(client);
+
+    runtime
+        .block_on(done)
+        .expect("connection broken while there were still steps remaining on the server");
+}
+
+#[test]
+fn dropped_client_sends_disconnect() {
+    use futures::{Future, Stream};
+
+    let mut runtime =
+        tokio::runtime::current_thread::Runtime::new().expect("couldn't initialize tokio runtime");
+
+    let (io_source, done) = common::IoSource::new(vec![vec![
+        common::TestConnectionStep::Receives(mqtt::proto::Packet::Connect {
+            username: None,
+            password: None,
+            will: None,
+            client_id: mqtt::proto::ClientId::ServerGenerated,
+            keep_alive: std::time::Duration::from_secs(4),
+            protocol_version: mqtt::proto::ProtocolVersion::V3_1_1,
+        }),
+        common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck {
+            session_present: false,
+            return_code: mqtt::proto::ConnectReturnCode::Accepted,
+        }),
+        common::TestConnectionStep::Receives(mqtt::proto::Packet::Disconnect),
+    ]]);
+
+    let client = mqtt::Client::new(
+        None,
+        None,
+        None,
+        None,
+        io_source,
+        std::time::Duration::from_secs(0),
+        std::time::Duration::from_secs(4),
+    )
+    .unwrap();
+
+    // Drop the client as soon as it has connected
+    runtime.spawn(
+        client
+            .into_future()
+            .map(|(event, client)| {
+                assert_eq!(
+                    event,
+                    Some(mqtt::Event::NewConnection {
+                        reset_session: true,
+                        retransmitted_publications: 0,
+                    })
+                );
+                drop(client);
+            })
+            .map_err(|(err, _)| panic!("{:?}", err)),
+    );
+
+    runtime
+        .block_on(done)
+        .expect("connection broken while there were still steps remaining on the server");
+}
+
+#[test]
+fn ping_interval_fraction_is_configurable() {
+    let mut runtime =
+        tokio::runtime::current_thread::Runtime::new().expect("couldn't initialize tokio runtime");
+
+    for &invalid in &[0.0, -0.5, 1.5, std::f64::NAN] {
+        let (io_source, _) = common::IoSource::new(vec![]);
+        match mqtt::Client::builder(io_source)
+            .ping_interval_fraction(invalid)
+            .build()
+        {
+            Err(mqtt::CreateClientError::InvalidPingIntervalFraction(_)) => (),
+            result => panic!("{:?}", result.map(|_| ())),
+        }
+    }
+
+    // Pinging at the full keep-alive instead of half of it
+    let (io_source, done) = common::IoSource::new(vec![vec![
+        common::TestConnectionStep::Receives(mqtt::proto::Packet::Connect {
+            username: None,
+            password: None,
+            will: None,
+            client_id: mqtt::proto::ClientId::ServerGenerated,
+            keep_alive: std::time::Duration::from_secs(2),
+            protocol_version: mqtt::proto::ProtocolVersion::V3_1_1,
+        }),
+        common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck {
+            session_present: false,
+            return_code: mqtt::proto::ConnectReturnCode::Accepted,
+        }),
+        common::TestConnectionStep::Receives(mqtt::proto::Packet::PingReq),
+        common::TestConnectionStep::Sends(mqtt::proto::Packet::PingResp),
+    ]]);
+
+    let client = mqtt::Client::builder(io_source)
+        .max_reconnect_back_off(std::time::Duration::from_secs(0))
+        .keep_alive(std::time::Duration::from_secs(2))
+        .ping_interval_fraction(1.0)
+        .build()
+        .unwrap();
+
+    common::verify_client_events(
+        &mut runtime,
+        client,
+        vec![mqtt::Event::NewConnection {
+            reset_session: true,
+            retransmitted_publications: 0,
+        }],
+    );
+
+    let started = std::time::Instant::now();
+    runtime
+        .block_on(done)
+        .expect("connection broken while there were still steps remaining on the server");
+    assert!(started.elapsed() >= std::time::Duration::from_secs(2));
+}
+
+#[test]
+fn ping_round_trip_is_measured() {
+    use futures::Stream;
+
+    let mut runtime =
+        tokio::runtime::current_thread::Runtime::new().expect("couldn't initialize tokio runtime");
+
+    let (io_source, done) = common::IoSource::new(vec![vec![
+        common::TestConnectionStep::Receives(mqtt::proto::Packet::Connect {
+            username: None,
+            password: None,
+            will: None,
+            client_id: mqtt::proto::ClientId::ServerGenerated,
+            keep_alive: std::time::Duration::from_secs(2),
+            protocol_version: mqtt::proto::ProtocolVersion::V3_1_1,
+        }),
+        common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck {
+            session_present: false,
+            return_code: mqtt::proto::ConnectReturnCode::Accepted,
+        }),
+        common::TestConnectionStep::Receives(mqtt::proto::Packet::PingReq),
+        common::TestConnectionStep::Sends(mqtt::proto::Packet::PingResp),
+        common::TestConnectionStep::Receives(mqtt::proto::Packet::Disconnect),
+    ]]);
+
+    let mut client = mqtt::Client::builder(io_source)
+        .max_reconnect_back_off(std::time::Duration::from_secs(0))
+        .keep_alive(std::time::Duration::from_secs(2))
+        .build()
+        .unwrap();
+
+    assert_eq!(client.last_ping_rtt(), None);
+
+    let last_ping_rtt = futures::future::poll_fn(|| -> futures::Poll<_, mqtt::Error> {
+        loop {
+            if let Some(last_ping_rtt) = client.last_ping_rtt() {
+                return Ok(futures::Async::Ready(last_ping_rtt));
+            }
+
+            match client.poll()? {
+                futures::Async::Ready(Some(mqtt::Event::NewConnection { .. })) => (),
+                futures::Async::Ready(event) => panic!("{:?}", event),
+                futures::Async::NotReady => return Ok(futures::Async::NotReady),
+            }
+        }
+    });
+    let last_ping_rtt =
+        tokio::timer::Timeout::new(last_ping_rtt, std::time::Duration::from_secs(5));
+    let last_ping_rtt = runtime
+        .block_on(last_ping_rtt)
+        .unwrap_or_else(|err| panic!("ping round-trip was not measured: {:?}", err));
+    assert!(last_ping_rtt < std::time::Duration::from_secs(1));
+
+    drop(client);
+
+    runtime
+        .block_on(done)
+        .expect("connection broken while there were still steps remaining on the server");
+}
+
+#[test]
+fn hung_connection_attempt_times_out() {
+    use futures::{Future, Stream};
+
+    let mut runtime =
+        tokio::runtime::current_thread::Runtime::new().expect("couldn't initialize tokio runtime");
+
+    let (mut io_source, done) = common::IoSource::new(vec![vec![
+        common::TestConnectionStep::Receives(mqtt::proto::Packet::Connect {
+            username: None,
+            password: None,
+            will: None,
+            client_id: mqtt::proto::ClientId::ServerGenerated,
+            keep_alive: std::time::Duration::from_secs(4),
+            protocol_version: mqtt::proto::ProtocolVersion::V3_1_1,
+        }),
+        common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck {
+            session_present: false,
+            return_code: mqtt::proto::ConnectReturnCode::Accepted,
+        }),
+    ]]);
+
+    // The first connection attempt never completes
+    let mut hang = true;
+    let io_source = move || -> Box<dyn Future<Item = _, Error = _> + Send> {
+        if std::mem::replace(&mut hang, false) {
+            Box::new(futures::future::empty())
+        } else {
+            mqtt::IoSource::connect(&mut io_source)
+        }
+    };
+
+    let client = mqtt::Client::builder(io_source)
+        .max_reconnect_back_off(std::time::Duration::from_secs(0))
+        .keep_alive(std::time::Duration::from_secs(4))
+        .connect_timeout(Some(std::time::Duration::from_secs(1)))
+        .build()
+        .unwrap();
+
+    let event = tokio::timer::Timeout::new(client.into_future(), std::time::Duration::from_secs(5))
+        .map(|(event, _client)| event);
+    let event = runtime
+        .block_on(event)
+        .unwrap_or_else(|_| panic!("client did not connect"));
+    assert_eq!(
+        event,
+        Some(mqtt::Event::NewConnection {
+            reset_session: true,
+            retransmitted_publications: 0,
+        })
+    );
+
+    runtime
+        .block_on(done)
+        .expect("connection broken while there were still steps remaining on the server");
+}
+
+#[test]
+fn reconnect_policy_can_give_up() {
+    use futures::Stream;
+
+    #[derive(Debug)]
+    struct NoReconnect;
+
+    impl mqtt::ReconnectPolicy for NoReconnect {
+        fn next_backoff(
+            &mut self,
+            _attempt: u32,
+            _last: std::time::Duration,
+        ) -> Option<std::time::Duration> {
+            None
+        }
+    }
+
+    let mut runtime =
+        tokio::runtime::current_thread::Runtime::new().expect("couldn't initialize tokio runtime");
+
+    let (io_source, done) = common::IoSource::new(vec![vec![
+        common::TestConnectionStep::Receives(mqtt::proto::Packet::Connect {
+            username: None,
+            password: None,
+            will: None,
+            client_id: mqtt::proto::ClientId::ServerGenerated,
+            keep_alive: std::time::Duration::from_secs(4),
+            protocol_version: mqtt::proto::ProtocolVersion::V3_1_1,
+        }),
+        common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck {
+            session_present: false,
+            return_code: mqtt::proto::ConnectReturnCode::Refused(
+                mqtt::proto::ConnectionRefusedReason::ServerUnavailable,
+            ),
+        }),
+    ]]);
+
+    let client = mqtt::Client::builder(io_source)
+        .keep_alive(std::time::Duration::from_secs(4))
+        .reconnect_policy(NoReconnect)
+        .build()
+        .unwrap();
+
+    match runtime.block_on(client.collect()) {
+        Err(mqtt::Error::ReconnectPolicyGaveUp) => (),
+        result => panic!("expected client to give up reconnecting, got {:?}", result),
+    }
+
+    runtime
+        .block_on(done)
+        .expect("connection broken while there were still steps remaining on the server");
+}
+
+#[test]
+fn session_present_for_clean_session_is_rejected() {
+    let mut runtime =
+        tokio::runtime::current_thread::Runtime::new().expect("couldn't initialize tokio runtime");
+
+    let (io_source, done) = common::IoSource::new(vec![
+        vec![
+            common::TestConnectionStep::Receives(mqtt::proto::Packet::Connect {
+                username: None,
+                password: None,
+                will: None,
+                client_id: mqtt::proto::ClientId::IdWithCleanSession("idle_client_id".to_string()),
+                keep_alive: std::time::Duration::from_secs(4),
+                protocol_version: mqtt::proto::ProtocolVersion::V3_1_1,
+            }),
+            common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck {
+                session_present: true,
+                return_code: mqtt::proto::ConnectReturnCode::Accepted,
+            }),
+        ],
+        vec![
+            common::TestConnectionStep::Receives(mqtt::proto::Packet::Connect {
+                username: None,
+                password: None,
+                will: None,
+                client_id: mqtt::proto::ClientId::IdWithCleanSession("idle_client_id".to_string()),
+                keep_alive: std::time::Duration::from_secs(4),
+                protocol_version: mqtt::proto::ProtocolVersion::V3_1_1,
+            }),
+            common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck {
+                session_present: false,
+                return_code: mqtt::proto::ConnectReturnCode::Accepted,
+            }),
+            common::TestConnectionStep::Receives(mqtt::proto::Packet::PingReq),
+            common::TestConnectionStep::Sends(mqtt::proto::Packet::PingResp),
+        ],
+    ]);
+
+    let client = mqtt::Client::new(
+        Some("idle_client_id".to_string()),
+        None,
+        None,
+        None,
+        io_source,
+        std::time::Duration::from_secs(0),
+        std::time::Duration::from_secs(4),
+    )
+    .unwrap();
+
+    common::verify_client_events(
+        &mut runtime,
+        client,
+        vec![mqtt::Event::NewConnection {
+            reset_session: true,
+            retransmitted_publications: 0,
+        }],
+    );
+
+    runtime
+        .block_on(done)
+        .expect("connection broken while there were still steps remaining on the server");
+}
+
+#[test]
+fn unexpected_packet_causes_reconnect() {
+    let mut runtime =
+        tokio::runtime::current_thread::Runtime::new().expect("couldn't initialize tokio runtime");
+
+    let (io_source, done) = common::IoSource::new(vec![
+        vec![
+            common::TestConnectionStep::Receives(mqtt::proto::Packet::Connect {
+                username: None,
+                password: None,
+                will: None,
+                client_id: mqtt::proto::ClientId::ServerGenerated,
+                keep_alive: std::time::Duration::from_secs(4),
+                protocol_version: mqtt::proto::ProtocolVersion::V3_1_1,
+            }),
+            common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck {
+                session_present: false,
+                return_code: mqtt::proto::ConnectReturnCode::Accepted,
+            }),
+            // Only clients send SUBSCRIBE, so the client should drop the connection rather than panic
+            common::TestConnectionStep::Sends(mqtt::proto::Packet::Subscribe {
+                packet_identifier: mqtt::proto::PacketIdentifier::new(1).unwrap(),
+                subscribe_to: vec![mqtt::proto::SubscribeTo {
+                    topic_filter: "topic1".to_string(),
+                    qos: mqtt::proto::QoS::AtMostOnce,
+                }],
+            }),
+        ],
+        vec![
+            common::TestConnectionStep::Receives(mqtt::proto::Packet::Connect {
+                username: None,
+                password: None,
+                will: None,
+                client_id: mqtt::proto::ClientId::ServerGenerated,
+                keep_alive: std::time::Duration::from_secs(4),
+                protocol_version: mqtt::proto::ProtocolVersion::V3_1_1,
+            }),
+            common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck {
+                session_present: false,
+                return_code: mqtt::proto::ConnectReturnCode::Accepted,
+            }),
+            common::TestConnectionStep::Receives(mqtt::proto::Packet::PingReq),
+            common::TestConnectionStep::Sends(mqtt::proto::Packet::PingResp),
+        ],
+    ]);
+
+    let client = mqtt::Client::new(
+        None,
+        None,
+        None,
+        None,
+        io_source,
+        std::time::Duration::from_secs(0),
+        std::time::Duration::from_secs(4),
+    )
+    .unwrap();
+
+    common::verify_client_events(
+        &mut runtime,
+        client,
+        vec![
+            mqtt::Event::NewConnection {
+                reset_session: true,
+                retransmitted_publications: 0,
+            },
+            mqtt::Event::NewConnection {
+                reset_session: true,
+                retransmitted_publications: 0,
+            },
+        ],
+    );
+
+    runtime
+        .block_on(done)
+        .expect("connection broken while there were still steps remaining on the server");
+}
+
+#[test]
+fn keyed_publications_can_be_cancelled_until_sent() {
+    use futures::{Future, Stream};
+
+    let mut runtime =
+        tokio::runtime::current_thread::Runtime::new().expect("couldn't initialize tokio runtime");
+
+    let (io_source, done) = common::IoSource::new(vec![vec![
+        common::TestConnectionStep::Receives(mqtt::proto::Packet::Connect {
+            username: None,
+            password: None,
+            will: None,
+            client_id: mqtt::proto::ClientId::ServerGenerated,
+            keep_alive: std::time::Duration::from_secs(4),
+            protocol_version: mqtt::proto::ProtocolVersion::V3_1_1,
+        }),
+        common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck {
+            session_present: false,
+            return_code: mqtt::proto::ConnectReturnCode::Accepted,
+        }),
+        common::TestConnectionStep::Receives(mqtt::proto::Packet::Publish {
+            packet_identifier_dup_qos: mqtt::proto::PacketIdentifierDupQoS::AtLeastOnce(
+                mqtt::proto::PacketIdentifier::new(1).unwrap(),
+                false,
+            ),
+            retain: false,
+            topic_name: "topic2".to_string(),
+            payload: b"payload2".to_vec(),
+        }),
+        common::TestConnectionStep::Sends(mqtt::proto::Packet::PubAck {
+            packet_identifier: mqtt::proto::PacketIdentifier::new(1).unwrap(),
+        }),
+    ]]);
+
+    let client = mqtt::Client::new(
+        None,
+        None,
+        None,
+        None,
+        io_source,
+        std::time::Duration::from_secs(0),
+        std::time::Duration::from_secs(4),
+    )
+    .unwrap();
+
+    let mut publish_handle = client.publish_handle().unwrap();
+    let publish1 = publish_handle.publish_keyed(
+        "key1".to_string(),
+        mqtt::proto::Publication {
+            topic_name: "topic1".to_string(),
+            qos: mqtt::proto::QoS::AtLeastOnce,
+            retain: false,
+            payload: b"payload1".to_vec(),
+        },
+    );
+    let publish2 = publish_handle.publish_keyed(
+        "key2".to_string(),
+        mqtt::proto::Publication {
+            topic_name: "topic2".to_string(),
+            qos: mqtt::proto::QoS::AtLeastOnce,
+            retain: false,
+            payload: b"payload2".to_vec(),
+        },
+    );
+
+    // The client hasn't connected yet, so neither publication has been sent
+    assert_eq!(publish_handle.cancel("key1"), 1);
+    assert_eq!(publish_handle.cancel("key3"), 0);
+
+    runtime.spawn(
+        client
+            .for_each(|_| Ok(()))
+            .map_err(|err| panic!("{:?}", err)),
+    );
+
+    match runtime.block_on(publish1) {
+        Err(mqtt::PublishError::Cancelled) => (),
+        result => panic!("{:?}", result),
+    }
+    runtime
+        .block_on(publish2)
+        .expect("uncancelled publication failed");
+
+    // The publication has been sent, so it can't be cancelled any more
+    assert_eq!(publish_handle.cancel("key2"), 0);
+
+    runtime
+        .block_on(done)
+        .expect("connection broken while there were still steps remaining on the server");
+}
+
+#[test]
+fn keys_of_unsent_keyed_publications_are_forgotten() {
+    use futures::Future;
+
+    let mut runtime =
+        tokio::runtime::current_thread::Runtime::new().expect("couldn't initialize tokio runtime");
+
+    let (io_source, _) = common::IoSource::new(vec![]);
+
+    let client = mqtt::Client::builder(io_source).build().unwrap();
+
+    let mut publish_handle = client.publish_handle().unwrap();
+    let publication = mqtt::proto::Publication {
+        topic_name: "topic1".to_string(),
+        qos: mqtt::proto::QoS::AtLeastOnce,
+        retain: false,
+        payload: b"payload1".to_vec(),
+    };
+
+    // The future is dropped before the request reaches the client
+    drop(publish_handle.publish_keyed("key1".to_string(), publication.clone()));
+    assert_eq!(publish_handle.cancel("key1"), 0);
+
+    // The request reaches the client, but the client is dropped before it sends it
+    let mut publish2 = publish_handle.publish_keyed("key2".to_string(), publication.clone());
+    runtime
+        .block_on(futures::future::lazy(|| {
+            assert!(publish2.poll().unwrap().is_not_ready());
+            Ok::<_, ()>(())
+        }))
+        .unwrap();
+    drop(client);
+    assert_eq!(publish_handle.cancel("key2"), 0);
+    match runtime.block_on(publish2) {
+        Err(mqtt::PublishError::ClientDoesNotExist) => (),
+        result => panic!("{:?}", result),
+    }
+
+    // The request can't reach the client because it's gone
+    match runtime.block_on(publish_handle.publish_keyed("key3".to_string(), publication)) {
+        Err(mqtt::PublishError::ClientDoesNotExist) => (),
+        result => panic!("{:?}", result),
+    }
+    assert_eq!(publish_handle.cancel("key3"), 0);
+}
+
+#[test]
+fn publication_packet_identifiers_are_reported() {
+    use futures::{Future, Stream};
+
+    let mut runtime =
+        tokio::runtime::current_thread::Runtime::new().expect("couldn't initialize tokio runtime");
+
+    let (io_source, done) = common::IoSource::new(vec![vec![
+        common::TestConnectionStep::Receives(mqtt::proto::Packet::Connect {
+            username: None,
+            password: None,
+            will: None,
+            client_id: mqtt::proto::ClientId::ServerGenerated,
+            keep_alive: std::time::Duration::from_secs(4),
+            protocol_version: mqtt::proto::ProtocolVersion::V3_1_1,
+        }),
+        common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck {
+            session_present: false,
+            return_code: mqtt::proto::ConnectReturnCode::Accepted,
+        }),
+        common::TestConnectionStep::Receives(mqtt::proto::Packet::Publish {
+            packet_identifier_dup_qos: mqtt::proto::PacketIdentifierDupQoS::AtMostOnce,
+            retain: false,
+            topic_name: "topic1".to_string(),
+            payload: b"payload1".to_vec(),
+        }),
+        common::TestConnectionStep::Receives(mqtt::proto::Packet::Publish {
+            packet_identifier_dup_qos: mqtt::proto::PacketIdentifierDupQoS::AtLeastOnce(
+                mqtt::proto::PacketIdentifier::new(1).unwrap(),
+                false,
+            ),
+            retain: false,
+            topic_name: "topic2".to_string(),
+            payload: b"payload2".to_vec(),
+        }),
+        common::TestConnectionStep::Sends(mqtt::proto::Packet::PubAck {
+            packet_identifier: mqtt::proto::PacketIdentifier::new(1).unwrap(),
+        }),
+    ]]);
+
+    let client = mqtt::Client::new(
+        None,
+        None,
+        None,
+        None,
+        io_source,
+        std::time::Duration::from_secs(0),
+        std::time::Duration::from_secs(4),
+    )
+    .unwrap();
+
+    let mut publish_handle = client.publish_handle().unwrap();
+    let publish1 = publish_handle.publish_with_packet_identifier(mqtt::proto::Publication {
+        topic_name: "topic1".to_string(),
+        qos: mqtt::proto::QoS::AtMostOnce,
+        retain: false,
+        payload: b"payload1".to_vec(),
+    });
+    let publish2 = publish_handle.publish_with_packet_identifier(mqtt::proto::Publication {
+        topic_name: "topic2".to_string(),
+        qos: mqtt::proto::QoS::AtLeastOnce,
+        retain: false,
+        payload: b"payload2".to_vec(),
+    });
+
+    runtime.spawn(
+        client
+            .for_each(|_| Ok(()))
+            .map_err(|err| panic!("{:?}", err)),
+    );
+
+    let (packet_identifier1, ack1) = runtime
+        .block_on(publish1)
+        .expect("AtMostOnce publication was not sent");
+    assert_eq!(packet_identifier1, None);
+    runtime
+        .block_on(ack1)
+        .expect("AtMostOnce publication failed");
+
+    let (packet_identifier2, ack2) = runtime
+        .block_on(publish2)
+        .expect("AtLeastOnce publication was not sent");
+    assert_eq!(
+        packet_identifier2,
+        Some(mqtt::proto::PacketIdentifier::new(1).unwrap())
+    );
+    runtime
+        .block_on(ack2)
+        .expect("AtLeastOnce publication failed");
+
+    runtime
+        .block_on(done)
+        .expect("connection broken while there were still steps remaining on the server");
+}
+
+#[test]
+fn topic_transforms_are_applied() {
+    use futures::Future;
+
+    let mut runtime =
+        tokio::runtime::current_thread::Runtime::new().expect("couldn't initialize tokio runtime");
+
+    let (io_source, done) = common::IoSource::new(vec![vec![
+        common::TestConnectionStep::Receives(mqtt::proto::Packet::Connect {
+            username: None,
+            password: None,
+            will: None,
+            client_id: mqtt::proto::ClientId::ServerGenerated,
+            keep_alive: std::time::Duration::from_secs(4),
+            protocol_version: mqtt::proto::ProtocolVersion::V3_1_1,
+        }),
+        common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck {
+            session_present: false,
+            return_code: mqtt::proto::ConnectReturnCode::Accepted,
+        }),
+        common::TestConnectionStep::Receives(mqtt::proto::Packet::Publish {
+            packet_identifier_dup_qos: mqtt::proto::PacketIdentifierDupQoS::AtMostOnce,
+            retain: false,
+            topic_name: "tenant1/topic1".to_string(),
+            payload: b"payload1".to_vec(),
+        }),
+        common::TestConnectionStep::Sends(mqtt::proto::Packet::Publish {
+            packet_identifier_dup_qos: mqtt::proto::PacketIdentifierDupQoS::AtMostOnce,
+            retain: false,
+            topic_name: "tenant1/topic2".to_string(),
+            payload: b"payload2".to_vec(),
+        }),
+    ]]);
+
+    let mut client = mqtt::Client::new(
+        None,
+        None,
+        None,
+        None,
+        io_source,
+        std::time::Duration::from_secs(0),
+        std::time::Duration::from_secs(4),
+    )
+    .unwrap();
+    client.set_outbound_topic_transform(Some(Box::new(|topic_name| {
+        format!("tenant1/{}", topic_name).into()
+    })));
+    client.set_inbound_topic_transform(Some(Box::new(|topic_name| {
+        if topic_name.starts_with("tenant1/") {
+            topic_name["tenant1/".len()..].into()
+        } else {
+            topic_name.into()
+        }
+    })));
+
+    let mut publish_handle = client.publish_handle().unwrap();
+    runtime.spawn(
+        publish_handle
+            .publish(mqtt::proto::Publication {
+                topic_name: "topic1".to_string(),
+                qos: mqtt::proto::QoS::AtMostOnce,
+                retain: false,
+                payload: b"payload1".to_vec(),
+            })
+            .map_err(|err| panic!("{:?}", err)),
+    );
+
+    common::verify_client_events(
+        &mut runtime,
+        client,
+        vec![
+            mqtt::Event::NewConnection {
+                reset_session: true,
+                retransmitted_publications: 0,
+            },
+            mqtt::Event::Publication(mqtt::ReceivedPublication {
+                topic_name: "topic2".to_string(),
+                dup: false,
+                qos: mqtt::proto::QoS::AtMostOnce,
+                retain: false,
+                payload: b"payload2".to_vec(),
+            }),
+        ],
+    );
+
+    runtime
+        .block_on(done)
+        .expect("connection broken while there were still steps remaining on the server");
+}
+
+#[test]
+fn oversized_publications_are_rejected_by_existing_handles() {
+    use futures::Future;
+
+    let (io_source, _) = common::IoSource::new(vec![]);
+
+    let mut client = mqtt::Client::builder(io_source).build().unwrap();
+
+    let mut publish_handle = client.publish_handle().unwrap();
+
+    client.set_max_publish_payload_len(Some(4));
+
+    let publication = mqtt::proto::Publication {
+        topic_name: "topic1".to_string(),
+        qos: mqtt::proto::QoS::AtLeastOnce,
+        retain: false,
+        payload: b"payload".to_vec(),
+    };
+
+    match publish_handle.publish(publication.clone()).wait() {
+        Err(mqtt::PublishError::PayloadTooLarge { len: 7, max: 4 }) => (),
+        result => panic!("unexpected result {:?}", result),
+    }
+
+    match client.publish(publication).wait() {
+        Err(mqtt::PublishError::PayloadTooLarge { len: 7, max: 4 }) => (),
+        result => panic!("unexpected result {:?}", result),
+    }
+}
+
+#[test]
+fn graceful_shutdown_drains_publications() {
+    use futures::Future;
+
+    let mut runtime =
+        tokio::runtime::current_thread::Runtime::new().expect("couldn't initialize tokio runtime");
+
+    let (io_source, done) = common::IoSource::new(vec![vec![
+        common::TestConnectionStep::Receives(mqtt::proto::Packet::Connect {
+            username: None,
+            password: None,
+            will: None,
+            client_id: mqtt::proto::ClientId::ServerGenerated,
+            keep_alive: std::time::Duration::from_secs(4),
+            protocol_version: mqtt::proto::ProtocolVersion::V3_1_1,
+        }),
+        common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck {
+            session_present: false,
+            return_code: mqtt::proto::ConnectReturnCode::Accepted,
+        }),
+        common::TestConnectionStep::Receives(mqtt::proto::Packet::Publish {
+            packet_identifier_dup_qos: mqtt::proto::PacketIdentifierDupQoS::AtLeastOnce(
+                mqtt::proto::PacketIdentifier::new(1).unwrap(),
+                false,
+            ),
+            retain: false,
+            topic_name: "topic1".to_string(),
+            payload: b"payload1".to_vec(),
+        }),
+        common::TestConnectionStep::Receives(mqtt::proto::Packet::Publish {
+            packet_identifier_dup_qos: mqtt::proto::PacketIdentifierDupQoS::AtLeastOnce(
+                mqtt::proto::PacketIdentifier::new(2).unwrap(),
+                false,
+            ),
+            retain: false,
+            topic_name: "topic2".to_string(),
+            payload: b"payload2".to_vec(),
+        }),
+        common::TestConnectionStep::Sends(mqtt::proto::Packet::PubAck {
+            packet_identifier: mqtt::proto::PacketIdentifier::new(1).unwrap(),
+        }),
+        common::TestConnectionStep::Sends(mqtt::proto::Packet::PubAck {
+            packet_identifier: mqtt::proto::PacketIdentifier::new(2).unwrap(),
+        }),
+        common::TestConnectionStep::Receives(mqtt::proto::Packet::Disconnect),
+    ]]);
+
+    let mut client = mqtt::Client::new(
+        None,
+        None,
+        None,
+        None,
+        io_source,
+        std::time::Duration::from_secs(0),
+        std::time::Duration::from_secs(4),
+    )
+    .unwrap();
+
+    let publish = client.publish(mqtt::proto::Publication {
+        topic_name: "topic1".to_string(),
+        qos: mqtt::proto::QoS::AtLeastOnce,
+        retain: false,
+        payload: b"payload1".to_vec(),
+    });
+    runtime.spawn(publish.map_err(|err| panic!("{:?}", err)));
+
+    // A publication that's still in the handle's channel when the shutdown is requested is drained too
+    let mut publish_handle = client.publish_handle().unwrap();
+    runtime.spawn(
+        publish_handle
+            .publish(mqtt::proto::Publication {
+                topic_name: "topic2".to_string(),
+                qos: mqtt::proto::QoS::AtLeastOnce,
+                retain: false,
+                payload: b"payload2".to_vec(),
+            })
+            .map_err(|err| panic!("{:?}", err)),
+    );
+
+    let shutdown_handle = client.shutdown_handle().unwrap();
+    runtime.spawn(
+        shutdown_handle
+            .shutdown_graceful(std::time::Duration::from_secs(10))
+            .map_err(|err| panic!("{:?}", err)),
+    );
+
+    common::verify_client_events(
+        &mut runtime,
+        client,
+        vec![
+            mqtt::Event::NewConnection {
+                reset_session: true,
+                retransmitted_publications: 0,
+            },
+            mqtt::Event::Disconnected { graceful: true },
+        ],
+    );
+
+    runtime
+        .block_on(done)
+        .expect("connection broken while there were still steps remaining on the server");
+}
+
+#[test]
+fn graceful_shutdown_drains_publications_waiting_in_handle_channel() {
+    use futures::Future;
+
+    let mut runtime =
+        tokio::runtime::current_thread::Runtime::new().expect("couldn't initialize tokio runtime");
+
+    let (io_source, done) = common::IoSource::new(vec![vec![
+        common::TestConnectionStep::Receives(mqtt::proto::Packet::Connect {
+            username: None,
+            password: None,
+            will: None,
+            client_id: mqtt::proto::ClientId::ServerGenerated,
+            keep_alive: std::time::Duration::from_secs(4),
+            protocol_version: mqtt::proto::ProtocolVersion::V3_1_1,
+        }),
+        common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck {
+            session_present: false,
+            return_code: mqtt::proto::ConnectReturnCode::Accepted,
+        }),
+        common::TestConnectionStep::Receives(mqtt::proto::Packet::Publish {
+            packet_identifier_dup_qos: mqtt::proto::PacketIdentifierDupQoS::AtLeastOnce(
+                mqtt::proto::PacketIdentifier::new(1).unwrap(),
+                false,
+            ),
+            retain: false,
+            topic_name: "topic1".to_string(),
+            payload: b"payload1".to_vec(),
+        }),
+        common::TestConnectionStep::Sends(mqtt::proto::Packet::PubAck {
+            packet_identifier: mqtt::proto::PacketIdentifier::new(1).unwrap(),
+        }),
+        common::TestConnectionStep::Receives(mqtt::proto::Packet::Disconnect),
+    ]]);
+
+    let client = mqtt::Client::new(
+        None,
+        None,
+        None,
+        None,
+        io_source,
+        std::time::Duration::from_secs(0),
+        std::time::Duration::from_secs(4),
+    )
+    .unwrap();
+
+    // The publication is still in the handle's channel when the client sees the shutdown request
+    let mut publish_handle = client.publish_handle().unwrap();
+    runtime.spawn(
+        publish_handle
+            .publish(mqtt::proto::Publication {
+                topic_name: "topic1".to_string(),
+                qos: mqtt::proto::QoS::AtLeastOnce,
+                retain: false,
+                payload: b"payload1".to_vec(),
+            })
+            .map_err(|err| panic!("{:?}", err)),
+    );
+
+    let shutdown_handle = client.shutdown_handle().unwrap();
+    runtime.spawn(
+        shutdown_handle
+            .shutdown_graceful(std::time::Duration::from_secs(10))
+            .map_err(|err| panic!("{:?}", err)),
+    );
+
+    common::verify_client_events(
+        &mut runtime,
+        client,
+        vec![
+            mqtt::Event::NewConnection {
+                reset_session: true,
+                retransmitted_publications: 0,
+            },
+            mqtt::Event::Disconnected { graceful: true },
+        ],
+    );
+
+    runtime
+        .block_on(done)
+        .expect("connection broken while there were still steps remaining on the server");
+}
+
+#[test]
+fn at_least_once_redelivery_is_suppressed() {
+    let mut runtime =
+        tokio::runtime::current_thread::Runtime::new().expect("couldn't initialize tokio runtime");
+
+    let publish = |dup| mqtt::proto::Packet::Publish {
+        packet_identifier_dup_qos: mqtt::proto::PacketIdentifierDupQoS::AtLeastOnce(
+            mqtt::proto::PacketIdentifier::new(1).unwrap(),
+            dup,
+        ),
+        retain: false,
+        topic_name: "topic1".to_string(),
+        payload: b"payload1".to_vec(),
+    };
+
+    let (io_source, done) = common::IoSource::new(vec![
+        vec![
+            common::TestConnectionStep::Receives(mqtt::proto::Packet::Connect {
+                username: None,
+                password: None,
+                will: None,
+                client_id: mqtt::proto::ClientId::IdWithCleanSession("dedup_client_id".to_string()),
+                keep_alive: std::time::Duration::from_secs(4),
+                protocol_version: mqtt::proto::ProtocolVersion::V3_1_1,
+            }),
+            common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck {
+                session_present: false,
+                return_code: mqtt::proto::ConnectReturnCode::Accepted,
+            }),
+            common::TestConnectionStep::Sends(publish(false)),
+            // The server does not receive this PUBACK because the connection breaks
+            common::TestConnectionStep::Receives(mqtt::proto::Packet::PubAck {
+                packet_identifier: mqtt::proto::PacketIdentifier::new(1).unwrap(),
+            }),
+        ],
+        vec![
+            common::TestConnectionStep::Receives(mqtt::proto::Packet::Connect {
+                username: None,
+                password: None,
+                will: None,
+                client_id: mqtt::proto::ClientId::IdWithExistingSession(
+                    "dedup_client_id".to_string(),
+                ),
+                keep_alive: std::time::Duration::from_secs(4),
+                protocol_version: mqtt::proto::ProtocolVersion::V3_1_1,
+            }),
+            common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck {
+                session_present: true,
+                return_code: mqtt::proto::ConnectReturnCode::Accepted,
+            }),
+            common::TestConnectionStep::Sends(publish(true)),
+            common::TestConnectionStep::Receives(mqtt::proto::Packet::PubAck {
+                packet_identifier: mqtt::proto::PacketIdentifier::new(1).unwrap(),
+            }),
+        ],
+    ]);
+
+    let mut client = mqtt::Client::new(
+        Some("dedup_client_id".to_string()),
+        None,
+        None,
+        None,
+        io_source,
+        std::time::Duration::from_secs(0),
+        std::time::Duration::from_secs(4),
+    )
+    .unwrap();
+    client.set_at_least_once_dedup_capacity(Some(16));
+
+    common::verify_client_events(
+        &mut runtime,
+        client,
+        vec![
+            mqtt::Event::NewConnection {
+                reset_session: true,
+                retransmitted_publications: 0,
+            },
+            mqtt::Event::Publication(mqtt::ReceivedPublication {
+                topic_name: "topic1".to_string(),
+                dup: false,
+                qos: mqtt::proto::QoS::AtLeastOnce,
+                retain: false,
+                payload: b"payload1".to_vec(),
+            }),
+            mqtt::Event::NewConnection {
+                reset_session: false,
+                retransmitted_publications: 0,
+            },
+        ],
+    );
+
+    runtime
+        .block_on(done)
+        .expect("connection broken while there were still steps remaining on the server");
+}
+
+#[test]
+fn exactly_once_publication_is_delivered_once_across_resumed_session() {
+    let mut runtime =
+        tokio::runtime::current_thread::Runtime::new().expect("couldn't initialize tokio runtime");
+
+    let publish = |dup| mqtt::proto::Packet::Publish {
+        packet_identifier_dup_qos: mqtt::proto::PacketIdentifierDupQoS::ExactlyOnce(
+            mqtt::proto::PacketIdentifier::new(1).unwrap(),
+            dup,
+        ),
+        retain: false,
+        topic_name: "topic1".to_string(),
+        payload: b"payload1".to_vec(),
+    };
+
+    let (io_source, done) = common::IoSource::new(vec![
+        vec![
+            common::TestConnectionStep::Receives(mqtt::proto::Packet::Connect {
+                username: None,
+                password: None,
+                will: None,
+                client_id: mqtt::proto::ClientId::IdWithCleanSession(
+                    "exactly_once_client_id".to_string(),
+                ),
+                keep_alive: std::time::Duration::from_secs(4),
+                protocol_version: mqtt::proto::ProtocolVersion::V3_1_1,
+            }),
+            common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck {
+                session_present: false,
+                return_code: mqtt::proto::ConnectReturnCode::Accepted,
+            }),
+            common::TestConnectionStep::Sends(publish(false)),
+            // The connection breaks before the server sends the PUBREL
+            common::TestConnectionStep::Receives(mqtt::proto::Packet::PubRec {
+                packet_identifier: mqtt::proto::PacketIdentifier::new(1).unwrap(),
+            }),
+        ],
+        vec![
+            common::TestConnectionStep::Receives(mqtt::proto::Packet::Connect {
+                username: None,
+                password: None,
+                will: None,
+                client_id: mqtt::proto::ClientId::IdWithExistingSession(
+                    "exactly_once_client_id".to_string(),
+                ),
+                keep_alive: std::time::Duration::from_secs(4),
+                protocol_version: mqtt::proto::ProtocolVersion::V3_1_1,
+            }),
+            common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck {
+                session_present: true,
+                return_code: mqtt::proto::ConnectReturnCode::Accepted,
+            }),
+            // The client still remembers the PUBLISH, so it re-sends the PUBREC
+            common::TestConnectionStep::Receives(mqtt::proto::Packet::PubRec {
+                packet_identifier: mqtt::proto::PacketIdentifier::new(1).unwrap(),
+            }),
+            // The server didn't receive the first PUBREC and redelivers the PUBLISH too
+            common::TestConnectionStep::Sends(publish(true)),
+            common::TestConnectionStep::Receives(mqtt::proto::Packet::PubRec {
+                packet_identifier: mqtt::proto::PacketIdentifier::new(1).unwrap(),
+            }),
+            common::TestConnectionStep::Sends(mqtt::proto::Packet::PubRel {
+                packet_identifier: mqtt::proto::PacketIdentifier::new(1).unwrap(),
+            }),
+            common::TestConnectionStep::Receives(mqtt::proto::Packet::PubComp {
+                packet_identifier: mqtt::proto::PacketIdentifier::new(1).unwrap(),
+            }),
+        ],
+    ]);
+
+    let client = mqtt::Client::new(
+        Some("exactly_once_client_id".to_string()),
+        None,
+        None,
+        None,
+        io_source,
+        std::time::Duration::from_secs(0),
+        std::time::Duration::from_secs(4),
+    )
+    .unwrap();
+
+    common::verify_client_events(
+        &mut runtime,
+        client,
+        vec![
+            mqtt::Event::NewConnection {
+                reset_session: true,
+                retransmitted_publications: 0,
+            },
+            mqtt::Event::NewConnection {
+                reset_session: false,
+                retransmitted_publications: 0,
+            },
+            mqtt::Event::Publication(mqtt::ReceivedPublication {
+                topic_name: "topic1".to_string(),
+                dup: false,
+                qos: mqtt::proto::QoS::ExactlyOnce,
+                retain: false,
+                payload: b"payload1".to_vec(),
+            }),
+        ],
+    );
+
+    runtime
+        .block_on(done)
+        .expect("connection broken while there were still steps remaining on the server");
+}
+
+#[test]
+fn exactly_once_publication_is_delivered_once_across_session_reset() {
+    let mut runtime =
+        tokio::runtime::current_thread::Runtime::new().expect("couldn't initialize tokio runtime");
+
+    let publish = |dup| mqtt::proto::Packet::Publish {
+        packet_identifier_dup_qos: mqtt::proto::PacketIdentifierDupQoS::ExactlyOnce(
+            mqtt::proto::PacketIdentifier::new(1).unwrap(),
+            dup,
+        ),
+        retain: false,
+        topic_name: "topic1".to_string(),
+        payload: b"payload1".to_vec(),
+    };
+
+    let (io_source, done) = common::IoSource::new(vec![
+        vec![
+            common::TestConnectionStep::Receives(mqtt::proto::Packet::Connect {
+                username: None,
+                password: None,
+                will: None,
+                client_id: mqtt::proto::ClientId::IdWithCleanSession(
+                    "exactly_once_client_id".to_string(),
+                ),
+                keep_alive: std::time::Duration::from_secs(4),
+                protocol_version: mqtt::proto::ProtocolVersion::V3_1_1,
+            }),
+            common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck {
+                session_present: false,
+                return_code: mqtt::proto::ConnectReturnCode::Accepted,
+            }),
+            common::TestConnectionStep::Sends(publish(false)),
+            // The connection breaks before the server sends the PUBREL
+            common::TestConnectionStep::Receives(mqtt::proto::Packet::PubRec {
+                packet_identifier: mqtt::proto::PacketIdentifier::new(1).unwrap(),
+            }),
+        ],
+        vec![
+            common::TestConnectionStep::Receives(mqtt::proto::Packet::Connect {
+                username: None,
+                password: None,
+                will: None,
+                client_id: mqtt::proto::ClientId::IdWithExistingSession(
+                    "exactly_once_client_id".to_string(),
+                ),
+                keep_alive: std::time::Duration::from_secs(4),
+                protocol_version: mqtt::proto::ProtocolVersion::V3_1_1,
+            }),
+            // The server claims to have lost the session, so the client forgets the PUBLISH that hasn't been released yet
+            common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck {
+                session_present: false,
+                return_code: mqtt::proto::ConnectReturnCode::Accepted,
+            }),
+            // ... but then redelivers it anyway
+            common::TestConnectionStep::Sends(publish(true)),
+            common::TestConnectionStep::Receives(mqtt::proto::Packet::PubRec {
+                packet_identifier: mqtt::proto::PacketIdentifier::new(1).unwrap(),
+            }),
+            common::TestConnectionStep::Sends(mqtt::proto::Packet::PubRel {
+                packet_identifier: mqtt::proto::PacketIdentifier::new(1).unwrap(),
+            }),
+            common::TestConnectionStep::Receives(mqtt::proto::Packet::PubComp {
+                packet_identifier: mqtt::proto::PacketIdentifier::new(1).unwrap(),
+            }),
+        ],
+    ]);
+
+    let client = mqtt::Client::new(
+        Some("exactly_once_client_id".to_string()),
+        None,
+        None,
+        None,
+        io_source,
+        std::time::Duration::from_secs(0),
+        std::time::Duration::from_secs(4),
+    )
+    .unwrap();
+
+    common::verify_client_events(
+        &mut runtime,
+        client,
+        vec![
+            mqtt::Event::NewConnection {
+                reset_session: true,
+                retransmitted_publications: 0,
+            },
+            mqtt::Event::NewConnection {
+                reset_session: true,
+                retransmitted_publications: 0,
+            },
+            // Publications are only surfaced once they're released, so forgetting the unreleased one
+            // doesn't cause it to be delivered twice
+            mqtt::Event::Publication(mqtt::ReceivedPublication {
+                topic_name: "topic1".to_string(),
+                dup: true,
+                qos: mqtt::proto::QoS::ExactlyOnce,
+                retain: false,
+                payload: b"payload1".to_vec(),
+            }),
+        ],
+    );
+
+    runtime
+        .block_on(done)
+        .expect("connection broken while there were still steps remaining on the server");
+}
+
+#[test]
+fn flush_waits_for_queued_publications_to_be_written() {
+    use futures::Future;
+
+    let mut runtime =
+        tokio::runtime::current_thread::Runtime::new().expect("couldn't initialize tokio runtime");
+
+    let (io_source, done) = common::IoSource::new(vec![vec![
+        common::TestConnectionStep::Receives(mqtt::proto::Packet::Connect {
+            username: None,
+            password: None,
+            will: None,
+            client_id: mqtt::proto::ClientId::ServerGenerated,
+            keep_alive: std::time::Duration::from_secs(4),
+            protocol_version: mqtt::proto::ProtocolVersion::V3_1_1,
+        }),
+        common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck {
+            session_present: false,
+            return_code: mqtt::proto::ConnectReturnCode::Accepted,
+        }),
+        common::TestConnectionStep::Receives(mqtt::proto::Packet::Publish {
+            packet_identifier_dup_qos: mqtt::proto::PacketIdentifierDupQoS::AtMostOnce,
+            retain: false,
+            topic_name: "topic1".to_string(),
+            payload: b"payload1".to_vec(),
+        }),
+        // An immediate shutdown doesn't send queued packets, so this is only received after the PUBLISH if the flush waited for it
+        common::TestConnectionStep::Receives(mqtt::proto::Packet::Disconnect),
+    ]]);
+
+    let mut client = mqtt::Client::new(
+        None,
+        None,
+        None,
+        None,
+        io_source,
+        std::time::Duration::from_secs(0),
+        std::time::Duration::from_secs(4),
+    )
+    .unwrap();
+
+    let publish = client.publish(mqtt::proto::Publication {
+        topic_name: "topic1".to_string(),
+        qos: mqtt::proto::QoS::AtMostOnce,
+        retain: false,
+        payload: b"payload1".to_vec(),
+    });
+    runtime.spawn(publish.map_err(|err| panic!("{:?}", err)));
+
+    let shutdown_handle = client.shutdown_handle().unwrap();
+    runtime.spawn(
+        client
+            .flush()
+            .map_err(|err| panic!("{:?}", err))
+            .and_then(move |()| shutdown_handle.shutdown())
+            .map_err(|err| panic!("{:?}", err)),
+    );
+
+    common::verify_client_events(
+        &mut runtime,
+        client,
+        vec![
+            mqtt::Event::NewConnection {
+                reset_session: true,
+                retransmitted_publications: 0,
+            },
+            mqtt::Event::Disconnected { graceful: true },
+        ],
+    );
+
+    runtime
+        .block_on(done)
+        .expect("connection broken while there were still steps remaining on the server");
+}
+
+#[test]
+fn unacked_publications_are_retransmitted_on_new_connection() {
+    use futures::Future;
+
+    let mut runtime =
+        tokio::runtime::current_thread::Runtime::new().expect("couldn't initialize tokio runtime");
+
+    let publish = |dup| mqtt::proto::Packet::Publish {
+        packet_identifier_dup_qos: mqtt::proto::PacketIdentifierDupQoS::AtLeastOnce(
+            mqtt::proto::PacketIdentifier::new(1).unwrap(),
+            dup,
+        ),
+        retain: false,
+        topic_name: "topic1".to_string(),
+        payload: b"payload1".to_vec(),
+    };
+
+    let (io_source, done) = common::IoSource::new(vec![
+        vec![
+            common::TestConnectionStep::Receives(mqtt::proto::Packet::Connect {
+                username: None,
+                password: None,
+                will: None,
+                client_id: mqtt::proto::ClientId::IdWithCleanSession(
+                    "retransmit_client_id".to_string(),
+                ),
+                keep_alive: std::time::Duration::from_secs(4),
+                protocol_version: mqtt::proto::ProtocolVersion::V3_1_1,
+            }),
+            common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck {
+                session_present: false,
+                return_code: mqtt::proto::ConnectReturnCode::Accepted,
+            }),
+            // The connection breaks before the server acks this
+            common::TestConnectionStep::Receives(publish(false)),
+        ],
+        vec![
+            common::TestConnectionStep::Receives(mqtt::proto::Packet::Connect {
+                username: None,
+                password: None,
+                will: None,
+                client_id: mqtt::proto::ClientId::IdWithExistingSession(
+                    "retransmit_client_id".to_string(),
+                ),
+                keep_alive: std::time::Duration::from_secs(4),
+                protocol_version: mqtt::proto::ProtocolVersion::V3_1_1,
+            }),
+            common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck {
+                session_present: true,
+                return_code: mqtt::proto::ConnectReturnCode::Accepted,
+            }),
+            common::TestConnectionStep::Receives(publish(true)),
+            common::TestConnectionStep::Sends(mqtt::proto::Packet::PubAck {
+                packet_identifier: mqtt::proto::PacketIdentifier::new(1).unwrap(),
+            }),
+        ],
+    ]);
+
+    let mut client = mqtt::Client::new(
+        Some("retransmit_client_id".to_string()),
+        None,
+        None,
+        None,
+        io_source,
+        std::time::Duration::from_secs(0),
+        std::time::Duration::from_secs(4),
+    )
+    .unwrap();
+
+    let publish = client.publish(mqtt::proto::Publication {
+        topic_name: "topic1".to_string(),
+        qos: mqtt::proto::QoS::AtLeastOnce,
+        retain: false,
+        payload: b"payload1".to_vec(),
+    });
+    runtime.spawn(publish.map_err(|err| panic!("{:?}", err)));
+
+    common::verify_client_events(
+        &mut runtime,
+        client,
+        vec![
+            mqtt::Event::NewConnection {
+                reset_session: true,
+                retransmitted_publications: 0,
+            },
+            mqtt::Event::NewConnection {
+                reset_session: false,
+                retransmitted_publications: 1,
+            },
+        ],
+    );
+
+    runtime
+        .block_on(done)
+        .expect("connection broken while there were still steps remaining on the server");
+}
+
+#[test]
+fn session_is_reset_on_server_close_if_configured() {
+    let mut runtime =
+        tokio::runtime::current_thread::Runtime::new().expect("couldn't initialize tokio runtime");
+
+    let connect = mqtt::proto::Packet::Connect {
+        username: None,
+        password: None,
+        will: None,
+        client_id: mqtt::proto::ClientId::IdWithCleanSession("expiring_client_id".to_string()),
+        keep_alive: std::time::Duration::from_secs(4),
+        protocol_version: mqtt::proto::ProtocolVersion::V3_1_1,
+    };
+
+    let publish = |dup| mqtt::proto::Packet::Publish {
+        packet_identifier_dup_qos: mqtt::proto::PacketIdentifierDupQoS::AtLeastOnce(
+            mqtt::proto::PacketIdentifier::new(1).unwrap(),
+            dup,
+        ),
+        retain: false,
+        topic_name: "topic1".to_string(),
+        payload: b"payload1".to_vec(),
+    };
+
+    let (io_source, done) = common::IoSource::new(vec![
+        vec![
+            common::TestConnectionStep::Receives(connect.clone()),
+            common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck {
+                session_present: false,
+                return_code: mqtt::proto::ConnectReturnCode::Accepted,
+            }),
+            // The server closes the connection before it acks this, eg because it discarded the session
+            common::TestConnectionStep::Receives(publish(false)),
+        ],
+        vec![
+            // The client doesn't ask the server to resume the session
+            common::TestConnectionStep::Receives(connect),
+            common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck {
+                session_present: false,
+                return_code: mqtt::proto::ConnectReturnCode::Accepted,
+            }),
+            common::TestConnectionStep::Receives(publish(true)),
+            common::TestConnectionStep::Sends(mqtt::proto::Packet::PubAck {
+                packet_identifier: mqtt::proto::PacketIdentifier::new(1).unwrap(),
+            }),
+        ],
+    ]);
+
+    let mut client = mqtt::Client::builder(io_source)
+        .client_id("expiring_client_id".to_string())
+        .max_reconnect_back_off(std::time::Duration::from_secs(0))
+        .keep_alive(std::time::Duration::from_secs(4))
+        .reset_session_on_server_close(true)
+        .build()
+        .unwrap();
+
+    let publish = client.publish(mqtt::proto::Publication {
+        topic_name: "topic1".to_string(),
+        qos: mqtt::proto::QoS::AtLeastOnce,
+        retain: false,
+        payload: b"payload1".to_vec(),
+    });
+
+    common::verify_client_events(
+        &mut runtime,
+        client,
+        vec![
+            mqtt::Event::NewConnection {
+                reset_session: true,
+                retransmitted_publications: 0,
+            },
+            mqtt::Event::NewConnection {
+                reset_session: true,
+                retransmitted_publications: 1,
+            },
+        ],
+    );
+
+    runtime.block_on(publish).unwrap();
+
+    runtime
+        .block_on(done)
+        .expect("connection broken while there were still steps remaining on the server");
+}
+
+#[test]
+fn unacked_publications_are_retransmitted_after_session_reset() {
+    let mut runtime =
+        tokio::runtime::current_thread::Runtime::new().expect("couldn't initialize tokio runtime");
+
+    let publish = |dup| mqtt::proto::Packet::Publish {
+        packet_identifier_dup_qos: mqtt::proto::PacketIdentifierDupQoS::AtLeastOnce(
+            mqtt::proto::PacketIdentifier::new(1).unwrap(),
+            dup,
+        ),
+        retain: false,
+        topic_name: "topic1".to_string(),
+        payload: b"payload1".to_vec(),
+    };
+
+    let (io_source, done) = common::IoSource::new(vec![
+        vec![
+            common::TestConnectionStep::Receives(mqtt::proto::Packet::Connect {
+                username: None,
+                password: None,
+                will: None,
+                client_id: mqtt::proto::ClientId::ServerGenerated,
+                keep_alive: std::time::Duration::from_secs(4),
+                protocol_version: mqtt::proto::ProtocolVersion::V3_1_1,
+            }),
+            common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck {
+                session_present: false,
+                return_code: mqtt::proto::ConnectReturnCode::Accepted,
+            }),
+            // The connection breaks before the server acks this
+            common::TestConnectionStep::Receives(publish(false)),
+        ],
+        vec![
+            common::TestConnectionStep::Receives(mqtt::proto::Packet::Connect {
+                username: None,
+                password: None,
+                will: None,
+                client_id: mqtt::proto::ClientId::ServerGenerated,
+                keep_alive: std::time::Duration::from_secs(4),
+                protocol_version: mqtt::proto::ProtocolVersion::V3_1_1,
+            }),
+            // The server has lost the session, but the publication must still be delivered, eg because it's the response to a request
+            // that the server is waiting for, so the client sends it again with the same packet identifier.
+            common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck {
+                session_present: false,
+                return_code: mqtt::proto::ConnectReturnCode::Accepted,
+            }),
+            common::TestConnectionStep::Receives(publish(true)),
+            common::TestConnectionStep::Sends(mqtt::proto::Packet::PubAck {
+                packet_identifier: mqtt::proto::PacketIdentifier::new(1).unwrap(),
+            }),
+        ],
+    ]);
+
+    let mut client = mqtt::Client::new(
+        None,
+        None,
+        None,
+        None,
+        io_source,
+        std::time::Duration::from_secs(0),
+        std::time::Duration::from_secs(4),
+    )
+    .unwrap();
+
+    let publish = client.publish(mqtt::proto::Publication {
+        topic_name: "topic1".to_string(),
+        qos: mqtt::proto::QoS::AtLeastOnce,
+        retain: false,
+        payload: b"payload1".to_vec(),
+    });
+
+    common::verify_client_events(
+        &mut runtime,
+        client,
+        vec![
+            mqtt::Event::NewConnection {
+                reset_session: true,
+                retransmitted_publications: 0,
+            },
+            mqtt::Event::NewConnection {
+                reset_session: true,
+                retransmitted_publications: 1,
+            },
+        ],
+    );
+
+    // The publish future only resolves once the retransmitted PUBLISH is acked
+    runtime.block_on(publish).unwrap();
+
+    runtime
+        .block_on(done)
+        .expect("connection broken while there were still steps remaining on the server");
+}
+
+#[test]
+fn force_reconnect_resumes_session() {
+    use futures::{Future, Stream};
+
+    let mut runtime =
+        tokio::runtime::current_thread::Runtime::new().expect("couldn't initialize tokio runtime");
+
+    let publish = |dup| mqtt::proto::Packet::Publish {
+        packet_identifier_dup_qos: mqtt::proto::PacketIdentifierDupQoS::AtLeastOnce(
+            mqtt::proto::PacketIdentifier::new(1).unwrap(),
+            dup,
+        ),
+        retain: false,
+        topic_name: "topic1".to_string(),
+        payload: b"payload1".to_vec(),
+    };
+
+    let (io_source, done) = common::IoSource::new(vec![
+        vec![
+            common::TestConnectionStep::Receives(mqtt::proto::Packet::Connect {
+                username: None,
+                password: None,
+                will: None,
+                client_id: mqtt::proto::ClientId::IdWithCleanSession(
+                    "force_reconnect_client_id".to_string(),
+                ),
+                keep_alive: std::time::Duration::from_secs(4),
+                protocol_version: mqtt::proto::ProtocolVersion::V3_1_1,
+            }),
+            common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck {
+                session_present: false,
+                return_code: mqtt::proto::ConnectReturnCode::Accepted,
+            }),
+            // The client reconnects before the server acks this
+            common::TestConnectionStep::Receives(publish(false)),
+            common::TestConnectionStep::Receives(mqtt::proto::Packet::Disconnect),
+        ],
+        vec![
+            common::TestConnectionStep::Receives(mqtt::proto::Packet::Connect {
+                username: None,
+                password: None,
+                will: None,
+                client_id: mqtt::proto::ClientId::IdWithExistingSession(
+                    "force_reconnect_client_id".to_string(),
+                ),
+                keep_alive: std::time::Duration::from_secs(4),
+                protocol_version: mqtt::proto::ProtocolVersion::V3_1_1,
+            }),
+            common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck {
+                session_present: true,
+                return_code: mqtt::proto::ConnectReturnCode::Accepted,
+            }),
+            common::TestConnectionStep::Receives(publish(true)),
+            common::TestConnectionStep::Sends(mqtt::proto::Packet::PubAck {
+                packet_identifier: mqtt::proto::PacketIdentifier::new(1).unwrap(),
+            }),
+        ],
+    ]);
+
+    let mut client = mqtt::Client::new(
+        Some("force_reconnect_client_id".to_string()),
+        None,
+        None,
+        None,
+        io_source,
+        std::time::Duration::from_secs(0),
+        std::time::Duration::from_secs(4),
+    )
+    .unwrap();
+
+    let publish = client.publish(mqtt::proto::Publication {
+        topic_name: "topic1".to_string(),
+        qos: mqtt::proto::QoS::AtLeastOnce,
+        retain: false,
+        payload: b"payload1".to_vec(),
+    });
+    runtime.spawn(publish.map_err(|err| panic!("{:?}", err)));
+
+    let mut flushed = client.flush();
+    let flushed = futures::future::poll_fn(|| -> futures::Poll<_, mqtt::Error> {
+        loop {
+            if let futures::Async::Ready(()) = flushed.poll().unwrap() {
+                return Ok(futures::Async::Ready(()));
+            }
+
+            match client.poll()? {
+                futures::Async::Ready(Some(mqtt::Event::NewConnection {
+                    reset_session: true,
+                    retransmitted_publications: 0,
+                })) => (),
+                futures::Async::Ready(event) => panic!("{:?}", event),
+                futures::Async::NotReady => return Ok(futures::Async::NotReady),
+            }
+        }
+    });
+    runtime.block_on(flushed).unwrap();
+
+    client.force_reconnect();
+
+    common::verify_client_events(
+        &mut runtime,
+        client,
+        vec![mqtt::Event::NewConnection {
+            reset_session: false,
+            retransmitted_publications: 1,
+        }],
+    );
+
+    runtime
+        .block_on(done)
+        .expect("connection broken while there were still steps remaining on the server");
+}
+
+#[test]
+fn metrics_count_reconnects_and_publications() {
+    use futures::{Future, Stream};
+
+    let mut runtime =
+        tokio::runtime::current_thread::Runtime::new().expect("couldn't initialize tokio runtime");
+
+    let publish = |dup| mqtt::proto::Packet::Publish {
+        packet_identifier_dup_qos: mqtt::proto::PacketIdentifierDupQoS::AtLeastOnce(
+            mqtt::proto::PacketIdentifier::new(1).unwrap(),
+            dup,
+        ),
+        retain: false,
+        topic_name: "topic1".to_string(),
+        payload: b"payload1".to_vec(),
+    };
+
+    let (io_source, done) = common::IoSource::new(vec![
+        vec![
+            common::TestConnectionStep::Receives(mqtt::proto::Packet::Connect {
+                username: None,
+                password: None,
+                will: None,
+                client_id: mqtt::proto::ClientId::IdWithCleanSession(
+                    "metrics_client_id".to_string(),
+                ),
+                keep_alive: std::time::Duration::from_secs(4),
+                protocol_version: mqtt::proto::ProtocolVersion::V3_1_1,
+            }),
+            common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck {
+                session_present: false,
+                return_code: mqtt::proto::ConnectReturnCode::Accepted,
+            }),
+            common::TestConnectionStep::Receives(mqtt::proto::Packet::Publish {
+                packet_identifier_dup_qos: mqtt::proto::PacketIdentifierDupQoS::AtMostOnce,
+                retain: false,
+                topic_name: "topic0".to_string(),
+                payload: b"payload0".to_vec(),
+            }),
+            // The client reconnects before the server acks this
+            common::TestConnectionStep::Receives(publish(false)),
+            common::TestConnectionStep::Receives(mqtt::proto::Packet::Disconnect),
+        ],
+        vec![
+            common::TestConnectionStep::Receives(mqtt::proto::Packet::Connect {
+                username: None,
+                password: None,
+                will: None,
+                client_id: mqtt::proto::ClientId::IdWithExistingSession(
+                    "metrics_client_id".to_string(),
+                ),
+                keep_alive: std::time::Duration::from_secs(4),
+                protocol_version: mqtt::proto::ProtocolVersion::V3_1_1,
+            }),
+            common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck {
+                session_present: true,
+                return_code: mqtt::proto::ConnectReturnCode::Accepted,
+            }),
+            common::TestConnectionStep::Receives(publish(true)),
+            common::TestConnectionStep::Sends(mqtt::proto::Packet::PubAck {
+                packet_identifier: mqtt::proto::PacketIdentifier::new(1).unwrap(),
+            }),
+            common::TestConnectionStep::Receives(mqtt::proto::Packet::Disconnect),
+        ],
+    ]);
+
+    let mut client = mqtt::Client::new(
+        Some("metrics_client_id".to_string()),
+        None,
+        None,
+        None,
+        io_source,
+        std::time::Duration::from_secs(0),
+        std::time::Duration::from_secs(4),
+    )
+    .unwrap();
+
+    assert_eq!(client.metrics(), Some(Default::default()));
+
+    let publish0 = client.publish(mqtt::proto::Publication {
+        topic_name: "topic0".to_string(),
+        qos: mqtt::proto::QoS::AtMostOnce,
+        retain: false,
+        payload: b"payload0".to_vec(),
+    });
+    runtime.spawn(publish0.map_err(|err| panic!("{:?}", err)));
+    let mut publish1 = client.publish(mqtt::proto::Publication {
+        topic_name: "topic1".to_string(),
+        qos: mqtt::proto::QoS::AtLeastOnce,
+        retain: false,
+        payload: b"payload1".to_vec(),
+    });
+
+    let mut flushed = client.flush();
+    let flushed = futures::future::poll_fn(|| -> futures::Poll<_, mqtt::Error> {
+        loop {
+            if let futures::Async::Ready(()) = flushed.poll().unwrap() {
+                return Ok(futures::Async::Ready(()));
+            }
+
+            match client.poll()? {
+                futures::Async::Ready(Some(mqtt::Event::NewConnection {
+                    reset_session: true,
+                    retransmitted_publications: 0,
+                })) => (),
+                futures::Async::Ready(event) => panic!("{:?}", event),
+                futures::Async::NotReady => return Ok(futures::Async::NotReady),
+            }
+        }
+    });
+    runtime.block_on(flushed).unwrap();
+
+    let metrics = client.metrics().unwrap();
+    assert_eq!(metrics.reconnects, 0);
+    assert!(metrics.io_stats.bytes_written > 0);
+    assert_eq!(
+        metrics.publications_sent,
+        mqtt::PublicationCounts {
+            at_most_once: 1,
+            at_least_once: 1,
+            exactly_once: 0,
+        }
+    );
+    assert_eq!(metrics.inflight_publications, 1);
+    assert_eq!(metrics.inflight_subscription_updates, 0);
+
+    client.force_reconnect();
+
+    let acked = futures::future::poll_fn(|| -> futures::Poll<_, mqtt::Error> {
+        loop {
+            if let futures::Async::Ready(()) = publish1.poll().unwrap() {
+                return Ok(futures::Async::Ready(()));
+            }
+
+            match client.poll()? {
+                futures::Async::Ready(Some(mqtt::Event::NewConnection {
+                    reset_session: false,
+                    retransmitted_publications: 1,
+                })) => (),
+                futures::Async::Ready(event) => panic!("{:?}", event),
+                futures::Async::NotReady => return Ok(futures::Async::NotReady),
+            }
+        }
+    });
+    runtime.block_on(acked).unwrap();
+
+    // The retransmitted publication isn't counted again
+    let metrics = client.metrics().unwrap();
+    assert_eq!(metrics.reconnects, 1);
+    assert_eq!(
+        metrics.publications_sent,
+        mqtt::PublicationCounts {
+            at_most_once: 1,
+            at_least_once: 1,
+            exactly_once: 0,
+        }
+    );
+    assert_eq!(metrics.inflight_publications, 0);
+
+    drop(client);
+
+    runtime
+        .block_on(done)
+        .expect("connection broken while there were still steps remaining on the server");
+}
+
+#[test]
+fn paused_client_waits_for_connect_handle() {
+    use futures::{Future, Stream};
+
+    let mut runtime =
+        tokio::runtime::current_thread::Runtime::new().expect("couldn't initialize tokio runtime");
+
+    let (io_source, done) = common::IoSource::new(vec![vec![
+        common::TestConnectionStep::Receives(mqtt::proto::Packet::Connect {
+            username: None,
+            password: None,
+            will: None,
+            client_id: mqtt::proto::ClientId::ServerGenerated,
+            keep_alive: std::time::Duration::from_secs(4),
+            protocol_version: mqtt::proto::ProtocolVersion::V3_1_1,
+        }),
+        common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck {
+            session_present: false,
+            return_code: mqtt::proto::ConnectReturnCode::Accepted,
+        }),
+        common::TestConnectionStep::Receives(mqtt::proto::Packet::PingReq),
+        common::TestConnectionStep::Sends(mqtt::proto::Packet::PingResp),
+    ]]);
+
+    let client = mqtt::ClientBuilder::new(io_source)
+        .max_reconnect_back_off(std::time::Duration::from_secs(0))
+        .keep_alive(std::time::Duration::from_secs(4))
+        .start_paused(true)
+        .build()
+        .unwrap();
+
+    let connect_requested = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+
+    let connect_handle = client.connect_handle().unwrap();
+    runtime.spawn({
+        let connect_requested = connect_requested.clone();
+        tokio::timer::Delay::new(std::time::Instant::now() + std::time::Duration::from_secs(1))
+            .map_err(|err| panic!("{:?}", err))
+            .and_then(move |()| {
+                connect_requested.store(true, std::sync::atomic::Ordering::SeqCst);
+                connect_handle.connect().map_err(|err| panic!("{:?}", err))
+            })
+    });
+
+    let mut expected = vec![mqtt::Event::NewConnection {
+        reset_session: true,
+        retransmitted_publications: 0,
+    }]
+    .into_iter();
+    runtime.spawn(
+        client
+            .map_err(|err| panic!("{:?}", err))
+            .for_each(move |event| {
+                // The client must not have connected before it was told to
+                assert!(connect_requested.load(std::sync::atomic::Ordering::SeqCst));
+                assert_eq!(expected.next(), Some(event));
+                Ok(())
+            }),
+    );
+
+    runtime
+        .block_on(done)
+        .expect("connection broken while there were still steps remaining on the server");
+}
+
+#[test]
+fn shutting_down_paused_client_does_not_disconnect_gracefully() {
+    use futures::{Future, Stream};
+
+    let mut runtime =
+        tokio::runtime::current_thread::Runtime::new().expect("couldn't initialize tokio runtime");
+
+    let (io_source, done) = common::IoSource::new(vec![]);
+
+    let client = mqtt::ClientBuilder::new(io_source)
+        .start_paused(true)
+        .build()
+        .unwrap();
+
+    let shutdown_handle = client.shutdown_handle().unwrap();
+    runtime.spawn(
+        shutdown_handle
+            .shutdown()
+            .map_err(|err| panic!("{:?}", err)),
+    );
+
+    let events = runtime.block_on(client.collect()).unwrap();
+    assert_eq!(events, vec![mqtt::Event::Disconnected { graceful: false }]);
+
+    runtime
+        .block_on(done)
+        .expect("connection broken while there were still steps remaining on the server");
+}
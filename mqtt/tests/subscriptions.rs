@@ -13,6 +13,7 @@ fn server_generated_id_must_always_resubscribe() {
                 will: None,
                 client_id: mqtt::proto::ClientId::ServerGenerated,
                 keep_alive: std::time::Duration::from_secs(4),
+                protocol_version: mqtt::proto::ProtocolVersion::V3_1_1,
             }),
             common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck {
                 session_present: false,
@@ -53,6 +54,7 @@ fn server_generated_id_must_always_resubscribe() {
                 will: None,
                 client_id: mqtt::proto::ClientId::ServerGenerated,
                 keep_alive: std::time::Duration::from_secs(4),
+                protocol_version: mqtt::proto::ProtocolVersion::V3_1_1,
             }),
             common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck {
                 session_present: false,
@@ -96,7 +98,8 @@ fn server_generated_id_must_always_resubscribe() {
         io_source,
         std::time::Duration::from_secs(0),
         std::time::Duration::from_secs(4),
-    );
+    )
+    .unwrap();
     client
         .subscribe(mqtt::proto::SubscribeTo {
             topic_filter: "topic1".to_string(),
@@ -122,6 +125,7 @@ fn server_generated_id_must_always_resubscribe() {
         vec![
             mqtt::Event::NewConnection {
                 reset_session: true,
+                retransmitted_publications: 0,
             },
             mqtt::Event::SubscriptionUpdates(vec![
                 mqtt::SubscriptionUpdate::Subscribe(mqtt::proto::SubscribeTo {
@@ -137,8 +141,10 @@ fn server_generated_id_must_always_resubscribe() {
                     qos: mqtt::proto::QoS::ExactlyOnce,
                 }),
             ]),
+            mqtt::Event::SubscriptionsSynced,
             mqtt::Event::NewConnection {
                 reset_session: true,
+                retransmitted_publications: 0,
             },
             mqtt::Event::SubscriptionUpdates(vec![
                 mqtt::SubscriptionUpdate::Subscribe(mqtt::proto::SubscribeTo {
@@ -154,8 +160,10 @@ fn server_generated_id_must_always_resubscribe() {
                     qos: mqtt::proto::QoS::ExactlyOnce,
                 }),
             ]),
+            mqtt::Event::SubscriptionsSynced,
             mqtt::Event::NewConnection {
                 reset_session: false,
+                retransmitted_publications: 0,
             },
             mqtt::Event::SubscriptionUpdates(vec![
                 mqtt::SubscriptionUpdate::Subscribe(mqtt::proto::SubscribeTo {
@@ -171,6 +179,7 @@ fn server_generated_id_must_always_resubscribe() {
                     qos: mqtt::proto::QoS::ExactlyOnce,
                 }),
             ]),
+            mqtt::Event::SubscriptionsSynced,
         ],
     );
 
@@ -192,6 +201,7 @@ fn client_id_should_not_resubscribe_when_session_is_present() {
                 will: None,
                 client_id: mqtt::proto::ClientId::IdWithCleanSession("idle_client_id".to_string()),
                 keep_alive: std::time::Duration::from_secs(4),
+                protocol_version: mqtt::proto::ProtocolVersion::V3_1_1,
             }),
             common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck {
                 session_present: false,
@@ -234,6 +244,7 @@ fn client_id_should_not_resubscribe_when_session_is_present() {
                     "idle_client_id".to_string(),
                 ),
                 keep_alive: std::time::Duration::from_secs(4),
+                protocol_version: mqtt::proto::ProtocolVersion::V3_1_1,
             }),
             common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck {
                 // The clean session bit also determines if the *current* session should be persisted.
@@ -279,6 +290,7 @@ fn client_id_should_not_resubscribe_when_session_is_present() {
                     "idle_client_id".to_string(),
                 ),
                 keep_alive: std::time::Duration::from_secs(4),
+                protocol_version: mqtt::proto::ProtocolVersion::V3_1_1,
             }),
             common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck {
                 session_present: true,
@@ -297,7 +309,8 @@ fn client_id_should_not_resubscribe_when_session_is_present() {
         io_source,
         std::time::Duration::from_secs(0),
         std::time::Duration::from_secs(4),
-    );
+    )
+    .unwrap();
     client
         .subscribe(mqtt::proto::SubscribeTo {
             topic_filter: "topic1".to_string(),
@@ -323,6 +336,7 @@ fn client_id_should_not_resubscribe_when_session_is_present() {
         vec![
             mqtt::Event::NewConnection {
                 reset_session: true,
+                retransmitted_publications: 0,
             },
             mqtt::Event::SubscriptionUpdates(vec![
                 mqtt::SubscriptionUpdate::Subscribe(mqtt::proto::SubscribeTo {
@@ -338,8 +352,10 @@ fn client_id_should_not_resubscribe_when_session_is_present() {
                     qos: mqtt::proto::QoS::ExactlyOnce,
                 }),
             ]),
+            mqtt::Event::SubscriptionsSynced,
             mqtt::Event::NewConnection {
                 reset_session: true,
+                retransmitted_publications: 0,
             },
             mqtt::Event::SubscriptionUpdates(vec![
                 mqtt::SubscriptionUpdate::Subscribe(mqtt::proto::SubscribeTo {
@@ -355,9 +371,12 @@ fn client_id_should_not_resubscribe_when_session_is_present() {
                     qos: mqtt::proto::QoS::ExactlyOnce,
                 }),
             ]),
+            mqtt::Event::SubscriptionsSynced,
             mqtt::Event::NewConnection {
                 reset_session: false,
+                retransmitted_publications: 0,
             },
+            mqtt::Event::SubscriptionsSynced,
         ],
     );
 
@@ -378,6 +397,7 @@ fn should_combine_pending_subscription_updates() {
             will: None,
             client_id: mqtt::proto::ClientId::ServerGenerated,
             keep_alive: std::time::Duration::from_secs(4),
+            protocol_version: mqtt::proto::ProtocolVersion::V3_1_1,
         }),
         common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck {
             session_present: false,
@@ -415,7 +435,8 @@ fn should_combine_pending_subscription_updates() {
         io_source,
         std::time::Duration::from_secs(0),
         std::time::Duration::from_secs(4),
-    );
+    )
+    .unwrap();
     client
         .subscribe(mqtt::proto::SubscribeTo {
             topic_filter: "topic1".to_string(),
@@ -448,6 +469,7 @@ fn should_combine_pending_subscription_updates() {
         vec![
             mqtt::Event::NewConnection {
                 reset_session: true,
+                retransmitted_publications: 0,
             },
             mqtt::Event::SubscriptionUpdates(vec![
                 mqtt::SubscriptionUpdate::Subscribe(mqtt::proto::SubscribeTo {
@@ -459,6 +481,216 @@ fn should_combine_pending_subscription_updates() {
                     qos: mqtt::proto::QoS::ExactlyOnce,
                 }),
             ]),
+            mqtt::Event::SubscriptionsSynced,
+        ],
+    );
+
+    runtime
+        .block_on(done)
+        .expect("connection broken while there were still steps remaining on the server");
+}
+
+#[test]
+fn subscribe_many_sends_single_subscribe() {
+    use futures::Future;
+
+    let mut runtime =
+        tokio::runtime::current_thread::Runtime::new().expect("couldn't initialize tokio runtime");
+
+    let (io_source, done) = common::IoSource::new(vec![vec![
+        common::TestConnectionStep::Receives(mqtt::proto::Packet::Connect {
+            username: None,
+            password: None,
+            will: None,
+            client_id: mqtt::proto::ClientId::ServerGenerated,
+            keep_alive: std::time::Duration::from_secs(4),
+            protocol_version: mqtt::proto::ProtocolVersion::V3_1_1,
+        }),
+        common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck {
+            session_present: false,
+            return_code: mqtt::proto::ConnectReturnCode::Accepted,
+        }),
+        common::TestConnectionStep::Receives(mqtt::proto::Packet::Subscribe {
+            packet_identifier: mqtt::proto::PacketIdentifier::new(1).unwrap(),
+            subscribe_to: vec![
+                mqtt::proto::SubscribeTo {
+                    topic_filter: "topic1".to_string(),
+                    qos: mqtt::proto::QoS::AtMostOnce,
+                },
+                mqtt::proto::SubscribeTo {
+                    topic_filter: "topic2".to_string(),
+                    qos: mqtt::proto::QoS::AtLeastOnce,
+                },
+            ],
+        }),
+        common::TestConnectionStep::Sends(mqtt::proto::Packet::SubAck {
+            packet_identifier: mqtt::proto::PacketIdentifier::new(1).unwrap(),
+            qos: vec![
+                mqtt::proto::SubAckQos::Success(mqtt::proto::QoS::AtMostOnce),
+                mqtt::proto::SubAckQos::Success(mqtt::proto::QoS::AtLeastOnce),
+            ],
+        }),
+        common::TestConnectionStep::Receives(mqtt::proto::Packet::PingReq),
+        common::TestConnectionStep::Sends(mqtt::proto::Packet::PingResp),
+    ]]);
+
+    let client = mqtt::Client::new(
+        None,
+        None,
+        None,
+        None,
+        io_source,
+        std::time::Duration::from_secs(0),
+        std::time::Duration::from_secs(4),
+    )
+    .unwrap();
+
+    let mut update_subscription_handle = client.update_subscription_handle().unwrap();
+    runtime.spawn(
+        update_subscription_handle
+            .subscribe_many(vec![
+                mqtt::proto::SubscribeTo {
+                    topic_filter: "topic1".to_string(),
+                    qos: mqtt::proto::QoS::AtMostOnce,
+                },
+                mqtt::proto::SubscribeTo {
+                    topic_filter: "topic2".to_string(),
+                    qos: mqtt::proto::QoS::AtLeastOnce,
+                },
+            ])
+            .map_err(|err| panic!("{:?}", err)),
+    );
+
+    common::verify_client_events(
+        &mut runtime,
+        client,
+        vec![
+            mqtt::Event::NewConnection {
+                reset_session: true,
+                retransmitted_publications: 0,
+            },
+            mqtt::Event::SubscriptionUpdates(vec![
+                mqtt::SubscriptionUpdate::Subscribe(mqtt::proto::SubscribeTo {
+                    topic_filter: "topic1".to_string(),
+                    qos: mqtt::proto::QoS::AtMostOnce,
+                }),
+                mqtt::SubscriptionUpdate::Subscribe(mqtt::proto::SubscribeTo {
+                    topic_filter: "topic2".to_string(),
+                    qos: mqtt::proto::QoS::AtLeastOnce,
+                }),
+            ]),
+            mqtt::Event::SubscriptionsSynced,
+        ],
+    );
+
+    runtime
+        .block_on(done)
+        .expect("connection broken while there were still steps remaining on the server");
+}
+
+#[test]
+fn subscription_ack_timeout_resends_subscription() {
+    let mut runtime =
+        tokio::runtime::current_thread::Runtime::new().expect("couldn't initialize tokio runtime");
+
+    let (io_source, done) = common::IoSource::new(vec![
+        vec![
+            common::TestConnectionStep::Receives(mqtt::proto::Packet::Connect {
+                username: None,
+                password: None,
+                will: None,
+                client_id: mqtt::proto::ClientId::IdWithCleanSession(
+                    "ack_timeout_client_id".to_string(),
+                ),
+                keep_alive: std::time::Duration::from_secs(4),
+                protocol_version: mqtt::proto::ProtocolVersion::V3_1_1,
+            }),
+            common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck {
+                session_present: false,
+                return_code: mqtt::proto::ConnectReturnCode::Accepted,
+            }),
+            // The server never acks this SUBSCRIBE
+            common::TestConnectionStep::Receives(mqtt::proto::Packet::Subscribe {
+                packet_identifier: mqtt::proto::PacketIdentifier::new(1).unwrap(),
+                subscribe_to: vec![mqtt::proto::SubscribeTo {
+                    topic_filter: "topic1".to_string(),
+                    qos: mqtt::proto::QoS::AtLeastOnce,
+                }],
+            }),
+            common::TestConnectionStep::StaysIdle,
+        ],
+        vec![
+            common::TestConnectionStep::Receives(mqtt::proto::Packet::Connect {
+                username: None,
+                password: None,
+                will: None,
+                client_id: mqtt::proto::ClientId::IdWithExistingSession(
+                    "ack_timeout_client_id".to_string(),
+                ),
+                keep_alive: std::time::Duration::from_secs(4),
+                protocol_version: mqtt::proto::ProtocolVersion::V3_1_1,
+            }),
+            common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck {
+                session_present: true,
+                return_code: mqtt::proto::ConnectReturnCode::Accepted,
+            }),
+            common::TestConnectionStep::Receives(mqtt::proto::Packet::Subscribe {
+                packet_identifier: mqtt::proto::PacketIdentifier::new(1).unwrap(),
+                subscribe_to: vec![mqtt::proto::SubscribeTo {
+                    topic_filter: "topic1".to_string(),
+                    qos: mqtt::proto::QoS::AtLeastOnce,
+                }],
+            }),
+            common::TestConnectionStep::Sends(mqtt::proto::Packet::SubAck {
+                packet_identifier: mqtt::proto::PacketIdentifier::new(1).unwrap(),
+                qos: vec![mqtt::proto::SubAckQos::Success(
+                    mqtt::proto::QoS::AtLeastOnce,
+                )],
+            }),
+            common::TestConnectionStep::Receives(mqtt::proto::Packet::PingReq),
+            common::TestConnectionStep::Sends(mqtt::proto::Packet::PingResp),
+        ],
+    ]);
+
+    let mut client = mqtt::ClientBuilder::new(io_source)
+        .client_id("ack_timeout_client_id".to_string())
+        .max_reconnect_back_off(std::time::Duration::from_secs(0))
+        .keep_alive(std::time::Duration::from_secs(4))
+        .subscription_ack_timeout(Some(std::time::Duration::from_secs(1)))
+        .build()
+        .unwrap();
+    client
+        .subscribe(mqtt::proto::SubscribeTo {
+            topic_filter: "topic1".to_string(),
+            qos: mqtt::proto::QoS::AtLeastOnce,
+        })
+        .unwrap();
+
+    // Nothing has been sent to the server yet
+    assert_eq!(client.unacked_subscription_updates(), vec![]);
+
+    common::verify_client_events(
+        &mut runtime,
+        client,
+        vec![
+            mqtt::Event::NewConnection {
+                reset_session: true,
+                retransmitted_publications: 0,
+            },
+            mqtt::Event::SubscriptionUpdates(vec![mqtt::SubscriptionUpdate::AckTimeout(vec![
+                "topic1".to_string(),
+            ])]),
+            mqtt::Event::NewConnection {
+                reset_session: false,
+                retransmitted_publications: 0,
+            },
+            mqtt::Event::SubscriptionUpdates(vec![mqtt::SubscriptionUpdate::Subscribe(
+                mqtt::proto::SubscribeTo {
+                    topic_filter: "topic1".to_string(),
+                    qos: mqtt::proto::QoS::AtLeastOnce,
+                },
+            )]),
+            mqtt::Event::SubscriptionsSynced,
         ],
     );
 
@@ -466,3 +698,414 @@ fn should_combine_pending_subscription_updates() {
         .block_on(done)
         .expect("connection broken while there were still steps remaining on the server");
 }
+
+#[test]
+fn subscription_priority_resubscribes_high_priority_first() {
+    let mut runtime =
+        tokio::runtime::current_thread::Runtime::new().expect("couldn't initialize tokio runtime");
+
+    let (io_source, done) = common::IoSource::new(vec![
+        vec![
+            common::TestConnectionStep::Receives(mqtt::proto::Packet::Connect {
+                username: None,
+                password: None,
+                will: None,
+                client_id: mqtt::proto::ClientId::ServerGenerated,
+                keep_alive: std::time::Duration::from_secs(4),
+                protocol_version: mqtt::proto::ProtocolVersion::V3_1_1,
+            }),
+            common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck {
+                session_present: false,
+                return_code: mqtt::proto::ConnectReturnCode::Accepted,
+            }),
+            // Subscriptions requested while connected are sent together regardless of their priority
+            common::TestConnectionStep::Receives(mqtt::proto::Packet::Subscribe {
+                packet_identifier: mqtt::proto::PacketIdentifier::new(1).unwrap(),
+                subscribe_to: vec![
+                    mqtt::proto::SubscribeTo {
+                        topic_filter: "bulk1".to_string(),
+                        qos: mqtt::proto::QoS::AtMostOnce,
+                    },
+                    mqtt::proto::SubscribeTo {
+                        topic_filter: "bulk2".to_string(),
+                        qos: mqtt::proto::QoS::AtMostOnce,
+                    },
+                    mqtt::proto::SubscribeTo {
+                        topic_filter: "control".to_string(),
+                        qos: mqtt::proto::QoS::AtLeastOnce,
+                    },
+                ],
+            }),
+            common::TestConnectionStep::Sends(mqtt::proto::Packet::SubAck {
+                packet_identifier: mqtt::proto::PacketIdentifier::new(1).unwrap(),
+                qos: vec![
+                    mqtt::proto::SubAckQos::Success(mqtt::proto::QoS::AtMostOnce),
+                    mqtt::proto::SubAckQos::Success(mqtt::proto::QoS::AtMostOnce),
+                    mqtt::proto::SubAckQos::Success(mqtt::proto::QoS::AtLeastOnce),
+                ],
+            }),
+        ],
+        vec![
+            common::TestConnectionStep::Receives(mqtt::proto::Packet::Connect {
+                username: None,
+                password: None,
+                will: None,
+                client_id: mqtt::proto::ClientId::ServerGenerated,
+                keep_alive: std::time::Duration::from_secs(4),
+                protocol_version: mqtt::proto::ProtocolVersion::V3_1_1,
+            }),
+            common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck {
+                session_present: false,
+                return_code: mqtt::proto::ConnectReturnCode::Accepted,
+            }),
+            // Resubscriptions are sent highest priority first
+            common::TestConnectionStep::Receives(mqtt::proto::Packet::Subscribe {
+                packet_identifier: mqtt::proto::PacketIdentifier::new(2).unwrap(),
+                subscribe_to: vec![mqtt::proto::SubscribeTo {
+                    topic_filter: "control".to_string(),
+                    qos: mqtt::proto::QoS::AtLeastOnce,
+                }],
+            }),
+            common::TestConnectionStep::Receives(mqtt::proto::Packet::Subscribe {
+                packet_identifier: mqtt::proto::PacketIdentifier::new(3).unwrap(),
+                subscribe_to: vec![
+                    mqtt::proto::SubscribeTo {
+                        topic_filter: "bulk1".to_string(),
+                        qos: mqtt::proto::QoS::AtMostOnce,
+                    },
+                    mqtt::proto::SubscribeTo {
+                        topic_filter: "bulk2".to_string(),
+                        qos: mqtt::proto::QoS::AtMostOnce,
+                    },
+                ],
+            }),
+            common::TestConnectionStep::Sends(mqtt::proto::Packet::SubAck {
+                packet_identifier: mqtt::proto::PacketIdentifier::new(2).unwrap(),
+                qos: vec![mqtt::proto::SubAckQos::Success(
+                    mqtt::proto::QoS::AtLeastOnce,
+                )],
+            }),
+            common::TestConnectionStep::Sends(mqtt::proto::Packet::SubAck {
+                packet_identifier: mqtt::proto::PacketIdentifier::new(3).unwrap(),
+                qos: vec![
+                    mqtt::proto::SubAckQos::Success(mqtt::proto::QoS::AtMostOnce),
+                    mqtt::proto::SubAckQos::Success(mqtt::proto::QoS::AtMostOnce),
+                ],
+            }),
+            common::TestConnectionStep::Receives(mqtt::proto::Packet::PingReq),
+            common::TestConnectionStep::Sends(mqtt::proto::Packet::PingResp),
+        ],
+    ]);
+
+    let mut client = mqtt::Client::new(
+        None,
+        None,
+        None,
+        None,
+        io_source,
+        std::time::Duration::from_secs(0),
+        std::time::Duration::from_secs(4),
+    )
+    .unwrap();
+    client.set_subscription_priority("control".to_string(), 1);
+    client
+        .subscribe_many(vec![
+            mqtt::proto::SubscribeTo {
+                topic_filter: "bulk1".to_string(),
+                qos: mqtt::proto::QoS::AtMostOnce,
+            },
+            mqtt::proto::SubscribeTo {
+                topic_filter: "control".to_string(),
+                qos: mqtt::proto::QoS::AtLeastOnce,
+            },
+            mqtt::proto::SubscribeTo {
+                topic_filter: "bulk2".to_string(),
+                qos: mqtt::proto::QoS::AtMostOnce,
+            },
+        ])
+        .unwrap();
+
+    common::verify_client_events(
+        &mut runtime,
+        client,
+        vec![
+            mqtt::Event::NewConnection {
+                reset_session: true,
+                retransmitted_publications: 0,
+            },
+            mqtt::Event::SubscriptionUpdates(vec![
+                mqtt::SubscriptionUpdate::Subscribe(mqtt::proto::SubscribeTo {
+                    topic_filter: "bulk1".to_string(),
+                    qos: mqtt::proto::QoS::AtMostOnce,
+                }),
+                mqtt::SubscriptionUpdate::Subscribe(mqtt::proto::SubscribeTo {
+                    topic_filter: "bulk2".to_string(),
+                    qos: mqtt::proto::QoS::AtMostOnce,
+                }),
+                mqtt::SubscriptionUpdate::Subscribe(mqtt::proto::SubscribeTo {
+                    topic_filter: "control".to_string(),
+                    qos: mqtt::proto::QoS::AtLeastOnce,
+                }),
+            ]),
+            mqtt::Event::NewConnection {
+                reset_session: true,
+                retransmitted_publications: 0,
+            },
+            mqtt::Event::SubscriptionUpdates(vec![mqtt::SubscriptionUpdate::Subscribe(
+                mqtt::proto::SubscribeTo {
+                    topic_filter: "control".to_string(),
+                    qos: mqtt::proto::QoS::AtLeastOnce,
+                },
+            )]),
+            mqtt::Event::SubscriptionUpdates(vec![
+                mqtt::SubscriptionUpdate::Subscribe(mqtt::proto::SubscribeTo {
+                    topic_filter: "bulk1".to_string(),
+                    qos: mqtt::proto::QoS::AtMostOnce,
+                }),
+                mqtt::SubscriptionUpdate::Subscribe(mqtt::proto::SubscribeTo {
+                    topic_filter: "bulk2".to_string(),
+                    qos: mqtt::proto::QoS::AtMostOnce,
+                }),
+            ]),
+            mqtt::Event::SubscriptionsSynced,
+        ],
+    );
+
+    runtime
+        .block_on(done)
+        .expect("connection broken while there were still steps remaining on the server");
+}
+
+#[test]
+fn subscribe_stream_receives_matching_publications_until_unsubscribed() {
+    use futures::{Future, Stream};
+
+    let mut runtime =
+        tokio::runtime::current_thread::Runtime::new().expect("couldn't initialize tokio runtime");
+
+    let publication = |topic_name: &str| mqtt::proto::Packet::Publish {
+        packet_identifier_dup_qos: mqtt::proto::PacketIdentifierDupQoS::AtMostOnce,
+        retain: false,
+        topic_name: topic_name.to_string(),
+        payload: topic_name.as_bytes().to_vec(),
+    };
+    let received_publication = |topic_name: &str| mqtt::ReceivedPublication {
+        topic_name: topic_name.to_string(),
+        dup: false,
+        qos: mqtt::proto::QoS::AtMostOnce,
+        retain: false,
+        payload: topic_name.as_bytes().to_vec(),
+    };
+
+    let (io_source, done) = common::IoSource::new(vec![vec![
+        common::TestConnectionStep::Receives(mqtt::proto::Packet::Connect {
+            username: None,
+            password: None,
+            will: None,
+            client_id: mqtt::proto::ClientId::ServerGenerated,
+            keep_alive: std::time::Duration::from_secs(4),
+            protocol_version: mqtt::proto::ProtocolVersion::V3_1_1,
+        }),
+        common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck {
+            session_present: false,
+            return_code: mqtt::proto::ConnectReturnCode::Accepted,
+        }),
+        common::TestConnectionStep::Receives(mqtt::proto::Packet::Subscribe {
+            packet_identifier: mqtt::proto::PacketIdentifier::new(1).unwrap(),
+            subscribe_to: vec![mqtt::proto::SubscribeTo {
+                topic_filter: "sensors/+".to_string(),
+                qos: mqtt::proto::QoS::AtMostOnce,
+            }],
+        }),
+        common::TestConnectionStep::Sends(mqtt::proto::Packet::SubAck {
+            packet_identifier: mqtt::proto::PacketIdentifier::new(1).unwrap(),
+            qos: vec![mqtt::proto::SubAckQos::Success(
+                mqtt::proto::QoS::AtMostOnce,
+            )],
+        }),
+        common::TestConnectionStep::Sends(publication("sensors/a")),
+        common::TestConnectionStep::Sends(publication("other")),
+        common::TestConnectionStep::Sends(publication("sensors/b")),
+        common::TestConnectionStep::Receives(mqtt::proto::Packet::Unsubscribe {
+            packet_identifier: mqtt::proto::PacketIdentifier::new(2).unwrap(),
+            unsubscribe_from: vec!["sensors/+".to_string()],
+        }),
+        common::TestConnectionStep::Sends(mqtt::proto::Packet::UnsubAck {
+            packet_identifier: mqtt::proto::PacketIdentifier::new(2).unwrap(),
+        }),
+    ]]);
+
+    let mut client = mqtt::Client::new(
+        None,
+        None,
+        None,
+        None,
+        io_source,
+        std::time::Duration::from_secs(0),
+        std::time::Duration::from_secs(4),
+    )
+    .unwrap();
+    let stream = client
+        .subscribe_stream(mqtt::proto::SubscribeTo {
+            topic_filter: "sensors/+".to_string(),
+            qos: mqtt::proto::QoS::AtMostOnce,
+        })
+        .unwrap();
+    let mut update_subscription_handle = client.update_subscription_handle().unwrap();
+
+    // Publications on other topics are still yielded by the client, but not by the stream
+    common::verify_client_events(
+        &mut runtime,
+        client,
+        vec![
+            mqtt::Event::NewConnection {
+                reset_session: true,
+                retransmitted_publications: 0,
+            },
+            mqtt::Event::SubscriptionUpdates(vec![mqtt::SubscriptionUpdate::Subscribe(
+                mqtt::proto::SubscribeTo {
+                    topic_filter: "sensors/+".to_string(),
+                    qos: mqtt::proto::QoS::AtMostOnce,
+                },
+            )]),
+            mqtt::Event::Publication(received_publication("sensors/a")),
+            mqtt::Event::Publication(received_publication("other")),
+            mqtt::Event::Publication(received_publication("sensors/b")),
+            mqtt::Event::SubscriptionsSynced,
+            mqtt::Event::SubscriptionUpdates(vec![mqtt::SubscriptionUpdate::Unsubscribe(
+                "sensors/+".to_string(),
+            )]),
+        ],
+    );
+
+    // The stream only ends once the unsubscription has been acked, so this only resolves if it does
+    let received = runtime
+        .block_on(stream.fold(vec![], move |mut received, publication| {
+            received.push(publication);
+            if received.len() == 2 {
+                tokio::runtime::current_thread::spawn(
+                    update_subscription_handle
+                        .unsubscribe("sensors/+".to_string())
+                        .map_err(|err| panic!("{:?}", err)),
+                );
+            }
+            Ok::<_, ()>(received)
+        }))
+        .unwrap();
+    assert_eq!(
+        received,
+        vec![
+            received_publication("sensors/a"),
+            received_publication("sensors/b"),
+        ]
+    );
+
+    runtime
+        .block_on(done)
+        .expect("connection broken while there were still steps remaining on the server");
+}
+
+#[test]
+fn subscription_after_empty_initial_sync_is_synced() {
+    use futures::{Future, Stream};
+
+    let mut runtime =
+        tokio::runtime::current_thread::Runtime::new().expect("couldn't initialize tokio runtime");
+
+    let (io_source, done) = common::IoSource::new(vec![vec![
+        common::TestConnectionStep::Receives(mqtt::proto::Packet::Connect {
+            username: None,
+            password: None,
+            will: None,
+            client_id: mqtt::proto::ClientId::ServerGenerated,
+            keep_alive: std::time::Duration::from_secs(4),
+            protocol_version: mqtt::proto::ProtocolVersion::V3_1_1,
+        }),
+        common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck {
+            session_present: false,
+            return_code: mqtt::proto::ConnectReturnCode::Accepted,
+        }),
+        common::TestConnectionStep::Receives(mqtt::proto::Packet::Subscribe {
+            packet_identifier: mqtt::proto::PacketIdentifier::new(1).unwrap(),
+            subscribe_to: vec![mqtt::proto::SubscribeTo {
+                topic_filter: "topic1".to_string(),
+                qos: mqtt::proto::QoS::AtLeastOnce,
+            }],
+        }),
+        common::TestConnectionStep::Sends(mqtt::proto::Packet::SubAck {
+            packet_identifier: mqtt::proto::PacketIdentifier::new(1).unwrap(),
+            qos: vec![mqtt::proto::SubAckQos::Success(
+                mqtt::proto::QoS::AtLeastOnce,
+            )],
+        }),
+        common::TestConnectionStep::Receives(mqtt::proto::Packet::PingReq),
+        common::TestConnectionStep::Sends(mqtt::proto::Packet::PingResp),
+    ]]);
+
+    let client = mqtt::Client::new(
+        None,
+        None,
+        None,
+        None,
+        io_source,
+        std::time::Duration::from_secs(0),
+        std::time::Duration::from_secs(4),
+    )
+    .unwrap();
+    let mut update_subscription_handle = client.update_subscription_handle().unwrap();
+
+    let mut expected = vec![
+        mqtt::Event::NewConnection {
+            reset_session: true,
+            retransmitted_publications: 0,
+        },
+        mqtt::Event::SubscriptionUpdates(vec![mqtt::SubscriptionUpdate::Subscribe(
+            mqtt::proto::SubscribeTo {
+                topic_filter: "topic1".to_string(),
+                qos: mqtt::proto::QoS::AtLeastOnce,
+            },
+        )]),
+        mqtt::Event::SubscriptionsSynced,
+    ]
+    .into_iter();
+    let (synced_send, synced_recv) = futures::sync::oneshot::channel();
+    let mut synced_send = Some(synced_send);
+
+    // The client has no subscriptions when it connects, so there's nothing to sync until it subscribes
+    runtime.spawn(
+        client
+            .map_err(|err| panic!("{:?}", err))
+            .for_each(move |event| {
+                if let mqtt::Event::NewConnection { .. } = event {
+                    tokio::runtime::current_thread::spawn(
+                        update_subscription_handle
+                            .subscribe(mqtt::proto::SubscribeTo {
+                                topic_filter: "topic1".to_string(),
+                                qos: mqtt::proto::QoS::AtLeastOnce,
+                            })
+                            .map_err(|err| panic!("{:?}", err)),
+                    );
+                }
+
+                assert_eq!(expected.next(), Some(event));
+                if expected.len() == 0 {
+                    if let Some(synced_send) = synced_send.take() {
+                        let _ = synced_send.send(());
+                    }
+                }
+
+                Ok(())
+            }),
+    );
+
+    runtime
+        .block_on(tokio::timer::Timeout::new(
+            synced_recv,
+            std::time::Duration::from_secs(1),
+        ))
+        .expect("subscription was not synced");
+
+    runtime
+        .block_on(done)
+        .expect("connection broken while there were still steps remaining on the server");
+}
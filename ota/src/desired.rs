@@ -0,0 +1,120 @@
+use serde_derive::Deserialize;
+use serde_json::Value;
+use url::Url;
+
+/// The firmware the cloud wants the device to run, as set in the `firmware` desired property
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FirmwareTarget {
+    pub target_version: String,
+    #[serde(with = "url_serde")]
+    pub url: Url,
+    /// The expected SHA-256 digest of the image, as a hex string
+    #[serde(default)]
+    pub sha256: Option<String>,
+}
+
+/// Tracks the `firmware` desired property and decides when the device should be updated to match it
+pub struct Reconciler {
+    running_version: Option<String>,
+    desired: Value,
+    /// The last target version an update was started for. An update is never started twice for the same target,
+    /// so a target that fails to install, or that doesn't change the running version, doesn't cause a reboot loop.
+    attempted: Option<String>,
+}
+
+impl Reconciler {
+    pub fn new(running_version: Option<String>) -> Self {
+        Reconciler {
+            running_version,
+            desired: Value::Null,
+            attempted: None,
+        }
+    }
+
+    /// Resets the desired firmware from the full twin, and returns the target to update to, if any.
+    ///
+    /// The last attempted target is restored from the `firmwareUpdate` reported property,
+    /// so that an update isn't retried after the agent restarts.
+    pub fn twin_initial(&mut self, twin: &azure_iot_mqtt::TwinState) -> Option<FirmwareTarget> {
+        self.desired = twin
+            .desired
+            .properties
+            .get("firmware")
+            .cloned()
+            .unwrap_or(Value::Null);
+
+        if self.attempted.is_none() {
+            self.attempted = twin
+                .reported
+                .properties
+                .get("firmwareUpdate")
+                .and_then(|update| update.get("targetVersion"))
+                .and_then(Value::as_str)
+                .map(ToString::to_string);
+        }
+
+        self.next_target()
+    }
+
+    /// Applies a desired properties patch, and returns the target to update to, if any.
+    pub fn twin_patch(&mut self, patch: &azure_iot_mqtt::TwinProperties) -> Option<FirmwareTarget> {
+        match patch.properties.get("firmware") {
+            Some(firmware) => {
+                merge_patch(&mut self.desired, firmware);
+                self.next_target()
+            }
+            None => None,
+        }
+    }
+
+    fn next_target(&mut self) -> Option<FirmwareTarget> {
+        if self.desired.is_null() {
+            return None;
+        }
+
+        let target: FirmwareTarget = match serde_json::from_value(self.desired.clone()) {
+            Ok(target) => target,
+            Err(err) => {
+                log::warn!("Ignoring invalid firmware desired property: {}", err);
+                return None;
+            }
+        };
+
+        if self.running_version.as_ref() == Some(&target.target_version) {
+            log::info!("Already running firmware {}", target.target_version);
+            return None;
+        }
+        if self.attempted.as_ref() == Some(&target.target_version) {
+            log::info!(
+                "Not updating to firmware {} again since it was already attempted",
+                target.target_version
+            );
+            return None;
+        }
+
+        self.attempted = Some(target.target_version.clone());
+        Some(target)
+    }
+}
+
+/// Applies `patch` to `target` the way twin patches are applied: objects are merged recursively,
+/// nulls remove properties, and anything else replaces the existing value.
+fn merge_patch(target: &mut Value, patch: &Value) {
+    match (target, patch) {
+        (Value::Object(target), Value::Object(patch)) => {
+            for (key, value) in patch {
+                if value.is_null() {
+                    target.remove(key);
+                } else {
+                    merge_patch(target.entry(key.clone()).or_insert(Value::Null), value);
+                }
+            }
+        }
+        (target, Value::Object(_)) => {
+            *target = Value::Object(Default::default());
+            merge_patch(target, patch);
+        }
+        (target, patch) => *target = patch.clone(),
+    }
+}
@@ -1,58 +1,274 @@
-use std::fmt;
-
-use failure::{Backtrace, Context, Fail};
-
 #[derive(Debug)]
-pub struct Error {
-    inner: Context<ErrorKind>,
+pub enum Error {
+    Busy,
+    Cancelled {
+        url: String,
+    },
+    ChecksumMismatch {
+        url: String,
+        expected: String,
+        actual: String,
+    },
+    Download {
+        url: String,
+        offset: u64,
+        err: reqwest::Error,
+    },
+    DownloadClient(reqwest::Error),
+    DownloadStatus {
+        url: String,
+        status: u16,
+    },
+    DownloadTimer(tokio::timer::Error),
+    EnvRead {
+        var_name: String,
+        err: EnvReadError,
+    },
+    ImageTooLarge {
+        url: String,
+        path: std::path::PathBuf,
+        size: u64,
+        max: u64,
+    },
+    IncompleteImage(i8),
+    InsecureUrl(String),
+    InvalidHeader(String),
+    InvalidUrl(reqwest::Error),
+    OpenDevice {
+        path: std::path::PathBuf,
+        err: std::io::Error,
+    },
+    ParseState {
+        path: std::path::PathBuf,
+        err: serde_json::Error,
+    },
+    ReadState {
+        path: std::path::PathBuf,
+        err: std::io::Error,
+    },
+    Reboot(std::io::Error),
+    ShuttingDown,
+    Swap {
+        partition: i8,
+        err: std::io::Error,
+    },
+    Write {
+        path: std::path::PathBuf,
+        offset: u64,
+        err: std::io::Error,
+    },
 }
 
-#[derive(Debug, Fail)]
-pub enum ErrorKind {
-    #[fail(display = "Failed to reboot device.")]
-    Reboot,
-    #[fail(display = "Failed to swap devices.")]
-    Swap,
-    #[fail(display = "Failed to download new image.")]
-    Download,
+#[derive(Debug)]
+pub enum EnvReadError {
+    ExitStatus {
+        printenv: std::path::PathBuf,
+        status: std::process::ExitStatus,
+        stderr: String,
+    },
+    Parse(std::num::ParseIntError),
+    Spawn {
+        printenv: std::path::PathBuf,
+        err: std::io::Error,
+    },
+    UnexpectedOutput(String),
+    Utf8(std::str::Utf8Error),
 }
 
-impl Fail for Error {
-    fn cause(&self) -> Option<&Fail> {
-        self.inner.cause()
+impl Error {
+    /// Whether the operation that failed with this error might succeed if it is retried,
+    /// such as after a network error or a server error.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            // Redirect errors are a loop or too many redirects, which retrying doesn't fix
+            Error::Download { err, .. } => !err.is_redirect(),
+            Error::DownloadStatus { status, .. } => *status >= 500,
+            _ => false,
+        }
     }
 
-    fn backtrace(&self) -> Option<&Backtrace> {
-        self.inner.backtrace()
+    /// Whether the operation failed because it was cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        match self {
+            Error::Cancelled { .. } => true,
+            _ => false,
+        }
     }
 }
 
-impl fmt::Display for Error {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        fmt::Display::fmt(&self.inner, f)
-    }
-}
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Busy => write!(f, "another operation is already in progress"),
 
-impl Error {
-    pub fn new(inner: Context<ErrorKind>) -> Self {
-        Error { inner }
+            Error::Cancelled { url } => write!(f, "load of {} was cancelled", url),
+
+            Error::ChecksumMismatch {
+                url,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "image {} has SHA-256 digest {} but expected {}",
+                url, actual, expected
+            ),
+
+            Error::Download { url, offset, err } => write!(
+                f,
+                "could not download image {} at byte {}: {}",
+                url, offset, err
+            ),
+
+            Error::DownloadClient(err) => write!(f, "could not create download client: {}", err),
+
+            Error::DownloadStatus { url, status } => write!(
+                f,
+                "download of image {} failed with HTTP status {}",
+                url, status
+            ),
+
+            Error::DownloadTimer(err) => write!(f, "download back-off timer failed: {}", err),
+
+            Error::EnvRead { var_name, err } => write!(
+                f,
+                "could not read boot environment variable {}: {}",
+                var_name, err
+            ),
+
+            Error::ImageTooLarge {
+                url,
+                path,
+                size,
+                max,
+            } => write!(
+                f,
+                "image {} is at least {} bytes, which is larger than the {} bytes of partition {}",
+                url,
+                size,
+                max,
+                path.display()
+            ),
+
+            Error::IncompleteImage(partition) => write!(
+                f,
+                "partition {} holds an incomplete image and cannot be booted",
+                partition
+            ),
+
+            Error::InsecureUrl(url) => write!(
+                f,
+                "image {} is not served over HTTPS, and plain HTTP is not allowed",
+                url
+            ),
+
+            Error::InvalidHeader(name) => {
+                write!(f, "download header {} has an invalid name or value", name)
+            }
+
+            Error::InvalidUrl(err) => write!(f, "invalid image URL: {}", err),
+
+            Error::OpenDevice { path, err } => {
+                write!(f, "could not open partition {}: {}", path.display(), err)
+            }
+
+            Error::ParseState { path, err } => write!(
+                f,
+                "could not parse updater state {}: {}",
+                path.display(),
+                err
+            ),
+
+            Error::ReadState { path, err } => write!(
+                f,
+                "could not read updater state {}: {}",
+                path.display(),
+                err
+            ),
+
+            Error::Reboot(err) => write!(f, "could not reboot device: {}", err),
+
+            Error::ShuttingDown => write!(f, "the agent is shutting down"),
+
+            Error::Swap { partition, err } => {
+                write!(f, "could not swap boot partition to {}: {}", partition, err)
+            }
+
+            Error::Write { path, offset, err } => write!(
+                f,
+                "could not write image to partition {} at byte {}: {}",
+                path.display(),
+                offset,
+                err
+            ),
+        }
     }
+}
 
-    pub fn kind(&self) -> &ErrorKind {
-        self.inner.get_context()
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        #[allow(clippy::match_same_arms)]
+        match self {
+            Error::Busy => None,
+            Error::Cancelled { .. } => None,
+            Error::ChecksumMismatch { .. } => None,
+            Error::Download { err, .. } => Some(err),
+            Error::DownloadClient(err) => Some(err),
+            Error::DownloadStatus { .. } => None,
+            Error::DownloadTimer(err) => Some(err),
+            Error::EnvRead { err, .. } => Some(err),
+            Error::ImageTooLarge { .. } => None,
+            Error::IncompleteImage(_) => None,
+            Error::InsecureUrl(_) => None,
+            Error::InvalidHeader(_) => None,
+            Error::InvalidUrl(err) => Some(err),
+            Error::OpenDevice { err, .. } => Some(err),
+            Error::ParseState { err, .. } => Some(err),
+            Error::ReadState { err, .. } => Some(err),
+            Error::Reboot(err) => Some(err),
+            Error::ShuttingDown => None,
+            Error::Swap { err, .. } => Some(err),
+            Error::Write { err, .. } => Some(err),
+        }
     }
 }
 
-impl From<ErrorKind> for Error {
-    fn from(kind: ErrorKind) -> Self {
-        Error {
-            inner: Context::new(kind),
+impl std::fmt::Display for EnvReadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EnvReadError::ExitStatus {
+                printenv,
+                status,
+                stderr,
+            } => write!(
+                f,
+                "{} exited with {}: {}",
+                printenv.display(),
+                status,
+                stderr
+            ),
+
+            EnvReadError::Parse(err) => write!(f, "could not parse value: {}", err),
+
+            EnvReadError::Spawn { printenv, err } => {
+                write!(f, "could not run {}: {}", printenv.display(), err)
+            }
+
+            EnvReadError::UnexpectedOutput(output) => write!(f, "unexpected output {:?}", output),
+
+            EnvReadError::Utf8(err) => write!(f, "output is not valid UTF-8: {}", err),
         }
     }
 }
 
-impl From<Context<ErrorKind>> for Error {
-    fn from(inner: Context<ErrorKind>) -> Self {
-        Error { inner }
+impl std::error::Error for EnvReadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        #[allow(clippy::match_same_arms)]
+        match self {
+            EnvReadError::ExitStatus { .. } => None,
+            EnvReadError::Parse(err) => Some(err),
+            EnvReadError::Spawn { err, .. } => Some(err),
+            EnvReadError::UnexpectedOutput(_) => None,
+            EnvReadError::Utf8(err) => Some(err),
+        }
     }
 }
@@ -1,25 +1,34 @@
-use std::process::Command;
+use std::env;
+use std::process;
 use std::str;
-use std::time::Duration;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use azure_iot_mqtt::device;
+use futures::future::{self, Either};
 use futures::{Future, Stream};
-use regex::Regex;
 use serde_derive::{Deserialize, Serialize};
 use serde_json::json;
-use tokio::runtime::Runtime;
+use tokio::runtime::{Runtime, TaskExecutor};
+use tokio::timer::Interval;
 use tokio_signal;
 use url::Url;
 
+mod desired;
 mod error;
 mod updater;
 
-use crate::updater::{Device, Updater};
+use crate::desired::{FirmwareTarget, Reconciler};
+use crate::error::Error;
+use crate::updater::{BootEnv, Device, DownloadOptions, OperationGuard, RetryPolicy, Updater};
 
 #[derive(Deserialize, Serialize)]
 pub struct UpdateRequest {
     #[serde(with = "url_serde")]
     url: Url,
+    /// The expected SHA-256 digest of the image, as a hex string
+    #[serde(default)]
+    sha256: Option<String>,
 }
 
 fn main() {
@@ -28,6 +37,9 @@ fn main() {
         "mqtt=debug,mqtt::logging=trace,azure_iot_mqtt=debug,ota=info",
     ))
     .init();
+    // In one-shot mode, the agent reconciles the firmware with the twin's desired properties once,
+    // reports the result and exits, rather than listening for changes.
+    let once = env::args().skip(1).any(|arg| arg == "--once");
     let iothub = "miyagley-edge.azure-devices.net";
     let device_id = "raspberrypi3";
     let sas_token = "SharedAccessSignature sr=miyagley-edge.azure-devices.net%2Fdevices%2Fraspberrypi3&sig=0AbCARoU3rONykEauTCY254PilXsaJ6Kl8m5zux%2BA8c%3D&se=1552521063";
@@ -36,33 +48,45 @@ fn main() {
     let mut runtime = Runtime::new().expect("couldn't initialize tokio runtime");
     let executor = runtime.executor();
 
-    let re = Regex::new(r"ota_boot_partition=(?P<partition>\d)").expect("regex failed");
-    let output = Command::new("/sbin/fw_printenv")
-        .arg("ota_boot_partition")
-        .output()
-        .unwrap();
-    let caps = re
-        .captures(str::from_utf8(&output.stdout).unwrap())
-        .unwrap();
-    let partition: i8 = caps["partition"].parse().unwrap();
+    let boot_env = boot_env_from_env();
+    let partition = match boot_env.read_partition() {
+        Ok(partition) => partition,
+        Err(err) => {
+            log::error!("Could not determine the active partition: {}", err);
+            process::exit(1);
+        }
+    };
     let mut updater = if partition == 3 {
-        let primary = Device::new("/dev/mmcblk0p3", 0, 3);
-        let secondary = Device::new("/dev/mmcblk0p2", 0, 2);
-        Updater::new(primary, secondary)
+        let primary = device_from_env("/dev/mmcblk0p3", 3);
+        let secondary = device_from_env("/dev/mmcblk0p2", 2);
+        Updater::new(primary, secondary, boot_env)
     } else {
-        let primary = Device::new("/dev/mmcblk0p2", 0, 2);
-        let secondary = Device::new("/dev/mmcblk0p3", 0, 3);
-        Updater::new(primary, secondary)
+        let primary = device_from_env("/dev/mmcblk0p2", 2);
+        let secondary = device_from_env("/dev/mmcblk0p3", 3);
+        Updater::new(primary, secondary, boot_env)
     };
+    updater.set_retry_policy(retry_policy_from_env());
+    if let Err(err) = updater.set_download_options(&download_options_from_env()) {
+        log::error!("Invalid download options: {}", err);
+        process::exit(1);
+    }
+    let state_path = env::var_os("AZURE_IOT_OTA_STATE_FILE")
+        .unwrap_or_else(|| "/var/lib/azure-iot-ota/state.json".into());
+    if let Err(err) = updater.load_state(state_path) {
+        log::warn!("Starting with a fresh updater state: {}", err);
+    }
+    let updater = Arc::new(Mutex::new(updater));
 
     let client = device::Client::new(
         iothub.to_string(),
+        None,
         device_id,
         auth,
         azure_iot_mqtt::Transport::Tcp,
         None,
         Duration::from_secs(30),
         Duration::from_secs(5),
+        azure_iot_mqtt::DEFAULT_HANDLE_CHANNEL_CAPACITY,
     )
     .expect("could not create client");
 
@@ -70,24 +94,106 @@ fn main() {
         .inner()
         .shutdown_handle()
         .expect("couldn't get shutdown handle");
+    // Taken by the reconciliation of the one-shot mode, so that it's only started for the first TwinInitial
+    let mut once_shutdown_handle = if once {
+        Some(
+            client
+                .inner()
+                .shutdown_handle()
+                .expect("couldn't get shutdown handle"),
+        )
+    } else {
+        None
+    };
     let direct_method_response_handle = client.direct_method_response_handle();
 
+    let firmware_version = env::var("AZURE_IOT_OTA_FIRMWARE_VERSION").ok();
+    let mut reconciler = Reconciler::new(firmware_version.clone());
+    let update_report_handle = Arc::new(client.report_twin_state_handle());
+
+    // Started once the first TwinInitial arrives, so that the initial report isn't lost before the client is connected
+    let mut health_report = if once {
+        None
+    } else {
+        Some(report_health(
+            client.report_twin_state_handle(),
+            firmware_version.clone(),
+            partition,
+            Duration::from_secs(parse_var("AZURE_IOT_OTA_HEALTH_REPORT_SECS").unwrap_or(300)),
+        ))
+    };
+
+    // The client is only shut down once the updater operation in progress, if any, has finished.
+    // A second Ctrl-C exits immediately, in case that operation is stuck.
+    let shutdown_updater = updater.clone();
+    let shutdown_executor = executor.clone();
+    let mut shutdown_handle = Some(shutdown_handle);
     let shutdown = tokio_signal::ctrl_c()
         .flatten_stream()
-        .into_future()
-        .then(move |_| {
+        .for_each(move |()| {
+            let shutdown_handle = match shutdown_handle.take() {
+                Some(shutdown_handle) => shutdown_handle,
+                None => {
+                    log::warn!("Shutdown requested again, exiting without waiting for the operation in progress");
+                    process::exit(1);
+                }
+            };
             log::info!("Shutdown requested...");
-            shutdown_handle.shutdown()
-        })
-        .then(|result| {
-            log::info!("Shutdown finished.");
-            result.expect("couldn't send shutdown notification");
+            let shutdown = shutdown_updater
+                .lock()
+                .expect("updater mutex poisoned")
+                .stop_operations()
+                .then(move |_| shutdown_handle.shutdown())
+                .then(|result| {
+                    log::info!("Shutdown finished.");
+                    result.expect("couldn't send shutdown notification");
+                    Ok(())
+                });
+            shutdown_executor.spawn(shutdown);
             Ok(())
-        });
+        })
+        .map_err(|err| log::warn!("couldn't listen for Ctrl-C: {}", err));
     runtime.spawn(shutdown);
 
     let f = client.for_each(move |message| {
         log::info!("received message {:?}", message);
+        let target = match &message {
+            azure_iot_mqtt::device::Message::TwinInitial(twin) => {
+                if let Some(health_report) = health_report.take() {
+                    executor.spawn(health_report);
+                }
+                reconciler.twin_initial(twin)
+            }
+            azure_iot_mqtt::device::Message::TwinPatch(patch) if !once => {
+                reconciler.twin_patch(patch)
+            }
+            _ => None,
+        };
+        if once {
+            // A TwinInitial after a reconnect doesn't start another reconciliation
+            if let azure_iot_mqtt::device::Message::TwinInitial(_) = &message {
+                if let Some(once_shutdown_handle) = once_shutdown_handle.take() {
+                    executor.spawn(reconcile_once(
+                        updater.clone(),
+                        update_report_handle.clone(),
+                        target,
+                        firmware_version.clone(),
+                        partition,
+                        once_shutdown_handle.shutdown().then(|result| {
+                            result.expect("couldn't send shutdown notification");
+                            Ok(())
+                        }),
+                    ));
+                }
+            }
+        } else if let Some(target) = target {
+            executor.spawn(apply_update(
+                updater.clone(),
+                update_report_handle.clone(),
+                target,
+                future::ok(()),
+            ));
+        }
         if let azure_iot_mqtt::device::Message::DirectMethod {
             name,
             payload,
@@ -104,7 +210,14 @@ fn main() {
             match name.as_ref() {
                 "reboot" => {
                     log::info!("Received reboot request...");
+                    let guard =
+                        match begin_operation(&updater, &name, &request_id, &handle, &executor) {
+                            Some(guard) => guard,
+                            None => return Ok(()),
+                        };
                     let result = updater
+                        .lock()
+                        .expect("updater mutex poisoned")
                         .reboot()
                         .then(move |result| match result {
                             Ok(_) => handle.respond(
@@ -112,22 +225,31 @@ fn main() {
                                 azure_iot_mqtt::Status::Ok,
                                 json!({"message": "rebooting"}),
                             ),
-                            Err(_e) => handle.respond(
+                            Err(e) => handle.respond(
                                 request_id.clone(),
                                 azure_iot_mqtt::Status::BadRequest,
-                                payload,
+                                json!({"message": e.to_string()}),
                             ),
                         })
                         .then(move |result| {
-                            let () = result.expect("couldn't send direct method response");
-                            log::info!("Rebooting finished and responded to request");
+                            match result {
+                                Ok(()) => log::info!("Rebooting finished and responded to request"),
+                                Err(err) => log::warn!("couldn't send direct method response: {}", err),
+                            }
                             Ok(())
                         });
-                    executor.spawn(result)
+                    executor.spawn(guard.until(result))
                 }
                 "swap" => {
                     log::info!("Received swap request...");
+                    let guard =
+                        match begin_operation(&updater, &name, &request_id, &handle, &executor) {
+                            Some(guard) => guard,
+                            None => return Ok(()),
+                        };
                     let result = updater
+                        .lock()
+                        .expect("updater mutex poisoned")
                         .swap()
                         .then(move |result| match result {
                             Ok(_) => handle.respond(
@@ -135,30 +257,108 @@ fn main() {
                                 azure_iot_mqtt::Status::Ok,
                                 json!({"message": "swapped"}),
                             ),
-                            Err(_e) => handle.respond(
+                            Err(e) => handle.respond(
                                 request_id.clone(),
                                 azure_iot_mqtt::Status::BadRequest,
-                                payload,
+                                json!({"message": e.to_string()}),
                             ),
                         })
                         .then(move |result| {
-                            let () = result.expect("couldn't send direct method response");
-                            log::info!("Swapping finished and responded to request");
+                            match result {
+                                Ok(()) => log::info!("Swapping finished and responded to request"),
+                                Err(err) => log::warn!("couldn't send direct method response: {}", err),
+                            }
+                            Ok(())
+                        });
+                    executor.spawn(guard.until(result))
+                }
+                "status" => {
+                    let status = updater.lock().expect("updater mutex poisoned").status();
+                    log::info!("Received status request, responding with {:?}", status);
+                    let payload = serde_json::to_value(status)
+                        .expect("cannot fail to serialize updater status");
+                    let result = handle
+                        .respond(request_id.clone(), azure_iot_mqtt::Status::Ok, payload)
+                        .then(move |result| {
+                            match result {
+                                Ok(()) => log::info!("Responded to status request"),
+                                Err(err) => log::warn!("couldn't send direct method response: {}", err),
+                            }
+                            Ok(())
+                        });
+                    executor.spawn(result)
+                }
+                "cancel" => {
+                    log::info!("Received cancel request...");
+                    let (status, message) = if updater
+                        .lock()
+                        .expect("updater mutex poisoned")
+                        .cancel_load()
+                    {
+                        (azure_iot_mqtt::Status::Ok, "cancelling")
+                    } else {
+                        (azure_iot_mqtt::Status::BadRequest, "no load in progress")
+                    };
+                    let result = handle
+                        .respond(request_id.clone(), status, json!({ "message": message }))
+                        .then(move |result| {
+                            match result {
+                                Ok(()) => log::info!("Responded to cancel request"),
+                                Err(err) => log::warn!("couldn't send direct method response: {}", err),
+                            }
                             Ok(())
                         });
                     executor.spawn(result)
                 }
-                "load" => {
-                    log::info!("Received load request...");
-                    let request: UpdateRequest =
-                        serde_json::from_value(payload).expect("failed to parse request");
+                "load" | "verify" => {
+                    let verify_only = name == "verify";
+                    log::info!("Received {} request...", name);
+                    let request: UpdateRequest = match serde_json::from_value(payload) {
+                        Ok(request) => request,
+                        Err(err) => {
+                            log::warn!(
+                                "Rejecting {} request with malformed payload: {}",
+                                name,
+                                err
+                            );
+                            let result = handle
+                                .respond(
+                                    request_id,
+                                    azure_iot_mqtt::Status::BadRequest,
+                                    json!({ "message": format!("could not parse request: {}", err) }),
+                                )
+                                .then(|result| {
+                                    if let Err(err) = result {
+                                        log::warn!("couldn't send direct method response: {}", err);
+                                    }
+                                    Ok(())
+                                });
+                            executor.spawn(result);
+                            return Ok(());
+                        }
+                    };
+                    let guard =
+                        match begin_operation(&updater, &name, &request_id, &handle, &executor) {
+                            Some(guard) => guard,
+                            None => return Ok(()),
+                        };
                     let result = updater
-                        .load(request.url)
+                        .lock()
+                        .expect("updater mutex poisoned")
+                        .load(request.url, request.sha256, verify_only)
                         .then(move |result| match result {
-                            Ok(_) => handle.respond(
+                            Ok(sha256) => handle.respond(
                                 request_id.clone(),
                                 azure_iot_mqtt::Status::Ok,
-                                json!({"message": "loaded"}),
+                                json!({
+                                    "message": if verify_only { "verified" } else { "loaded" },
+                                    "sha256": sha256,
+                                }),
+                            ),
+                            Err(ref e) if e.is_cancelled() => handle.respond(
+                                request_id.clone(),
+                                azure_iot_mqtt::Status::Other(409),
+                                json!({"message": "cancelled"}),
                             ),
                             Err(e) => handle.respond(
                                 request_id.clone(),
@@ -167,19 +367,23 @@ fn main() {
                             ),
                         })
                         .then(move |result| {
-                            let () = result.expect("couldn't send direct method response");
-                            log::info!("Loading finished and responded to request");
+                            match result {
+                                Ok(()) => log::info!("Loading finished and responded to request"),
+                                Err(err) => log::warn!("couldn't send direct method response: {}", err),
+                            }
                             Ok(())
                         });
-                    executor.spawn(result)
+                    executor.spawn(guard.until(result))
                 }
                 _ => {
                     // Respond with status 200 and same payload
                     let result = handle
                         .respond(request_id.clone(), azure_iot_mqtt::Status::Ok, payload)
                         .then(move |result| {
-                            let () = result.expect("couldn't send direct method response");
-                            log::info!("Responded to request {}", request_id);
+                            match result {
+                                Ok(()) => log::info!("Responded to request {}", request_id),
+                                Err(err) => log::warn!("couldn't send direct method response: {}", err),
+                            }
                             Ok(())
                         });
                     executor.spawn(result)
@@ -192,3 +396,322 @@ fn main() {
 
     runtime.block_on(f).expect("azure-iot-mqtt-client failed");
 }
+
+/// Begins an operation on the updater for the direct method `name`. If another operation is already in progress,
+/// responds to the direct method with 409 instead and returns `None`.
+fn begin_operation(
+    updater: &Mutex<Updater>,
+    name: &str,
+    request_id: &str,
+    handle: &azure_iot_mqtt::DirectMethodResponseHandle,
+    executor: &TaskExecutor,
+) -> Option<OperationGuard> {
+    match updater
+        .lock()
+        .expect("updater mutex poisoned")
+        .begin_operation()
+    {
+        Ok(guard) => Some(guard),
+        Err(err) => {
+            log::warn!("Rejecting {} request: {}", name, err);
+            let result = handle
+                .respond(
+                    request_id.to_string(),
+                    azure_iot_mqtt::Status::Other(409),
+                    json!({ "message": err.to_string() }),
+                )
+                .then(|result| {
+                    if let Err(err) = result {
+                        log::warn!("couldn't send direct method response: {}", err);
+                    }
+                    Ok(())
+                });
+            executor.spawn(result);
+            None
+        }
+    }
+}
+
+/// Builds the boot environment from the `AZURE_IOT_OTA_FW_PRINTENV`, `AZURE_IOT_OTA_FW_SETENV` and `AZURE_IOT_OTA_BOOT_VAR`
+/// environment variables, falling back to u-boot's defaults for any that aren't set.
+fn boot_env_from_env() -> BootEnv {
+    BootEnv::new(
+        env::var_os("AZURE_IOT_OTA_FW_PRINTENV").unwrap_or_else(|| "/sbin/fw_printenv".into()),
+        env::var_os("AZURE_IOT_OTA_FW_SETENV").unwrap_or_else(|| "/sbin/fw_setenv".into()),
+        env::var("AZURE_IOT_OTA_BOOT_VAR").unwrap_or_else(|_| "ota_boot_partition".to_string()),
+    )
+}
+
+/// Builds the device for `partition` at `path`, with the size in bytes from the `AZURE_IOT_OTA_PARTITION<partition>_SIZE`
+/// environment variable if it's set, eg `AZURE_IOT_OTA_PARTITION2_SIZE` for partition 2.
+fn device_from_env(path: &str, partition: i8) -> Device {
+    let mut device = Device::new(path, 0, partition);
+    device.set_size(parse_var(&format!(
+        "AZURE_IOT_OTA_PARTITION{}_SIZE",
+        partition
+    )));
+    device
+}
+
+/// Builds the download retry policy from the `AZURE_IOT_OTA_DOWNLOAD_ATTEMPTS` and `AZURE_IOT_OTA_DOWNLOAD_MAX_BACK_OFF_SECS`
+/// environment variables, falling back to the defaults for any that aren't set or can't be parsed.
+fn retry_policy_from_env() -> RetryPolicy {
+    let mut retry = RetryPolicy::default();
+    if let Some(max_attempts) = parse_var("AZURE_IOT_OTA_DOWNLOAD_ATTEMPTS") {
+        retry.max_attempts = max_attempts;
+    }
+    if let Some(max_back_off) = parse_var("AZURE_IOT_OTA_DOWNLOAD_MAX_BACK_OFF_SECS") {
+        retry.max_back_off = Duration::from_secs(max_back_off);
+    }
+    retry
+}
+
+/// Builds the download options from the `AZURE_IOT_OTA_DOWNLOAD_AUTHORIZATION`, `AZURE_IOT_OTA_DOWNLOAD_MAX_REDIRECTS`
+/// and `AZURE_IOT_OTA_DOWNLOAD_ALLOW_HTTP` environment variables, falling back to the defaults for any that aren't set or can't be parsed.
+///
+/// `AZURE_IOT_OTA_DOWNLOAD_AUTHORIZATION` is sent as the `Authorization` header. SAS URLs don't need it, since they carry their signature.
+fn download_options_from_env() -> DownloadOptions {
+    let mut options = DownloadOptions::default();
+    if let Ok(authorization) = env::var("AZURE_IOT_OTA_DOWNLOAD_AUTHORIZATION") {
+        options
+            .headers
+            .insert("Authorization".to_string(), authorization);
+    }
+    if let Some(max_redirects) = parse_var("AZURE_IOT_OTA_DOWNLOAD_MAX_REDIRECTS") {
+        options.max_redirects = max_redirects;
+    }
+    if let Some(allow_http) = parse_var("AZURE_IOT_OTA_DOWNLOAD_ALLOW_HTTP") {
+        options.allow_http = allow_http;
+    }
+    options
+}
+
+/// Reads and parses the environment variable `name`, ignoring it with a warning if it can't be parsed.
+fn parse_var<T: str::FromStr>(name: &str) -> Option<T> {
+    let value = env::var(name).ok()?;
+    match value.parse() {
+        Ok(value) => Some(value),
+        Err(_) => {
+            log::warn!("Ignoring invalid value {:?} for {}", value, name);
+            None
+        }
+    }
+}
+
+/// Reports the running firmware `version` and boot `partition` to the `firmware` reported property,
+/// immediately and then every `period`.
+fn report_health(
+    report_twin_state_handle: azure_iot_mqtt::ReportTwinStateHandle,
+    version: Option<String>,
+    partition: i8,
+    period: Duration,
+) -> impl Future<Item = (), Error = ()> {
+    Interval::new(Instant::now(), period)
+        .map_err(|err| log::warn!("health report timer failed: {}", err))
+        .for_each(move |_| report_firmware(&report_twin_state_handle, &version, partition))
+}
+
+/// The `firmware` reported property for the running firmware `version` and boot `partition`
+fn firmware(version: &Option<String>, partition: i8) -> serde_json::Value {
+    json!({
+        "version": version,
+        "partition": partition,
+    })
+}
+
+/// Reports the running firmware `version` and boot `partition` to the `firmware` reported property
+fn report_firmware(
+    report_twin_state_handle: &azure_iot_mqtt::ReportTwinStateHandle,
+    version: &Option<String>,
+    partition: i8,
+) -> impl Future<Item = (), Error = ()> {
+    report_twin_state_handle
+        .report_twin_state(azure_iot_mqtt::ReportTwinStateRequest::Patch(
+            vec![("firmware".to_string(), firmware(version, partition))]
+                .into_iter()
+                .collect(),
+        ))
+        .then(|result| {
+            if let Err(err) = result {
+                log::warn!("couldn't report firmware to twin: {}", err);
+            }
+            Ok(())
+        })
+}
+
+/// Reports the running firmware like [`report_firmware`], and the current time in seconds since the Unix epoch
+/// to the `lastReconciled` reported property. Resolves once the hub has acknowledged the report.
+fn report_reconciled(
+    report_twin_state_handle: &azure_iot_mqtt::ReportTwinStateHandle,
+    version: &Option<String>,
+    partition: i8,
+) -> impl Future<Item = (), Error = ()> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs())
+        .unwrap_or(0);
+    report_twin_state_handle
+        .report_twin_state_acked(azure_iot_mqtt::ReportTwinStateRequest::Patch(
+            vec![
+                ("firmware".to_string(), firmware(version, partition)),
+                ("lastReconciled".to_string(), json!(now)),
+            ]
+            .into_iter()
+            .collect(),
+        ))
+        .then(|result| {
+            match result {
+                Ok(azure_iot_mqtt::ReportTwinStateAck::Accepted(_)) => {
+                    log::info!("Reported the reconciled firmware")
+                }
+                Ok(azure_iot_mqtt::ReportTwinStateAck::Rejected(status)) => log::warn!(
+                    "The hub rejected the report of the reconciled firmware with status {}",
+                    status
+                ),
+                Ok(azure_iot_mqtt::ReportTwinStateAck::TooLarge { len, max }) => log::warn!(
+                    "The report of the reconciled firmware of {} bytes was larger than the maximum of {} bytes",
+                    len,
+                    max
+                ),
+                Err(err) => log::warn!("couldn't report firmware to twin: {}", err),
+            }
+            Ok(())
+        })
+}
+
+/// Applies `target`, if any, like in the normal mode, reports the reconciliation with [`report_reconciled`] and then runs `shutdown`.
+///
+/// With a target, the reconciliation is reported before the device reboots into the new firmware, so the agent doesn't wait
+/// for a reboot that ends it. If the update fails instead, it's reported afterwards.
+fn reconcile_once<F>(
+    updater: Arc<Mutex<Updater>>,
+    report_twin_state_handle: Arc<azure_iot_mqtt::ReportTwinStateHandle>,
+    target: Option<FirmwareTarget>,
+    version: Option<String>,
+    partition: i8,
+    shutdown: F,
+) -> impl Future<Item = (), Error = ()>
+where
+    F: Future<Item = (), Error = ()>,
+{
+    // Shared so that the reconciliation is only reported once, whether that's before rebooting or after the update
+    let report = {
+        let report_twin_state_handle = report_twin_state_handle.clone();
+        future::lazy(move || report_reconciled(&report_twin_state_handle, &version, partition))
+            .shared()
+    };
+
+    let update = match target {
+        Some(target) => Either::A(apply_update(
+            updater,
+            report_twin_state_handle,
+            target,
+            report.clone().then(|_| Ok(())),
+        )),
+        None => {
+            log::info!("Firmware is already up to date");
+            Either::B(future::ok(()))
+        }
+    };
+
+    update
+        .and_then(move |()| report.then(|_| Ok(())))
+        .and_then(move |()| {
+            log::info!("Reconciled firmware, shutting down...");
+            shutdown
+        })
+}
+
+/// Loads `target` into the secondary partition, swaps to it and reboots,
+/// reporting progress to the `firmwareUpdate` reported property.
+///
+/// `before_reboot` runs once the `rebooting` state has been reported, right before the device reboots.
+///
+/// Fails without touching the partitions if another operation on the updater is already in progress.
+fn apply_update<F>(
+    updater: Arc<Mutex<Updater>>,
+    report_twin_state_handle: Arc<azure_iot_mqtt::ReportTwinStateHandle>,
+    target: FirmwareTarget,
+    before_reboot: F,
+) -> impl Future<Item = (), Error = ()>
+where
+    F: Future<Item = (), Error = Error>,
+{
+    log::info!(
+        "Updating to firmware {} from {}",
+        target.target_version,
+        target.url
+    );
+
+    let target_version = target.target_version;
+    let report = move |state: &str, message: Option<String>| {
+        let update = json!({
+            "targetVersion": target_version,
+            "state": state,
+            "message": message,
+        });
+        report_twin_state_handle
+            .report_twin_state(azure_iot_mqtt::ReportTwinStateRequest::Patch(
+                vec![("firmwareUpdate".to_string(), update)]
+                    .into_iter()
+                    .collect(),
+            ))
+            .then(|result| -> Result<(), Error> {
+                if let Err(err) = result {
+                    log::warn!("couldn't report firmware update state to twin: {}", err);
+                }
+                Ok(())
+            })
+    };
+
+    let url = target.url;
+    let sha256 = target.sha256;
+    let load = {
+        let updater = updater.clone();
+        move |()| {
+            updater
+                .lock()
+                .expect("updater mutex poisoned")
+                .load(url, sha256, false)
+        }
+    };
+    let swap = {
+        let updater = updater.clone();
+        let report = report.clone();
+        move |_| {
+            report("swapping", None)
+                .and_then(move |()| updater.lock().expect("updater mutex poisoned").swap())
+        }
+    };
+    let guard = updater
+        .lock()
+        .expect("updater mutex poisoned")
+        .begin_operation();
+    let reboot = {
+        let report = report.clone();
+        move |()| {
+            report("rebooting", None)
+                .and_then(move |()| before_reboot)
+                .and_then(move |()| updater.lock().expect("updater mutex poisoned").reboot())
+        }
+    };
+    let update = {
+        let report = report.clone();
+        move |guard: OperationGuard| {
+            guard.until(
+                report("downloading", None)
+                    .and_then(load)
+                    .and_then(swap)
+                    .and_then(reboot),
+            )
+        }
+    };
+
+    future::result(guard)
+        .and_then(update)
+        .or_else(move |err| {
+            log::error!("Firmware update failed: {}", err);
+            report("failed", Some(err.to_string()))
+        })
+        .then(|_| Ok(()))
+}
@@ -1,26 +1,39 @@
+use std::cmp;
+use std::collections::BTreeMap;
 use std::fs;
-use std::io::Write;
+use std::io::{self, Seek, SeekFrom, Write};
 use std::mem;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::str;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use failure::Fail;
-use futures::future::IntoFuture;
+use futures::future::{self, Either, IntoFuture, Loop};
+use futures::sync::oneshot;
 use futures::{Future, Stream};
 use log;
+use openssl::sha::Sha256;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
 use reqwest::r#async::{Client, Decoder};
-use reqwest::IntoUrl;
+use reqwest::{IntoUrl, RedirectPolicy, StatusCode};
 use serde_derive::{Deserialize, Serialize};
+use tokio::timer::Delay;
 use tokio_fs::file::File;
 use tokio_process::CommandExt;
+use url::Url;
 
-use crate::error::{Error, ErrorKind};
+use crate::error::{EnvReadError, Error};
 
-#[derive(Deserialize, Serialize)]
+#[derive(Clone, Deserialize, Serialize)]
 pub struct Device {
     path: PathBuf,
     num: i8,
     partition: i8,
+    /// The size of the partition in bytes. If not set, the size of the block device at `path` is used.
+    #[serde(default)]
+    size: Option<u64>,
 }
 
 impl Device {
@@ -29,88 +42,1410 @@ impl Device {
             path: path.into(),
             num,
             partition,
+            size: None,
+        }
+    }
+
+    /// Sets the size of the partition in bytes. If `None`, the size of the block device at `path` is used.
+    pub fn set_size(&mut self, size: Option<u64>) {
+        self.size = size;
+    }
+
+    /// The number of bytes that can be written to this device. `file` must be this device, and is left at the start.
+    fn capacity(&self, file: &mut fs::File) -> io::Result<u64> {
+        match self.size {
+            Some(size) => Ok(size),
+            None => {
+                let size = file.seek(SeekFrom::End(0))?;
+                file.seek(SeekFrom::Start(0))?;
+                Ok(size)
+            }
+        }
+    }
+}
+
+/// The bootloader environment that selects the partition to boot from
+#[derive(Deserialize, Serialize)]
+pub struct BootEnv {
+    printenv: PathBuf,
+    setenv: PathBuf,
+    var_name: String,
+}
+
+impl BootEnv {
+    pub fn new<P: Into<PathBuf>, S: Into<PathBuf>, V: Into<String>>(
+        printenv: P,
+        setenv: S,
+        var_name: V,
+    ) -> Self {
+        BootEnv {
+            printenv: printenv.into(),
+            setenv: setenv.into(),
+            var_name: var_name.into(),
+        }
+    }
+
+    /// Reads the partition number the bootloader is configured to boot from.
+    ///
+    /// Expects the `printenv` tool to print the variable as `name=value`, like u-boot's `fw_printenv` does.
+    pub fn read_partition(&self) -> Result<i8, Error> {
+        let env_read = |err| Error::EnvRead {
+            var_name: self.var_name.clone(),
+            err,
+        };
+
+        let output = Command::new(&self.printenv)
+            .arg(&self.var_name)
+            .output()
+            .map_err(|err| {
+                env_read(EnvReadError::Spawn {
+                    printenv: self.printenv.clone(),
+                    err,
+                })
+            })?;
+
+        if !output.status.success() {
+            return Err(env_read(EnvReadError::ExitStatus {
+                printenv: self.printenv.clone(),
+                status: output.status,
+                stderr: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            }));
+        }
+
+        let stdout =
+            str::from_utf8(&output.stdout).map_err(|err| env_read(EnvReadError::Utf8(err)))?;
+        let prefix = format!("{}=", self.var_name);
+        let stdout = stdout.trim();
+        if !stdout.starts_with(&prefix) {
+            return Err(env_read(EnvReadError::UnexpectedOutput(stdout.to_string())));
+        }
+
+        stdout[prefix.len()..]
+            .parse()
+            .map_err(|err| env_read(EnvReadError::Parse(err)))
+    }
+}
+
+/// The operations of the `Updater` that act on the device itself, so that they can be replaced by fakes in tests
+pub trait Platform: Send + Sync {
+    fn reboot(&self) -> Box<dyn Future<Item = (), Error = io::Error> + Send>;
+
+    /// Configures the bootloader to boot from `partition` from the next reboot on
+    fn set_boot_partition(
+        &self,
+        boot_env: &BootEnv,
+        partition: i8,
+    ) -> Box<dyn Future<Item = (), Error = io::Error> + Send>;
+
+    /// Opens `device`, and returns the number of bytes that can be written to it. If `write` is set,
+    /// also returns a writer that writes to the device from its start.
+    fn open_device(
+        &self,
+        device: &Device,
+        write: bool,
+    ) -> io::Result<(u64, Option<Box<dyn Write + Send>>)>;
+}
+
+/// The `Platform` of a real device. Runs `/sbin/reboot` and the bootloader environment tools, and writes to the block devices.
+pub struct LinuxPlatform;
+
+impl Platform for LinuxPlatform {
+    fn reboot(&self) -> Box<dyn Future<Item = (), Error = io::Error> + Send> {
+        Box::new(
+            Command::new("/sbin/reboot")
+                .status_async()
+                .into_future()
+                .and_then(|child| {
+                    log::info!("Rebooting...");
+                    child.map(|status| {
+                        log::info!("reboot finished with status {}", status);
+                    })
+                }),
+        )
+    }
+
+    fn set_boot_partition(
+        &self,
+        boot_env: &BootEnv,
+        partition: i8,
+    ) -> Box<dyn Future<Item = (), Error = io::Error> + Send> {
+        Box::new(
+            Command::new(&boot_env.setenv)
+                .arg(&boot_env.var_name)
+                .arg(format!("{}", partition))
+                .status_async()
+                .into_future()
+                .and_then(|child| {
+                    log::info!("Swapping partitions...");
+                    child.map(|status| {
+                        log::info!("swap finished with status {}", status);
+                    })
+                }),
+        )
+    }
+
+    fn open_device(
+        &self,
+        device: &Device,
+        write: bool,
+    ) -> io::Result<(u64, Option<Box<dyn Write + Send>>)> {
+        let mut file = fs::OpenOptions::new()
+            .read(true)
+            .write(write)
+            .open(&device.path)?;
+        let capacity = device.capacity(&mut file)?;
+        let writer: Option<Box<dyn Write + Send>> = if write {
+            Some(Box::new(File::from_std(file)))
+        } else {
+            None
+        };
+        Ok((capacity, writer))
+    }
+}
+
+/// The response to a `Downloader::get`
+pub struct DownloadResponse {
+    pub status: StatusCode,
+    pub content_length: Option<u64>,
+    pub body: Box<dyn Stream<Item = Vec<u8>, Error = reqwest::Error> + Send>,
+}
+
+/// Downloads images for the `Updater`, so that it can be replaced by a fake in tests
+pub trait Downloader: Send + Sync {
+    /// Sends a GET request for `url`, and resolves to the response once its headers have been received
+    fn get(&self, url: Url) -> Box<dyn Future<Item = DownloadResponse, Error = Error> + Send>;
+}
+
+/// How the `HttpDownloader` requests images
+#[derive(Clone)]
+pub struct DownloadOptions {
+    /// Headers sent with every request, eg `Authorization` for servers that don't take the credentials in the URL
+    pub headers: BTreeMap<String, String>,
+    /// The maximum number of redirects followed for a single request
+    pub max_redirects: usize,
+    /// Whether images may be downloaded over plain HTTP. Otherwise both the image URL and the URLs it redirects to must use HTTPS.
+    pub allow_http: bool,
+}
+
+impl Default for DownloadOptions {
+    fn default() -> Self {
+        DownloadOptions {
+            headers: Default::default(),
+            max_redirects: 10,
+            allow_http: false,
+        }
+    }
+}
+
+/// The `Downloader` that downloads images over HTTPS, or plain HTTP if allowed.
+///
+/// The image URL is requested exactly as it's given, so signed URLs such as Azure Blob Storage SAS URLs keep their query string.
+pub struct HttpDownloader {
+    client: Client,
+    allow_http: bool,
+}
+
+impl HttpDownloader {
+    pub fn new(options: &DownloadOptions) -> Result<Self, Error> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in &options.headers {
+            match (
+                HeaderName::from_bytes(name.as_bytes()),
+                HeaderValue::from_str(value),
+            ) {
+                (Ok(name), Ok(value)) => {
+                    headers.insert(name, value);
+                }
+                _ => return Err(Error::InvalidHeader(name.clone())),
+            }
+        }
+
+        let max_redirects = options.max_redirects;
+        let allow_http = options.allow_http;
+        let redirect_policy = RedirectPolicy::custom(move |attempt| {
+            if attempt.previous().len() > max_redirects {
+                attempt.too_many_redirects()
+            } else if !allow_http && attempt.url().scheme() != "https" {
+                log::warn!(
+                    "Not following redirect to {}, which is not HTTPS",
+                    attempt.url()
+                );
+                attempt.stop()
+            } else {
+                attempt.follow()
+            }
+        });
+
+        let client = Client::builder()
+            .default_headers(headers)
+            .redirect(redirect_policy)
+            .build()
+            .map_err(Error::DownloadClient)?;
+        Ok(HttpDownloader { client, allow_http })
+    }
+}
+
+impl Downloader for HttpDownloader {
+    fn get(&self, url: Url) -> Box<dyn Future<Item = DownloadResponse, Error = Error> + Send> {
+        if !self.allow_http && url.scheme() != "https" {
+            return Box::new(future::err(Error::InsecureUrl(url.to_string())));
+        }
+
+        let url_string = url.to_string();
+        Box::new(
+            self.client
+                .get(url)
+                .send()
+                .map(|mut res| {
+                    let body = mem::replace(res.body_mut(), Decoder::empty());
+                    DownloadResponse {
+                        status: res.status(),
+                        content_length: res.content_length(),
+                        body: Box::new(body.map(|chunk| chunk.to_vec())),
+                    }
+                })
+                .map_err(move |err| Error::Download {
+                    url: url_string,
+                    offset: 0,
+                    err,
+                }),
+        )
+    }
+}
+
+fn default_platform() -> Arc<dyn Platform> {
+    Arc::new(LinuxPlatform)
+}
+
+fn default_downloader() -> Arc<dyn Downloader> {
+    Arc::new(
+        HttpDownloader::new(&DownloadOptions::default())
+            .expect("cannot fail to create downloader without any headers"),
+    )
+}
+
+/// How `load` retries failed downloads
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RetryPolicy {
+    /// The maximum number of download attempts, including the first one
+    pub max_attempts: u32,
+    /// The delay before the first retry. Doubles after every failed attempt, up to `max_back_off`.
+    pub initial_back_off: Duration,
+    pub max_back_off: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 5,
+            initial_back_off: Duration::from_secs(1),
+            max_back_off: Duration::from_secs(60),
+        }
+    }
+}
+
+/// The outcome of the most recent `load`
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum LoadStatus {
+    InProgress { url: String, attempt: u32 },
+    Succeeded { url: String, sha256: String },
+    Failed { url: String, message: String },
+    Cancelled { url: String },
+}
+
+/// A snapshot of the updater's state, as reported by the `status` direct method
+#[derive(Debug, Serialize)]
+pub struct Status {
+    /// The partition the device is currently running from
+    active_partition: i8,
+    /// The partition that `load` writes to
+    secondary_partition: i8,
+    /// Whether the partitions have been swapped, so the next reboot will boot from a different partition
+    swap_pending: bool,
+    /// The partition that a load was started on but did not finish, if any. Swapping to it is refused.
+    incomplete_partition: Option<i8>,
+    last_load: Option<LoadStatus>,
+}
+
+/// The part of the `Updater`'s state that is persisted across reboots
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+struct PersistentState {
+    /// The partition the agent was running from when the state was last saved
+    booted_partition: Option<i8>,
+    incomplete_partition: Option<i8>,
+    last_load: Option<LoadStatus>,
+}
+
+/// Shares the `PersistentState` between the `Updater` and the futures it returns,
+/// and saves it to the state file, if any, whenever it changes.
+#[derive(Clone, Default)]
+struct StateStore {
+    state: Arc<Mutex<PersistentState>>,
+    path: Option<Arc<PathBuf>>,
+}
+
+impl StateStore {
+    fn read<T>(&self, f: impl FnOnce(&PersistentState) -> T) -> T {
+        f(&self.state.lock().expect("updater state mutex poisoned"))
+    }
+
+    /// Failing to save the state is logged rather than returned, since it shouldn't fail the operation that changed it.
+    fn update(&self, f: impl FnOnce(&mut PersistentState)) {
+        let mut state = self.state.lock().expect("updater state mutex poisoned");
+        f(&mut state);
+        if let Some(path) = &self.path {
+            if let Err(err) = save_state(path, &state) {
+                log::warn!(
+                    "could not save updater state to {}: {}",
+                    path.display(),
+                    err
+                );
+            }
         }
     }
 }
 
+/// Writes `state` to a temporary file next to `path` and then renames it over `path`,
+/// so that a crash or power loss leaves either the old or the new state behind.
+fn save_state(path: &Path, state: &PersistentState) -> io::Result<()> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut file = fs::File::create(&tmp_path)?;
+    serde_json::to_writer(&mut file, state)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)
+}
+
 #[derive(Deserialize, Serialize)]
 pub struct Updater {
     primary: Device,
     secondary: Device,
+    boot_env: BootEnv,
+    booted_partition: i8,
+    #[serde(default)]
+    retry: RetryPolicy,
+    #[serde(skip)]
+    state: StateStore,
+    #[serde(skip)]
+    cancel_requested: Arc<AtomicBool>,
+    /// Set while a load is in progress, including one that only verifies the image and so doesn't show up in `last_load`
+    #[serde(skip)]
+    loading: Arc<AtomicBool>,
+    #[serde(skip)]
+    operations: Arc<Mutex<Operations>>,
+    #[serde(skip, default = "default_platform")]
+    platform: Arc<dyn Platform>,
+    #[serde(skip, default = "default_downloader")]
+    downloader: Arc<dyn Downloader>,
+}
+
+/// Tracks the operation on the `Updater` that's in progress, if any. See `Updater::begin_operation`
+#[derive(Default)]
+struct Operations {
+    busy: bool,
+    /// Set once the agent is shutting down, after which no new operations can begin
+    stopped: bool,
+    /// Notified when the operation in progress finishes
+    idle_waiters: Vec<oneshot::Sender<()>>,
+}
+
+/// Marks an operation on the `Updater` as in progress until it's dropped. See `Updater::begin_operation`
+pub struct OperationGuard(Arc<Mutex<Operations>>);
+
+impl OperationGuard {
+    /// Keeps the operation marked as in progress until `f` completes
+    pub fn until<F: Future>(self, f: F) -> impl Future<Item = F::Item, Error = F::Error> {
+        f.then(move |result| {
+            drop(self);
+            result
+        })
+    }
+}
+
+impl Drop for OperationGuard {
+    fn drop(&mut self) {
+        let mut operations = self.0.lock().expect("updater operations mutex poisoned");
+        operations.busy = false;
+        for idle_waiter in operations.idle_waiters.drain(..) {
+            let _ = idle_waiter.send(());
+        }
+    }
+}
+
+/// Marks a load as in progress until it's dropped, even if the load future is dropped before it completes
+struct LoadingGuard(Arc<AtomicBool>);
+
+impl Drop for LoadingGuard {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
 }
 
 impl Updater {
-    pub fn new(primary: Device, secondary: Device) -> Self {
-        Updater { primary, secondary }
+    pub fn new(primary: Device, secondary: Device, boot_env: BootEnv) -> Self {
+        let booted_partition = primary.partition;
+        Updater {
+            primary,
+            secondary,
+            boot_env,
+            booted_partition,
+            retry: Default::default(),
+            state: Default::default(),
+            cancel_requested: Default::default(),
+            loading: Default::default(),
+            operations: Default::default(),
+            platform: default_platform(),
+            downloader: default_downloader(),
+        }
+    }
+
+    /// Loads the state persisted at `path` by a previous run, if any, and reconciles it with the partition the device booted from.
+    /// The state is saved to `path` whenever it changes from now on, even if loading it fails.
+    ///
+    /// A load that was in progress when the previous run stopped is recorded as failed.
+    pub fn load_state<P: Into<PathBuf>>(&mut self, path: P) -> Result<(), Error> {
+        let path = path.into();
+        self.state.path = Some(Arc::new(path.clone()));
+
+        let mut state: PersistentState = match fs::read(&path) {
+            Ok(contents) => serde_json::from_slice(&contents).map_err(|err| Error::ParseState {
+                path: path.clone(),
+                err,
+            })?,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Default::default(),
+            Err(err) => return Err(Error::ReadState { path, err }),
+        };
+
+        if let Some(previous) = state.booted_partition {
+            if previous != self.booted_partition {
+                log::info!(
+                    "Booted from partition {}, previously running from partition {}",
+                    self.booted_partition,
+                    previous
+                );
+            }
+        }
+        state.booted_partition = Some(self.booted_partition);
+
+        if state.incomplete_partition == Some(self.booted_partition) {
+            log::warn!(
+                "Booted from partition {} even though it holds an incomplete image",
+                self.booted_partition
+            );
+            state.incomplete_partition = None;
+        }
+
+        if let Some(LoadStatus::InProgress { url, .. }) = &state.last_load {
+            state.last_load = Some(LoadStatus::Failed {
+                url: url.clone(),
+                message: "interrupted by a restart of the agent".to_string(),
+            });
+        }
+
+        self.state.update(move |current| *current = state);
+        Ok(())
+    }
+
+    /// Marks an operation that reboots, swaps or writes to the partitions as in progress, so that only one runs at a time.
+    /// Fails with `Error::Busy` if another one is already in progress, or with `Error::ShuttingDown` once `stop_operations` has been called.
+    ///
+    /// The operation is in progress until the returned guard is dropped.
+    pub fn begin_operation(&self) -> Result<OperationGuard, Error> {
+        let mut operations = self
+            .operations
+            .lock()
+            .expect("updater operations mutex poisoned");
+        if operations.stopped {
+            Err(Error::ShuttingDown)
+        } else if operations.busy {
+            Err(Error::Busy)
+        } else {
+            operations.busy = true;
+            Ok(OperationGuard(self.operations.clone()))
+        }
+    }
+
+    /// Prevents any new operations from beginning, and resolves once the operation in progress, if any, has finished,
+    /// so that the agent can shut down without abandoning a swap or a write to a partition halfway.
+    ///
+    /// A load in progress is cancelled, since the partial image it leaves behind is marked as incomplete and can't be booted.
+    pub fn stop_operations(&self) -> impl Future<Item = (), Error = Error> {
+        let mut operations = self
+            .operations
+            .lock()
+            .expect("updater operations mutex poisoned");
+        operations.stopped = true;
+        if !operations.busy {
+            return Either::A(future::ok(()));
+        }
+
+        if self.cancel_load() {
+            log::info!("Cancelling the load in progress before shutting down...");
+        } else {
+            log::info!("Waiting for the operation in progress to finish before shutting down...");
+        }
+        let (idle_send, idle_recv) = oneshot::channel();
+        operations.idle_waiters.push(idle_send);
+        Either::B(idle_recv.then(|_| Ok(())))
+    }
+
+    pub fn status(&self) -> Status {
+        Status {
+            active_partition: self.booted_partition,
+            secondary_partition: self.secondary.partition,
+            swap_pending: self.primary.partition != self.booted_partition,
+            incomplete_partition: self.state.read(|state| state.incomplete_partition),
+            last_load: self.state.read(|state| state.last_load.clone()),
+        }
     }
 
     pub fn reboot(&self) -> impl Future<Item = (), Error = Error> {
-        Command::new("/sbin/reboot")
-            .status_async()
-            .map_err(|e| e.context(ErrorKind::Reboot))
-            .into_future()
-            .and_then(|child| {
-                log::info!("Rebooting...");
-                child
-                    .map(|status| {
-                        log::info!("reboot finished with status {}", status);
-                    })
-                    .map_err(|e| e.context(ErrorKind::Reboot))
-            })
-            .map_err(|e| e.context(ErrorKind::Reboot).into())
+        self.platform.reboot().map_err(Error::Reboot)
     }
 
     pub fn swap(&mut self) -> impl Future<Item = (), Error = Error> {
         let partition = self.secondary.partition;
+        if self.state.read(|state| state.incomplete_partition) == Some(partition) {
+            return Either::A(future::err(Error::IncompleteImage(partition)));
+        }
+
         std::mem::swap(&mut self.primary, &mut self.secondary);
 
-        Command::new("/sbin/fw_setenv")
-            .arg("ota_boot_partition")
-            .arg(format!("{}", partition))
-            .status_async()
-            .map_err(|e| e.context(ErrorKind::Swap))
-            .into_future()
-            .and_then(|child| {
-                log::info!("Swapping partitions...");
-                child
-                    .map(|status| {
-                        log::info!("swap finished with status {}", status);
-                    })
-                    .map_err(|e| e.context(ErrorKind::Swap))
+        Either::B(
+            self.platform
+                .set_boot_partition(&self.boot_env, partition)
+                .map_err(move |err| Error::Swap { partition, err }),
+        )
+    }
+
+    /// Requests that the load in progress, if any, is stopped. Returns whether a load was in progress.
+    ///
+    /// The cancelled load fails with `Error::Cancelled`, and the partial image it leaves behind can't be swapped to.
+    pub fn cancel_load(&self) -> bool {
+        let in_progress = self.loading.load(Ordering::SeqCst);
+        if in_progress {
+            self.cancel_requested.store(true, Ordering::SeqCst);
+        }
+        in_progress
+    }
+
+    pub fn set_retry_policy(&mut self, retry: RetryPolicy) {
+        self.retry = retry;
+    }
+
+    /// Sets how images are requested. Fails if one of the headers has an invalid name or value.
+    pub fn set_download_options(&mut self, options: &DownloadOptions) -> Result<(), Error> {
+        self.downloader = Arc::new(HttpDownloader::new(options)?);
+        Ok(())
+    }
+
+    /// Downloads the image at `url` into the secondary device, and resolves to the image's SHA-256 digest as a hex string.
+    ///
+    /// If `sha256` is set, the load fails if the downloaded image has a different digest.
+    ///
+    /// If `verify_only` is set, the image is downloaded and hashed but not written to the device,
+    /// and the result is not recorded as the last load.
+    pub fn load<I: IntoUrl>(
+        &self,
+        url: I,
+        sha256: Option<String>,
+        verify_only: bool,
+    ) -> impl Future<Item = String, Error = Error> {
+        let u = match url.into_url() {
+            Ok(u) => u,
+            Err(err) => return Either::A(future::err(Error::InvalidUrl(err))),
+        };
+        if verify_only {
+            log::info!("Verifying {}", u);
+        } else {
+            log::info!("Loading {} into {:?}", u, self.secondary.path);
+        }
+        let url = u.to_string();
+        let cancel_requested = self.cancel_requested.clone();
+        cancel_requested.store(false, Ordering::SeqCst);
+        self.loading.store(true, Ordering::SeqCst);
+        let loading = LoadingGuard(self.loading.clone());
+        let state = if verify_only {
+            None
+        } else {
+            let partition = self.secondary.partition;
+            self.state
+                .update(|state| state.incomplete_partition = Some(partition));
+            Some(self.state.clone())
+        };
+        let set_in_progress = {
+            let state = state.clone();
+            let url = url.clone();
+            move |attempt| {
+                if let Some(state) = &state {
+                    state.update(|state| {
+                        state.last_load = Some(LoadStatus::InProgress {
+                            url: url.clone(),
+                            attempt,
+                        })
+                    });
+                }
+            }
+        };
+        set_in_progress(1);
+
+        let platform = self.platform.clone();
+        let downloader = self.downloader.clone();
+        let device = self.secondary.clone();
+        let retry = self.retry.clone();
+        let loop_url = url.clone();
+        let checksum_url = url.clone();
+        let load = future::loop_fn((1, retry.initial_back_off), move |(attempt, back_off)| {
+            let set_in_progress = set_in_progress.clone();
+            let url = loop_url.clone();
+            let max_back_off = retry.max_back_off;
+            let max_attempts = retry.max_attempts;
+            let cancel_requested = cancel_requested.clone();
+            download(
+                &*platform,
+                &*downloader,
+                u.clone(),
+                &device,
+                verify_only,
+                cancel_requested.clone(),
+            )
+            .then(move |result| match result {
+                Ok(digest) => Either::A(future::ok(Loop::Break(digest))),
+                Err(e) if attempt < max_attempts && e.is_retryable() => {
+                    log::warn!(
+                        "Download attempt {} of {} failed, retrying in {:?}: {}",
+                        attempt,
+                        max_attempts,
+                        back_off,
+                        e
+                    );
+                    set_in_progress(attempt + 1);
+                    Either::B(
+                        Delay::new(Instant::now() + back_off)
+                            .map_err(Error::DownloadTimer)
+                            .and_then(move |()| {
+                                if cancel_requested.load(Ordering::SeqCst) {
+                                    return Err(Error::Cancelled { url });
+                                }
+
+                                Ok(Loop::Continue((
+                                    attempt + 1,
+                                    cmp::min(back_off * 2, max_back_off),
+                                )))
+                            }),
+                    )
+                }
+                Err(e) => Either::A(future::err(e)),
             })
-            .map_err(|e| e.context(ErrorKind::Swap).into())
+        })
+        .and_then(move |digest| match sha256 {
+            Some(ref expected) if !expected.eq_ignore_ascii_case(&digest) => {
+                Err(Error::ChecksumMismatch {
+                    url: checksum_url,
+                    expected: expected.clone(),
+                    actual: digest,
+                })
+            }
+            _ => Ok(digest),
+        })
+        .then(move |result: Result<String, Error>| {
+            drop(loading);
+            if let Some(state) = state {
+                let status = match &result {
+                    Ok(digest) => LoadStatus::Succeeded {
+                        url,
+                        sha256: digest.clone(),
+                    },
+                    Err(e) if e.is_cancelled() => LoadStatus::Cancelled { url },
+                    Err(e) => LoadStatus::Failed {
+                        url,
+                        message: e.to_string(),
+                    },
+                };
+                let succeeded = result.is_ok();
+                state.update(move |state| {
+                    state.last_load = Some(status);
+                    if succeeded {
+                        state.incomplete_partition = None;
+                    }
+                });
+            }
+            result
+        });
+        Either::B(load)
     }
+}
 
-    pub fn load<I: IntoUrl>(&self, url: I) -> impl Future<Item = (), Error = Error> {
-        let u = url.into_url().unwrap();
-        log::info!("Loading {} into {:?}", u, self.secondary.path);
-        let device = fs::OpenOptions::new()
-            .read(true)
-            .write(true)
-            .open(&self.secondary.path)
-            .unwrap();
-        let mut file = File::from_std(device);
-        Client::new()
-            .get(u)
-            .send()
-            .map_err(|e| e.context(ErrorKind::Download))
-            .and_then(move |mut res| {
-                log::info!("Download status: {}", res.status());
-                let mut chunks = 0;
-                let mut bytes = 0;
-                let body = mem::replace(res.body_mut(), Decoder::empty());
-                body.map_err(|e| e.context(ErrorKind::Download))
-                    .for_each(move |chunk| {
-                        bytes = bytes + chunk.len();
-                        chunks = chunks + 1;
+/// Makes a single attempt at downloading the image at `url` into `device`, starting from the beginning of the device.
+/// Resolves to the image's SHA-256 digest as a hex string.
+///
+/// If `verify_only` is set, the image is not written to the device.
+/// The download stops with `Error::Cancelled` before the next chunk once `cancel_requested` is set.
+fn download(
+    platform: &dyn Platform,
+    downloader: &dyn Downloader,
+    url: Url,
+    device: &Device,
+    verify_only: bool,
+    cancel_requested: Arc<AtomicBool>,
+) -> impl Future<Item = String, Error = Error> {
+    let (capacity, file) = match platform.open_device(device, !verify_only) {
+        Ok((capacity, file)) => (capacity, file),
+        Err(err) => {
+            return Either::A(future::err(Error::OpenDevice {
+                path: device.path.clone(),
+                err,
+            }))
+        }
+    };
+    let path = device.path.clone();
+    let url_string = url.to_string();
+
+    Either::B(downloader.get(url).and_then(move |res| {
+        let url = url_string;
+        log::info!("Download status: {}", res.status);
+        if !res.status.is_success() {
+            return Either::A(future::err(Error::DownloadStatus {
+                url,
+                status: res.status.as_u16(),
+            }));
+        }
+
+        if let Some(len) = res.content_length {
+            if len > capacity {
+                return Either::A(future::err(Error::ImageTooLarge {
+                    url,
+                    path,
+                    size: len,
+                    max: capacity,
+                }));
+            }
+        }
+
+        let mut chunks = 0;
+        let mut bytes: u64 = 0;
+        Either::B(
+            // Body errors are passed through to the fold so that they can be reported with the offset they happened at
+            res.body
+                .then(Ok::<_, Error>)
+                .fold(
+                    (Sha256::new(), file),
+                    move |(mut hasher, mut file), chunk| {
+                        if cancel_requested.load(Ordering::SeqCst) {
+                            return Err(Error::Cancelled { url: url.clone() });
+                        }
+
+                        let offset = bytes;
+                        let chunk = chunk.map_err(|err| Error::Download {
+                            url: url.clone(),
+                            offset,
+                            err,
+                        })?;
+
+                        // Content-Length is not always present, or may be wrong,
+                        // so also make sure we never write past the end of the partition
+                        bytes += chunk.len() as u64;
+                        if bytes > capacity {
+                            return Err(Error::ImageTooLarge {
+                                url: url.clone(),
+                                path: path.clone(),
+                                size: bytes,
+                                max: capacity,
+                            });
+                        }
+
+                        chunks += 1;
                         if chunks % 100 == 0 {
                             log::info!("Progress - {}", bytes);
                         }
-                        file.write_all(&chunk)
-                            .map_err(|e| e.context(ErrorKind::Download))
-                    })
-                    .map_err(|e| e.context(ErrorKind::Download))
-            })
-            .map_err(|e| e.context(ErrorKind::Download).into())
+
+                        hasher.update(&chunk);
+                        if let Some(file) = &mut file {
+                            file.write_all(&chunk).map_err(|err| Error::Write {
+                                path: path.clone(),
+                                offset,
+                                err,
+                            })?;
+                        }
+                        Ok((hasher, file))
+                    },
+                )
+                .map(|(hasher, _)| {
+                    hasher
+                        .finish()
+                        .iter()
+                        .map(|b| format!("{:02x}", b))
+                        .collect()
+                }),
+        )
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::runtime::current_thread::Runtime;
+
+    /// Records the side effects that the `Updater` has on the device instead of performing them
+    #[derive(Default)]
+    struct FakePlatform {
+        reboots: Mutex<u32>,
+        boot_partitions: Mutex<Vec<i8>>,
+        written: Mutex<Vec<(PathBuf, DeviceContents)>>,
+    }
+
+    type DeviceContents = Arc<Mutex<Vec<u8>>>;
+
+    impl FakePlatform {
+        fn written(&self, path: &str) -> Vec<u8> {
+            let written = self.written.lock().unwrap();
+            let (_, contents) = written
+                .iter()
+                .rev()
+                .find(|(written_path, _)| written_path == Path::new(path))
+                .expect("device was not written to");
+            let contents = contents.lock().unwrap();
+            contents.clone()
+        }
+    }
+
+    impl Platform for FakePlatform {
+        fn reboot(&self) -> Box<dyn Future<Item = (), Error = io::Error> + Send> {
+            *self.reboots.lock().unwrap() += 1;
+            Box::new(future::ok(()))
+        }
+
+        fn set_boot_partition(
+            &self,
+            _boot_env: &BootEnv,
+            partition: i8,
+        ) -> Box<dyn Future<Item = (), Error = io::Error> + Send> {
+            self.boot_partitions.lock().unwrap().push(partition);
+            Box::new(future::ok(()))
+        }
+
+        fn open_device(
+            &self,
+            device: &Device,
+            write: bool,
+        ) -> io::Result<(u64, Option<Box<dyn Write + Send>>)> {
+            let capacity = device.size.unwrap_or(1024);
+            if !write {
+                return Ok((capacity, None));
+            }
+
+            let contents: DeviceContents = Default::default();
+            self.written
+                .lock()
+                .unwrap()
+                .push((device.path.clone(), contents.clone()));
+            Ok((capacity, Some(Box::new(FakeDeviceWriter(contents)))))
+        }
+    }
+
+    struct FakeDeviceWriter(DeviceContents);
+
+    impl Write for FakeDeviceWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Serves each request with the next of the scripted statuses and bodies, each split into the given chunks.
+    /// The last response is repeated once the script runs out.
+    ///
+    /// Records every requested URL, and the updater's last load at the time of the request.
+    struct FakeDownloader {
+        responses: Mutex<Vec<(StatusCode, Vec<Vec<u8>>)>>,
+        state: StateStore,
+        requested: Mutex<Vec<Url>>,
+        last_loads: Mutex<Vec<Option<LoadStatus>>>,
+    }
+
+    impl FakeDownloader {
+        fn new(updater: &Updater, responses: Vec<(StatusCode, Vec<Vec<u8>>)>) -> Self {
+            assert!(!responses.is_empty());
+            FakeDownloader {
+                responses: Mutex::new(responses),
+                state: updater.state.clone(),
+                requested: Default::default(),
+                last_loads: Default::default(),
+            }
+        }
+    }
+
+    impl Downloader for FakeDownloader {
+        fn get(&self, url: Url) -> Box<dyn Future<Item = DownloadResponse, Error = Error> + Send> {
+            self.requested.lock().unwrap().push(url);
+            self.last_loads
+                .lock()
+                .unwrap()
+                .push(self.state.read(|state| state.last_load.clone()));
+
+            let (status, chunks) = {
+                let mut responses = self.responses.lock().unwrap();
+                if responses.len() > 1 {
+                    responses.remove(0)
+                } else {
+                    responses[0].clone()
+                }
+            };
+            Box::new(future::ok(DownloadResponse {
+                status,
+                content_length: Some(chunks.iter().map(|chunk| chunk.len() as u64).sum()),
+                body: Box::new(futures::stream::iter_ok(chunks)),
+            }))
+        }
+    }
+
+    const OTHER_IMAGE_SHA256: &str =
+        "f6e8a6e4a3f57a2cf4c33ec91ab2316fd7d2e1ed1e2e1ab2d23fd11d5e4fbe3e";
+
+    fn updater(chunks: Vec<Vec<u8>>) -> (Updater, Arc<FakePlatform>) {
+        let platform: Arc<FakePlatform> = Default::default();
+        let mut updater = Updater::new(
+            Device::new("/dev/primary", 0, 2),
+            Device::new("/dev/secondary", 0, 3),
+            BootEnv::new("printenv", "setenv", "mmcpart"),
+        );
+        updater.platform = platform.clone();
+        updater.downloader = Arc::new(FakeDownloader::new(
+            &updater,
+            vec![(StatusCode::OK, chunks)],
+        ));
+        updater.set_retry_policy(RetryPolicy {
+            max_attempts: 1,
+            ..Default::default()
+        });
+        (updater, platform)
+    }
+
+    fn image_digest(image: &[u8]) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(image);
+        hasher
+            .finish()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    #[test]
+    fn swap_sets_secondary_partition() {
+        let (mut updater, platform) = updater(vec![]);
+
+        Runtime::new().unwrap().block_on(updater.swap()).unwrap();
+        assert_eq!(*platform.boot_partitions.lock().unwrap(), vec![3]);
+        assert!(updater.status().swap_pending);
+
+        // Swapping back selects the partition the device booted from
+        Runtime::new().unwrap().block_on(updater.swap()).unwrap();
+        assert_eq!(*platform.boot_partitions.lock().unwrap(), vec![3, 2]);
+        assert!(!updater.status().swap_pending);
+    }
+
+    #[test]
+    fn load_writes_image_to_secondary() {
+        let (updater, platform) = updater(vec![b"first chunk ".to_vec(), b"second chunk".to_vec()]);
+
+        let digest = Runtime::new()
+            .unwrap()
+            .block_on(updater.load("http://example.com/image", None, false))
+            .unwrap();
+        assert_eq!(digest, image_digest(b"first chunk second chunk"));
+        assert_eq!(
+            platform.written("/dev/secondary"),
+            b"first chunk second chunk"
+        );
+        assert_eq!(updater.status().incomplete_partition, None);
+        match updater.status().last_load {
+            Some(LoadStatus::Succeeded { sha256, .. }) => assert_eq!(sha256, digest),
+            last_load => panic!("unexpected last load {:?}", last_load),
+        }
+    }
+
+    #[test]
+    fn load_with_wrong_checksum_cannot_be_swapped_to() {
+        let (mut updater, platform) = updater(vec![b"image".to_vec()]);
+
+        let result = Runtime::new().unwrap().block_on(updater.load(
+            "http://example.com/image",
+            Some(OTHER_IMAGE_SHA256.to_string()),
+            false,
+        ));
+        match result {
+            Err(Error::ChecksumMismatch {
+                expected, actual, ..
+            }) => {
+                assert_eq!(expected, OTHER_IMAGE_SHA256);
+                assert_eq!(actual, image_digest(b"image"));
+            }
+            result => panic!(
+                "unexpected result {:?}",
+                result.map_err(|err| err.to_string())
+            ),
+        }
+        assert_eq!(updater.status().incomplete_partition, Some(3));
+
+        match Runtime::new().unwrap().block_on(updater.swap()) {
+            Err(Error::IncompleteImage(3)) => (),
+            result => panic!(
+                "unexpected result {:?}",
+                result.map_err(|err| err.to_string())
+            ),
+        }
+        assert!(platform.boot_partitions.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn load_larger_than_partition_size_is_refused() {
+        let (mut updater, platform) = updater(vec![b"image".to_vec()]);
+        updater.secondary.set_size(Some(4));
+
+        let result =
+            Runtime::new()
+                .unwrap()
+                .block_on(updater.load("http://example.com/image", None, false));
+        match result {
+            Err(Error::ImageTooLarge { size, max, .. }) => assert_eq!((size, max), (5, 4)),
+            result => panic!(
+                "unexpected load result {:?}",
+                result.map_err(|err| err.to_string())
+            ),
+        }
+        assert!(platform.written("/dev/secondary").is_empty());
+    }
+
+    #[test]
+    fn verify_only_load_does_not_write() {
+        let (updater, platform) = updater(vec![b"image".to_vec()]);
+
+        let digest = Runtime::new()
+            .unwrap()
+            .block_on(updater.load("http://example.com/image", None, true))
+            .unwrap();
+        assert_eq!(digest, image_digest(b"image"));
+        assert!(platform.written.lock().unwrap().is_empty());
+        assert!(updater.status().last_load.is_none());
+    }
+
+    #[test]
+    fn verify_only_load_can_be_cancelled() {
+        let (updater, platform) = updater(vec![b"image".to_vec()]);
+
+        let load = updater.load("http://example.com/image", None, true);
+        assert!(updater.cancel_load());
+        match Runtime::new().unwrap().block_on(load) {
+            Err(Error::Cancelled { .. }) => (),
+            result => panic!(
+                "unexpected load result {:?}",
+                result.map_err(|err| err.to_string())
+            ),
+        }
+        assert!(!updater.cancel_load());
+        assert!(platform.written.lock().unwrap().is_empty());
+        assert!(updater.status().last_load.is_none());
+    }
+
+    #[test]
+    fn load_requests_sas_url_unchanged() {
+        let (mut updater, _) = updater(vec![]);
+        let downloader = Arc::new(FakeDownloader::new(
+            &updater,
+            vec![(StatusCode::OK, vec![b"image".to_vec()])],
+        ));
+        updater.downloader = downloader.clone();
+
+        let url = "https://account.blob.core.windows.net/images/fw.img?sv=2018-03-28&sr=b&sig=a%2Bb%2Fc%3D&se=2019-03-13T23%3A59%3A59Z&sp=r";
+        Runtime::new()
+            .unwrap()
+            .block_on(updater.load(url, None, false))
+            .unwrap();
+        let requested = downloader.requested.lock().unwrap();
+        assert_eq!(requested.len(), 1);
+        assert_eq!(requested[0].as_str(), url);
+    }
+
+    /// An updater that makes up to three download attempts, getting the scripted responses
+    fn retrying_updater(
+        responses: Vec<(StatusCode, Vec<Vec<u8>>)>,
+    ) -> (Updater, Arc<FakeDownloader>) {
+        let (mut updater, _) = updater(vec![]);
+        let downloader = Arc::new(FakeDownloader::new(&updater, responses));
+        updater.downloader = downloader.clone();
+        updater.set_retry_policy(RetryPolicy {
+            max_attempts: 3,
+            initial_back_off: Duration::from_millis(1),
+            max_back_off: Duration::from_millis(1),
+        });
+        (updater, downloader)
+    }
+
+    #[test]
+    fn load_retries_server_error() {
+        let (updater, downloader) = retrying_updater(vec![
+            (StatusCode::SERVICE_UNAVAILABLE, vec![]),
+            (StatusCode::OK, vec![b"image".to_vec()]),
+        ]);
+
+        let digest = Runtime::new()
+            .unwrap()
+            .block_on(updater.load("http://example.com/image", None, false))
+            .unwrap();
+        assert_eq!(digest, image_digest(b"image"));
+
+        // The second attempt is recorded before it's made
+        let last_loads = downloader.last_loads.lock().unwrap();
+        assert_eq!(last_loads.len(), 2);
+        match &last_loads[1] {
+            Some(LoadStatus::InProgress { attempt: 2, .. }) => (),
+            last_load => panic!("unexpected last load {:?}", last_load),
+        }
+        match updater.status().last_load {
+            Some(LoadStatus::Succeeded { .. }) => (),
+            last_load => panic!("unexpected last load {:?}", last_load),
+        }
+    }
+
+    #[test]
+    fn load_does_not_retry_client_error() {
+        let (updater, downloader) = retrying_updater(vec![(StatusCode::NOT_FOUND, vec![])]);
+
+        match Runtime::new().unwrap().block_on(updater.load(
+            "http://example.com/image",
+            None,
+            false,
+        )) {
+            Err(Error::DownloadStatus { status: 404, .. }) => (),
+            result => panic!(
+                "unexpected result {:?}",
+                result.map_err(|err| err.to_string())
+            ),
+        }
+        assert_eq!(downloader.requested.lock().unwrap().len(), 1);
+        match updater.status().last_load {
+            Some(LoadStatus::Failed { .. }) => (),
+            last_load => panic!("unexpected last load {:?}", last_load),
+        }
+    }
+
+    #[test]
+    fn load_does_not_retry_checksum_mismatch() {
+        let (updater, downloader) =
+            retrying_updater(vec![(StatusCode::OK, vec![b"image".to_vec()])]);
+
+        match Runtime::new().unwrap().block_on(updater.load(
+            "http://example.com/image",
+            Some(OTHER_IMAGE_SHA256.to_string()),
+            false,
+        )) {
+            Err(Error::ChecksumMismatch { .. }) => (),
+            result => panic!(
+                "unexpected result {:?}",
+                result.map_err(|err| err.to_string())
+            ),
+        }
+        assert_eq!(downloader.requested.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn load_rejects_invalid_url() {
+        let (updater, platform) = updater(vec![b"image".to_vec()]);
+
+        match Runtime::new()
+            .unwrap()
+            .block_on(updater.load("not a url", None, false))
+        {
+            Err(Error::InvalidUrl(_)) => (),
+            result => panic!(
+                "unexpected result {:?}",
+                result.map_err(|err| err.to_string())
+            ),
+        }
+        assert!(platform.written.lock().unwrap().is_empty());
+        assert_eq!(updater.status().incomplete_partition, None);
+    }
+
+    /// A fresh directory for a test's state file, removed when it's dropped
+    struct StateDir(PathBuf);
+
+    impl StateDir {
+        fn new(name: &str) -> Self {
+            let path =
+                std::env::temp_dir().join(format!("ota-updater-{}-{}", std::process::id(), name));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            StateDir(path)
+        }
+
+        fn state_path(&self) -> PathBuf {
+            self.0.join("state.json")
+        }
+    }
+
+    impl Drop for StateDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn read_state(path: &Path) -> PersistentState {
+        serde_json::from_slice(&fs::read(path).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn load_state_fails_interrupted_load() {
+        let dir = StateDir::new("interrupted-load");
+        save_state(
+            &dir.state_path(),
+            &PersistentState {
+                booted_partition: Some(2),
+                incomplete_partition: Some(3),
+                last_load: Some(LoadStatus::InProgress {
+                    url: "https://example.com/image".to_string(),
+                    attempt: 2,
+                }),
+            },
+        )
+        .unwrap();
+
+        let (mut updater, _) = updater(vec![]);
+        updater.load_state(dir.state_path()).unwrap();
+
+        let status = updater.status();
+        match status.last_load {
+            Some(LoadStatus::Failed { url, .. }) => assert_eq!(url, "https://example.com/image"),
+            last_load => panic!("unexpected last load {:?}", last_load),
+        }
+        assert_eq!(status.incomplete_partition, Some(3));
+
+        // The reconciled state is saved right away
+        match read_state(&dir.state_path()).last_load {
+            Some(LoadStatus::Failed { .. }) => (),
+            last_load => panic!("unexpected saved last load {:?}", last_load),
+        }
+    }
+
+    #[test]
+    fn load_state_clears_incomplete_booted_partition() {
+        let dir = StateDir::new("incomplete-booted-partition");
+        save_state(
+            &dir.state_path(),
+            &PersistentState {
+                booted_partition: Some(3),
+                incomplete_partition: Some(2),
+                last_load: None,
+            },
+        )
+        .unwrap();
+
+        let (mut updater, _) = updater(vec![]);
+        updater.load_state(dir.state_path()).unwrap();
+        assert_eq!(updater.status().incomplete_partition, None);
+
+        let saved = read_state(&dir.state_path());
+        assert_eq!(saved.booted_partition, Some(2));
+        assert_eq!(saved.incomplete_partition, None);
+    }
+
+    #[test]
+    fn save_state_replaces_state_file() {
+        let dir = StateDir::new("save-state");
+        let path = dir.0.join("nested").join("state.json");
+
+        for partition in &[2, 3] {
+            save_state(
+                &path,
+                &PersistentState {
+                    booted_partition: Some(*partition),
+                    incomplete_partition: None,
+                    last_load: Some(LoadStatus::Succeeded {
+                        url: "https://example.com/image".to_string(),
+                        sha256: image_digest(b"image"),
+                    }),
+                },
+            )
+            .unwrap();
+
+            let saved = read_state(&path);
+            assert_eq!(saved.booted_partition, Some(*partition));
+            match saved.last_load {
+                Some(LoadStatus::Succeeded { sha256, .. }) => {
+                    assert_eq!(sha256, image_digest(b"image"))
+                }
+                last_load => panic!("unexpected saved last load {:?}", last_load),
+            }
+
+            // The state is written to a temporary file that's renamed over the state file
+            assert!(!dir.0.join("nested").join("state.json.tmp").exists());
+        }
+
+        // A state file that's missing is the same as an empty one
+        let (mut updater, _) = updater(vec![]);
+        updater.load_state(dir.0.join("missing.json")).unwrap();
+        assert!(updater.status().last_load.is_none());
+    }
+
+    #[test]
+    fn http_downloader_rejects_plain_http() {
+        let downloader = HttpDownloader::new(&Default::default()).unwrap();
+
+        let url = Url::parse("http://example.com/image").unwrap();
+        match Runtime::new().unwrap().block_on(downloader.get(url)) {
+            Err(Error::InsecureUrl(url)) => assert_eq!(url, "http://example.com/image"),
+            result => panic!(
+                "unexpected result {:?}",
+                result.map(|res| res.status).map_err(|err| err.to_string())
+            ),
+        }
+    }
+
+    #[test]
+    fn http_downloader_rejects_invalid_header() {
+        let mut options = DownloadOptions::default();
+        options
+            .headers
+            .insert("Authorization".to_string(), "line\nbreak".to_string());
+
+        match HttpDownloader::new(&options) {
+            Err(Error::InvalidHeader(name)) => assert_eq!(name, "Authorization"),
+            Err(err) => panic!("unexpected error {}", err),
+            Ok(_) => panic!("invalid header was accepted"),
+        }
+    }
+
+    #[test]
+    fn stop_operations_waits_for_operation_in_progress() {
+        let (updater, _) = updater(vec![]);
+        let mut runtime = Runtime::new().unwrap();
+
+        let guard = updater.begin_operation().unwrap();
+        let mut stop = updater.stop_operations();
+        match updater.begin_operation() {
+            Err(Error::ShuttingDown) => (),
+            Err(err) => panic!("unexpected error {}", err),
+            Ok(_) => panic!("operation began after operations were stopped"),
+        }
+
+        runtime
+            .block_on(future::lazy(|| {
+                assert!(stop.poll().unwrap().is_not_ready());
+                drop(guard);
+                Ok::<_, Error>(())
+            }))
+            .unwrap();
+        runtime.block_on(stop).unwrap();
     }
 }